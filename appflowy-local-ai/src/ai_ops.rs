@@ -1,24 +1,62 @@
+use crate::embedding_ops::{PurgeExpiredParams, PurgeExpiredResponse, SearchResult};
+use crate::file_index::{IndexFileOptions, IndexFileType, IndexProgress};
+use crate::vector_store::unix_timestamp;
 use anyhow::anyhow;
 use appflowy_plugin::core::parser::{DefaultResponseParser, ResponseParser};
-use appflowy_plugin::core::plugin::Plugin;
+use appflowy_plugin::core::plugin::{Plugin, RequestStream, UPLOAD};
+use appflowy_plugin::core::rpc_peer::RequestPriority;
 use appflowy_plugin::error::{PluginError, RemoteError};
 use bytes::Bytes;
+use serde::de::{DeserializeOwned, IgnoredAny};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use serde_json::Value as JsonValue;
 use std::collections::HashMap;
-use std::fmt::Debug;
-use std::sync::Weak;
-use tokio_stream::wrappers::ReceiverStream;
-use tracing::{instrument, trace};
+use std::fmt::{Debug, Formatter};
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::SystemTime;
+use tokio::fs::File;
+use tracing::{error, instrument, trace};
+
+static COMPLETION_ID_COUNTER: AtomicU64 = AtomicU64::new(0);
+static UPLOAD_ID_COUNTER: AtomicU64 = AtomicU64::new(0);
+static IDEMPOTENCY_KEY_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// The top level field of a `handle` request that holds its idempotency key
+const IDEMPOTENCY_KEY: &str = "idempotency_key";
+
+/// Creates a key that identifies one logical request across retries. A plugin that already did
+/// the work of a request with the same key, e.g. before the connection dropped, answers with the
+/// previous result instead of doing it again. Plugins that don't know the field ignore it.
+pub fn new_idempotency_key() -> String {
+  format!(
+    "{}-{}-{}",
+    std::process::id(),
+    unix_timestamp(SystemTime::now()),
+    IDEMPOTENCY_KEY_COUNTER.fetch_add(1, Ordering::Relaxed)
+  )
+}
 
 pub struct AIPluginOperation {
   plugin: Weak<Plugin>,
+  idempotency_key: Option<String>,
 }
 
 impl AIPluginOperation {
   pub fn new(plugin: Weak<Plugin>) -> Self {
-    AIPluginOperation { plugin }
+    AIPluginOperation {
+      plugin,
+      idempotency_key: None,
+    }
+  }
+
+  /// Sends `idempotency_key` with every request of this operation, so requests sent again with
+  /// the same key after a reconnect don't do the work twice, see [new_idempotency_key].
+  pub fn with_idempotency_key(mut self, idempotency_key: &str) -> Self {
+    self.idempotency_key = Some(idempotency_key.to_string());
+    self
   }
 
   fn get_plugin(&self) -> Result<std::sync::Arc<Plugin>, PluginError> {
@@ -39,14 +77,69 @@ impl AIPluginOperation {
       .as_object_mut()
       .unwrap()
       .extend(params.as_object().unwrap().clone());
-    plugin.async_request::<T>("handle", &request).await
+    if let Some(idempotency_key) = &self.idempotency_key {
+      request[IDEMPOTENCY_KEY] = json!(idempotency_key);
+    }
+    plugin.async_request::<T>("handle", &request, None).await
+  }
+
+  /// Sends `params` to `method` and deserializes the `data` of the response.
+  async fn send_typed_request<P, R>(
+    &self,
+    method: &str,
+    chat_id: Option<&str>,
+    params: P,
+  ) -> Result<R, PluginError>
+  where
+    P: Serialize,
+    R: DeserializeOwned + Send + Sync + 'static,
+  {
+    let plugin = self.get_plugin()?;
+    let request = self.handle_request(method, chat_id, params);
+    plugin.typed_request("handle", &request, None).await
+  }
+
+  /// Streams the responses of `method` to `params`, parsed with `P`.
+  fn send_stream_request<P, T>(
+    &self,
+    method: &str,
+    chat_id: Option<&str>,
+    params: T,
+    priority: RequestPriority,
+  ) -> Result<RequestStream<Result<P::ValueType, PluginError>>, PluginError>
+  where
+    P: ResponseParser,
+    T: Serialize,
+  {
+    let plugin = self.get_plugin()?;
+    let request = self.handle_request(method, chat_id, params);
+    let params = serde_json::to_value(&request)
+      .map_err(|err| PluginError::Internal(anyhow!("failed to serialize {}: {}", method, err)))?;
+    match priority {
+      RequestPriority::Interactive => plugin.stream_request::<P>("handle", &params, None),
+      RequestPriority::Background => plugin.background_stream_request::<P>("handle", &params, None),
+    }
+  }
+
+  fn handle_request<'a, P>(
+    &'a self,
+    method: &'a str,
+    chat_id: Option<&'a str>,
+    params: P,
+  ) -> HandleRequest<'a, P> {
+    HandleRequest {
+      method,
+      chat_id,
+      params,
+      idempotency_key: self.idempotency_key.as_deref(),
+    }
   }
 
   pub async fn create_chat(&self, chat_id: &str) -> Result<(), PluginError> {
     self
       .send_request::<DefaultResponseParser>(
         "create_chat",
-        json!({ "chat_id": chat_id, "top_k": 2}),
+        json!({ "chat_id": chat_id, "top_k": 2 }),
       )
       .await
   }
@@ -57,17 +150,29 @@ impl AIPluginOperation {
       .await
   }
 
+  pub async fn update_chat(
+    &self,
+    chat_id: &str,
+    update: ChatSessionUpdate,
+  ) -> Result<(), PluginError> {
+    self
+      .send_typed_request::<_, IgnoredAny>("update_chat", Some(chat_id), update)
+      .await?;
+    Ok(())
+  }
+
   pub async fn send_message(
     &self,
     chat_id: &str,
     message: &str,
     rag_enabled: bool,
   ) -> Result<String, PluginError> {
+    let params = AnswerParams {
+      content: message,
+      rag_enabled,
+    };
     self
-      .send_request::<ChatResponseParser>(
-        "answer",
-        json!({ "chat_id": chat_id, "params": { "content": message, "rag_enabled": rag_enabled } }),
-      )
+      .send_typed_request("answer", Some(chat_id), params)
       .await
   }
 
@@ -77,29 +182,36 @@ impl AIPluginOperation {
     chat_id: &str,
     message: &str,
     metadata: serde_json::Value,
-  ) -> Result<ReceiverStream<Result<Bytes, PluginError>>, PluginError> {
-    let plugin = self.get_plugin()?;
-    let params = json!({
-        "chat_id": chat_id,
-        "method": "stream_answer",
-        "params": { "content": message, "metadata": metadata }
-    });
-    plugin.stream_request::<ChatStreamResponseParser>("handle", &params)
+  ) -> Result<RequestStream<Result<Bytes, PluginError>>, PluginError> {
+    let params = StreamAnswerParams {
+      content: message,
+      metadata,
+    };
+    self.send_stream_request::<ChatStreamResponseParser, _>(
+      "stream_answer",
+      Some(chat_id),
+      params,
+      RequestPriority::Interactive,
+    )
   }
+
   #[instrument(level = "debug", skip(self), err)]
   pub async fn stream_message_v2(
     &self,
     chat_id: &str,
     message: &str,
     metadata: serde_json::Value,
-  ) -> Result<ReceiverStream<Result<serde_json::Value, PluginError>>, PluginError> {
-    let plugin = self.get_plugin()?;
-    let params = json!({
-        "chat_id": chat_id,
-        "method": "stream_answer_v2",
-        "params": { "content": message, "metadata": metadata }
-    });
-    plugin.stream_request::<ChatStreamResponseV2Parser>("handle", &params)
+  ) -> Result<RequestStream<Result<serde_json::Value, PluginError>>, PluginError> {
+    let params = StreamAnswerParams {
+      content: message,
+      metadata,
+    };
+    self.send_stream_request::<ChatStreamResponseV2Parser, _>(
+      "stream_answer_v2",
+      Some(chat_id),
+      params,
+      RequestPriority::Interactive,
+    )
   }
 
   pub async fn get_related_questions(&self, chat_id: &str) -> Result<Vec<String>, PluginError> {
@@ -111,14 +223,24 @@ impl AIPluginOperation {
       .await
   }
 
+  /// Indexes a file, or raw content, into the chat's retrieval store. The plugin reports an
+  /// [IndexProgress] for every step; the stream ends once the file is persisted.
+  ///
+  /// Plugins that report the [UPLOAD] capability can't read host paths, e.g. because they're
+  /// sandboxed, so the file is uploaded with [Plugin::upload] and referred to by `upload_id`.
+  ///
+  /// Requests sent again with the same idempotency key after a reconnect don't index the file
+  /// twice, see [Self::with_idempotency_key].
   #[instrument(level = "debug", skip_all, err)]
   pub async fn index_file(
     &self,
     chat_id: &str,
     file_path: Option<String>,
     file_content: Option<String>,
+    file_type: Option<IndexFileType>,
     metadata: Option<HashMap<String, serde_json::Value>>,
-  ) -> Result<(), PluginError> {
+    options: IndexFileOptions,
+  ) -> Result<RequestStream<Result<IndexProgress, PluginError>>, PluginError> {
     if file_path.is_none() && file_content.is_none() {
       return Err(PluginError::Internal(anyhow!(
         "file_path or content must be provided"
@@ -127,58 +249,455 @@ impl AIPluginOperation {
 
     let mut metadata = metadata.unwrap_or_default();
     metadata.insert("chat_id".to_string(), json!(chat_id));
-    let mut params = json!({ "metadata": [metadata] });
+    let mut params = IndexFileParams {
+      metadata: [metadata],
+      file_path: None,
+      upload_id: None,
+      file_content,
+      file_type: file_type.map(|file_type| file_type.as_str()),
+      pages: options.pages,
+      expires_at: options.expires_at.map(unix_timestamp),
+      password: options.password,
+    };
 
     if let Some(file_path) = file_path {
-      params["file_path"] = json!(file_path);
+      match self.upload_file("index_file", &file_path).await? {
+        Some(upload_id) => params.upload_id = Some(upload_id),
+        None => params.file_path = Some(file_path),
+      }
     }
 
-    if let Some(content) = file_content {
-      params["file_content"] = json!(content);
+    trace!("[AI Plugin] indexing file: {:?}", params);
+    self.send_stream_request::<IndexProgressResponseParser, _>(
+      "index_file",
+      Some(chat_id),
+      params,
+      RequestPriority::Background,
+    )
+  }
+
+  /// Searches everything indexed in the shared vector store, regardless of the chat it was indexed
+  /// for. Only hits whose metadata matches `filter` are returned.
+  #[instrument(level = "debug", skip(self), err)]
+  pub async fn search_workspace(
+    &self,
+    query: &str,
+    filter: HashMap<String, serde_json::Value>,
+  ) -> Result<Vec<SearchResult>, PluginError> {
+    let params = SearchWorkspaceParams { query, filter };
+    self
+      .send_typed_request("search_workspace", None, params)
+      .await
+  }
+
+  /// Runs OCR on the image at `file_path` and returns the recognized text. The image is uploaded
+  /// to plugins that report the [UPLOAD] capability, like in [Self::index_file].
+  #[instrument(level = "debug", skip(self), err)]
+  pub async fn extract_image_text(&self, file_path: &str) -> Result<String, PluginError> {
+    let params = match self.upload_file("ocr_image", file_path).await? {
+      Some(upload_id) => OcrImageParams {
+        file_path: None,
+        upload_id: Some(upload_id),
+      },
+      None => OcrImageParams {
+        file_path: Some(file_path),
+        upload_id: None,
+      },
+    };
+    self.send_typed_request("ocr_image", None, params).await
+  }
+
+  /// Uploads the file at `file_path` to a plugin that reports the [UPLOAD] capability and returns
+  /// the id to refer to it by. Returns `None` for plugins that read host paths themselves.
+  async fn upload_file(
+    &self,
+    method: &str,
+    file_path: &str,
+  ) -> Result<Option<String>, PluginError> {
+    let plugin = self.get_plugin()?;
+    if !plugin.supports(UPLOAD) {
+      return Ok(None);
     }
+    let upload_id = format!(
+      "{}_{}",
+      method,
+      UPLOAD_ID_COUNTER.fetch_add(1, Ordering::Relaxed)
+    );
+    let file = File::open(file_path).await?;
+    plugin.upload(&upload_id, file).await?;
+    Ok(Some(upload_id))
+  }
 
-    trace!("[AI Plugin] indexing file: {:?}", params);
+  #[instrument(level = "debug", skip(self), err)]
+  pub async fn expand_query(&self, query: &str) -> Result<String, PluginError> {
     self
-      .send_request::<DefaultResponseParser>(
-        "index_file",
-        json!({ "chat_id": chat_id, "params": params }),
-      )
+      .send_typed_request("expand_query", None, ExpandQueryParams { query })
       .await
   }
 
+  /// Removes all indexed content whose `expires_at` has passed, returning the number of removed
+  /// documents.
+  #[instrument(level = "debug", skip(self), err)]
+  pub async fn purge_expired(&self) -> Result<u64, PluginError> {
+    let response: PurgeExpiredResponse = self
+      .send_typed_request("purge_expired", None, PurgeExpiredParams::now())
+      .await?;
+    Ok(response.purged)
+  }
+
+  #[instrument(level = "debug", skip(self), err)]
+  pub async fn rerank(
+    &self,
+    query: &str,
+    candidates: Vec<String>,
+  ) -> Result<Vec<RerankedCandidate>, PluginError> {
+    let params = RerankParams { query, candidates };
+    self.send_typed_request("rerank", None, params).await
+  }
+
   #[instrument(level = "debug", skip(self), err)]
   pub async fn complete_text<T: Into<CompleteTextType> + Debug>(
     &self,
     message: &str,
     complete_type: T,
-  ) -> Result<ReceiverStream<Result<Bytes, PluginError>>, PluginError> {
-    let plugin = self.get_plugin()?;
-    let complete_type = complete_type.into() as u8;
-    let params = json!({
-        "method": "complete_text",
-        "params": { "text": message, "type": complete_type }
-    });
-    plugin.stream_request::<ChatStreamResponseParser>("handle", &params)
+    options: CompleteTextOptions,
+  ) -> Result<(RequestStream<Result<Bytes, PluginError>>, CompletionHandle), PluginError> {
+    let completion_id = COMPLETION_ID_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let params = CompleteTextParams {
+      text: message,
+      complete_type: complete_type.into() as u8,
+      completion_id,
+      options,
+    };
+    let stream = self.send_stream_request::<ChatStreamResponseParser, _>(
+      "complete_text",
+      None,
+      params,
+      RequestPriority::Interactive,
+    )?;
+    let handle = CompletionHandle {
+      plugin: self.plugin.clone(),
+      completion_id,
+      cancelled: AtomicBool::new(false),
+      finished: stream.finished_flag(),
+    };
+    Ok((stream, handle))
   }
 
+  /// Checks the text for spelling and grammar mistakes. Unlike
+  /// [CompleteTextType::SpellingAndGrammar], the text is not rewritten; every issue is returned
+  /// with its location so the editor can highlight it.
   #[instrument(level = "debug", skip(self), err)]
-  pub async fn summary_row(&self, row: HashMap<String, String>) -> Result<String, PluginError> {
-    let params = json!({"params": row });
+  pub async fn check_grammar(&self, text: &str) -> Result<Vec<GrammarIssue>, PluginError> {
     self
-      .send_request::<DatabaseSummaryResponseParser>("database_summary", params)
+      .send_typed_request("check_grammar", None, TextParams { text })
       .await
   }
 
+  /// Generates a short title for a document, e.g. to name an untitled page.
+  #[instrument(level = "debug", skip(self), err)]
+  pub async fn generate_title(&self, text: &str) -> Result<String, PluginError> {
+    let title: String = self
+      .send_typed_request("generate_title", None, TextParams { text })
+      .await?;
+    Ok(title.trim().trim_matches('"').to_string())
+  }
+
+  /// Generates a summary of a document with at most `max_words` words.
+  #[instrument(level = "debug", skip(self), err)]
+  pub async fn generate_summary(
+    &self,
+    text: &str,
+    max_words: usize,
+  ) -> Result<String, PluginError> {
+    let params = GenerateSummaryParams { text, max_words };
+    let summary: String = self
+      .send_typed_request("generate_summary", None, params)
+      .await?;
+    Ok(summary.trim().to_string())
+  }
+
+  #[instrument(level = "debug", skip(self), err)]
+  pub async fn summary_row(&self, row: HashMap<String, String>) -> Result<String, PluginError> {
+    self.send_typed_request("database_summary", None, row).await
+  }
+
+  #[instrument(level = "debug", skip(self), err)]
+  pub async fn stream_summary_row(
+    &self,
+    row: HashMap<String, String>,
+  ) -> Result<RequestStream<Result<Bytes, PluginError>>, PluginError> {
+    self.send_stream_request::<ChatStreamResponseParser, _>(
+      "stream_database_summary",
+      None,
+      row,
+      RequestPriority::Interactive,
+    )
+  }
+
   #[instrument(level = "debug", skip(self), err)]
   pub async fn translate_row(
     &self,
     data: LocalAITranslateRowData,
   ) -> Result<LocalAITranslateRowResponse, PluginError> {
-    let params = json!({"params": data });
     self
-      .send_request::<DatabaseTranslateResponseParser>("database_translate", params)
+      .send_typed_request("database_translate", None, data)
       .await
   }
+
+  /// Suggests content for `target_field` based on the other cells of the row.
+  #[instrument(level = "debug", skip(self), err)]
+  pub async fn autofill_cell(
+    &self,
+    row_context: HashMap<String, String>,
+    target_field: &str,
+  ) -> Result<String, PluginError> {
+    let params = AutofillCellParams {
+      row: row_context,
+      target_field,
+    };
+    self
+      .send_typed_request("database_autofill", None, params)
+      .await
+  }
+
+  /// Returns the option(s) from `options` that best describe the row, most relevant first.
+  #[instrument(level = "debug", skip(self), err)]
+  pub async fn classify_row(
+    &self,
+    row: HashMap<String, String>,
+    options: Vec<String>,
+  ) -> Result<Vec<String>, PluginError> {
+    let params = ClassifyRowParams { row, options };
+    self
+      .send_typed_request("database_classify", None, params)
+      .await
+  }
+
+  /// Translates multiple rows with a single request. Each translated row is streamed back as soon
+  /// as it's ready, tagged with its index in `rows`.
+  #[instrument(level = "debug", skip(self), err)]
+  pub async fn translate_rows(
+    &self,
+    rows: Vec<LocalAITranslateRowData>,
+  ) -> Result<RequestStream<Result<LocalAITranslateRowResult, PluginError>>, PluginError> {
+    self.send_stream_request::<DatabaseBatchTranslateResponseParser, _>(
+      "database_translate_batch",
+      None,
+      TranslateRowsParams { rows },
+      RequestPriority::Interactive,
+    )
+  }
+}
+
+/// The params of the `handle` request, which the plugin forwards to `method`.
+#[derive(Serialize)]
+struct HandleRequest<'a, P> {
+  method: &'a str,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  chat_id: Option<&'a str>,
+  params: P,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  idempotency_key: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct AnswerParams<'a> {
+  content: &'a str,
+  rag_enabled: bool,
+}
+
+#[derive(Serialize)]
+struct StreamAnswerParams<'a> {
+  content: &'a str,
+  metadata: JsonValue,
+}
+
+#[derive(Serialize)]
+struct IndexFileParams {
+  metadata: [HashMap<String, JsonValue>; 1],
+  #[serde(skip_serializing_if = "Option::is_none")]
+  file_path: Option<String>,
+  /// Replaces `file_path` for plugins that report the [UPLOAD] capability
+  #[serde(skip_serializing_if = "Option::is_none")]
+  upload_id: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  file_content: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  file_type: Option<&'static str>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pages: Option<Range<usize>>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  expires_at: Option<u64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  password: Option<String>,
+}
+
+impl Debug for IndexFileParams {
+  /// Redacts the password and leaves out the content, which may be large
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("IndexFileParams")
+      .field("metadata", &self.metadata)
+      .field("file_path", &self.file_path)
+      .field("upload_id", &self.upload_id)
+      .field(
+        "file_content_len",
+        &self.file_content.as_ref().map(String::len),
+      )
+      .field("file_type", &self.file_type)
+      .field("pages", &self.pages)
+      .field("expires_at", &self.expires_at)
+      .field("password", &self.password.as_ref().map(|_| "<redacted>"))
+      .finish()
+  }
+}
+
+#[derive(Serialize)]
+struct CompleteTextParams<'a> {
+  text: &'a str,
+  #[serde(rename = "type")]
+  complete_type: u8,
+  completion_id: u64,
+  #[serde(flatten)]
+  options: CompleteTextOptions,
+}
+
+#[derive(Serialize)]
+struct StopCompleteTextParams {
+  completion_id: u64,
+}
+
+#[derive(Serialize)]
+struct TranslateRowsParams {
+  rows: Vec<LocalAITranslateRowData>,
+}
+
+#[derive(Serialize)]
+struct SearchWorkspaceParams<'a> {
+  query: &'a str,
+  filter: HashMap<String, JsonValue>,
+}
+
+#[derive(Serialize)]
+struct OcrImageParams<'a> {
+  #[serde(skip_serializing_if = "Option::is_none")]
+  file_path: Option<&'a str>,
+  /// Replaces `file_path` for plugins that report the [UPLOAD] capability
+  #[serde(skip_serializing_if = "Option::is_none")]
+  upload_id: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ExpandQueryParams<'a> {
+  query: &'a str,
+}
+
+#[derive(Serialize)]
+struct RerankParams<'a> {
+  query: &'a str,
+  candidates: Vec<String>,
+}
+
+/// The params of the requests that only take the text of a document
+#[derive(Serialize)]
+struct TextParams<'a> {
+  text: &'a str,
+}
+
+#[derive(Serialize)]
+struct GenerateSummaryParams<'a> {
+  text: &'a str,
+  max_words: usize,
+}
+
+#[derive(Serialize)]
+struct AutofillCellParams<'a> {
+  row: HashMap<String, String>,
+  target_field: &'a str,
+}
+
+#[derive(Serialize)]
+struct ClassifyRowParams {
+  row: HashMap<String, String>,
+  options: Vec<String>,
+}
+
+/// Changes applied to a chat session that is persisted by the plugin. Fields that are `None` are
+/// left untouched.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ChatSessionUpdate {
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub name: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub workspace_id: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RerankedCandidate {
+  pub content: String,
+  pub score: f64,
+}
+
+/// Controls a running text completion. Calling [CompletionHandle::cancel] or dropping the handle
+/// sends `stop_complete_text` to the plugin so it stops generating tokens, unless the stream of
+/// the completion was read to its end.
+pub struct CompletionHandle {
+  plugin: Weak<Plugin>,
+  completion_id: u64,
+  cancelled: AtomicBool,
+  /// See [RequestStream::finished_flag]
+  finished: Arc<AtomicBool>,
+}
+
+impl CompletionHandle {
+  pub fn completion_id(&self) -> u64 {
+    self.completion_id
+  }
+
+  /// Whether the stream of the completion was read to its end.
+  pub fn is_finished(&self) -> bool {
+    self.finished.load(Ordering::Acquire)
+  }
+
+  pub fn cancel(&self) {
+    if self.is_finished() || self.cancelled.swap(true, Ordering::AcqRel) {
+      return;
+    }
+
+    let plugin = match self.plugin.upgrade() {
+      Some(plugin) => plugin,
+      None => return,
+    };
+    let runtime = match tokio::runtime::Handle::try_current() {
+      Ok(runtime) => runtime,
+      Err(_) => return,
+    };
+    let completion_id = self.completion_id;
+    trace!("[AI Plugin] stop complete text: {}", completion_id);
+    runtime.spawn(async move {
+      let request = HandleRequest {
+        method: "stop_complete_text",
+        chat_id: None,
+        params: StopCompleteTextParams { completion_id },
+        idempotency_key: None,
+      };
+      if let Err(err) = plugin
+        .typed_request::<_, IgnoredAny>("handle", &request, None)
+        .await
+      {
+        error!(
+          "[AI Plugin] failed to stop complete text {}: {:?}",
+          completion_id, err
+        );
+      }
+    });
+  }
+}
+
+impl Drop for CompletionHandle {
+  fn drop(&mut self) {
+    self.cancel();
+  }
 }
 
 #[derive(Clone, Debug, Serialize)]
@@ -199,17 +718,12 @@ pub struct LocalAITranslateRowResponse {
   pub items: Vec<HashMap<String, String>>,
 }
 
-pub struct ChatResponseParser;
-impl ResponseParser for ChatResponseParser {
-  type ValueType = String;
-
-  fn parse_json(json: JsonValue) -> Result<Self::ValueType, RemoteError> {
-    json
-      .get("data")
-      .and_then(|data| data.as_str())
-      .map(String::from)
-      .ok_or(RemoteError::ParseResponse(json))
-  }
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct LocalAITranslateRowResult {
+  /// Index of the row in the request
+  pub row_index: usize,
+  #[serde(flatten)]
+  pub response: LocalAITranslateRowResponse,
 }
 
 pub struct ChatStreamResponseParser;
@@ -281,27 +795,111 @@ impl From<u8> for CompleteTextType {
   }
 }
 
-pub struct DatabaseSummaryResponseParser;
-impl ResponseParser for DatabaseSummaryResponseParser {
-  type ValueType = String;
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GrammarIssueKind {
+  Spelling,
+  Grammar,
+  Punctuation,
+  Style,
+  #[serde(other)]
+  Other,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct GrammarIssue {
+  /// Character range of the issue in the checked text
+  pub range: Range<usize>,
+  pub original: String,
+  pub suggestion: String,
+  pub kind: GrammarIssueKind,
+}
+
+/// The voice the completion should be written in.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Tone {
+  Professional,
+  Casual,
+  Friendly,
+  Confident,
+  Straightforward,
+}
+
+/// The format the completion should be written in, so the output can be inserted into the editor
+/// as is.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputFormat {
+  Markdown,
+  PlainText,
+  BulletList,
+}
+
+/// Optional parameters for [AIPluginOperation::complete_text].
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CompleteTextOptions {
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub tone: Option<Tone>,
+  /// The language the output should be written in, e.g. "chinese". When not set, the model
+  /// decides, which usually means English.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub language: Option<String>,
+  /// Text that comes before the selection in the document, e.g. the previous paragraphs.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub context_before: Option<String>,
+  /// Text that comes after the selection in the document.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub context_after: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub format: Option<OutputFormat>,
+}
+
+impl CompleteTextOptions {
+  pub fn with_tone(mut self, tone: Tone) -> Self {
+    self.tone = Some(tone);
+    self
+  }
+
+  pub fn with_language<T: Into<String>>(mut self, language: T) -> Self {
+    self.language = Some(language.into());
+    self
+  }
+
+  pub fn with_format(mut self, format: OutputFormat) -> Self {
+    self.format = Some(format);
+    self
+  }
+
+  pub fn with_context<T: Into<String>>(mut self, before: Option<T>, after: Option<T>) -> Self {
+    self.context_before = before.map(Into::into);
+    self.context_after = after.map(Into::into);
+    self
+  }
+}
+
+pub struct DatabaseBatchTranslateResponseParser;
+impl ResponseParser for DatabaseBatchTranslateResponseParser {
+  type ValueType = LocalAITranslateRowResult;
 
   fn parse_json(json: JsonValue) -> Result<Self::ValueType, RemoteError> {
-    json
-      .get("data")
-      .and_then(|data| data.as_str())
-      .map(|s| s.to_string())
-      .ok_or(RemoteError::ParseResponse(json))
+    let result = match json.as_str() {
+      Some(s) => serde_json::from_str(s).ok(),
+      None => LocalAITranslateRowResult::deserialize(&json).ok(),
+    };
+    result.ok_or(RemoteError::ParseResponse(json))
   }
 }
 
-pub struct DatabaseTranslateResponseParser;
-impl ResponseParser for DatabaseTranslateResponseParser {
-  type ValueType = LocalAITranslateRowResponse;
+pub struct IndexProgressResponseParser;
+impl ResponseParser for IndexProgressResponseParser {
+  type ValueType = IndexProgress;
 
   fn parse_json(json: JsonValue) -> Result<Self::ValueType, RemoteError> {
-    json
-      .get("data")
-      .and_then(|data| LocalAITranslateRowResponse::deserialize(data).ok())
-      .ok_or(RemoteError::ParseResponse(json))
+    let result = match json.as_str() {
+      Some(s) => serde_json::from_str(s).ok(),
+      None => IndexProgress::deserialize(&json).ok(),
+    };
+    result.ok_or(RemoteError::ParseResponse(json))
   }
 }
@@ -1,28 +1,46 @@
 use crate::ai_ops::{
-  AIPluginOperation, CompleteTextType, LocalAITranslateRowData, LocalAITranslateRowResponse,
+  new_idempotency_key, AIPluginOperation, ChatSessionUpdate, CompleteTextOptions, CompleteTextType,
+  CompletionHandle, GrammarIssue, LocalAITranslateRowData, LocalAITranslateRowResponse,
+  LocalAITranslateRowResult, RerankedCandidate,
 };
+use crate::chat_session::{ChatSessionEvent, ChatSessionTracker};
+use crate::embedding_ops::SearchResult;
+use crate::file_index::{
+  collect_index_files, html_to_text, is_image_file, DirectoryIndexProgress, FileIndexStatus,
+  IndexFileOptions, IndexFileType, IndexOptions, IndexProgress,
+};
+use crate::request_limiter::{stream_with_permit, RequestLimiter};
+use crate::vector_store::{ChunkingConfig, VectorStorePaths};
 use anyhow::{anyhow, Result};
+use appflowy_plugin::core::compression::CompressionConfig;
 use appflowy_plugin::core::plugin::{
-  Plugin, PluginInfo, RunningState, RunningStateReceiver, RunningStateSender,
+  Plugin, PluginHandshake, PluginInfo, RunningState, RunningStateReceiver, RunningStateSender,
 };
+use appflowy_plugin::core::resource_limit::{ProcessPriority, ResourceLimits};
+use appflowy_plugin::core::rpc_peer::RequestQueueConfig;
+use appflowy_plugin::core::sandbox::SandboxPolicy;
+use appflowy_plugin::core::transport::PluginTransport;
 use appflowy_plugin::error::PluginError;
-use appflowy_plugin::manager::PluginManager;
+use appflowy_plugin::manager::{HealthCheckConfig, PluginManager, PluginMetrics, WatchdogConfig};
 use appflowy_plugin::util::{get_operating_system, OperatingSystem};
 use bytes::Bytes;
+use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
 
 use serde_json::Value;
 use std::collections::HashMap;
 use std::fmt::Debug;
+use std::future::Future;
 use std::path::PathBuf;
 use std::sync::{Arc, Weak};
 use std::time::Duration;
 use tokio::io;
-use tokio::sync::RwLock;
+use tokio::sync::{OwnedSemaphorePermit, RwLock};
+use tokio::task::JoinHandle;
 use tokio::time::timeout;
-use tokio_stream::wrappers::{ReceiverStream, WatchStream};
-use tokio_stream::StreamExt;
-use tracing::{error, info, instrument, trace};
+use tokio_stream::wrappers::{BroadcastStream, ReceiverStream, WatchStream};
+use tokio_stream::{Stream, StreamExt};
+use tracing::{error, info, instrument, trace, warn};
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct LocalLLMSetting {
@@ -39,6 +57,21 @@ impl LocalLLMSetting {
   }
 }
 
+/// Resource usage of the chat plugin, e.g. for showing how much memory Local AI uses.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChatPluginMetrics {
+  pub process: PluginMetrics,
+  pub open_chats: usize,
+}
+
+const WARM_UP_CHAT_ID: &str = "appflowy_warm_up";
+/// How often [AppFlowyLocalAI::retry_after_reconnect] sends a request again
+const MAX_REQUEST_RETRIES: usize = 2;
+/// How long [AppFlowyLocalAI::index_url] waits for the whole page
+const INDEX_URL_TIMEOUT: Duration = Duration::from_secs(30);
+/// Larger pages are rejected by [AppFlowyLocalAI::index_url]
+const MAX_INDEX_URL_BODY_SIZE: u64 = 10 * 1024 * 1024;
+
 pub struct AppFlowyLocalAI {
   plugin_manager: Arc<PluginManager>,
   plugin_config: RwLock<Option<AIPluginConfig>>,
@@ -46,16 +79,27 @@ pub struct AppFlowyLocalAI {
   #[allow(dead_code)]
   // keep at least one receiver that make sure the sender can receive value
   running_state_rx: RunningStateReceiver,
+  chat_sessions: Arc<ChatSessionTracker>,
+  idle_sweeper: Mutex<Option<JoinHandle<()>>>,
+  idle_shutdown_watcher: Mutex<Option<JoinHandle<()>>>,
+  request_limiter: Arc<RequestLimiter>,
+  restart_lock: tokio::sync::Mutex<()>,
 }
 
 impl AppFlowyLocalAI {
   pub fn new(plugin_manager: Arc<PluginManager>) -> Self {
     let (running_state, rx) = tokio::sync::watch::channel(RunningState::Connecting);
+    let running_state = Arc::new(running_state);
     Self {
       plugin_manager,
       plugin_config: Default::default(),
-      running_state: Arc::new(running_state),
+      running_state: running_state.clone(),
       running_state_rx: rx,
+      chat_sessions: Arc::new(ChatSessionTracker::new()),
+      idle_sweeper: Mutex::new(None),
+      idle_shutdown_watcher: Mutex::new(None),
+      request_limiter: Arc::new(RequestLimiter::new(running_state)),
+      restart_lock: tokio::sync::Mutex::new(()),
     }
   }
 
@@ -72,9 +116,10 @@ impl AppFlowyLocalAI {
     trace!("[AI Plugin] create chat: {}", chat_id);
     self.wait_until_plugin_ready().await?;
 
-    let plugin = self.get_ai_plugin().await?;
-    let operation = AIPluginOperation::new(plugin);
-    operation.create_chat(chat_id).await?;
+    self
+      .retry_after_reconnect(|operation| async move { operation.create_chat(chat_id).await })
+      .await?;
+    self.chat_sessions.touch(chat_id);
     Ok(())
   }
 
@@ -89,12 +134,38 @@ impl AppFlowyLocalAI {
   /// A `Result<()>` indicating success or failure.
   pub async fn close_chat(&self, chat_id: &str) -> Result<()> {
     trace!("[AI Plugin] close chat: {}", chat_id);
+    self.chat_sessions.remove(chat_id);
     let plugin = self.get_ai_plugin().await?;
     let operation = AIPluginOperation::new(plugin);
     operation.close_chat(chat_id).await?;
     Ok(())
   }
 
+  /// Updates the name or workspace binding of an existing chat session, keeping the plugin-side
+  /// store (and the vector filters derived from it) in sync with the host.
+  ///
+  /// # Arguments
+  ///
+  /// * `chat_id` - A string slice containing the unique identifier for the chat session.
+  /// * `update` - The fields to change.
+  ///
+  /// # Returns
+  ///
+  /// A `Result<()>` indicating success or failure.
+  pub async fn update_chat(
+    &self,
+    chat_id: &str,
+    update: ChatSessionUpdate,
+  ) -> Result<(), PluginError> {
+    trace!("[AI Plugin] update chat: {}, {:?}", chat_id, update);
+    self.wait_until_plugin_ready().await?;
+    self.chat_sessions.touch(chat_id);
+    let plugin = self.get_ai_plugin().await?;
+    let operation = AIPluginOperation::new(plugin);
+    operation.update_chat(chat_id, update).await?;
+    Ok(())
+  }
+
   pub fn subscribe_running_state(&self) -> WatchStream<RunningState> {
     WatchStream::new(self.running_state.subscribe())
   }
@@ -103,6 +174,112 @@ impl AppFlowyLocalAI {
     self.running_state.borrow().clone()
   }
 
+  /// Restarts the chat plugin with the configuration it was initialized with. Open chats are lost
+  /// and have to be created again.
+  #[instrument(skip_all, err)]
+  pub async fn restart(&self) -> Result<(), PluginError> {
+    let plugin_id = self
+      .running_state
+      .borrow()
+      .plugin_id()
+      .ok_or(PluginError::PluginNotConnected)?;
+    self.chat_sessions.clear();
+    self.plugin_manager.restart_plugin(plugin_id).await?;
+    Ok(())
+  }
+
+  /// Switches the chat plugin to an updated binary without restarting the app. Requests in flight
+  /// are finished first, see [PluginManager::reload_binary]. Open chats are lost like with
+  /// [Self::restart].
+  #[instrument(skip_all, err)]
+  pub async fn reload_binary(&self, chat_bin_path: PathBuf) -> Result<(), PluginError> {
+    let plugin_id = self
+      .running_state
+      .borrow()
+      .plugin_id()
+      .ok_or(PluginError::PluginNotConnected)?;
+    self
+      .plugin_manager
+      .reload_binary(plugin_id, chat_bin_path.clone())
+      .await?;
+    self.chat_sessions.clear();
+    // Later re-initializations, e.g. after the plugin became unhealthy, use the new binary too
+    if let Some(config) = self.plugin_config.write().await.as_mut() {
+      config.chat_bin_path = chat_bin_path;
+    }
+    Ok(())
+  }
+
+  /// The config the chat plugin was initialized with, including the binary of the last
+  /// [Self::reload_binary]. `None` before [Self::init_chat_plugin].
+  pub(crate) async fn plugin_config(&self) -> Option<AIPluginConfig> {
+    self.plugin_config.read().await.clone()
+  }
+
+  /// Pauses the chat plugin without unloading the model. See [PluginManager::suspend].
+  pub async fn suspend(&self) -> Result<(), PluginError> {
+    let plugin_id = self
+      .running_state
+      .borrow()
+      .plugin_id()
+      .ok_or(PluginError::PluginNotConnected)?;
+    self.plugin_manager.suspend(plugin_id).await
+  }
+
+  pub async fn resume(&self) -> Result<(), PluginError> {
+    let plugin_id = self
+      .running_state
+      .borrow()
+      .plugin_id()
+      .ok_or(PluginError::PluginNotConnected)?;
+    self.plugin_manager.resume(plugin_id).await
+  }
+
+  /// Whether the chat plugin supports `capability`, e.g. `vision`. Returns false when the plugin
+  /// isn't running. A plugin that was shut down for being idle isn't relaunched to answer.
+  pub async fn supports(&self, capability: &str) -> bool {
+    self
+      .plugin_handshake()
+      .map(|handshake| handshake.capabilities.iter().any(|c| c == capability))
+      .unwrap_or(false)
+  }
+
+  /// The version the chat plugin reported when it was initialized. Returns `None` when the plugin
+  /// isn't running. A plugin that was shut down for being idle isn't relaunched to answer.
+  pub async fn plugin_version(&self) -> Option<String> {
+    Some(self.plugin_handshake()?.version)
+  }
+
+  fn plugin_handshake(&self) -> Option<PluginHandshake> {
+    let plugin_id = self.running_state.borrow().plugin_id()?;
+    self.plugin_manager.handshake(plugin_id)
+  }
+
+  pub async fn plugin_metrics(&self) -> Result<ChatPluginMetrics, PluginError> {
+    let plugin_id = self
+      .running_state
+      .borrow()
+      .plugin_id()
+      .ok_or(PluginError::PluginNotConnected)?;
+    let process = self.plugin_manager.plugin_metrics(plugin_id).await?;
+    Ok(ChatPluginMetrics {
+      process,
+      open_chats: self.chat_sessions.len(),
+    })
+  }
+
+  /// Subscribes to chat session events, such as a chat being closed after it was idle for longer
+  /// than [AIPluginConfig::chat_idle_timeout].
+  pub fn subscribe_chat_session_events(&self) -> BroadcastStream<ChatSessionEvent> {
+    self.chat_sessions.subscribe()
+  }
+
+  /// Returns the number of requests waiting for a free slot when
+  /// [AIPluginConfig::max_concurrent_requests] is reached.
+  pub fn queue_depth(&self) -> usize {
+    self.request_limiter.queue_depth()
+  }
+
   /// Asks a question and returns a stream of responses.
   ///
   /// # Arguments
@@ -121,30 +298,49 @@ impl AppFlowyLocalAI {
   ) -> Result<ReceiverStream<anyhow::Result<Value, PluginError>>, PluginError> {
     trace!("[AI Plugin] ask question: {}", message);
     self.wait_until_plugin_ready().await?;
+    self.chat_sessions.touch(chat_id);
+    let permit = self.request_limiter.acquire().await;
     let plugin = self.get_ai_plugin().await?;
     let operation = AIPluginOperation::new(plugin);
     let stream = operation
       .stream_message_v2(chat_id, message, metadata)
       .await?;
-    Ok(stream)
+    Ok(stream_with_permit(stream, permit))
   }
 
   pub async fn get_related_question(&self, chat_id: &str) -> Result<Vec<String>, PluginError> {
     self.wait_until_plugin_ready().await?;
+    self.chat_sessions.touch(chat_id);
+    let _permit = self.request_limiter.acquire().await;
     let plugin = self.get_ai_plugin().await?;
     let operation = AIPluginOperation::new(plugin);
     let values = operation.get_related_questions(chat_id).await?;
     Ok(values)
   }
 
+  /// Indexes a file, or raw content, into the retrieval store of `chat_id`.
+  ///
+  /// Large files can take a while, so the returned stream reports an [IndexProgress] for each
+  /// stage (parsing, chunking, embedding, persisting). Indexing is done when the stream ends.
   pub async fn index_file(
     &self,
     chat_id: &str,
     file_path: Option<PathBuf>,
     file_content: Option<String>,
     metadata: Option<HashMap<String, serde_json::Value>>,
-  ) -> Result<(), PluginError> {
+    options: IndexFileOptions,
+  ) -> Result<ReceiverStream<Result<IndexProgress, PluginError>>, PluginError> {
+    if let Some(pages) = options.pages.as_ref() {
+      if pages.is_empty() {
+        return Err(PluginError::Internal(anyhow!(
+          "invalid page range: {:?}",
+          pages
+        )));
+      }
+    }
+
     let mut file_path_str = None;
+    let mut file_type = None;
     if let Some(file_path) = file_path {
       if !file_path.exists() {
         return Err(PluginError::Io(io::Error::new(
@@ -153,6 +349,10 @@ impl AppFlowyLocalAI {
         )));
       }
 
+      // Files of an unknown type are sent without a hint, the plugin decides whether it can read
+      // them
+      file_type = IndexFileType::from_path(&file_path);
+
       file_path_str = Some(
         file_path
           .to_str()
@@ -165,15 +365,331 @@ impl AppFlowyLocalAI {
     }
 
     self.wait_until_plugin_ready().await?;
+    self.chat_sessions.touch(chat_id);
+    let permit = self.request_limiter.acquire().await;
+    let chat_id = chat_id.to_string();
+    self
+      .retry_stream_after_reconnect(permit, move |operation| {
+        let chat_id = chat_id.clone();
+        let file_path_str = file_path_str.clone();
+        let file_content = file_content.clone();
+        let metadata = metadata.clone();
+        let options = options.clone();
+        async move {
+          operation
+            .index_file(
+              &chat_id,
+              file_path_str,
+              file_content,
+              file_type,
+              metadata,
+              options,
+            )
+            .await
+        }
+      })
+      .await
+  }
+
+  /// Indexes `text` into the retrieval store of `chat_id`, e.g. the content of document blocks,
+  /// without writing it to a file first. Returns once the text is persisted.
+  pub async fn index_text(
+    &self,
+    chat_id: &str,
+    text: &str,
+    metadata: HashMap<String, serde_json::Value>,
+  ) -> Result<(), PluginError> {
+    trace!("[AI Plugin] index text: {}", text);
+    self.wait_until_plugin_ready().await?;
+    self.chat_sessions.touch(chat_id);
+    let _permit = self.request_limiter.acquire().await;
     let plugin = self.get_ai_plugin().await?;
     let operation = AIPluginOperation::new(plugin);
-
-    operation
-      .index_file(chat_id, file_path_str, file_content, metadata)
+    let mut stream = operation
+      .index_file(
+        chat_id,
+        None,
+        Some(text.to_string()),
+        Some(IndexFileType::Text),
+        Some(metadata),
+        IndexFileOptions::default(),
+      )
       .await?;
+    while let Some(progress) = stream.next().await {
+      progress?;
+    }
     Ok(())
   }
 
+  /// Makes the text in an image, e.g. a screenshot, searchable. The plugin extracts the text with
+  /// OCR, which then goes through the same chunk and embed pipeline as [Self::index_file]. The
+  /// image path is stored in the `source` metadata of the indexed text. Plugins that can't read
+  /// host paths, e.g. remote ones, get the image uploaded.
+  pub async fn index_image(
+    &self,
+    chat_id: &str,
+    file_path: PathBuf,
+  ) -> Result<ReceiverStream<Result<IndexProgress, PluginError>>, PluginError> {
+    trace!("[AI Plugin] index image: {:?}", file_path);
+    if !file_path.exists() {
+      return Err(PluginError::Io(io::Error::new(
+        io::ErrorKind::NotFound,
+        "file not found",
+      )));
+    }
+    if !is_image_file(&file_path) {
+      return Err(PluginError::Io(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("unsupported image type: {:?}", file_path),
+      )));
+    }
+    let file_path_str = file_path.to_string_lossy().to_string();
+
+    self.wait_until_plugin_ready().await?;
+    self.chat_sessions.touch(chat_id);
+    let permit = self.request_limiter.acquire().await;
+    let plugin = self.get_ai_plugin().await?;
+    let operation = AIPluginOperation::new(plugin);
+    let text = operation.extract_image_text(&file_path_str).await?;
+    if text.trim().is_empty() {
+      return Err(PluginError::Internal(anyhow!(
+        "no text found in image: {:?}",
+        file_path
+      )));
+    }
+
+    let mut metadata = HashMap::new();
+    metadata.insert("source".to_string(), serde_json::json!(file_path_str));
+    let stream = operation
+      .index_file(
+        chat_id,
+        None,
+        Some(text),
+        Some(IndexFileType::Text),
+        Some(metadata),
+        IndexFileOptions::default(),
+      )
+      .await?;
+    Ok(stream_with_permit(stream, permit))
+  }
+
+  /// Downloads the page at `url` and indexes its readable text, so web references can be added to
+  /// the knowledge base of a chat. HTML is reduced to text with [html_to_text]. The url is stored
+  /// in the `source` metadata of the indexed text.
+  ///
+  /// The download fails after 30 seconds, and pages larger than 10 MiB are rejected.
+  pub async fn index_url(
+    &self,
+    chat_id: &str,
+    url: &str,
+  ) -> Result<ReceiverStream<Result<IndexProgress, PluginError>>, PluginError> {
+    trace!("[AI Plugin] index url: {}", url);
+    let mut response = reqwest::Client::builder()
+      .timeout(INDEX_URL_TIMEOUT)
+      .build()
+      .map(|client| client.get(url))
+      .map_err(|err| PluginError::Internal(err.into()))?
+      .send()
+      .await
+      .map_err(|err| PluginError::Internal(err.into()))?;
+    if !response.status().is_success() {
+      return Err(PluginError::Internal(anyhow!(
+        "Failed to download {}: {}",
+        url,
+        response.status()
+      )));
+    }
+
+    let content_type = response
+      .headers()
+      .get(reqwest::header::CONTENT_TYPE)
+      .and_then(|value| value.to_str().ok())
+      .unwrap_or("text/html")
+      .to_ascii_lowercase();
+    let too_large = || {
+      PluginError::Internal(anyhow!(
+        "{} is larger than {} bytes",
+        url,
+        MAX_INDEX_URL_BODY_SIZE
+      ))
+    };
+    if response.content_length() > Some(MAX_INDEX_URL_BODY_SIZE) {
+      return Err(too_large());
+    }
+    let mut body = vec![];
+    while let Some(chunk) = response
+      .chunk()
+      .await
+      .map_err(|err| PluginError::Internal(err.into()))?
+    {
+      if (body.len() + chunk.len()) as u64 > MAX_INDEX_URL_BODY_SIZE {
+        return Err(too_large());
+      }
+      body.extend_from_slice(&chunk);
+    }
+    let body = String::from_utf8_lossy(&body).into_owned();
+    let text =
+      if content_type.starts_with("text/html") || content_type.starts_with("application/xhtml") {
+        html_to_text(&body)
+      } else if content_type.starts_with("text/") {
+        body
+      } else {
+        return Err(PluginError::Io(io::Error::new(
+          io::ErrorKind::Unsupported,
+          format!("unsupported content type: {}", content_type),
+        )));
+      };
+
+    let mut metadata = HashMap::new();
+    metadata.insert("source".to_string(), serde_json::json!(url));
+
+    self.wait_until_plugin_ready().await?;
+    self.chat_sessions.touch(chat_id);
+    let permit = self.request_limiter.acquire().await;
+    let plugin = self.get_ai_plugin().await?;
+    let operation = AIPluginOperation::new(plugin);
+    let stream = operation
+      .index_file(
+        chat_id,
+        None,
+        Some(text),
+        Some(IndexFileType::Text),
+        Some(metadata),
+        IndexFileOptions::default(),
+      )
+      .await?;
+    Ok(stream_with_permit(stream, permit))
+  }
+
+  /// Recursively indexes all supported files in `dir` that match `options`.
+  ///
+  /// Files are indexed one after another in the background. The returned stream yields one
+  /// [DirectoryIndexProgress] per file; a file that fails to index does not stop the others.
+  pub async fn index_directory(
+    &self,
+    chat_id: &str,
+    dir: PathBuf,
+    options: IndexOptions,
+  ) -> Result<ReceiverStream<DirectoryIndexProgress>, PluginError> {
+    trace!("[AI Plugin] index directory: {:?}, {:?}", dir, options);
+    let files = tokio::task::spawn_blocking(move || collect_index_files(&dir, &options))
+      .await
+      .map_err(|err| PluginError::Internal(err.into()))??;
+
+    self.wait_until_plugin_ready().await?;
+    self.chat_sessions.touch(chat_id);
+    let plugin = self.get_ai_plugin().await?;
+    let operation = AIPluginOperation::new(plugin);
+
+    let chat_id = chat_id.to_string();
+    let request_limiter = self.request_limiter.clone();
+    let (tx, rx) = tokio::sync::mpsc::channel(100);
+    tokio::spawn(async move {
+      let total = files.len();
+      for (index, file_path) in files.into_iter().enumerate() {
+        // Acquired per file, so other requests get their turn between the files of a large
+        // directory
+        let _permit = request_limiter.acquire().await;
+        let file_type = IndexFileType::from_path(&file_path);
+        let result = operation
+          .index_file(
+            &chat_id,
+            Some(file_path.to_string_lossy().to_string()),
+            None,
+            file_type,
+            None,
+            IndexFileOptions::default(),
+          )
+          .await;
+        let status = match result {
+          Ok(mut stream) => {
+            let mut status = FileIndexStatus::Indexed;
+            while let Some(progress) = stream.next().await {
+              if let Err(err) = progress {
+                status = FileIndexStatus::Failed(err.to_string());
+                break;
+              }
+            }
+            status
+          },
+          Err(err) => FileIndexStatus::Failed(err.to_string()),
+        };
+        let progress = DirectoryIndexProgress {
+          file_path,
+          current: index + 1,
+          total,
+          status,
+        };
+        if tx.send(progress).await.is_err() {
+          break;
+        }
+      }
+    });
+    Ok(ReceiverStream::new(rx))
+  }
+
+  /// Searches the knowledge base of the whole workspace instead of the documents of a single chat.
+  /// `filter` is matched against the metadata the documents were indexed with, e.g. `chat_id`.
+  /// The plugin expands the query itself if [AIPluginConfig::expand_query] is set.
+  pub async fn search_workspace(
+    &self,
+    query: &str,
+    filter: HashMap<String, serde_json::Value>,
+  ) -> Result<Vec<SearchResult>, PluginError> {
+    trace!("[AI Plugin] search workspace: {}, {:?}", query, filter);
+    self.wait_until_plugin_ready().await?;
+    let _permit = self.request_limiter.acquire().await;
+    let plugin = self.get_ai_plugin().await?;
+    let operation = AIPluginOperation::new(plugin);
+    let result = operation.search_workspace(query, filter).await?;
+    Ok(result)
+  }
+
+  /// Asks the chat model to rewrite `query` with related terms and synonyms, which gives better
+  /// retrieval results than a terse question.
+  pub async fn expand_query(&self, query: &str) -> Result<String, PluginError> {
+    trace!("[AI Plugin] expand query: {}", query);
+    self.wait_until_plugin_ready().await?;
+    let _permit = self.request_limiter.acquire().await;
+    let plugin = self.get_ai_plugin().await?;
+    let operation = AIPluginOperation::new(plugin);
+    let expanded_query = operation.expand_query(query).await?;
+    Ok(expanded_query)
+  }
+
+  /// Removes indexed content whose [IndexFileOptions::expires_at] has passed. Hosts should call
+  /// this periodically, e.g. on startup and once a day.
+  pub async fn purge_expired(&self) -> Result<u64, PluginError> {
+    self.wait_until_plugin_ready().await?;
+    let _permit = self.request_limiter.acquire().await;
+    let plugin = self.get_ai_plugin().await?;
+    let operation = AIPluginOperation::new(plugin);
+    let purged = operation.purge_expired().await?;
+    info!("[AI Plugin] purged {} expired documents", purged);
+    Ok(purged)
+  }
+
+  /// Re-scores `candidates` against `query` with the reranker model configured in
+  /// [AIPluginConfig::set_rag_enabled]. The result is sorted by score, best first.
+  pub async fn rerank(
+    &self,
+    query: &str,
+    candidates: Vec<String>,
+  ) -> Result<Vec<RerankedCandidate>, PluginError> {
+    trace!(
+      "[AI Plugin] rerank {} candidates for: {}",
+      candidates.len(),
+      query
+    );
+    self.wait_until_plugin_ready().await?;
+    let _permit = self.request_limiter.acquire().await;
+    let plugin = self.get_ai_plugin().await?;
+    let operation = AIPluginOperation::new(plugin);
+    let mut result = operation.rerank(query, candidates).await?;
+    result.sort_by(|a, b| b.score.total_cmp(&a.score));
+    Ok(result)
+  }
+
   /// Generates a complete answer for a given message.
   ///
   /// # Arguments
@@ -186,17 +702,43 @@ impl AppFlowyLocalAI {
   /// A `Result<String>` containing the generated answer.
   pub async fn ask_question(&self, chat_id: &str, message: &str) -> Result<String, PluginError> {
     self.wait_until_plugin_ready().await?;
+    self.chat_sessions.touch(chat_id);
+    let _permit = self.request_limiter.acquire().await;
     let plugin = self.get_ai_plugin().await?;
     let operation = AIPluginOperation::new(plugin);
     let answer = operation.send_message(chat_id, message, true).await?;
     Ok(answer)
   }
 
+  /// Sends a short message through a temporary chat, so the model is loaded and compiled before
+  /// the first real request. Call it after [Self::init_chat_plugin].
+  #[instrument(skip_all, err)]
+  pub async fn warm_up(&self) -> Result<(), PluginError> {
+    self.wait_until_plugin_ready().await?;
+    let _permit = self.request_limiter.acquire().await;
+    let plugin = self.get_ai_plugin().await?;
+    let operation = AIPluginOperation::new(plugin);
+    operation.create_chat(WARM_UP_CHAT_ID).await?;
+    let result = operation.send_message(WARM_UP_CHAT_ID, "Hi", false).await;
+    operation.close_chat(WARM_UP_CHAT_ID).await?;
+    result?;
+    info!("[AI Plugin] warm up finished");
+    Ok(())
+  }
+
   #[instrument(skip_all, err)]
   pub async fn destroy_chat_plugin(&self) -> Result<()> {
-    let plugin_id = self.running_state.borrow().plugin_id();
-    if let Some(plugin_id) = plugin_id {
-      if let Err(err) = self.plugin_manager.remove_plugin(plugin_id).await {
+    self.stop_idle_sweeper();
+    self.chat_sessions.clear();
+    let state = self.running_state.borrow().clone();
+    if let Some(plugin_id) = state.plugin_id() {
+      // An unhealthy plugin won't answer the shutdown request of remove_plugin
+      let result = if state.is_unhealthy() {
+        self.plugin_manager.kill_plugin(plugin_id).await
+      } else {
+        self.plugin_manager.remove_plugin(plugin_id).await
+      };
+      if let Err(err) = result {
         error!("remove plugin failed: {:?}", err);
       }
     }
@@ -204,17 +746,84 @@ impl AppFlowyLocalAI {
     Ok(())
   }
 
+  /// Completes the given text and returns a stream of the generated output, together with a
+  /// [CompletionHandle]. Dropping the handle, or calling [CompletionHandle::cancel], stops the
+  /// completion in the plugin.
   pub async fn complete_text<T: Into<CompleteTextType> + Debug>(
     &self,
     message: &str,
     complete_type: T,
-  ) -> Result<ReceiverStream<anyhow::Result<Bytes, PluginError>>, PluginError> {
-    trace!("[AI Plugin]  complete text: {}", message);
+  ) -> Result<
+    (
+      ReceiverStream<anyhow::Result<Bytes, PluginError>>,
+      CompletionHandle,
+    ),
+    PluginError,
+  > {
+    self
+      .complete_text_with_options(message, complete_type, CompleteTextOptions::default())
+      .await
+  }
+
+  /// Same as [Self::complete_text], with additional options such as the tone of the output.
+  pub async fn complete_text_with_options<T: Into<CompleteTextType> + Debug>(
+    &self,
+    message: &str,
+    complete_type: T,
+    options: CompleteTextOptions,
+  ) -> Result<
+    (
+      ReceiverStream<anyhow::Result<Bytes, PluginError>>,
+      CompletionHandle,
+    ),
+    PluginError,
+  > {
+    trace!("[AI Plugin]  complete text: {}, {:?}", message, options);
     self.wait_until_plugin_ready().await?;
+    let permit = self.request_limiter.acquire().await;
     let plugin = self.get_ai_plugin().await?;
     let operation = AIPluginOperation::new(plugin);
-    let stream = operation.complete_text(message, complete_type).await?;
-    Ok(stream)
+    let (stream, handle) = operation
+      .complete_text(message, complete_type, options)
+      .await?;
+    Ok((stream_with_permit(stream, permit), handle))
+  }
+
+  /// Returns the spelling and grammar issues found in `text`, without rewriting it.
+  pub async fn check_grammar(&self, text: &str) -> Result<Vec<GrammarIssue>, PluginError> {
+    trace!("[AI Plugin] check grammar: {}", text);
+    self.wait_until_plugin_ready().await?;
+    let _permit = self.request_limiter.acquire().await;
+    let plugin = self.get_ai_plugin().await?;
+    let operation = AIPluginOperation::new(plugin);
+    let issues = operation.check_grammar(text).await?;
+    Ok(issues)
+  }
+
+  /// Generates a title for the given document text.
+  pub async fn generate_title(&self, text: &str) -> Result<String, PluginError> {
+    trace!("[AI Plugin] generate title: {}", text);
+    self.wait_until_plugin_ready().await?;
+    let _permit = self.request_limiter.acquire().await;
+    let plugin = self.get_ai_plugin().await?;
+    let operation = AIPluginOperation::new(plugin);
+    let title = operation.generate_title(text).await?;
+    Ok(title)
+  }
+
+  /// Generates a summary of the given document text with at most `max_words` words.
+  pub async fn generate_summary(
+    &self,
+    text: &str,
+    max_words: usize,
+  ) -> Result<String, PluginError> {
+    trace!("[AI Plugin] generate summary: {}", text);
+    self.wait_until_plugin_ready().await?;
+    let _permit = self.request_limiter.acquire().await;
+    let plugin = self.get_ai_plugin().await?;
+    let operation = AIPluginOperation::new(plugin);
+    let summary = operation.generate_summary(text, max_words).await?;
+    Ok(summary)
   }
 
   pub async fn summary_database_row(
@@ -223,24 +832,93 @@ impl AppFlowyLocalAI {
   ) -> Result<String, PluginError> {
     trace!("[AI Plugin] summary database row: {:?}", row);
     self.wait_until_plugin_ready().await?;
+    let _permit = self.request_limiter.acquire().await;
     let plugin = self.get_ai_plugin().await?;
     let operation = AIPluginOperation::new(plugin);
     let text = operation.summary_row(row).await?;
     Ok(text)
   }
 
+  /// Same as [Self::summary_database_row], but streams the summary as it's generated.
+  pub async fn stream_summary_database_row(
+    &self,
+    row: HashMap<String, String>,
+  ) -> Result<ReceiverStream<anyhow::Result<Bytes, PluginError>>, PluginError> {
+    trace!("[AI Plugin] stream summary database row: {:?}", row);
+    self.wait_until_plugin_ready().await?;
+    let permit = self.request_limiter.acquire().await;
+    let plugin = self.get_ai_plugin().await?;
+    let operation = AIPluginOperation::new(plugin);
+    let stream = operation.stream_summary_row(row).await?;
+    Ok(stream_with_permit(stream, permit))
+  }
+
   pub async fn translate_database_row(
     &self,
     row: LocalAITranslateRowData,
   ) -> Result<LocalAITranslateRowResponse, PluginError> {
     trace!("[AI Plugin] summary database row: {:?}", row);
     self.wait_until_plugin_ready().await?;
+    let _permit = self.request_limiter.acquire().await;
     let plugin = self.get_ai_plugin().await?;
     let operation = AIPluginOperation::new(plugin);
     let resp = operation.translate_row(row).await?;
     Ok(resp)
   }
 
+  /// Suggests content for the `target_field` cell of a row, based on the row's other cells.
+  pub async fn autofill_database_cell(
+    &self,
+    row_context: HashMap<String, String>,
+    target_field: &str,
+  ) -> Result<String, PluginError> {
+    trace!(
+      "[AI Plugin] autofill database cell: {}, row: {:?}",
+      target_field,
+      row_context
+    );
+    self.wait_until_plugin_ready().await?;
+    let _permit = self.request_limiter.acquire().await;
+    let plugin = self.get_ai_plugin().await?;
+    let operation = AIPluginOperation::new(plugin);
+    let text = operation.autofill_cell(row_context, target_field).await?;
+    Ok(text)
+  }
+
+  /// Picks the select option(s) that best match the row, so rows can be tagged automatically.
+  pub async fn classify_database_row(
+    &self,
+    row: HashMap<String, String>,
+    options: Vec<String>,
+  ) -> Result<Vec<String>, PluginError> {
+    trace!(
+      "[AI Plugin] classify database row: {:?}, options: {:?}",
+      row,
+      options
+    );
+    self.wait_until_plugin_ready().await?;
+    let _permit = self.request_limiter.acquire().await;
+    let plugin = self.get_ai_plugin().await?;
+    let operation = AIPluginOperation::new(plugin);
+    let values = operation.classify_row(row, options).await?;
+    Ok(values)
+  }
+
+  /// Translates multiple rows in one request, streaming back each row as it's translated.
+  pub async fn translate_database_rows(
+    &self,
+    rows: Vec<LocalAITranslateRowData>,
+  ) -> Result<ReceiverStream<anyhow::Result<LocalAITranslateRowResult, PluginError>>, PluginError>
+  {
+    trace!("[AI Plugin] translate {} database rows", rows.len());
+    self.wait_until_plugin_ready().await?;
+    let permit = self.request_limiter.acquire().await;
+    let plugin = self.get_ai_plugin().await?;
+    let operation = AIPluginOperation::new(plugin);
+    let stream = operation.translate_rows(rows).await?;
+    Ok(stream_with_permit(stream, permit))
+  }
+
   #[instrument(skip_all, err)]
   pub async fn init_chat_plugin(&self, config: AIPluginConfig) -> Result<()> {
     let state = self.running_state.borrow().clone();
@@ -266,6 +944,18 @@ impl AppFlowyLocalAI {
     let plugin_info = PluginInfo {
       name: "chat_plugin".to_string(),
       exec_path: config.chat_bin_path.clone(),
+      resource_limits: config.resource_limits.clone(),
+      priority: config.priority,
+      env: config.env.clone(),
+      args: config.args.clone(),
+      sandbox: config.sandbox_policy(),
+      startup_timeout: config.startup_timeout,
+      request_queue: config.request_queue,
+      compression: config.compression,
+      transport: config.transport.clone(),
+      depends_on: config.depends_on.clone(),
+      max_message_size: config.max_message_size,
+      recording: config.recording.clone(),
     };
     let plugin_id = self
       .plugin_manager
@@ -315,6 +1005,15 @@ impl AppFlowyLocalAI {
         "absolute_model_path": embedding_model_path,
         "persist_directory": persist_directory,
       });
+      if let Some(chunking) = config.chunking.as_ref() {
+        params["vectorstore_config"]["chunking"] = serde_json::json!(chunking);
+      }
+      if let Some(reranker_model_path) = config.reranker_model_path.as_ref() {
+        params["vectorstore_config"]["absolute_reranker_model_path"] =
+          serde_json::json!(reranker_model_path);
+      }
+      params["vectorstore_config"]["expand_query"] = serde_json::json!(config.expand_query);
+      params["vectorstore_config"]["dedup"] = serde_json::json!(config.dedup);
     }
 
     info!(
@@ -323,10 +1022,108 @@ impl AppFlowyLocalAI {
     );
     let plugin = self.plugin_manager.init_plugin(plugin_id, params).await?;
     info!("[AI Plugin] {} setup success", plugin);
+    self
+      .request_limiter
+      .set_max_concurrency(config.max_concurrent_requests);
+    if let Some(idle_timeout) = config.chat_idle_timeout {
+      self.start_idle_sweeper(idle_timeout);
+    }
+    if let Some(health_check) = config.health_check.clone() {
+      self
+        .plugin_manager
+        .start_health_check(plugin_id, health_check)
+        .await?;
+    }
+    if let Some(watchdog) = config.watchdog.clone() {
+      self.plugin_manager.start_watchdog(plugin_id, watchdog)?;
+    }
+    if let Some(idle_timeout) = config.plugin_idle_timeout {
+      self
+        .plugin_manager
+        .set_idle_shutdown(plugin_id, idle_timeout)?;
+      self.start_idle_shutdown_watcher();
+    }
     self.plugin_config.write().await.replace(config);
     Ok(())
   }
 
+  /// Spawns a background task that periodically closes chats which have been idle for at least
+  /// `idle_timeout`, and emits [ChatSessionEvent::IdleClosed] for each of them so the host can
+  /// recreate the chat on demand.
+  fn start_idle_sweeper(&self, idle_timeout: Duration) {
+    let chat_sessions = self.chat_sessions.clone();
+    let plugin_manager = self.plugin_manager.clone();
+    let running_state = self.running_state.clone();
+    let sweep_interval = idle_timeout.min(Duration::from_secs(60));
+    let handle = tokio::spawn(async move {
+      let mut interval = tokio::time::interval(sweep_interval);
+      loop {
+        interval.tick().await;
+        let idle_chat_ids = chat_sessions.take_idle(idle_timeout);
+        if idle_chat_ids.is_empty() {
+          continue;
+        }
+
+        // A plugin that isn't running has no open chats
+        let state = running_state.borrow().clone();
+        let plugin = match state.plugin_id() {
+          Some(plugin_id) if state.is_ready() => plugin_manager.get_plugin(plugin_id).await.ok(),
+          _ => None,
+        };
+        for chat_id in idle_chat_ids {
+          trace!("[AI Plugin] close idle chat: {}", chat_id);
+          if let Some(plugin) = plugin.clone() {
+            if let Err(err) = AIPluginOperation::new(plugin).close_chat(&chat_id).await {
+              error!(
+                "[AI Plugin] failed to close idle chat {}: {:?}",
+                chat_id, err
+              );
+            }
+          }
+          chat_sessions.send_event(ChatSessionEvent::IdleClosed { chat_id });
+        }
+      }
+    });
+
+    if let Some(old) = self.idle_sweeper.lock().replace(handle) {
+      old.abort();
+    }
+  }
+
+  fn stop_idle_sweeper(&self) {
+    if let Some(handle) = self.idle_sweeper.lock().take() {
+      handle.abort();
+    }
+  }
+
+  /// Spawns a background task that forgets the open chats when the plugin is shut down for being
+  /// idle, as the relaunched plugin doesn't know them, and emits [ChatSessionEvent::IdleClosed]
+  /// for each of them so the host can recreate the chat on demand.
+  fn start_idle_shutdown_watcher(&self) {
+    let chat_sessions = self.chat_sessions.clone();
+    let plugin_manager = self.plugin_manager.clone();
+    let mut states = WatchStream::new(self.running_state.subscribe());
+    let handle = tokio::spawn(async move {
+      while let Some(state) = states.next().await {
+        let plugin_id = match state {
+          RunningState::Stopped { plugin_id } => plugin_id,
+          _ => continue,
+        };
+        if !plugin_manager.is_idle_stopped(plugin_id) {
+          continue;
+        }
+        for chat_id in chat_sessions.take_idle(Duration::ZERO) {
+          trace!("[AI Plugin] chat {} closed with the idle plugin", chat_id);
+          chat_sessions.send_event(ChatSessionEvent::IdleClosed { chat_id });
+        }
+      }
+    });
+
+    if let Some(old) = self.idle_shutdown_watcher.lock().replace(handle) {
+      old.abort();
+    }
+  }
+
   /// Waits for the plugin to be ready.
   ///
   /// The wait_plugin_ready method is an asynchronous function designed to ensure that the chat
@@ -338,12 +1135,189 @@ impl AppFlowyLocalAI {
   ///
   /// A `Result<()>` indicating success or failure.
   async fn wait_until_plugin_ready(&self) -> Result<()> {
+    if self.running_state.borrow().is_unhealthy() {
+      self.restart_unhealthy_plugin().await?;
+    }
+    self.reconnector().wait_until_ready().await?;
+    Ok(())
+  }
+
+  async fn restart_unhealthy_plugin(&self) -> Result<()> {
+    let _guard = self.restart_lock.lock().await;
+    // Another request may have restarted the plugin while this one waited for the lock
+    if !self.running_state.borrow().is_unhealthy() {
+      return Ok(());
+    }
+
+    let config = self
+      .plugin_config
+      .read()
+      .await
+      .clone()
+      .ok_or_else(|| anyhow!("chat plugin is unhealthy and has no config to restart with"))?;
+    info!("[AI Plugin] chat plugin is unhealthy, restarting");
+    self.init_chat_plugin(config).await
+  }
+
+  /// Retrieves the chat plugin.
+  ///
+  /// # Returns
+  ///
+  /// A `Result<Weak<Plugin>>` containing a weak reference to the plugin.
+  pub async fn get_ai_plugin(&self) -> Result<Weak<Plugin>, PluginError> {
+    self.reconnector().plugin().await
+  }
+
+  fn reconnector(&self) -> Reconnector {
+    Reconnector {
+      plugin_manager: self.plugin_manager.clone(),
+      running_state: self.running_state.clone(),
+    }
+  }
+
+  /// Sends `request` again after the plugin is ready if the connection dropped before the
+  /// response arrived, at most [MAX_REQUEST_RETRIES] times. Every attempt gets an operation with
+  /// the same idempotency key, so a plugin that finished an earlier attempt doesn't do the work
+  /// twice.
+  async fn retry_after_reconnect<T, F, Fut>(&self, request: F) -> Result<T, PluginError>
+  where
+    F: FnMut(AIPluginOperation) -> Fut,
+    Fut: Future<Output = Result<T, PluginError>>,
+  {
+    self
+      .retry_with_idempotency_key(&new_idempotency_key(), request)
+      .await
+  }
+
+  async fn retry_with_idempotency_key<T, F, Fut>(
+    &self,
+    idempotency_key: &str,
+    mut request: F,
+  ) -> Result<T, PluginError>
+  where
+    F: FnMut(AIPluginOperation) -> Fut,
+    Fut: Future<Output = Result<T, PluginError>>,
+  {
+    let mut retries = 0;
+    loop {
+      let plugin = self.get_ai_plugin().await?;
+      let operation = AIPluginOperation::new(plugin).with_idempotency_key(idempotency_key);
+      match request(operation).await {
+        Err(err) if is_disconnect(&err) && retries < MAX_REQUEST_RETRIES => {
+          retries += 1;
+          warn!(
+            "[AI Plugin] connection lost, retrying request ({})",
+            retries
+          );
+          self.wait_until_plugin_ready().await?;
+        },
+        result => return result,
+      }
+    }
+  }
+
+  /// Like [Self::retry_after_reconnect], but also sends the request again if the connection drops
+  /// while its response streams in. The stream of the new attempt continues the returned stream,
+  /// and the plugin, which knows the idempotency key, resumes or repeats the work without doing it
+  /// twice. `permit` is held until the stream ends.
+  async fn retry_stream_after_reconnect<T, S, F, Fut>(
+    &self,
+    permit: Option<OwnedSemaphorePermit>,
+    mut request: F,
+  ) -> Result<ReceiverStream<Result<T, PluginError>>, PluginError>
+  where
+    T: Send + 'static,
+    S: Stream<Item = Result<T, PluginError>> + Unpin + Send + 'static,
+    F: FnMut(AIPluginOperation) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<S, PluginError>> + Send,
+  {
+    let idempotency_key = new_idempotency_key();
+    let mut stream = self
+      .retry_with_idempotency_key(&idempotency_key, &mut request)
+      .await?;
+    let reconnector = self.reconnector();
+    let (tx, rx) = tokio::sync::mpsc::channel(100);
+    tokio::spawn(async move {
+      let _permit = permit;
+      let mut retries = 0;
+      loop {
+        let item = tokio::select! {
+          item = stream.next() => item,
+          _ = tx.closed() => break,
+        };
+        let err = match item {
+          Some(Err(err)) if is_disconnect(&err) && retries < MAX_REQUEST_RETRIES => err,
+          Some(item) => {
+            if tx.send(item).await.is_err() {
+              break;
+            }
+            continue;
+          },
+          None => break,
+        };
+        retries += 1;
+        warn!(
+          "[AI Plugin] connection lost while streaming, retrying request ({}): {}",
+          retries, err
+        );
+        let next = match reconnector.wait_until_ready().await {
+          Ok(()) => match reconnector.plugin().await {
+            Ok(plugin) => {
+              let operation = AIPluginOperation::new(plugin).with_idempotency_key(&idempotency_key);
+              request(operation).await
+            },
+            Err(err) => Err(err),
+          },
+          Err(err) => Err(err),
+        };
+        match next {
+          Ok(next) => stream = next,
+          Err(err) => {
+            let _ = tx.send(Err(err)).await;
+            break;
+          },
+        }
+      }
+    });
+    Ok(ReceiverStream::new(rx))
+  }
+}
+
+/// Whether a request failed because the connection to the plugin dropped, so it can be sent again
+/// once the plugin is back.
+fn is_disconnect(err: &PluginError) -> bool {
+  matches!(
+    err,
+    PluginError::PeerDisconnect | PluginError::PluginNotConnected
+  )
+}
+
+/// What reconnecting to the chat plugin needs of [AppFlowyLocalAI], for tasks that outlive a call
+#[derive(Clone)]
+struct Reconnector {
+  plugin_manager: Arc<PluginManager>,
+  running_state: RunningStateSender,
+}
+
+impl Reconnector {
+  async fn plugin(&self) -> Result<Weak<Plugin>, PluginError> {
+    let plugin_id = self
+      .running_state
+      .borrow()
+      .plugin_id()
+      .ok_or_else(|| PluginError::Internal(anyhow!("chat plugin not initialized")))?;
+    let plugin = self.plugin_manager.acquire_plugin(plugin_id).await?;
+    Ok(plugin)
+  }
+
+  /// Waits until a loading plugin is ready, see [AppFlowyLocalAI::wait_until_plugin_ready].
+  async fn wait_until_ready(&self) -> Result<(), PluginError> {
     let is_loading = self.running_state.borrow().is_loading();
     if !is_loading {
       return Ok(());
     }
     info!("[AI Plugin] wait for chat plugin to be ready");
-    let mut rx = self.subscribe_running_state();
+    let mut rx = WatchStream::new(self.running_state.subscribe());
     let timeout_duration = Duration::from_secs(30);
     let result = timeout(timeout_duration, async {
       while let Some(state) = rx.next().await {
@@ -359,23 +1333,19 @@ impl AppFlowyLocalAI {
         trace!("[AI Plugin] is ready");
         Ok(())
       },
-      Err(_) => Err(anyhow!("Timeout while waiting for chat plugin to be ready")),
+      Err(_) => Err(PluginError::Internal(anyhow!(
+        "Timeout while waiting for chat plugin to be ready"
+      ))),
     }
   }
+}
 
-  /// Retrieves the chat plugin.
-  ///
-  /// # Returns
-  ///
-  /// A `Result<Weak<Plugin>>` containing a weak reference to the plugin.
-  pub async fn get_ai_plugin(&self) -> Result<Weak<Plugin>, PluginError> {
-    let plugin_id = self
-      .running_state
-      .borrow()
-      .plugin_id()
-      .ok_or_else(|| PluginError::Internal(anyhow!("chat plugin not initialized")))?;
-    let plugin = self.plugin_manager.get_plugin(plugin_id).await?;
-    Ok(plugin)
+impl Drop for AppFlowyLocalAI {
+  fn drop(&mut self) {
+    self.stop_idle_sweeper();
+    if let Some(handle) = self.idle_shutdown_watcher.lock().take() {
+      handle.abort();
+    }
   }
 }
 
@@ -386,8 +1356,69 @@ pub struct AIPluginConfig {
   pub related_model_path: Option<PathBuf>,
   pub embedding_model_path: Option<PathBuf>,
   pub persist_directory: Option<PathBuf>,
+  /// Cross-encoder used to re-score retrieved chunks before they're added to the chat prompt
+  pub reranker_model_path: Option<PathBuf>,
   pub device: String,
   pub verbose: bool,
+  /// Chats without any activity for this long are closed automatically. `None` keeps chats open
+  /// until [AppFlowyLocalAI::close_chat] is called.
+  pub chat_idle_timeout: Option<Duration>,
+  /// The plugin process is shut down after this long without requests, to free the memory of the
+  /// model, and started again on the next request. Open chats are closed with it, see
+  /// [ChatSessionEvent::IdleClosed]. `None` keeps the plugin running.
+  pub plugin_idle_timeout: Option<Duration>,
+  /// Maximum number of requests the plugin processes at the same time. Additional requests are
+  /// queued. `None` means no limit.
+  pub max_concurrent_requests: Option<usize>,
+  /// How indexed documents are split into chunks. Only used when RAG is enabled. The plugin's
+  /// default is used when `None`.
+  pub chunking: Option<ChunkingConfig>,
+  /// When true, the chat model rewrites the user's query into a more detailed one before
+  /// retrieval. Improves recall for terse questions at the cost of an extra generation. Passed to
+  /// the plugin, which expands the query during its own retrieval, for chat answers as well as
+  /// [AppFlowyLocalAI::search_workspace], so the host doesn't expand queries again.
+  pub expand_query: bool,
+  /// When true, chunks whose content was already indexed are skipped, so boilerplate that shows
+  /// up in many documents doesn't crowd out other retrieval results.
+  pub dedup: bool,
+  /// When set, the plugin is pinged periodically. A plugin that stops answering is restarted on
+  /// the next request instead of letting the request hang.
+  pub health_check: Option<HealthCheckConfig>,
+  /// When set, requests that stop making progress, e.g. because inference is wedged, are
+  /// reported through [PluginManager::subscribe_events].
+  pub watchdog: Option<WatchdogConfig>,
+  /// Memory and CPU limits of the chat plugin process
+  pub resource_limits: ResourceLimits,
+  /// CPU priority of the plugin process. [ProcessPriority::Low] keeps indexing from slowing
+  /// down the app.
+  pub priority: ProcessPriority,
+  /// Environment variables for the plugin process, e.g. `CUDA_VISIBLE_DEVICES`
+  pub env: HashMap<String, String>,
+  /// Command line arguments passed to the plugin binary
+  pub args: Vec<String>,
+  /// Restricts the plugin process, e.g. to keep it off the network. [Self::persist_directory] is
+  /// always writable.
+  pub sandbox: Option<SandboxPolicy>,
+  /// Starting the plugin fails with [PluginError::StartupTimeout] if it doesn't answer within
+  /// this duration. `None` waits indefinitely.
+  pub startup_timeout: Option<Duration>,
+  /// Limits the number of RPCs sent to the plugin process at the same time. Excess requests wait
+  /// or fail with [PluginError::Busy]. Background requests, e.g. indexing, only give way to
+  /// interactive ones while they wait here.
+  pub request_queue: Option<RequestQueueConfig>,
+  /// Compresses large messages to the plugin, e.g. indexed documents. Only used if the plugin supports it.
+  pub compression: Option<CompressionConfig>,
+  /// How messages are exchanged with the plugin, over stdio by default
+  pub transport: PluginTransport,
+  /// Plugins that must be running on the same [PluginManager] before the chat plugin starts, e.g.
+  /// [crate::embedding_plugin::EMBEDDING_PLUGIN_NAME]. Initializing the chat plugin fails with
+  /// [PluginError::MissingDependency] otherwise.
+  pub depends_on: Vec<String>,
+  /// Largest response accepted from the plugin, larger ones fail with
+  /// [PluginError::MessageTooLarge]. `None` uses the default of the plugin crate.
+  pub max_message_size: Option<usize>,
+  /// Records the RPC traffic with the plugin to this file, to reproduce bugs reported by users
+  pub recording: Option<PathBuf>,
 }
 
 impl AIPluginConfig {
@@ -415,15 +1446,50 @@ impl AIPluginConfig {
       return Err(anyhow!("Local model is not a file: {:?}", chat_model_path));
     }
 
-    Ok(Self {
+    Ok(Self::from_paths(chat_bin_path, chat_model_path))
+  }
+
+  /// For a chat plugin that already runs on another machine and listens on `addr`, see
+  /// [PluginTransport::Tcp]. `auth_token` must match the token the plugin was set up with.
+  /// `chat_model_path` is the path of the model on that machine, so it isn't checked.
+  pub fn new_remote<T: Into<PathBuf>>(addr: &str, auth_token: &str, chat_model_path: T) -> Self {
+    Self::from_paths(PathBuf::new(), chat_model_path.into()).with_transport(PluginTransport::Tcp {
+      addr: addr.to_string(),
+      auth_token: auth_token.to_string(),
+    })
+  }
+
+  fn from_paths(chat_bin_path: PathBuf, chat_model_path: PathBuf) -> Self {
+    Self {
       chat_bin_path,
       chat_model_path,
       related_model_path: None,
       embedding_model_path: None,
       persist_directory: None,
+      reranker_model_path: None,
       device: "cpu".to_string(),
       verbose: false,
-    })
+      chat_idle_timeout: None,
+      plugin_idle_timeout: None,
+      max_concurrent_requests: None,
+      chunking: None,
+      expand_query: false,
+      dedup: false,
+      health_check: None,
+      watchdog: None,
+      resource_limits: ResourceLimits::default(),
+      env: HashMap::new(),
+      args: vec![],
+      sandbox: None,
+      priority: ProcessPriority::Normal,
+      startup_timeout: None,
+      request_queue: None,
+      compression: None,
+      transport: PluginTransport::Stdio,
+      depends_on: vec![],
+      max_message_size: None,
+      recording: None,
+    }
   }
 
   pub fn with_device(mut self, device: &str) -> Self {
@@ -435,10 +1501,120 @@ impl AIPluginConfig {
     self.verbose = verbose;
     self
   }
+
+  pub fn with_chat_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+    self.chat_idle_timeout = Some(idle_timeout);
+    self
+  }
+
+  pub fn with_chunking(mut self, chunking: ChunkingConfig) -> Self {
+    self.chunking = Some(chunking);
+    self
+  }
+
+  pub fn with_max_concurrent_requests(mut self, max_concurrent_requests: usize) -> Self {
+    self.max_concurrent_requests = Some(max_concurrent_requests);
+    self
+  }
+
+  pub fn with_expand_query(mut self, expand_query: bool) -> Self {
+    self.expand_query = expand_query;
+    self
+  }
+
+  pub fn with_dedup(mut self, dedup: bool) -> Self {
+    self.dedup = dedup;
+    self
+  }
+
+  pub fn with_plugin_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+    self.plugin_idle_timeout = Some(idle_timeout);
+    self
+  }
+
+  pub fn with_health_check(mut self, health_check: HealthCheckConfig) -> Self {
+    self.health_check = Some(health_check);
+    self
+  }
+
+  pub fn with_watchdog(mut self, watchdog: WatchdogConfig) -> Self {
+    self.watchdog = Some(watchdog);
+    self
+  }
+
+  pub fn with_resource_limits(mut self, resource_limits: ResourceLimits) -> Self {
+    self.resource_limits = resource_limits;
+    self
+  }
+
+  pub fn with_env(mut self, key: &str, value: &str) -> Self {
+    self.env.insert(key.to_string(), value.to_string());
+    self
+  }
+
+  pub fn with_args(mut self, args: Vec<String>) -> Self {
+    self.args = args;
+    self
+  }
+
+  pub fn with_sandbox(mut self, sandbox: SandboxPolicy) -> Self {
+    self.sandbox = Some(sandbox);
+    self
+  }
+
+  pub fn with_priority(mut self, priority: ProcessPriority) -> Self {
+    self.priority = priority;
+    self
+  }
+
+  pub fn with_startup_timeout(mut self, startup_timeout: Duration) -> Self {
+    self.startup_timeout = Some(startup_timeout);
+    self
+  }
+
+  pub fn with_request_queue(mut self, request_queue: RequestQueueConfig) -> Self {
+    self.request_queue = Some(request_queue);
+    self
+  }
+
+  pub fn with_compression(mut self, compression: CompressionConfig) -> Self {
+    self.compression = Some(compression);
+    self
+  }
+
+  pub fn with_transport(mut self, transport: PluginTransport) -> Self {
+    self.transport = transport;
+    self
+  }
+
+  pub fn with_dependency(mut self, name: &str) -> Self {
+    self.depends_on.push(name.to_string());
+    self
+  }
+
+  pub fn with_max_message_size(mut self, max_message_size: usize) -> Self {
+    self.max_message_size = Some(max_message_size);
+    self
+  }
+
+  pub fn with_recording<T: Into<PathBuf>>(mut self, path: T) -> Self {
+    self.recording = Some(path.into());
+    self
+  }
+
+  fn sandbox_policy(&self) -> Option<SandboxPolicy> {
+    let policy = self.sandbox.clone()?;
+    Some(match &self.persist_directory {
+      Some(persist_directory) => policy.with_writable_path(persist_directory),
+      None => policy,
+    })
+  }
+
   pub fn set_rag_enabled(
     &mut self,
     embedding_model_path: &PathBuf,
     persist_directory: &PathBuf,
+    reranker_model_path: Option<&PathBuf>,
   ) -> Result<()> {
     if !embedding_model_path.exists() {
       return Err(anyhow!(
@@ -453,15 +1629,42 @@ impl AIPluginConfig {
       ));
     }
 
+    if let Some(reranker_model_path) = reranker_model_path {
+      if !reranker_model_path.is_file() {
+        return Err(anyhow!(
+          "reranker model is not a file: {:?}",
+          reranker_model_path
+        ));
+      }
+    }
+
     if !persist_directory.exists() {
       std::fs::create_dir_all(persist_directory)?;
     }
 
     self.embedding_model_path = Some(embedding_model_path.clone());
     self.persist_directory = Some(persist_directory.clone());
+    self.reranker_model_path = reranker_model_path.cloned();
     Ok(())
   }
 
+  /// Same as [Self::set_rag_enabled], with the persist directory of `workspace_id` derived from
+  /// `paths`.
+  pub fn set_workspace_rag_enabled(
+    &mut self,
+    embedding_model_path: &PathBuf,
+    paths: &VectorStorePaths,
+    workspace_id: &str,
+    reranker_model_path: Option<&PathBuf>,
+  ) -> Result<()> {
+    let persist_directory = paths.prepare_workspace_dir(workspace_id)?;
+    self.set_rag_enabled(
+      embedding_model_path,
+      &persist_directory,
+      reranker_model_path,
+    )
+  }
+
   pub fn with_related_model_path<T: Into<PathBuf>>(mut self, related_model_path: T) -> Self {
     self.related_model_path = Some(related_model_path.into());
     self
@@ -0,0 +1,85 @@
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::BroadcastStream;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChatSessionEvent {
+  /// The chat was closed in the plugin because it exceeded the idle timeout, or because the
+  /// plugin was shut down for being idle. The host should call `create_chat` again before sending
+  /// new messages to it.
+  IdleClosed { chat_id: String },
+}
+
+/// Tracks the last activity of every open chat session so idle chats can be closed in the
+/// plugin to reclaim memory.
+pub struct ChatSessionTracker {
+  last_active: Mutex<HashMap<String, Instant>>,
+  event_tx: broadcast::Sender<ChatSessionEvent>,
+}
+
+impl Default for ChatSessionTracker {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl ChatSessionTracker {
+  pub fn new() -> Self {
+    let (event_tx, _) = broadcast::channel(100);
+    Self {
+      last_active: Mutex::new(HashMap::new()),
+      event_tx,
+    }
+  }
+
+  /// Number of chats that are currently open.
+  pub fn len(&self) -> usize {
+    self.last_active.lock().len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.last_active.lock().is_empty()
+  }
+
+  /// Marks the chat as active right now.
+  pub fn touch(&self, chat_id: &str) {
+    self
+      .last_active
+      .lock()
+      .insert(chat_id.to_string(), Instant::now());
+  }
+
+  pub fn remove(&self, chat_id: &str) {
+    self.last_active.lock().remove(chat_id);
+  }
+
+  pub fn clear(&self) {
+    self.last_active.lock().clear();
+  }
+
+  /// Removes and returns all chats that have not been active for at least `idle_timeout`.
+  pub fn take_idle(&self, idle_timeout: Duration) -> Vec<String> {
+    let now = Instant::now();
+    let mut last_active = self.last_active.lock();
+    let idle_chat_ids = last_active
+      .iter()
+      .filter(|(_, last)| now.duration_since(**last) >= idle_timeout)
+      .map(|(chat_id, _)| chat_id.clone())
+      .collect::<Vec<_>>();
+    for chat_id in &idle_chat_ids {
+      last_active.remove(chat_id);
+    }
+    idle_chat_ids
+  }
+
+  pub fn send_event(&self, event: ChatSessionEvent) {
+    // It's ok if there is no subscriber
+    let _ = self.event_tx.send(event);
+  }
+
+  pub fn subscribe(&self) -> BroadcastStream<ChatSessionEvent> {
+    BroadcastStream::new(self.event_tx.subscribe())
+  }
+}
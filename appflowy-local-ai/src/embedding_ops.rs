@@ -1,11 +1,21 @@
+use crate::shared_memory::{self, SharedMemorySlice, SHARED_MEMORY};
+use crate::vector_store::{unix_timestamp, VectorStoreStats};
 use anyhow::anyhow;
-use appflowy_plugin::core::parser::{DefaultResponseParser, ResponseParser};
-use appflowy_plugin::core::plugin::Plugin;
+use appflowy_plugin::core::parser::ResponseParser;
+use appflowy_plugin::core::plugin::{Plugin, RequestStream};
+use appflowy_plugin::core::rpc_peer::RequestPriority;
 use appflowy_plugin::error::{PluginError, RemoteError};
+use serde::de::{DeserializeOwned, IgnoredAny};
+use serde::{Deserialize, Serialize};
 use serde_json::Value as JsonValue;
 use serde_json::{json, Value};
 use std::collections::HashMap;
+use std::path::PathBuf;
 use std::sync::Weak;
+use std::time::SystemTime;
+
+/// The collection used by hosts that don't need to separate their documents into namespaces.
+pub const DEFAULT_COLLECTION: &str = "default";
 
 pub struct EmbeddingPluginOperation {
   plugin: Weak<Plugin>,
@@ -16,54 +26,464 @@ impl EmbeddingPluginOperation {
     EmbeddingPluginOperation { plugin }
   }
 
-  pub async fn embed_documents(&self, message: &str) -> Result<Vec<Vec<f64>>, PluginError> {
+  /// Embeds `message`.
+  ///
+  /// When `normalize` is true the plugin scales every returned vector to unit length, so the
+  /// cosine similarity of two embeddings is simply their dot product.
+  pub async fn embed_documents(
+    &self,
+    message: &str,
+    normalize: bool,
+  ) -> Result<Vec<Vec<f64>>, PluginError> {
+    self
+      .embed(
+        "embed_documents",
+        json!({"input": message, "normalize": normalize}),
+      )
+      .await
+  }
+
+  /// Same as [Self::embed_documents], but asks the plugin for single precision values, which
+  /// halves the memory and serialization cost of large embeddings.
+  pub async fn embed_documents_f32(
+    &self,
+    message: &str,
+    normalize: bool,
+  ) -> Result<Vec<Vec<f32>>, PluginError> {
+    self
+      .embed(
+        "embed_documents",
+        json!({"input": message, "normalize": normalize}),
+      )
+      .await
+  }
+
+  /// Embeds all `texts` with a single request. The returned embeddings are in the same order as
+  /// `texts`. See [Self::embed_documents] for `normalize`.
+  pub async fn embed_documents_batch(
+    &self,
+    texts: &[String],
+    normalize: bool,
+  ) -> Result<Vec<Vec<f64>>, PluginError> {
+    self
+      .embed(
+        "embed_documents_batch",
+        json!({"inputs": texts, "normalize": normalize}),
+      )
+      .await
+  }
+
+  /// Single precision variant of [Self::embed_documents_batch].
+  pub async fn embed_documents_batch_f32(
+    &self,
+    texts: &[String],
+    normalize: bool,
+  ) -> Result<Vec<Vec<f32>>, PluginError> {
+    self
+      .embed(
+        "embed_documents_batch",
+        json!({"inputs": texts, "normalize": normalize}),
+      )
+      .await
+  }
+
+  /// Sends an embedding request. The embeddings are read from the shared memory of the plugin if
+  /// it was set up at init, see [SHARED_MEMORY].
+  async fn embed<T: EmbeddingValue>(
+    &self,
+    method: &str,
+    mut params: Value,
+  ) -> Result<Vec<Vec<T>>, PluginError> {
     let plugin = self
       .plugin
       .upgrade()
       .ok_or(PluginError::Internal(anyhow!("Plugin is dropped")))?;
-    let params = json!({"method": "embed_documents", "params": {"input": message }});
-    plugin
-      .async_request::<EmbeddingResponseParse>("handle", &params)
-      .await
+    let shared_memory = shared_memory_path(&plugin);
+    params["precision"] = json!(T::PRECISION);
+    if shared_memory.is_some() {
+      params["output"] = json!(SHARED_MEMORY);
+    }
+    let params = json!({"method": method, "params": params});
+    let json = plugin
+      .async_request::<EmbeddingOutputParse>("handle", &params, None)
+      .await?;
+
+    let slice = json
+      .get("data")
+      .and_then(|data| data.get(SHARED_MEMORY))
+      .and_then(|slice| SharedMemorySlice::deserialize(slice).ok());
+    match (shared_memory, slice) {
+      (Some(path), Some(slice)) => {
+        let bytes = shared_memory::read(&path, &slice);
+        plugin.notify("shared_memory_release", &json!({ "offset": slice.offset }));
+        decode_embeddings(&bytes?, slice.dimensions)
+          .ok_or_else(|| RemoteError::ParseResponse(json).into())
+      },
+      _ => Ok(parse_embeddings(json, T::from_f64)?),
+    }
   }
 
+  /// Indexes the document. When `expires_at` is set, the document is removed by the next
+  /// [Self::purge_expired] after that time.
   pub async fn index_document(
     &self,
+    collection: &str,
     message: &str,
     metadata: HashMap<String, Value>,
+    expires_at: Option<SystemTime>,
   ) -> Result<(), PluginError> {
+    let params = IndexDocumentParams {
+      collection,
+      doc_id: None,
+      input: message,
+      metadata,
+      expires_at: Some(expires_at.map(unix_timestamp)),
+    };
+    self
+      .handle::<_, IgnoredAny>("index_document", params, RequestPriority::Background)
+      .await?;
+    Ok(())
+  }
+
+  /// Indexes the document, replacing all chunks that were previously indexed for `doc_id`.
+  pub async fn upsert_document(
+    &self,
+    collection: &str,
+    doc_id: &str,
+    message: &str,
+    metadata: HashMap<String, Value>,
+  ) -> Result<(), PluginError> {
+    let params = IndexDocumentParams {
+      collection,
+      doc_id: Some(doc_id),
+      input: message,
+      metadata,
+      expires_at: None,
+    };
+    self
+      .handle::<_, IgnoredAny>("upsert_document", params, RequestPriority::Background)
+      .await?;
+    Ok(())
+  }
+
+  /// Deletes all documents in `collection` whose metadata matches `filter`.
+  pub async fn delete_documents(
+    &self,
+    collection: &str,
+    filter: HashMap<String, Value>,
+  ) -> Result<(), PluginError> {
+    let params = DeleteDocumentsParams { collection, filter };
+    self
+      .handle::<_, IgnoredAny>("delete_documents", params, RequestPriority::Interactive)
+      .await?;
+    Ok(())
+  }
+
+  pub async fn vector_store_stats(&self) -> Result<VectorStoreStats, PluginError> {
+    self
+      .handle(
+        "vector_store_stats",
+        NoParams {},
+        RequestPriority::Interactive,
+      )
+      .await
+  }
+
+  pub async fn model_info(&self) -> Result<EmbeddingModelInfo, PluginError> {
+    self
+      .handle("model_info", NoParams {}, RequestPriority::Interactive)
+      .await
+  }
+
+  /// Removes every document whose `expires_at` has passed and returns how many were removed.
+  pub async fn purge_expired(&self) -> Result<u64, PluginError> {
+    let response: PurgeExpiredResponse = self
+      .handle(
+        "purge_expired",
+        PurgeExpiredParams::now(),
+        RequestPriority::Background,
+      )
+      .await?;
+    Ok(response.purged)
+  }
+
+  /// Vacuums the vector store and returns the number of bytes reclaimed on disk.
+  pub async fn compact_vector_store(&self) -> Result<u64, PluginError> {
+    let response: CompactResponse = self
+      .handle(
+        "vector_store_compact",
+        NoParams {},
+        RequestPriority::Background,
+      )
+      .await?;
+    Ok(response.reclaimed_bytes)
+  }
+
+  pub async fn similarity_search(
+    &self,
+    collection: &str,
+    query: &str,
+    filter: HashMap<String, Value>,
+    options: SimilaritySearchOptions,
+  ) -> Result<Vec<SearchHit>, PluginError> {
     let plugin = self
       .plugin
       .upgrade()
       .ok_or(PluginError::Internal(anyhow!("Plugin is dropped")))?;
-    let metadata = json!(metadata);
-    let params =
-      json!({"method": "index_document", "params": {"input": message, "metadata": metadata }});
+    let params = handle_params(
+      "similarity_search",
+      SimilaritySearchParams::new(collection, query, filter, &options),
+    )?;
     plugin
-      .async_request::<DefaultResponseParser>("handle", &params)
+      .async_request::<SimilaritySearchResponseParse>("handle", &params, None)
       .await
   }
 
-  pub async fn similarity_search(
+  pub async fn similarity_search_with_score(
     &self,
+    collection: &str,
     query: &str,
     filter: HashMap<String, Value>,
-  ) -> Result<Vec<String>, PluginError> {
+    options: SimilaritySearchOptions,
+  ) -> Result<Vec<SearchResult>, PluginError> {
+    let params = SimilaritySearchParams::new(collection, query, filter, &options);
+    self
+      .handle(
+        "similarity_search_with_score",
+        params,
+        RequestPriority::Interactive,
+      )
+      .await
+  }
+
+  /// Streams the hits of a similarity search one by one instead of returning them in a single
+  /// response, which keeps messages small when `options.k` is large.
+  pub async fn stream_similarity_search(
+    &self,
+    collection: &str,
+    query: &str,
+    filter: HashMap<String, Value>,
+    options: SimilaritySearchOptions,
+  ) -> Result<RequestStream<Result<SearchResult, PluginError>>, PluginError> {
     let plugin = self
       .plugin
       .upgrade()
       .ok_or(PluginError::Internal(anyhow!("Plugin is dropped")))?;
-    let params =
-      json!({"method": "similarity_search", "params": {"query": query, "filter": filter }});
-    plugin
-      .async_request::<SimilaritySearchResponseParse>("handle", &params)
-      .await
+    let params = handle_params(
+      "stream_similarity_search",
+      SimilaritySearchParams::new(collection, query, filter, &options),
+    )?;
+    plugin.stream_request::<SimilaritySearchStreamResponseParse>("handle", &params, None)
+  }
+
+  /// Sends `params` to the plugin's `method` and deserializes the `data` of the response.
+  async fn handle<P, R>(
+    &self,
+    method: &str,
+    params: P,
+    priority: RequestPriority,
+  ) -> Result<R, PluginError>
+  where
+    P: Serialize,
+    R: DeserializeOwned + Send + Sync + 'static,
+  {
+    let plugin = self
+      .plugin
+      .upgrade()
+      .ok_or(PluginError::Internal(anyhow!("Plugin is dropped")))?;
+    let request = HandleRequest { method, params };
+    match priority {
+      RequestPriority::Interactive => plugin.typed_request("handle", &request, None).await,
+      RequestPriority::Background => {
+        plugin
+          .typed_background_request("handle", &request, None)
+          .await
+      },
+    }
+  }
+}
+
+/// The params of the `handle` request, which the plugin forwards to `method`.
+#[derive(Serialize)]
+struct HandleRequest<'a, P> {
+  method: &'a str,
+  params: P,
+}
+
+/// Serializes a [HandleRequest] for the requests that parse their response themselves.
+fn handle_params<P: Serialize>(method: &str, params: P) -> Result<JsonValue, PluginError> {
+  serde_json::to_value(HandleRequest { method, params })
+    .map_err(|err| PluginError::Internal(anyhow!("failed to serialize {}: {}", method, err)))
+}
+
+#[derive(Serialize)]
+struct NoParams {}
+
+#[derive(Serialize)]
+struct IndexDocumentParams<'a> {
+  collection: &'a str,
+  /// Only sent by `upsert_document`
+  #[serde(skip_serializing_if = "Option::is_none")]
+  doc_id: Option<&'a str>,
+  input: &'a str,
+  metadata: HashMap<String, Value>,
+  /// Only sent by `index_document`, where `null` means the document never expires
+  #[serde(skip_serializing_if = "Option::is_none")]
+  expires_at: Option<Option<u64>>,
+}
+
+#[derive(Serialize)]
+struct DeleteDocumentsParams<'a> {
+  collection: &'a str,
+  filter: HashMap<String, Value>,
+}
+
+#[derive(Serialize)]
+struct SimilaritySearchParams<'a> {
+  collection: &'a str,
+  query: &'a str,
+  filter: HashMap<String, Value>,
+  k: usize,
+  min_score: Option<f32>,
+  diversity: Option<f32>,
+}
+
+impl<'a> SimilaritySearchParams<'a> {
+  fn new(
+    collection: &'a str,
+    query: &'a str,
+    filter: HashMap<String, Value>,
+    options: &SimilaritySearchOptions,
+  ) -> Self {
+    Self {
+      collection,
+      query,
+      filter,
+      k: options.k,
+      min_score: options.min_score,
+      diversity: options.diversity,
+    }
+  }
+}
+
+/// Also sent by [AIPluginOperation::purge_expired](crate::ai_ops::AIPluginOperation::purge_expired).
+#[derive(Serialize)]
+pub(crate) struct PurgeExpiredParams {
+  now: u64,
+}
+
+impl PurgeExpiredParams {
+  pub(crate) fn now() -> Self {
+    Self {
+      now: unix_timestamp(SystemTime::now()),
+    }
+  }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct PurgeExpiredResponse {
+  pub purged: u64,
+}
+
+#[derive(Deserialize)]
+struct CompactResponse {
+  reclaimed_bytes: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimilaritySearchOptions {
+  /// Maximum number of hits to return
+  pub k: usize,
+  /// Hits with a lower score are dropped by the plugin
+  pub min_score: Option<f32>,
+  /// When set, hits are selected with Maximal Marginal Relevance instead of by score alone. `0.0`
+  /// only considers relevance, `1.0` favours chunks that differ the most from the ones already
+  /// selected, which avoids near-duplicate chunks of long documents.
+  pub diversity: Option<f32>,
+}
+
+impl Default for SimilaritySearchOptions {
+  fn default() -> Self {
+    Self {
+      k: 4,
+      min_score: None,
+      diversity: None,
+    }
+  }
+}
+
+impl SimilaritySearchOptions {
+  pub fn new(k: usize) -> Self {
+    Self {
+      k,
+      ..Default::default()
+    }
+  }
+
+  pub fn with_min_score(mut self, min_score: f32) -> Self {
+    self.min_score = Some(min_score);
+    self
+  }
+
+  pub fn with_diversity(mut self, diversity: f32) -> Self {
+    self.diversity = Some(diversity.clamp(0.0, 1.0));
+    self
+  }
+}
+
+/// Precision of the values in the embeddings returned by the plugin.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EmbeddingPrecision {
+  F32,
+  #[default]
+  F64,
+}
+
+/// Describes the embedding model loaded by the plugin.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct EmbeddingModelInfo {
+  pub name: String,
+  /// Length of every embedding the model produces
+  pub dimension: usize,
+  /// Maximum number of tokens the model embeds, longer input is truncated
+  pub max_sequence_length: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SearchHit {
+  pub content: String,
+  /// The metadata stored when the content was indexed, e.g. the id of the AppFlowy page
+  #[serde(default)]
+  pub metadata: HashMap<String, Value>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SearchResult {
+  pub content: String,
+  /// Relevance of the hit, higher is more similar
+  pub score: f64,
+  /// The metadata stored when the content was indexed
+  #[serde(default)]
+  pub metadata: HashMap<String, Value>,
+}
+
+pub struct SimilaritySearchStreamResponseParse;
+impl ResponseParser for SimilaritySearchStreamResponseParse {
+  type ValueType = SearchResult;
+
+  fn parse_json(json: JsonValue) -> Result<Self::ValueType, RemoteError> {
+    let result = match json.as_str() {
+      Some(s) => serde_json::from_str(s).ok(),
+      None => SearchResult::deserialize(&json).ok(),
+    };
+    result.ok_or(RemoteError::ParseResponse(json))
   }
 }
 
 pub struct SimilaritySearchResponseParse;
 impl ResponseParser for SimilaritySearchResponseParse {
-  type ValueType = Vec<String>;
+  type ValueType = Vec<SearchHit>;
 
   fn parse_json(json: JsonValue) -> Result<Self::ValueType, RemoteError> {
     if json.is_object() {
@@ -71,8 +491,14 @@ impl ResponseParser for SimilaritySearchResponseParse {
         if let Some(array) = data.as_array() {
           let mut result = Vec::new();
           for item in array {
+            // Older plugins only return the content of each hit
             if let Some(value) = item.as_str() {
-              result.push(value.to_string());
+              result.push(SearchHit {
+                content: value.to_string(),
+                metadata: HashMap::new(),
+              });
+            } else if let Ok(hit) = SearchHit::deserialize(item) {
+              result.push(hit);
             } else {
               return Err(RemoteError::ParseResponse(json));
             }
@@ -85,34 +511,144 @@ impl ResponseParser for SimilaritySearchResponseParse {
   }
 }
 
+/// The value type of embeddings, see [EmbeddingPrecision].
+trait EmbeddingValue: Sized {
+  const PRECISION: EmbeddingPrecision;
+  const SIZE: usize;
+
+  fn from_f64(value: f64) -> Self;
+  fn from_le_bytes(bytes: &[u8]) -> Self;
+}
+
+impl EmbeddingValue for f64 {
+  const PRECISION: EmbeddingPrecision = EmbeddingPrecision::F64;
+  const SIZE: usize = 8;
+
+  fn from_f64(value: f64) -> Self {
+    value
+  }
+
+  fn from_le_bytes(bytes: &[u8]) -> Self {
+    f64::from_le_bytes(bytes.try_into().unwrap())
+  }
+}
+
+impl EmbeddingValue for f32 {
+  const PRECISION: EmbeddingPrecision = EmbeddingPrecision::F32;
+  const SIZE: usize = 4;
+
+  fn from_f64(value: f64) -> Self {
+    value as f32
+  }
+
+  fn from_le_bytes(bytes: &[u8]) -> Self {
+    f32::from_le_bytes(bytes.try_into().unwrap())
+  }
+}
+
+/// The shared memory of the plugin, if it was set up at init and the plugin supports it.
+fn shared_memory_path(plugin: &Plugin) -> Option<PathBuf> {
+  if !plugin.supports(SHARED_MEMORY) {
+    return None;
+  }
+  let init_params = plugin.init_params()?;
+  let path = init_params.get(SHARED_MEMORY)?.get("path")?.as_str()?;
+  Some(PathBuf::from(path))
+}
+
+/// Splits the values written to shared memory into embeddings of `dimensions` values. `None` if
+/// the bytes don't add up to whole embeddings.
+fn decode_embeddings<T: EmbeddingValue>(bytes: &[u8], dimensions: usize) -> Option<Vec<Vec<T>>> {
+  let row_size = dimensions.checked_mul(T::SIZE)?;
+  if row_size == 0 || bytes.len() % row_size != 0 {
+    return None;
+  }
+  let embeddings = bytes
+    .chunks_exact(row_size)
+    .map(|row| row.chunks_exact(T::SIZE).map(T::from_le_bytes).collect())
+    .collect();
+  Some(embeddings)
+}
+
+/// The raw response of an embedding request, which holds either the embeddings or where to find
+/// them in shared memory.
+struct EmbeddingOutputParse;
+impl ResponseParser for EmbeddingOutputParse {
+  type ValueType = JsonValue;
+
+  fn parse_json(json: JsonValue) -> Result<Self::ValueType, RemoteError> {
+    Ok(json)
+  }
+}
+
 pub struct EmbeddingResponseParse;
 impl ResponseParser for EmbeddingResponseParse {
   type ValueType = Vec<Vec<f64>>;
 
   fn parse_json(json: JsonValue) -> Result<Self::ValueType, RemoteError> {
-    if json.is_object() {
-      if let Some(embeddings) = json.get("data") {
-        if let Some(array) = embeddings.as_array() {
-          let mut result = Vec::new();
-          for item in array {
-            if let Some(inner_array) = item.as_array() {
-              let mut inner_result = Vec::new();
-              for num in inner_array {
-                if let Some(value) = num.as_f64() {
-                  inner_result.push(value);
-                } else {
-                  return Err(RemoteError::ParseResponse(json));
-                }
+    parse_embeddings(json, |value| value)
+  }
+}
+
+fn parse_embeddings<T>(json: JsonValue, convert: fn(f64) -> T) -> Result<Vec<Vec<T>>, RemoteError> {
+  if json.is_object() {
+    if let Some(embeddings) = json.get("data") {
+      if let Some(array) = embeddings.as_array() {
+        let mut result = Vec::new();
+        for item in array {
+          if let Some(inner_array) = item.as_array() {
+            let mut inner_result = Vec::with_capacity(inner_array.len());
+            for num in inner_array {
+              if let Some(value) = num.as_f64() {
+                inner_result.push(convert(value));
+              } else {
+                return Err(RemoteError::ParseResponse(json));
               }
-              result.push(inner_result);
-            } else {
-              return Err(RemoteError::ParseResponse(json));
             }
+            result.push(inner_result);
+          } else {
+            return Err(RemoteError::ParseResponse(json));
           }
-          return Ok(result);
         }
+        return Ok(result);
       }
     }
-    Err(RemoteError::ParseResponse(json))
+  }
+  Err(RemoteError::ParseResponse(json))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn le_bytes<T: Copy>(values: &[T], to_le_bytes: fn(T) -> Vec<u8>) -> Vec<u8> {
+    values
+      .iter()
+      .flat_map(|value| to_le_bytes(*value))
+      .collect()
+  }
+
+  #[test]
+  fn decode_embeddings_test() {
+    let bytes = le_bytes(&[1.0f32, 2.0, 3.0, 4.0, 5.0, 6.0], |v| {
+      v.to_le_bytes().to_vec()
+    });
+    let embeddings = decode_embeddings::<f32>(&bytes, 3).unwrap();
+    assert_eq!(embeddings, vec![vec![1.0, 2.0, 3.0], vec![4.0, 5.0, 6.0]]);
+
+    let bytes = le_bytes(&[0.5f64, -0.25], |v| v.to_le_bytes().to_vec());
+    let embeddings = decode_embeddings::<f64>(&bytes, 1).unwrap();
+    assert_eq!(embeddings, vec![vec![0.5], vec![-0.25]]);
+
+    assert_eq!(decode_embeddings::<f32>(&[], 3), Some(vec![]));
+  }
+
+  #[test]
+  fn decode_embeddings_rejects_partial_embeddings_test() {
+    let bytes = le_bytes(&[1.0f32, 2.0, 3.0, 4.0], |v| v.to_le_bytes().to_vec());
+    assert_eq!(decode_embeddings::<f32>(&bytes, 3), None);
+    assert_eq!(decode_embeddings::<f32>(&bytes[..6], 1), None);
+    assert_eq!(decode_embeddings::<f32>(&bytes, 0), None);
+    assert_eq!(decode_embeddings::<f64>(&bytes, usize::MAX), None);
   }
 }
@@ -1,22 +1,39 @@
-use crate::embedding_ops::EmbeddingPluginOperation;
+use crate::embedding_ops::{
+  EmbeddingModelInfo, EmbeddingPluginOperation, SearchHit, SearchResult, SimilaritySearchOptions,
+};
+use crate::shared_memory::{self, SharedMemory, SharedMemoryConfig, SHARED_MEMORY};
+use crate::vector_store::{
+  export_vector_store, import_vector_store, read_vector_store_manifest, ChunkingConfig,
+  VectorStoreManifest, VectorStorePaths, VectorStoreStats,
+};
 use std::collections::HashMap;
 
 use anyhow::anyhow;
 use anyhow::Result;
+use appflowy_plugin::core::compression::CompressionConfig;
 use appflowy_plugin::core::plugin::{
-  Plugin, PluginInfo, RunningState, RunningStateReceiver, RunningStateSender,
+  Plugin, PluginId, PluginInfo, RequestStream, RunningState, RunningStateReceiver,
+  RunningStateSender,
 };
+use appflowy_plugin::core::resource_limit::{ProcessPriority, ResourceLimits};
+use appflowy_plugin::core::rpc_peer::RequestQueueConfig;
+use appflowy_plugin::core::sandbox::SandboxPolicy;
+use appflowy_plugin::core::transport::PluginTransport;
 use appflowy_plugin::error::PluginError;
 use appflowy_plugin::manager::PluginManager;
+use appflowy_plugin::router::{PluginRouter, RoutingStrategy};
 use serde_json::{json, Value};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Weak};
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 use tokio::sync::RwLock;
 use tokio::time::timeout;
 use tokio_stream::wrappers::WatchStream;
 use tokio_stream::StreamExt;
-use tracing::{info, trace};
+use tracing::{error, info, trace, warn};
+
+/// The [PluginInfo::name] of the embedding plugin, for use in [PluginInfo::depends_on]
+pub const EMBEDDING_PLUGIN_NAME: &str = "embedding";
 
 pub struct LocalEmbedding {
   plugin_manager: Arc<PluginManager>,
@@ -25,6 +42,19 @@ pub struct LocalEmbedding {
   #[allow(dead_code)]
   // keep at least one receiver that make sure the sender can receive value
   running_state_rx: RunningStateReceiver,
+  workers: RwLock<Option<EmbeddingWorkers>>,
+  /// The shared memory of the primary plugin, removed when the plugin is destroyed
+  shared_memory: RwLock<Option<SharedMemory>>,
+}
+
+/// Additional embedding processes started when [EmbeddingPluginConfig::instances] is greater
+/// than one.
+struct EmbeddingWorkers {
+  router: PluginRouter,
+  worker_ids: Vec<PluginId>,
+  // keep the receivers so that the workers can update their running state
+  _running_states: Vec<RunningStateReceiver>,
+  _shared_memory: Vec<SharedMemory>,
 }
 
 impl LocalEmbedding {
@@ -35,6 +65,8 @@ impl LocalEmbedding {
       plugin_config: Default::default(),
       running_state: Arc::new(running_state),
       running_state_rx: rx,
+      workers: Default::default(),
+      shared_memory: Default::default(),
     }
   }
 
@@ -50,25 +82,142 @@ impl LocalEmbedding {
       );
     }
 
+    *self.plugin_config.write().await = Some(config.clone());
+    // The region creates the directory the sandbox allows writing to
+    let region = config.create_shared_memory();
+    let sandbox = config.sandbox_policy();
     let info = PluginInfo {
-      name: "embedding".to_string(),
-      exec_path: config.bin_path,
+      name: EMBEDDING_PLUGIN_NAME.to_string(),
+      exec_path: config.bin_path.clone(),
+      resource_limits: config.resource_limits.clone(),
+      priority: config.priority,
+      env: config.env.clone(),
+      args: config.args.clone(),
+      sandbox,
+      startup_timeout: config.startup_timeout,
+      request_queue: config.request_queue,
+      compression: config.compression,
+      transport: config.transport.clone(),
+      depends_on: vec![],
+      max_message_size: config.max_message_size,
+      recording: config.recording.clone(),
     };
     let plugin_id = self
       .plugin_manager
-      .create_plugin(info, self.running_state.clone())
+      .create_plugin(info.clone(), self.running_state.clone())
       .await?;
 
     let mut params = json!({
         "absolute_model_path":config.model_path,
     });
 
-    if let Some(persist_directory) = config.persist_directory {
+    if let Some(persist_directory) = &config.persist_directory {
       params["persist_directory"] = json!(persist_directory);
     }
 
-    let plugin = self.plugin_manager.init_plugin(plugin_id, params).await?;
+    if let Some(chunking) = &config.chunking {
+      params["chunking"] = json!(chunking);
+    }
+
+    params["dedup"] = json!(config.dedup);
+
+    if let Some(region) = &region {
+      params[SHARED_MEMORY] = region.init_param();
+    }
+    let plugin = self
+      .plugin_manager
+      .init_plugin(plugin_id, params.clone())
+      .await?;
     info!("[Embedding Plugin] {} setup success", plugin);
+    *self.shared_memory.write().await = region;
+
+    self.stop_workers().await;
+    if config.instances > 1 {
+      self.start_workers(plugin_id, info, params, &config).await?;
+    }
+    Ok(())
+  }
+
+  /// Starts `instances - 1` additional embedding processes that share embedding requests with the
+  /// primary plugin. The additional processes don't open the vector store, so indexing and search
+  /// always go to the primary plugin.
+  async fn start_workers(
+    &self,
+    primary_id: PluginId,
+    info: PluginInfo,
+    mut params: Value,
+    config: &EmbeddingPluginConfig,
+  ) -> Result<(), PluginError> {
+    let (running_states, receivers): (Vec<_>, Vec<_>) = (1..config.instances)
+      .map(|_| {
+        let (tx, rx) = tokio::sync::watch::channel(RunningState::Connecting);
+        (Arc::new(tx), rx)
+      })
+      .unzip();
+    let worker_ids = self
+      .plugin_manager
+      .create_plugin_instances(info, running_states)
+      .await?;
+
+    if let Some(params) = params.as_object_mut() {
+      params.remove("persist_directory");
+    }
+    let mut regions = vec![];
+    for worker_id in &worker_ids {
+      let mut params = params.clone();
+      match config.create_shared_memory() {
+        Some(region) => {
+          params[SHARED_MEMORY] = region.init_param();
+          regions.push(region);
+        },
+        None => {
+          if let Some(params) = params.as_object_mut() {
+            params.remove(SHARED_MEMORY);
+          }
+        },
+      }
+      if let Err(err) = self.plugin_manager.init_plugin(*worker_id, params).await {
+        for worker_id in &worker_ids {
+          let _ = self.plugin_manager.remove_plugin(*worker_id).await;
+        }
+        return Err(err);
+      }
+    }
+
+    info!(
+      "[Embedding Plugin] started {} additional embedding workers",
+      worker_ids.len()
+    );
+    let mut plugin_ids = vec![primary_id];
+    plugin_ids.extend(worker_ids.iter().copied());
+    *self.workers.write().await = Some(EmbeddingWorkers {
+      router: PluginRouter::new(self.plugin_manager.clone(), plugin_ids, config.routing),
+      worker_ids,
+      _running_states: receivers,
+      _shared_memory: regions,
+    });
+    Ok(())
+  }
+
+  async fn stop_workers(&self) {
+    if let Some(workers) = self.workers.write().await.take() {
+      for worker_id in workers.worker_ids {
+        if let Err(err) = self.plugin_manager.remove_plugin(worker_id).await {
+          error!("remove embedding worker failed: {:?}", err);
+        }
+      }
+    }
+  }
+
+  pub async fn destroy_embedding_plugin(&self) -> Result<()> {
+    self.stop_workers().await;
+    let plugin_id = self.running_state.borrow().plugin_id();
+    if let Some(plugin_id) = plugin_id {
+      if let Err(err) = self.plugin_manager.remove_plugin(plugin_id).await {
+        error!("remove plugin failed: {:?}", err);
+      }
+    }
+    self.shared_memory.write().await.take();
     Ok(())
   }
 
@@ -79,45 +228,369 @@ impl LocalEmbedding {
   pub async fn generate_embedding(&self, text: &str) -> Result<Vec<Vec<f64>>, PluginError> {
     trace!("[Embedding Plugin] generate embedding for text: {}", text);
     self.wait_plugin_ready().await?;
-    let plugin = self.get_embedding_plugin().await?;
+    let plugin = self.get_embedding_worker().await?;
+    let operation = EmbeddingPluginOperation::new(plugin);
+    let embeddings = operation
+      .embed_documents(text, self.normalize_embeddings().await)
+      .await?;
+    Ok(embeddings)
+  }
+
+  /// Same as [Self::generate_embedding], with single precision values.
+  pub async fn generate_embedding_f32(&self, text: &str) -> Result<Vec<Vec<f32>>, PluginError> {
+    trace!(
+      "[Embedding Plugin] generate f32 embedding for text: {}",
+      text
+    );
+    self.wait_plugin_ready().await?;
+    let plugin = self.get_embedding_worker().await?;
     let operation = EmbeddingPluginOperation::new(plugin);
-    let embeddings = operation.embed_documents(text).await?;
+    let embeddings = operation
+      .embed_documents_f32(text, self.normalize_embeddings().await)
+      .await?;
     Ok(embeddings)
   }
 
+  /// Generates embeddings for multiple texts in one request, which is much faster than calling
+  /// [Self::generate_embedding] for each text when indexing many documents.
+  pub async fn generate_embeddings(
+    &self,
+    texts: Vec<String>,
+  ) -> Result<Vec<Vec<f64>>, PluginError> {
+    trace!(
+      "[Embedding Plugin] generate embeddings for {} texts",
+      texts.len()
+    );
+    if texts.is_empty() {
+      return Ok(vec![]);
+    }
+    self.wait_plugin_ready().await?;
+    let plugin = self.get_embedding_worker().await?;
+    let operation = EmbeddingPluginOperation::new(plugin);
+    let embeddings = operation
+      .embed_documents_batch(&texts, self.normalize_embeddings().await)
+      .await?;
+    if embeddings.len() != texts.len() {
+      return Err(PluginError::Internal(anyhow!(
+        "expected {} embeddings, got {}",
+        texts.len(),
+        embeddings.len()
+      )));
+    }
+    Ok(embeddings)
+  }
+
+  /// Same as [Self::generate_embeddings], with single precision values.
+  pub async fn generate_embeddings_f32(
+    &self,
+    texts: Vec<String>,
+  ) -> Result<Vec<Vec<f32>>, PluginError> {
+    trace!(
+      "[Embedding Plugin] generate f32 embeddings for {} texts",
+      texts.len()
+    );
+    if texts.is_empty() {
+      return Ok(vec![]);
+    }
+    self.wait_plugin_ready().await?;
+    let plugin = self.get_embedding_worker().await?;
+    let operation = EmbeddingPluginOperation::new(plugin);
+    let embeddings = operation
+      .embed_documents_batch_f32(&texts, self.normalize_embeddings().await)
+      .await?;
+    if embeddings.len() != texts.len() {
+      return Err(PluginError::Internal(anyhow!(
+        "expected {} embeddings, got {}",
+        texts.len(),
+        embeddings.len()
+      )));
+    }
+    Ok(embeddings)
+  }
+
+  /// Indexes `text` into `collection`. Collections are isolated namespaces inside the same persist
+  /// directory, e.g. one per workspace. Use [crate::embedding_ops::DEFAULT_COLLECTION] when no separation is needed.
+  ///
+  /// Temporary content, e.g. clipboard captures, can set `expires_at` so it's removed by
+  /// [Self::purge_expired].
   pub async fn index(
     &self,
+    collection: &str,
     text: &str,
     metadata: HashMap<String, Value>,
+    expires_at: Option<SystemTime>,
   ) -> Result<(), PluginError> {
-    trace!("[Embedding Plugin] generate embedding for text: {}", text);
+    trace!(
+      "[Embedding Plugin] index text into {}: {}",
+      collection,
+      text
+    );
     self.wait_plugin_ready().await?;
     let plugin = self.get_embedding_plugin().await?;
     let operation = EmbeddingPluginOperation::new(plugin);
-    operation.index_document(text, metadata).await?;
+    operation
+      .index_document(collection, text, metadata, expires_at)
+      .await?;
+    Ok(())
+  }
+
+  /// Indexes `text` as the content of `doc_id`. Chunks indexed earlier for the same `doc_id` are
+  /// replaced, so re-indexing an edited page does not accumulate duplicates.
+  pub async fn upsert(
+    &self,
+    collection: &str,
+    doc_id: &str,
+    text: &str,
+    metadata: HashMap<String, Value>,
+  ) -> Result<(), PluginError> {
+    trace!("[Embedding Plugin] upsert document: {}", doc_id);
+    self.wait_plugin_ready().await?;
+    let plugin = self.get_embedding_plugin().await?;
+    let operation = EmbeddingPluginOperation::new(plugin);
+    operation
+      .upsert_document(collection, doc_id, text, metadata)
+      .await?;
+    Ok(())
+  }
+
+  /// Deletes all documents in `collection` whose metadata matches `filter`.
+  pub async fn delete(
+    &self,
+    collection: &str,
+    filter: HashMap<String, Value>,
+  ) -> Result<(), PluginError> {
+    trace!(
+      "[Embedding Plugin] delete documents in {}: {:?}",
+      collection,
+      filter
+    );
+    self.wait_plugin_ready().await?;
+    let plugin = self.get_embedding_plugin().await?;
+    let operation = EmbeddingPluginOperation::new(plugin);
+    operation.delete_documents(collection, filter).await?;
     Ok(())
   }
 
   pub async fn similarity_search(
     &self,
+    collection: &str,
     query: &str,
     filter: HashMap<String, Value>,
-  ) -> Result<Vec<String>, PluginError> {
+    options: SimilaritySearchOptions,
+  ) -> Result<Vec<SearchHit>, PluginError> {
     trace!("[Embedding Plugin] similarity search for query: {}", query);
     self.wait_plugin_ready().await?;
     let plugin = self.get_embedding_plugin().await?;
     let operation = EmbeddingPluginOperation::new(plugin);
-    let result = operation.similarity_search(query, filter).await?;
+    let result = operation
+      .similarity_search(collection, query, filter, options)
+      .await?;
+    Ok(result)
+  }
+
+  /// Same as [Self::similarity_search], but every hit also carries its similarity score, so callers
+  /// can drop weak matches.
+  pub async fn similarity_search_with_score(
+    &self,
+    collection: &str,
+    query: &str,
+    filter: HashMap<String, Value>,
+    options: SimilaritySearchOptions,
+  ) -> Result<Vec<SearchResult>, PluginError> {
+    trace!(
+      "[Embedding Plugin] similarity search with score for query: {}",
+      query
+    );
+    self.wait_plugin_ready().await?;
+    let plugin = self.get_embedding_plugin().await?;
+    let operation = EmbeddingPluginOperation::new(plugin);
+    let result = operation
+      .similarity_search_with_score(collection, query, filter, options)
+      .await?;
     Ok(result)
   }
 
+  /// Streaming variant of [Self::similarity_search_with_score] for searches that return many hits,
+  /// e.g. across a whole workspace. Hits are yielded as the plugin sends them.
+  pub async fn stream_similarity_search(
+    &self,
+    collection: &str,
+    query: &str,
+    filter: HashMap<String, Value>,
+    options: SimilaritySearchOptions,
+  ) -> Result<RequestStream<Result<SearchResult, PluginError>>, PluginError> {
+    trace!(
+      "[Embedding Plugin] stream similarity search for query: {}",
+      query
+    );
+    self.wait_plugin_ready().await?;
+    let plugin = self.get_embedding_plugin().await?;
+    let operation = EmbeddingPluginOperation::new(plugin);
+    let stream = operation
+      .stream_similarity_search(collection, query, filter, options)
+      .await?;
+    Ok(stream)
+  }
+
+  /// Embeds a short text with every embedding process, so the model is loaded before the first
+  /// real request. Call it after [Self::init_embedding_plugin].
+  pub async fn warm_up(&self) -> Result<(), PluginError> {
+    self.wait_plugin_ready().await?;
+    let mut plugins = vec![self.get_embedding_plugin().await?];
+    if let Some(workers) = self.workers.read().await.as_ref() {
+      for worker_id in &workers.worker_ids {
+        plugins.push(self.plugin_manager.acquire_plugin(*worker_id).await?);
+      }
+    }
+
+    let normalize = self.normalize_embeddings().await;
+    for plugin in plugins {
+      EmbeddingPluginOperation::new(plugin)
+        .embed_documents("warm up", normalize)
+        .await?;
+    }
+    info!("[Embedding Plugin] warm up finished");
+    Ok(())
+  }
+
+  /// Returns the name, embedding dimension and maximum sequence length of the loaded model. Compare
+  /// the dimension with [VectorStoreStats::model_dimension] before indexing into an existing store.
+  pub async fn model_info(&self) -> Result<EmbeddingModelInfo, PluginError> {
+    self.wait_plugin_ready().await?;
+    let plugin = self.get_embedding_plugin().await?;
+    let operation = EmbeddingPluginOperation::new(plugin);
+    let info = operation.model_info().await?;
+    Ok(info)
+  }
+
+  /// Returns how many documents and chunks are stored and how much disk space the store uses.
+  pub async fn stats(&self) -> Result<VectorStoreStats, PluginError> {
+    self.wait_plugin_ready().await?;
+    let plugin = self.get_embedding_plugin().await?;
+    let operation = EmbeddingPluginOperation::new(plugin);
+    let stats = operation.vector_store_stats().await?;
+    Ok(stats)
+  }
+
+  /// Removes all documents that expired. Returns the number of removed documents.
+  pub async fn purge_expired(&self) -> Result<u64, PluginError> {
+    self.wait_plugin_ready().await?;
+    let plugin = self.get_embedding_plugin().await?;
+    let operation = EmbeddingPluginOperation::new(plugin);
+    let purged = operation.purge_expired().await?;
+    info!("[Embedding Plugin] purged {} expired documents", purged);
+    Ok(purged)
+  }
+
+  /// Compacts the vector store, which is useful after deleting many documents since deleted chunks
+  /// still take up space in the persist directory. Returns the number of bytes reclaimed.
+  pub async fn compact(&self) -> Result<u64, PluginError> {
+    self.wait_plugin_ready().await?;
+    let plugin = self.get_embedding_plugin().await?;
+    let operation = EmbeddingPluginOperation::new(plugin);
+    let reclaimed_bytes = operation.compact_vector_store().await?;
+    info!(
+      "[Embedding Plugin] compaction reclaimed {} bytes",
+      reclaimed_bytes
+    );
+    Ok(reclaimed_bytes)
+  }
+
+  /// Snapshots the persist directory, together with a [VectorStoreManifest], into a single zip
+  /// archive at `path` for backup or for moving the store to another machine.
+  pub async fn export(&self, path: &Path) -> Result<(), PluginError> {
+    let config = self
+      .plugin_config
+      .read()
+      .await
+      .clone()
+      .ok_or_else(|| anyhow!("Embedding plugin is not initialized yet"))?;
+    let persist_directory = config
+      .persist_directory
+      .clone()
+      .ok_or_else(|| anyhow!("Embedding plugin has no persist directory"))?;
+
+    let manifest = VectorStoreManifest::new(&config.model_path, config.chunking.clone());
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || export_vector_store(&persist_directory, &manifest, &path))
+      .await
+      .map_err(|err| anyhow!("export task failed: {}", err))??;
+    Ok(())
+  }
+
+  /// Replaces the persist directory with a store exported by [Self::export]. The plugin is
+  /// restarted so it picks up the imported store. Fails without touching the store if the archive
+  /// was created with a different embedding model, as its vectors wouldn't be comparable.
+  pub async fn import(&self, path: &Path) -> Result<VectorStoreManifest, PluginError> {
+    let config = self
+      .plugin_config
+      .read()
+      .await
+      .clone()
+      .ok_or_else(|| anyhow!("Embedding plugin is not initialized yet"))?;
+    let persist_directory = config
+      .persist_directory
+      .clone()
+      .ok_or_else(|| anyhow!("Embedding plugin has no persist directory"))?;
+
+    let archive_path = path.to_path_buf();
+    let manifest = tokio::task::spawn_blocking(move || read_vector_store_manifest(&archive_path))
+      .await
+      .map_err(|err| anyhow!("import task failed: {}", err))??;
+    let model_file_name = config
+      .model_path
+      .file_name()
+      .map(|name| name.to_string_lossy().to_string());
+    if manifest.model_file_name.is_some() && manifest.model_file_name != model_file_name {
+      return Err(PluginError::Internal(anyhow!(
+        "imported store was created with {:?}, current model is {:?}",
+        manifest.model_file_name,
+        model_file_name
+      )));
+    }
+
+    // The plugin holds the store open, so it must stop before the directory is swapped.
+    let plugin_id = self.running_state.borrow().plugin_id();
+    if let Some(plugin_id) = plugin_id {
+      self.plugin_manager.remove_plugin(plugin_id).await?;
+    }
+
+    let path = path.to_path_buf();
+    let result =
+      tokio::task::spawn_blocking(move || import_vector_store(&path, &persist_directory))
+        .await
+        .map_err(|err| anyhow!("import task failed: {}", err))?;
+
+    self.init_embedding_plugin(config).await?;
+    Ok(result?)
+  }
+
+  async fn normalize_embeddings(&self) -> bool {
+    self
+      .plugin_config
+      .read()
+      .await
+      .as_ref()
+      .map(|config| config.normalize)
+      .unwrap_or(false)
+  }
+
+  /// Plugin that embedding requests are sent to. Requests are spread over all instances when
+  /// more than one is configured.
+  async fn get_embedding_worker(&self) -> Result<Weak<Plugin>> {
+    if let Some(workers) = self.workers.read().await.as_ref() {
+      return Ok(workers.router.route().await?);
+    }
+    self.get_embedding_plugin().await
+  }
+
   async fn get_embedding_plugin(&self) -> Result<Weak<Plugin>> {
     let plugin_id = self
       .running_state
       .borrow()
       .plugin_id()
       .ok_or_else(|| anyhow!("Embedding plugin is not initialized yet"))?;
-    let plugin = self.plugin_manager.get_plugin(plugin_id).await?;
+    let plugin = self.plugin_manager.acquire_plugin(plugin_id).await?;
     Ok(plugin)
   }
 
@@ -153,6 +626,48 @@ pub struct EmbeddingPluginConfig {
   pub bin_path: PathBuf,
   pub model_path: PathBuf,
   pub persist_directory: Option<PathBuf>,
+  /// How indexed documents are split into chunks. The plugin's default is used when `None`.
+  pub chunking: Option<ChunkingConfig>,
+  /// Whether generated embeddings are scaled to unit length
+  pub normalize: bool,
+  /// When true, chunks whose content was already indexed are skipped. The number of skipped
+  /// chunks is reported in [VectorStoreStats::deduplicated_chunks].
+  pub dedup: bool,
+  /// Memory and CPU limits of the embedding plugin process
+  pub resource_limits: ResourceLimits,
+  /// CPU priority of the plugin process. [ProcessPriority::Low] keeps indexing from slowing
+  /// down the app.
+  pub priority: ProcessPriority,
+  /// Number of embedding processes. Embedding requests are spread over all of them according to
+  /// [Self::routing], which speeds up indexing on many-core machines.
+  pub instances: usize,
+  pub routing: RoutingStrategy,
+  /// Environment variables for the plugin process, e.g. `CUDA_VISIBLE_DEVICES`
+  pub env: HashMap<String, String>,
+  /// Command line arguments passed to the plugin binary
+  pub args: Vec<String>,
+  /// Restricts the plugin process, e.g. to keep it off the network. [Self::persist_directory] is
+  /// always writable.
+  pub sandbox: Option<SandboxPolicy>,
+  /// Starting the plugin fails with [PluginError::StartupTimeout] if it doesn't answer within
+  /// this duration. `None` waits indefinitely.
+  pub startup_timeout: Option<Duration>,
+  /// Limits the number of RPCs sent to the plugin process at the same time. Excess requests wait
+  /// or fail with [PluginError::Busy]. Background requests, e.g. indexing, only give way to
+  /// interactive ones while they wait here.
+  pub request_queue: Option<RequestQueueConfig>,
+  /// Compresses large messages to the plugin, e.g. embedding batches. Only used if the plugin supports it.
+  pub compression: Option<CompressionConfig>,
+  /// How messages are exchanged with the plugin, over stdio by default
+  pub transport: PluginTransport,
+  /// Returns embeddings through shared memory instead of JSON if the plugin supports it. Not
+  /// available for [PluginTransport::Tcp].
+  pub shared_memory: Option<SharedMemoryConfig>,
+  /// Largest response accepted from the plugin, larger ones fail with
+  /// [PluginError::MessageTooLarge]. `None` uses the default of the plugin crate.
+  pub max_message_size: Option<usize>,
+  /// Records the RPC traffic with the plugin to this file, to reproduce bugs reported by users
+  pub recording: Option<PathBuf>,
 }
 
 impl EmbeddingPluginConfig {
@@ -188,6 +703,144 @@ impl EmbeddingPluginConfig {
       bin_path,
       model_path,
       persist_directory: storage_path,
+      chunking: None,
+      normalize: false,
+      dedup: false,
+      resource_limits: ResourceLimits::default(),
+      instances: 1,
+      routing: RoutingStrategy::default(),
+      env: HashMap::new(),
+      args: vec![],
+      sandbox: None,
+      priority: ProcessPriority::Normal,
+      startup_timeout: None,
+      request_queue: None,
+      compression: None,
+      transport: PluginTransport::Stdio,
+      shared_memory: None,
+      max_message_size: None,
+      recording: None,
     })
   }
+
+  /// Persists the store in the directory of `workspace_id` derived from `paths`.
+  pub fn with_workspace_store(
+    mut self,
+    paths: &VectorStorePaths,
+    workspace_id: &str,
+  ) -> Result<Self> {
+    self.persist_directory = Some(paths.prepare_workspace_dir(workspace_id)?);
+    Ok(self)
+  }
+
+  pub fn with_normalize(mut self, normalize: bool) -> Self {
+    self.normalize = normalize;
+    self
+  }
+
+  pub fn with_dedup(mut self, dedup: bool) -> Self {
+    self.dedup = dedup;
+    self
+  }
+
+  pub fn with_chunking(mut self, chunking: ChunkingConfig) -> Self {
+    self.chunking = Some(chunking);
+    self
+  }
+
+  pub fn with_resource_limits(mut self, resource_limits: ResourceLimits) -> Self {
+    self.resource_limits = resource_limits;
+    self
+  }
+
+  pub fn with_instances(mut self, instances: usize, routing: RoutingStrategy) -> Self {
+    self.instances = instances.max(1);
+    self.routing = routing;
+    self
+  }
+
+  pub fn with_env(mut self, key: &str, value: &str) -> Self {
+    self.env.insert(key.to_string(), value.to_string());
+    self
+  }
+
+  pub fn with_args(mut self, args: Vec<String>) -> Self {
+    self.args = args;
+    self
+  }
+
+  pub fn with_sandbox(mut self, sandbox: SandboxPolicy) -> Self {
+    self.sandbox = Some(sandbox);
+    self
+  }
+
+  pub fn with_priority(mut self, priority: ProcessPriority) -> Self {
+    self.priority = priority;
+    self
+  }
+
+  pub fn with_startup_timeout(mut self, startup_timeout: Duration) -> Self {
+    self.startup_timeout = Some(startup_timeout);
+    self
+  }
+
+  pub fn with_request_queue(mut self, request_queue: RequestQueueConfig) -> Self {
+    self.request_queue = Some(request_queue);
+    self
+  }
+
+  pub fn with_compression(mut self, compression: CompressionConfig) -> Self {
+    self.compression = Some(compression);
+    self
+  }
+
+  pub fn with_transport(mut self, transport: PluginTransport) -> Self {
+    self.transport = transport;
+    self
+  }
+
+  pub fn with_shared_memory(mut self, shared_memory: SharedMemoryConfig) -> Self {
+    self.shared_memory = Some(shared_memory);
+    self
+  }
+
+  pub fn with_max_message_size(mut self, max_message_size: usize) -> Self {
+    self.max_message_size = Some(max_message_size);
+    self
+  }
+
+  pub fn with_recording<T: Into<PathBuf>>(mut self, path: T) -> Self {
+    self.recording = Some(path.into());
+    self
+  }
+
+  fn sandbox_policy(&self) -> Option<SandboxPolicy> {
+    let mut policy = self.sandbox.clone()?;
+    if let Some(persist_directory) = &self.persist_directory {
+      policy = policy.with_writable_path(persist_directory);
+    }
+    if self.shared_memory.is_some() {
+      policy = policy.with_writable_path(shared_memory::dir());
+    }
+    Some(policy)
+  }
+
+  /// Creates the shared memory of one plugin process. Falls back to JSON responses if it can't be
+  /// created.
+  fn create_shared_memory(&self) -> Option<SharedMemory> {
+    let config = self.shared_memory.as_ref()?;
+    if matches!(self.transport, PluginTransport::Tcp { .. }) {
+      return None;
+    }
+    match SharedMemory::create(config) {
+      Ok(region) => Some(region),
+      Err(err) => {
+        warn!(
+          "[Embedding Plugin] failed to create shared memory: {:?}",
+          err
+        );
+        None
+      },
+    }
+  }
 }
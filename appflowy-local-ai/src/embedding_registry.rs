@@ -0,0 +1,96 @@
+use crate::embedding_plugin::{EmbeddingPluginConfig, LocalEmbedding};
+use anyhow::anyhow;
+use appflowy_plugin::error::PluginError;
+use appflowy_plugin::manager::PluginManager;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tracing::{info, trace};
+
+/// Manages several embedding models at the same time, each running in its own [LocalEmbedding]
+/// plugin, e.g. a multilingual model next to an English one. Models are addressed by the id they
+/// were added with.
+pub struct EmbeddingModelRegistry {
+  plugin_manager: Arc<PluginManager>,
+  models: RwLock<HashMap<String, Arc<LocalEmbedding>>>,
+}
+
+impl EmbeddingModelRegistry {
+  pub fn new(plugin_manager: Arc<PluginManager>) -> Self {
+    Self {
+      plugin_manager,
+      models: Default::default(),
+    }
+  }
+
+  /// Starts a plugin for `model_id`. A model that was already added under the same id is
+  /// replaced.
+  pub async fn add_model(
+    &self,
+    model_id: &str,
+    config: EmbeddingPluginConfig,
+  ) -> Result<Arc<LocalEmbedding>, PluginError> {
+    trace!("[Embedding Registry] add model {}: {:?}", model_id, config);
+    let embedding = Arc::new(LocalEmbedding::new(self.plugin_manager.clone()));
+    embedding.init_embedding_plugin(config).await?;
+
+    let old = self
+      .models
+      .write()
+      .await
+      .insert(model_id.to_string(), embedding.clone());
+    if let Some(old) = old {
+      info!("[Embedding Registry] replace model {}", model_id);
+      old.destroy_embedding_plugin().await?;
+    }
+    Ok(embedding)
+  }
+
+  pub async fn remove_model(&self, model_id: &str) -> Result<(), PluginError> {
+    let embedding = self.models.write().await.remove(model_id);
+    if let Some(embedding) = embedding {
+      embedding.destroy_embedding_plugin().await?;
+    }
+    Ok(())
+  }
+
+  pub async fn get_model(&self, model_id: &str) -> Result<Arc<LocalEmbedding>, PluginError> {
+    self
+      .models
+      .read()
+      .await
+      .get(model_id)
+      .cloned()
+      .ok_or_else(|| PluginError::Internal(anyhow!("embedding model not found: {}", model_id)))
+  }
+
+  pub async fn model_ids(&self) -> Vec<String> {
+    let mut ids = self.models.read().await.keys().cloned().collect::<Vec<_>>();
+    ids.sort();
+    ids
+  }
+
+  pub async fn generate_embedding(
+    &self,
+    model_id: &str,
+    text: &str,
+  ) -> Result<Vec<Vec<f64>>, PluginError> {
+    self
+      .get_model(model_id)
+      .await?
+      .generate_embedding(text)
+      .await
+  }
+
+  pub async fn generate_embeddings(
+    &self,
+    model_id: &str,
+    texts: Vec<String>,
+  ) -> Result<Vec<Vec<f64>>, PluginError> {
+    self
+      .get_model(model_id)
+      .await?
+      .generate_embeddings(texts)
+      .await
+  }
+}
@@ -0,0 +1,397 @@
+use anyhow::anyhow;
+use glob::Pattern;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Debug, Formatter};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tracing::warn;
+
+/// The formats the chat plugin knows how to extract text from. The detected type is sent to the
+/// plugin as `file_type` so it can pick the right loader.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum IndexFileType {
+  Pdf,
+  Text,
+  Markdown,
+  Html,
+  Docx,
+  Epub,
+  Csv,
+}
+
+impl IndexFileType {
+  pub fn from_extension(ext: &str) -> Option<Self> {
+    match ext.to_ascii_lowercase().as_str() {
+      "pdf" => Some(IndexFileType::Pdf),
+      "txt" | "text" | "log" => Some(IndexFileType::Text),
+      "md" | "markdown" => Some(IndexFileType::Markdown),
+      "html" | "htm" | "xhtml" => Some(IndexFileType::Html),
+      "docx" => Some(IndexFileType::Docx),
+      "epub" => Some(IndexFileType::Epub),
+      "csv" => Some(IndexFileType::Csv),
+      _ => None,
+    }
+  }
+
+  /// Detects the type from the file extension. Only files without an extension are sniffed, for a
+  /// PDF header; files with an unknown extension are not opened.
+  pub fn from_path(path: &Path) -> Option<Self> {
+    if let Some(ext) = path.extension() {
+      return ext.to_str().and_then(Self::from_extension);
+    }
+
+    let mut header = [0u8; 5];
+    let mut file = std::fs::File::open(path).ok()?;
+    std::io::Read::read_exact(&mut file, &mut header).ok()?;
+    if &header == b"%PDF-" {
+      return Some(IndexFileType::Pdf);
+    }
+    None
+  }
+
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      IndexFileType::Pdf => "pdf",
+      IndexFileType::Text => "text",
+      IndexFileType::Markdown => "markdown",
+      IndexFileType::Html => "html",
+      IndexFileType::Docx => "docx",
+      IndexFileType::Epub => "epub",
+      IndexFileType::Csv => "csv",
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IndexStage {
+  Parsing,
+  Chunking,
+  Embedding,
+  Persisting,
+}
+
+/// Reported by the plugin while a single file is indexed. `current` and `total` count the units
+/// of work in `stage`, e.g. pages while parsing or chunks while embedding.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct IndexProgress {
+  pub stage: IndexStage,
+  pub current: u64,
+  pub total: u64,
+}
+
+/// Options for [crate::chat_plugin::AppFlowyLocalAI::index_file].
+#[derive(Clone, Default, Eq, PartialEq)]
+pub struct IndexFileOptions {
+  /// 0-based range of the PDF pages to index, the end is exclusive. All pages are indexed when
+  /// `None`.
+  pub pages: Option<Range<usize>>,
+  /// Password of an encrypted PDF
+  pub password: Option<String>,
+  /// The indexed content is removed by
+  /// [crate::chat_plugin::AppFlowyLocalAI::purge_expired] after this time. Useful for temporary
+  /// content such as chat attachments.
+  pub expires_at: Option<SystemTime>,
+}
+
+impl Debug for IndexFileOptions {
+  /// Redacts the password, since jobs that contain the options are logged
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("IndexFileOptions")
+      .field("pages", &self.pages)
+      .field("password", &self.password.as_ref().map(|_| "<redacted>"))
+      .field("expires_at", &self.expires_at)
+      .finish()
+  }
+}
+
+impl IndexFileOptions {
+  pub fn with_pages(mut self, pages: Range<usize>) -> Self {
+    self.pages = Some(pages);
+    self
+  }
+
+  pub fn with_password(mut self, password: &str) -> Self {
+    self.password = Some(password.to_string());
+    self
+  }
+
+  pub fn with_expires_at(mut self, expires_at: SystemTime) -> Self {
+    self.expires_at = Some(expires_at);
+    self
+  }
+}
+
+/// Options for [crate::chat_plugin::AppFlowyLocalAI::index_directory].
+#[derive(Debug, Clone, Default)]
+pub struct IndexOptions {
+  /// Glob patterns, relative to the indexed directory, that a file must match to be indexed.
+  /// All supported files are indexed when empty.
+  pub globs: Vec<String>,
+  /// Files larger than this many bytes are skipped.
+  pub max_file_size: Option<u64>,
+}
+
+#[derive(Debug)]
+pub enum FileIndexStatus {
+  Indexed,
+  Failed(String),
+}
+
+/// Emitted once for every file processed by
+/// [crate::chat_plugin::AppFlowyLocalAI::index_directory].
+#[derive(Debug)]
+pub struct DirectoryIndexProgress {
+  pub file_path: PathBuf,
+  /// 1-based position of this file among all files that are indexed
+  pub current: usize,
+  pub total: usize,
+  pub status: FileIndexStatus,
+}
+
+/// Returns true for images that can be indexed with
+/// [crate::chat_plugin::AppFlowyLocalAI::index_image].
+pub fn is_image_file(path: &Path) -> bool {
+  path
+    .extension()
+    .and_then(|ext| ext.to_str())
+    .map(|ext| {
+      matches!(
+        ext.to_ascii_lowercase().as_str(),
+        "png" | "jpg" | "jpeg" | "webp" | "bmp" | "tif" | "tiff" | "gif"
+      )
+    })
+    .unwrap_or(false)
+}
+
+pub fn is_supported_file(path: &Path) -> bool {
+  IndexFileType::from_path(path).is_some()
+}
+
+/// Recursively collects all files under `dir` that are supported and match `options`. The
+/// result is sorted so files are indexed in a stable order.
+pub fn collect_index_files(
+  dir: &Path,
+  options: &IndexOptions,
+) -> Result<Vec<PathBuf>, anyhow::Error> {
+  if !dir.is_dir() {
+    return Err(anyhow!("{:?} is not a directory", dir));
+  }
+
+  let patterns = options
+    .globs
+    .iter()
+    .map(|glob| Pattern::new(glob))
+    .collect::<Result<Vec<_>, _>>()?;
+
+  let mut files = vec![];
+  let mut pending_dirs = vec![dir.to_path_buf()];
+  while let Some(current_dir) = pending_dirs.pop() {
+    let entries = match std::fs::read_dir(&current_dir) {
+      Ok(entries) => entries,
+      Err(err) => {
+        warn!("failed to read directory {:?}: {:?}", current_dir, err);
+        continue;
+      },
+    };
+
+    for entry in entries.flatten() {
+      let path = entry.path();
+      let metadata = match entry.metadata() {
+        Ok(metadata) => metadata,
+        Err(_) => continue,
+      };
+
+      if metadata.is_dir() {
+        pending_dirs.push(path);
+        continue;
+      }
+
+      if !metadata.is_file() || !is_supported_file(&path) {
+        continue;
+      }
+
+      if let Some(max_file_size) = options.max_file_size {
+        if metadata.len() > max_file_size {
+          continue;
+        }
+      }
+
+      if !patterns.is_empty() {
+        let relative_path = path.strip_prefix(dir).unwrap_or(&path);
+        if !patterns.iter().any(|p| p.matches_path(relative_path)) {
+          continue;
+        }
+      }
+      files.push(path);
+    }
+  }
+
+  files.sort();
+  Ok(files)
+}
+
+/// Elements whose content is never part of the readable text of a page.
+const SKIPPED_HTML_ELEMENTS: [&str; 9] = [
+  "script", "style", "noscript", "template", "svg", "nav", "header", "footer", "aside",
+];
+
+/// Elements that start a new line in the extracted text.
+const BLOCK_HTML_ELEMENTS: [&str; 18] = [
+  "p",
+  "div",
+  "br",
+  "li",
+  "ul",
+  "ol",
+  "h1",
+  "h2",
+  "h3",
+  "h4",
+  "h5",
+  "h6",
+  "tr",
+  "table",
+  "section",
+  "article",
+  "blockquote",
+  "pre",
+];
+
+/// Extracts the readable text of an HTML page. Scripts, styles and page chrome such as
+/// navigation bars and footers are dropped, block elements become line breaks and whitespace is
+/// collapsed.
+pub fn html_to_text(html: &str) -> String {
+  let mut text = String::new();
+  let mut skip_depth = 0usize;
+  let mut rest = html;
+
+  while let Some(start) = rest.find('<') {
+    if skip_depth == 0 {
+      push_text(&mut text, &rest[..start]);
+    }
+    rest = &rest[start..];
+
+    if rest.starts_with("<!--") {
+      rest = rest.find("-->").map(|end| &rest[end + 3..]).unwrap_or("");
+      continue;
+    }
+
+    // A `<` that doesn't start a tag, e.g. in `a < b`, is text
+    let starts_tag = rest[1..].starts_with(|c: char| c.is_ascii_alphabetic() || "/!?".contains(c));
+    let end = match rest.find('>') {
+      Some(end) if starts_tag => end,
+      _ => {
+        if skip_depth == 0 {
+          push_text(&mut text, "<");
+        }
+        rest = &rest[1..];
+        continue;
+      },
+    };
+    let tag = &rest[1..end];
+    rest = &rest[end + 1..];
+
+    let is_closing = tag.starts_with('/');
+    let name = tag
+      .trim_start_matches('/')
+      .split(|c: char| c.is_whitespace() || c == '/')
+      .next()
+      .unwrap_or("")
+      .to_ascii_lowercase();
+    let is_self_closing = tag.ends_with('/');
+
+    if SKIPPED_HTML_ELEMENTS.contains(&name.as_str()) {
+      if is_closing {
+        skip_depth = skip_depth.saturating_sub(1);
+      } else if !is_self_closing {
+        skip_depth += 1;
+      }
+      continue;
+    }
+
+    if skip_depth == 0 && BLOCK_HTML_ELEMENTS.contains(&name.as_str()) && !text.ends_with('\n') {
+      text.push('\n');
+    }
+  }
+  if skip_depth == 0 {
+    push_text(&mut text, rest);
+  }
+
+  text
+    .lines()
+    .map(|line| line.trim())
+    .filter(|line| !line.is_empty())
+    .collect::<Vec<_>>()
+    .join("\n")
+}
+
+fn push_text(text: &mut String, raw: &str) {
+  let decoded = decode_html_entities(raw);
+  let needs_separator = |text: &String| !text.is_empty() && !text.ends_with(['\n', ' ']);
+  if decoded.starts_with(char::is_whitespace) && needs_separator(text) {
+    text.push(' ');
+  }
+  let mut words = decoded.split_whitespace().peekable();
+  while let Some(word) = words.next() {
+    text.push_str(word);
+    if words.peek().is_some() {
+      text.push(' ');
+    }
+  }
+  if decoded.ends_with(char::is_whitespace) && needs_separator(text) {
+    text.push(' ');
+  }
+}
+
+/// Longest entity [decode_html_entities] knows, `&#x10FFFF;`
+const MAX_HTML_ENTITY_LEN: usize = 10;
+
+fn decode_html_entities(raw: &str) -> String {
+  let mut decoded = String::with_capacity(raw.len());
+  let mut rest = raw;
+  while let Some(start) = rest.find('&') {
+    decoded.push_str(&rest[..start]);
+    rest = &rest[start..];
+    let entity = rest
+      .find(';')
+      .filter(|end| *end < MAX_HTML_ENTITY_LEN)
+      .and_then(|end| Some((end, decode_html_entity(&rest[1..end])?)));
+    match entity {
+      Some((end, c)) => {
+        decoded.push(c);
+        rest = &rest[end + 1..];
+      },
+      // Not an entity, e.g. `a & b`
+      None => {
+        decoded.push('&');
+        rest = &rest[1..];
+      },
+    }
+  }
+  decoded.push_str(rest);
+  decoded
+}
+
+/// Decodes the name of an entity without `&` and `;`, named or numeric like `#169` and `#xA9`.
+fn decode_html_entity(entity: &str) -> Option<char> {
+  let code = match entity.strip_prefix('#') {
+    Some(number) => match number.strip_prefix(['x', 'X']) {
+      Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+      None => number.parse().ok()?,
+    },
+    None => {
+      return match entity {
+        "nbsp" => Some(' '),
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        "amp" => Some('&'),
+        _ => None,
+      }
+    },
+  };
+  char::from_u32(code)
+}
@@ -0,0 +1,318 @@
+use crate::chat_plugin::AppFlowyLocalAI;
+use crate::file_index::{IndexFileOptions, IndexProgress};
+use appflowy_plugin::core::plugin::RunningState;
+use appflowy_plugin::error::PluginError;
+use parking_lot::Mutex;
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use tokio::sync::{broadcast, Notify};
+use tokio::task::JoinHandle;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::StreamExt;
+use tracing::{error, info, trace};
+
+/// How many times a job is re-queued because the plugin went away while it was indexed.
+const MAX_JOB_ATTEMPTS: usize = 3;
+
+pub type IndexJobId = u64;
+
+/// A file or a piece of text that should be indexed into the retrieval store of a chat.
+#[derive(Debug, Clone)]
+pub struct IndexJob {
+  pub chat_id: String,
+  pub file_path: Option<PathBuf>,
+  pub content: Option<String>,
+  pub metadata: Option<HashMap<String, Value>>,
+  pub options: IndexFileOptions,
+}
+
+impl IndexJob {
+  pub fn file<T: Into<PathBuf>>(chat_id: &str, file_path: T) -> Self {
+    Self {
+      chat_id: chat_id.to_string(),
+      file_path: Some(file_path.into()),
+      content: None,
+      metadata: None,
+      options: IndexFileOptions::default(),
+    }
+  }
+
+  pub fn text(chat_id: &str, content: &str) -> Self {
+    Self {
+      chat_id: chat_id.to_string(),
+      file_path: None,
+      content: Some(content.to_string()),
+      metadata: None,
+      options: IndexFileOptions::default(),
+    }
+  }
+
+  pub fn with_metadata(mut self, metadata: HashMap<String, Value>) -> Self {
+    self.metadata = Some(metadata);
+    self
+  }
+
+  pub fn with_options(mut self, options: IndexFileOptions) -> Self {
+    self.options = options;
+    self
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum IndexJobEvent {
+  Queued {
+    job_id: IndexJobId,
+  },
+  Started {
+    job_id: IndexJobId,
+  },
+  Progress {
+    job_id: IndexJobId,
+    progress: IndexProgress,
+  },
+  Completed {
+    job_id: IndexJobId,
+  },
+  Failed {
+    job_id: IndexJobId,
+    error: String,
+  },
+  /// The plugin stopped while the job was indexed. The job runs again once the plugin is back.
+  Requeued {
+    job_id: IndexJobId,
+  },
+  /// Removed with [IndexingQueue::cancel] before it started.
+  Cancelled {
+    job_id: IndexJobId,
+  },
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct IndexingQueueStatus {
+  pub pending: usize,
+  pub running: Option<IndexJobId>,
+  pub completed: usize,
+  pub failed: usize,
+}
+
+struct QueuedJob {
+  id: IndexJobId,
+  job: IndexJob,
+  attempts: usize,
+}
+
+#[derive(Default)]
+struct QueueState {
+  pending: VecDeque<QueuedJob>,
+  running: Option<IndexJobId>,
+  completed: usize,
+  failed: usize,
+}
+
+struct QueueInner {
+  state: Mutex<QueueState>,
+  notify: Notify,
+  event_tx: broadcast::Sender<IndexJobEvent>,
+  next_job_id: AtomicU64,
+}
+
+impl QueueInner {
+  fn send_event(&self, event: IndexJobEvent) {
+    let _ = self.event_tx.send(event);
+  }
+}
+
+/// Runs index jobs one after another in the background, so hosts can hand over many files
+/// without holding on to the progress streams of [AppFlowyLocalAI::index_file].
+///
+/// Jobs that fail because the plugin stopped are put back at the front of the queue and run again
+/// once the plugin is running, e.g. after the host restarted it. A plugin that was shut down for
+/// being idle is relaunched for the next job.
+pub struct IndexingQueue {
+  inner: Arc<QueueInner>,
+  worker: JoinHandle<()>,
+}
+
+impl IndexingQueue {
+  pub fn new(local_ai: &Arc<AppFlowyLocalAI>) -> Self {
+    let (event_tx, _) = broadcast::channel(100);
+    let inner = Arc::new(QueueInner {
+      state: Mutex::new(QueueState::default()),
+      notify: Notify::new(),
+      event_tx,
+      next_job_id: AtomicU64::new(1),
+    });
+    let worker = tokio::spawn(run_jobs(Arc::downgrade(local_ai), inner.clone()));
+    Self { inner, worker }
+  }
+
+  /// Adds `job` to the end of the queue and returns its id, which identifies the job in the
+  /// events of [Self::subscribe_events].
+  pub fn enqueue(&self, job: IndexJob) -> IndexJobId {
+    let id = self.inner.next_job_id.fetch_add(1, Ordering::Relaxed);
+    trace!("[Indexing Queue] enqueue job {}: {:?}", id, job);
+    self.inner.state.lock().pending.push_back(QueuedJob {
+      id,
+      job,
+      attempts: 0,
+    });
+    self.inner.send_event(IndexJobEvent::Queued { job_id: id });
+    self.inner.notify.notify_one();
+    id
+  }
+
+  /// Removes a job that has not started yet, including one waiting for the plugin. Returns false
+  /// if the job is unknown or already running.
+  pub fn cancel(&self, job_id: IndexJobId) -> bool {
+    let cancelled = {
+      let mut state = self.inner.state.lock();
+      let len = state.pending.len();
+      state.pending.retain(|job| job.id != job_id);
+      len != state.pending.len()
+    };
+    if cancelled {
+      trace!("[Indexing Queue] cancel job {}", job_id);
+      self.inner.send_event(IndexJobEvent::Cancelled { job_id });
+    }
+    cancelled
+  }
+
+  pub fn queue_status(&self) -> IndexingQueueStatus {
+    let state = self.inner.state.lock();
+    IndexingQueueStatus {
+      pending: state.pending.len(),
+      running: state.running,
+      completed: state.completed,
+      failed: state.failed,
+    }
+  }
+
+  pub fn subscribe_events(&self) -> BroadcastStream<IndexJobEvent> {
+    BroadcastStream::new(self.inner.event_tx.subscribe())
+  }
+}
+
+impl Drop for IndexingQueue {
+  fn drop(&mut self) {
+    self.worker.abort();
+  }
+}
+
+async fn run_jobs(local_ai: Weak<AppFlowyLocalAI>, inner: Arc<QueueInner>) {
+  loop {
+    if inner.state.lock().pending.is_empty() {
+      inner.notify.notified().await;
+      continue;
+    }
+
+    // The job stays pending until the plugin can take it, so it can still be cancelled
+    if !wait_until_available(&local_ai).await {
+      return;
+    }
+    let local_ai = match local_ai.upgrade() {
+      Some(local_ai) => local_ai,
+      None => return,
+    };
+    let next = {
+      let mut state = inner.state.lock();
+      let next = state.pending.pop_front();
+      state.running = next.as_ref().map(|job| job.id);
+      next
+    };
+    let mut queued_job = match next {
+      Some(job) => job,
+      // Cancelled while waiting for the plugin
+      None => continue,
+    };
+
+    queued_job.attempts += 1;
+    let job_id = queued_job.id;
+    inner.send_event(IndexJobEvent::Started { job_id });
+
+    let result = run_job(&local_ai, &inner, &queued_job).await;
+    let mut state = inner.state.lock();
+    state.running = None;
+    match result {
+      Ok(_) => {
+        state.completed += 1;
+        inner.send_event(IndexJobEvent::Completed { job_id });
+      },
+      Err(err) if is_plugin_stopped(&err) && queued_job.attempts < MAX_JOB_ATTEMPTS => {
+        info!(
+          "[Indexing Queue] plugin stopped while indexing job {}, re-queue",
+          job_id
+        );
+        state.pending.push_front(queued_job);
+        inner.send_event(IndexJobEvent::Requeued { job_id });
+      },
+      Err(err) => {
+        error!("[Indexing Queue] job {} failed: {:?}", job_id, err);
+        state.failed += 1;
+        inner.send_event(IndexJobEvent::Failed {
+          job_id,
+          error: err.to_string(),
+        });
+      },
+    }
+  }
+}
+
+async fn run_job(
+  local_ai: &AppFlowyLocalAI,
+  inner: &QueueInner,
+  queued_job: &QueuedJob,
+) -> Result<(), PluginError> {
+  let job = &queued_job.job;
+  let mut stream = local_ai
+    .index_file(
+      &job.chat_id,
+      job.file_path.clone(),
+      job.content.clone(),
+      job.metadata.clone(),
+      job.options.clone(),
+    )
+    .await?;
+  while let Some(progress) = stream.next().await {
+    inner.send_event(IndexJobEvent::Progress {
+      job_id: queued_job.id,
+      progress: progress?,
+    });
+  }
+  Ok(())
+}
+
+/// Waits until the plugin can take a job, relaunching a plugin that was shut down for being idle.
+/// Returns false if the local AI was dropped. Only holds a weak reference while waiting, so a
+/// plugin that never becomes ready doesn't keep the local AI alive.
+async fn wait_until_available(local_ai: &Weak<AppFlowyLocalAI>) -> bool {
+  let mut rx = match local_ai.upgrade() {
+    Some(local_ai) => local_ai.subscribe_running_state(),
+    None => return false,
+  };
+  while let Some(state) = rx.next().await {
+    if state.is_ready() {
+      return true;
+    }
+    if let RunningState::Stopped { .. } = state {
+      let local_ai = match local_ai.upgrade() {
+        Some(local_ai) => local_ai,
+        None => return false,
+      };
+      // Only an idle plugin is relaunched, any other stopped plugin fails to be acquired
+      if local_ai.get_ai_plugin().await.is_ok() {
+        return true;
+      }
+    }
+  }
+  false
+}
+
+fn is_plugin_stopped(err: &PluginError) -> bool {
+  matches!(
+    err,
+    PluginError::PeerDisconnect | PluginError::PluginNotConnected
+  )
+}
@@ -1,5 +1,14 @@
 pub mod ai_ops;
 pub mod chat_plugin;
+pub mod chat_session;
 pub mod embedding_ops;
 pub mod embedding_plugin;
+pub mod embedding_registry;
+pub mod file_index;
+pub mod indexing_queue;
 pub mod plugin_request;
+pub mod release_manifest;
+pub mod request_limiter;
+pub mod shared_memory;
+pub mod updater;
+pub mod vector_store;
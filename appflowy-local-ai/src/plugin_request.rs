@@ -1,76 +1,994 @@
 use anyhow::anyhow;
-use reqwest::Client;
+use parking_lot::Mutex;
+use reqwest::header::{CONTENT_RANGE, ETAG, IF_RANGE, LAST_MODIFIED, RANGE};
+use reqwest::{Client, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::future::Future;
+use std::io::SeekFrom;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokio::fs;
-use tokio::fs::File;
-use tokio::io::AsyncWriteExt;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio::task::JoinSet;
 use tokio_stream::StreamExt;
 use tokio_util::sync::CancellationToken;
-use tracing::trace;
+use tracing::{trace, warn};
+use zip::ZipArchive;
 
-type ProgressCallback = Arc<dyn Fn(u64, u64) + Send + Sync>;
+/// Called with the downloaded and the total number of bytes
+pub type ProgressCallback = Arc<dyn Fn(u64, u64) + Send + Sync>;
 
+/// Optional settings of [download_plugin].
+#[derive(Clone, Default)]
+pub struct DownloadOptions {
+  pub cancel_token: Option<CancellationToken>,
+  pub progress_callback: Option<ProgressCallback>,
+  /// Minimum time between two calls of the progress callback, 500 ms when `None`
+  pub callback_debounce: Option<Duration>,
+  /// The download is checked with [verify_file_checksum] before it's moved to its file name
+  pub expected_sha256: Option<String>,
+  pub retry: DownloadRetryConfig,
+  /// Caps the download speed, e.g. so a background model download doesn't saturate the user's
+  /// connection. Unlimited when `None` or 0.
+  pub max_bytes_per_sec: Option<u64>,
+  /// Downloads large files in this many concurrent ranged segments, which is much faster from S3
+  /// or a CDN for multi-GB models. The segments are written into a preallocated
+  /// `<file_name>.segments` file, and the progress of every segment into
+  /// `<file_name>.segments.json`, so an interrupted segmented download resumes every segment
+  /// where it stopped. Only used when the server supports ranges and there is no partial file to
+  /// resume. One connection when `None`.
+  pub connections: Option<usize>,
+  /// Fails a request that sends no data for this long, so a stalled connection is retried
+  /// instead of hanging forever. 30 seconds when `None`.
+  pub read_timeout: Option<Duration>,
+}
+
+impl DownloadOptions {
+  pub fn with_cancel_token(mut self, cancel_token: CancellationToken) -> Self {
+    self.cancel_token = Some(cancel_token);
+    self
+  }
+
+  pub fn with_progress_callback(mut self, progress_callback: ProgressCallback) -> Self {
+    self.progress_callback = Some(progress_callback);
+    self
+  }
+
+  pub fn with_callback_debounce(mut self, callback_debounce: Duration) -> Self {
+    self.callback_debounce = Some(callback_debounce);
+    self
+  }
+
+  pub fn with_expected_sha256(mut self, expected_sha256: &str) -> Self {
+    self.expected_sha256 = Some(expected_sha256.to_string());
+    self
+  }
+
+  pub fn with_retry(mut self, retry: DownloadRetryConfig) -> Self {
+    self.retry = retry;
+    self
+  }
+
+  pub fn with_max_bytes_per_sec(mut self, max_bytes_per_sec: u64) -> Self {
+    self.max_bytes_per_sec = Some(max_bytes_per_sec);
+    self
+  }
+
+  pub fn with_connections(mut self, connections: usize) -> Self {
+    self.connections = Some(connections);
+    self
+  }
+
+  pub fn with_read_timeout(mut self, read_timeout: Duration) -> Self {
+    self.read_timeout = Some(read_timeout);
+    self
+  }
+
+  fn read_timeout(&self) -> Duration {
+    self.read_timeout.unwrap_or(DEFAULT_READ_TIMEOUT)
+  }
+}
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Retries a url of [download_plugin] after transient failures, i.e. network errors, timeouts and
+/// 5xx responses. Every retry resumes the partial file. The wait starts at [Self::initial_backoff]
+/// and doubles after every failed retry up to [Self::max_backoff].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DownloadRetryConfig {
+  /// Retries per url. 0 moves on to the next url after the first failure.
+  pub max_retries: usize,
+  pub initial_backoff: Duration,
+  pub max_backoff: Duration,
+}
+
+impl Default for DownloadRetryConfig {
+  fn default() -> Self {
+    Self {
+      max_retries: 3,
+      initial_backoff: Duration::from_secs(1),
+      max_backoff: Duration::from_secs(30),
+    }
+  }
+}
+
+/// Errors of [download_plugin] that callers may want to handle, found with
+/// `anyhow::Error::downcast_ref`.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum DownloadError {
+  /// The downloaded file doesn't have the expected SHA-256 checksum, e.g. because it was
+  /// corrupted in transit. The file is removed.
+  #[error("checksum mismatch of {path:?}: expected {expected}, found {actual}")]
+  ChecksumMismatch {
+    path: PathBuf,
+    expected: String,
+    actual: String,
+  },
+  /// The download was cancelled with its `CancellationToken`
+  #[error("download canceled")]
+  Cancelled,
+  /// The server answered with an error status
+  #[error("Failed to download file: {0}")]
+  HttpStatus(u16),
+  /// The server sent no data for [DownloadOptions::read_timeout]
+  #[error("no data received for {0:?}")]
+  Timeout(Duration),
+  /// None of the urls could be downloaded. Holds the error of each url, in the order they were
+  /// tried.
+  #[error("all download urls failed: {0:?}")]
+  AllMirrorsFailed(Vec<MirrorError>),
+  /// An archive entry would be written outside of the destination directory ("zip slip"), e.g.
+  /// because its name starts with `../` or is absolute. Nothing after it is extracted.
+  #[error("archive entry {0} points outside of the destination")]
+  UnsafeArchivePath(String),
+}
+
+/// Why the download from one of the urls of [download_plugin] failed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MirrorError {
+  pub url: String,
+  pub error: String,
+}
+
+/// Fails with [DownloadError::ChecksumMismatch] if the SHA-256 checksum of the file at `path`
+/// isn't `expected_sha256`, a hex string of any case.
+pub async fn verify_file_checksum(path: &Path, expected_sha256: &str) -> Result<(), anyhow::Error> {
+  let hash_path = path.to_path_buf();
+  let actual = tokio::task::spawn_blocking(move || {
+    let mut file = std::fs::File::open(hash_path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok::<_, std::io::Error>(format!("{:x}", hasher.finalize()))
+  })
+  .await??;
+  if !actual.eq_ignore_ascii_case(expected_sha256.trim()) {
+    return Err(
+      DownloadError::ChecksumMismatch {
+        path: path.to_path_buf(),
+        expected: expected_sha256.to_string(),
+        actual,
+      }
+      .into(),
+    );
+  }
+  Ok(())
+}
+
+/// Downloads the file to `file_name` in `plugin_dir` from the first of `urls` that works, e.g.
+/// S3, then GitHub releases, then a custom mirror, so one blocked endpoint doesn't break the
+/// setup. Transient failures of a url are retried first, see [DownloadRetryConfig]. If every url
+/// fails, the error is [DownloadError::AllMirrorsFailed] with the last error of each url.
+///
+/// The bytes are written to `<file_name>.part` first, which is kept when the download is
+/// interrupted, together with the `ETag` or `Last-Modified` header of the remote file. The next
+/// attempt resumes it with a `Range` request guarded by `If-Range`, so the server sends the whole
+/// file instead if it changed in the meantime. It starts over if the server doesn't support
+/// ranges or sent no validator. Cancelling removes the partial file and fails with
+/// [DownloadError::Cancelled].
+///
+/// The next url only resumes the partial file of the previous one with
+/// [DownloadOptions::expected_sha256], which catches bytes of two mirrors that don't fit
+/// together and removes the corrupted download. Without a checksum, the next url starts over.
 pub async fn download_plugin(
-  url: &str,
+  urls: &[&str],
   plugin_dir: &Path,
   file_name: &str,
-  cancel_token: Option<CancellationToken>,
-  progress_callback: Option<ProgressCallback>,
-  callback_debounce: Option<Duration>,
+  options: DownloadOptions,
+) -> Result<PathBuf, anyhow::Error> {
+  let download = Download {
+    client: Client::builder().connect_timeout(CONNECT_TIMEOUT).build()?,
+    // Create paths for the partial and final files
+    partial_path: plugin_dir.join(format!("{}.part", file_name)),
+    validator_path: plugin_dir.join(format!("{}.part.validator", file_name)),
+    segments_path: plugin_dir.join(format!("{}.segments", file_name)),
+    segments_state_path: plugin_dir.join(format!("{}.segments.json", file_name)),
+    final_path: plugin_dir.join(file_name),
+    options,
+  };
+
+  let mut errors = vec![];
+  for (index, url) in urls.iter().enumerate() {
+    if index > 0 && download.options.expected_sha256.is_none() {
+      download.remove_partial().await;
+    }
+    match download.fetch_with_retry(url).await {
+      Ok(path) => return Ok(path),
+      Err(err) => {
+        if let Some(DownloadError::Cancelled) = err.downcast_ref::<DownloadError>() {
+          return Err(err);
+        }
+        warn!("Failed to download {} from {}: {:?}", file_name, url, err);
+        errors.push(MirrorError {
+          url: url.to_string(),
+          error: err.to_string(),
+        });
+      },
+    }
+  }
+  Err(DownloadError::AllMirrorsFailed(errors).into())
+}
+
+/// Downloads the zip archive at `url` and extracts it into `dest`, which is created if needed.
+/// The archive is downloaded next to `dest` with [download_plugin], so retries, resuming,
+/// throttling and the checksum of `options` apply to it, and it is removed after the extraction.
+///
+/// The progress callback covers both steps: the total is twice the archive size, the download
+/// reports the first half and the extraction the compressed size of the extracted entries. Entries
+/// are streamed to disk one by one, entries that would end up outside of `dest` fail with
+/// [DownloadError::UnsafeArchivePath], and on unix the permission bits stored in the archive, e.g.
+/// the executable bit of a plugin binary, are restored. Setuid, setgid and sticky bits are
+/// dropped.
+pub async fn download_and_extract(
+  url: &str,
+  dest: &Path,
+  options: DownloadOptions,
+) -> Result<PathBuf, anyhow::Error> {
+  download_and_extract_from(&[url], dest, options).await
+}
+
+/// [download_and_extract] from the first of `urls` that works, see [download_plugin].
+pub(crate) async fn download_and_extract_from(
+  urls: &[&str],
+  dest: &Path,
+  options: DownloadOptions,
 ) -> Result<PathBuf, anyhow::Error> {
-  let client = Client::new();
-  let response = client.get(url).send().await?;
-
-  if !response.status().is_success() {
-    return Err(anyhow!("Failed to download file: {}", response.status()));
-  }
-  // Debounce settings
-  let debounce_duration = callback_debounce.unwrap_or_else(|| Duration::from_millis(500));
-  let mut last_update = Instant::now()
-    .checked_sub(debounce_duration)
-    .unwrap_or(Instant::now());
-
-  let total_size = response
-    .content_length()
-    .ok_or(anyhow!("Failed to get content length"))?;
-
-  // Create paths for the partial and final files
-  let partial_path = plugin_dir.join(format!("{}.part", file_name));
-  let final_path = plugin_dir.join(file_name);
-  let mut part_file = File::create(&partial_path).await?;
-  let mut stream = response.bytes_stream();
-  let mut downloaded: u64 = 0;
-
-  while let Some(chunk) = stream.next().await {
-    if let Some(cancel_token) = &cancel_token {
+  let dest_name = dest
+    .file_name()
+    .ok_or_else(|| anyhow!("Invalid destination: {:?}", dest))?
+    .to_string_lossy();
+  let download_dir = dest.parent().unwrap_or_else(|| Path::new("."));
+  fs::create_dir_all(download_dir).await?;
+
+  let mut download_options = options.clone();
+  if let Some(progress_callback) = options.progress_callback.clone() {
+    download_options.progress_callback = Some(Arc::new(move |downloaded, total| {
+      progress_callback(downloaded, total * 2)
+    }));
+  }
+  let archive_path = download_plugin(
+    urls,
+    download_dir,
+    &format!("{}.zip", dest_name),
+    download_options,
+  )
+  .await?;
+
+  let dest = dest.to_path_buf();
+  let extract_archive_path = archive_path.clone();
+  let result = tokio::task::spawn_blocking(move || {
+    extract_archive(&extract_archive_path, &dest, &options)?;
+    Ok::<_, anyhow::Error>(dest)
+  })
+  .await?;
+  let _ = fs::remove_file(&archive_path).await;
+  result
+}
+
+fn extract_archive(
+  archive_path: &Path,
+  dest: &Path,
+  options: &DownloadOptions,
+) -> Result<(), anyhow::Error> {
+  let archive_size = std::fs::metadata(archive_path)?.len();
+  let mut archive = ZipArchive::new(std::fs::File::open(archive_path)?)?;
+  std::fs::create_dir_all(dest)?;
+
+  let mut extracted = 0;
+  // Applied after all files were written, so a read-only directory doesn't stop the extraction
+  let mut dir_modes = vec![];
+  for index in 0..archive.len() {
+    if let Some(cancel_token) = &options.cancel_token {
       if cancel_token.is_cancelled() {
+        trace!("Extraction canceled");
+        return Err(DownloadError::Cancelled.into());
+      }
+    }
+
+    let mut entry = archive.by_index(index)?;
+    let relative_path = entry
+      .enclosed_name()
+      .ok_or_else(|| DownloadError::UnsafeArchivePath(entry.name().to_string()))?;
+    let out_path = dest.join(relative_path);
+    if entry.is_dir() {
+      std::fs::create_dir_all(&out_path)?;
+    } else {
+      if let Some(parent) = out_path.parent() {
+        std::fs::create_dir_all(parent)?;
+      }
+      let mut out_file = std::fs::File::create(&out_path)?;
+      std::io::copy(&mut entry, &mut out_file)?;
+    }
+
+    // Only the permission bits, a downloaded archive must not create setuid binaries
+    if let Some(mode) = entry.unix_mode().map(|mode| mode & 0o777) {
+      if entry.is_dir() {
+        dir_modes.push((out_path, mode));
+      } else {
+        set_mode(&out_path, mode)?;
+      }
+    }
+
+    extracted += entry.compressed_size();
+    if let Some(progress_callback) = &options.progress_callback {
+      progress_callback(archive_size + extracted.min(archive_size), archive_size * 2);
+    }
+  }
+
+  // Children before their parents
+  for (path, mode) in dir_modes.into_iter().rev() {
+    set_mode(&path, mode)?;
+  }
+  Ok(())
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> std::io::Result<()> {
+  use std::os::unix::fs::PermissionsExt;
+  std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: u32) -> std::io::Result<()> {
+  Ok(())
+}
+
+/// The parts of a [download_plugin] call that are the same for every url
+struct Download {
+  client: Client,
+  partial_path: PathBuf,
+  /// The `ETag` or `Last-Modified` header of the remote file the partial file belongs to
+  validator_path: PathBuf,
+  /// The preallocated file of a segmented download. Never resumed without its state file.
+  segments_path: PathBuf,
+  /// The [SegmentsState] of a segmented download
+  segments_state_path: PathBuf,
+  final_path: PathBuf,
+  options: DownloadOptions,
+}
+
+impl Download {
+  async fn fetch_with_retry(&self, url: &str) -> Result<PathBuf, anyhow::Error> {
+    let retry = &self.options.retry;
+    let mut backoff = retry.initial_backoff;
+    let mut retries = 0;
+    loop {
+      match self.fetch(url).await {
+        Err(err) if retries < retry.max_retries && is_transient(&err) => {
+          retries += 1;
+          warn!(
+            "Download from {} failed: {}, retry {} in {:?}",
+            url, err, retries, backoff
+          );
+          self.sleep(backoff).await?;
+          backoff = (backoff * 2).min(retry.max_backoff);
+        },
+        result => return result,
+      }
+    }
+  }
+
+  async fn fetch(&self, url: &str) -> Result<PathBuf, anyhow::Error> {
+    let mut resume_from = match fs::metadata(&self.partial_path).await {
+      Ok(metadata) if metadata.is_file() => metadata.len(),
+      _ => 0,
+    };
+    let validator = fs::read_to_string(&self.validator_path)
+      .await
+      .ok()
+      .map(|validator| validator.trim().to_string())
+      .filter(|validator| !validator.is_empty());
+    if resume_from > 0 && validator.is_none() {
+      trace!(
+        "Partial download of {} can't be validated, starting over",
+        url
+      );
+      resume_from = 0;
+    }
+    if let Some(state) = self.segments_to_resume(url).await? {
+      return self.fetch_segmented(url, state).await;
+    }
+    let connections = self.options.connections.unwrap_or(1);
+    if connections > 1 && resume_from == 0 {
+      if let Some(remote) = self.probe(url).await? {
+        let segments = connections.min(remote.size.div_ceil(MIN_SEGMENT_SIZE) as usize);
+        if segments > 1 {
+          let state = SegmentsState::new(remote, segments);
+          return self.fetch_segmented(url, state).await;
+        }
+      }
+    }
+
+    let response = loop {
+      let mut request = self.client.get(url);
+      if let (true, Some(validator)) = (resume_from > 0, &validator) {
+        request = request
+          .header(RANGE, format!("bytes={}-", resume_from))
+          .header(IF_RANGE, validator.as_str());
+      }
+      let response = self.wait(request.send()).await??;
+      if resume_from > 0 && response.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+        // The partial file is complete if it has the size of the remote file. The server only
+        // looks at the range if the validator matched.
+        if content_range_total(&response) == Some(resume_from) {
+          return self.finish().await;
+        }
+        trace!("Partial download does not match {}, starting over", url);
+        resume_from = 0;
+        continue;
+      }
+      if resume_from > 0
+        && response.status() == StatusCode::PARTIAL_CONTENT
+        && content_range_start(&response) != Some(resume_from)
+      {
+        trace!("{} answered with another range, starting over", url);
+        resume_from = 0;
+        continue;
+      }
+      break response;
+    };
+
+    if !response.status().is_success() {
+      return Err(DownloadError::HttpStatus(response.status().as_u16()).into());
+    }
+    let content_length = response
+      .content_length()
+      .ok_or(anyhow!("Failed to get content length"))?;
+
+    // A server that ignores the range, or whose file changed, sends the whole file
+    let resumed = response.status() == StatusCode::PARTIAL_CONTENT;
+    if resumed && resume_from == 0 {
+      return Err(anyhow!("{} sent a partial response to a full request", url));
+    }
+    let (mut part_file, downloaded) = if resumed {
+      trace!("Resuming download of {} at {} bytes", url, resume_from);
+      let file = OpenOptions::new()
+        .append(true)
+        .open(&self.partial_path)
+        .await?;
+      (file, resume_from)
+    } else {
+      let file = File::create(&self.partial_path).await?;
+      match response_validator(&response) {
+        Some(validator) => fs::write(&self.validator_path, validator).await?,
+        None => {
+          let _ = fs::remove_file(&self.validator_path).await;
+        },
+      }
+      (file, 0)
+    };
+    let progress = Progress::new(&self.options, downloaded, downloaded + content_length);
+    let throttle = Throttle::new(&self.options);
+    let mut stream = response.bytes_stream();
+
+    while let Some(chunk) = self.wait(stream.next()).await? {
+      let bytes = chunk?;
+      part_file.write_all(&bytes).await?;
+      progress.add(bytes.len() as u64);
+      if let Some(delay) = throttle.as_ref().and_then(|t| t.delay(bytes.len() as u64)) {
+        self.sleep(delay).await?;
+      }
+    }
+
+    // Ensure all data is written to disk
+    part_file.sync_all().await?;
+    drop(part_file);
+    self.finish().await
+  }
+
+  /// Returns the size and validator of the remote file if the server supports ranges, by
+  /// requesting its first byte. A `HEAD` request doesn't work with presigned S3 urls, which are
+  /// signed for `GET`.
+  async fn probe(&self, url: &str) -> Result<Option<RemoteFile>, anyhow::Error> {
+    let request = self.client.get(url).header(RANGE, "bytes=0-0");
+    let response = self.wait(request.send()).await??;
+    if !response.status().is_success() {
+      return Err(DownloadError::HttpStatus(response.status().as_u16()).into());
+    }
+    if response.status() != StatusCode::PARTIAL_CONTENT {
+      return Ok(None);
+    }
+    Ok(content_range_total(&response).map(|size| RemoteFile {
+      size,
+      validator: response_validator(&response),
+    }))
+  }
+
+  /// Returns the state of an interrupted segmented download if it belongs to the remote file.
+  /// Otherwise the segments are removed.
+  async fn segments_to_resume(&self, url: &str) -> Result<Option<SegmentsState>, anyhow::Error> {
+    let state = match fs::read(&self.segments_state_path).await {
+      Ok(state) => serde_json::from_slice::<SegmentsState>(&state).ok(),
+      Err(_) => None,
+    };
+    let state = match state {
+      Some(state) if state.validator.is_some() && self.segments_path.is_file() => state,
+      _ => {
+        self.remove_segments().await;
+        return Ok(None);
+      },
+    };
+    match self.probe(url).await? {
+      Some(remote) if remote.size == state.total_size && remote.validator == state.validator => {
+        trace!(
+          "Resuming segmented download of {} at {} bytes",
+          url,
+          state.downloaded()
+        );
+        Ok(Some(state))
+      },
+      _ => {
+        trace!("Segments don't match {}, starting over", url);
+        self.remove_segments().await;
+        Ok(None)
+      },
+    }
+  }
+
+  /// Downloads the unfinished segments of `state` in concurrent ranged requests. On failure, the
+  /// segments file and its state are kept so the next attempt resumes them.
+  async fn fetch_segmented(
+    &self,
+    url: &str,
+    state: SegmentsState,
+  ) -> Result<PathBuf, anyhow::Error> {
+    trace!("Downloading {} in {} segments", url, state.segments.len());
+    let segments_file = if state.downloaded() == 0 {
+      let file = File::create(&self.segments_path).await?;
+      file.set_len(state.total_size).await?;
+      file
+    } else {
+      OpenOptions::new()
+        .write(true)
+        .open(&self.segments_path)
+        .await?
+    };
+
+    let progress = Progress::new(&self.options, state.downloaded(), state.total_size);
+    let shared = Arc::new(SegmentedDownload {
+      client: self.client.clone(),
+      url: url.to_string(),
+      segments_path: self.segments_path.clone(),
+      state_path: self.segments_state_path.clone(),
+      cancel_token: self.options.cancel_token.clone(),
+      read_timeout: self.options.read_timeout(),
+      progress,
+      throttle: Throttle::new(&self.options),
+      state: Mutex::new(state),
+      last_save: tokio::sync::Mutex::new(None),
+    });
+    shared.save_state(true).await?;
+
+    let mut tasks = JoinSet::new();
+    let unfinished = shared.state.lock().unfinished();
+    for index in unfinished {
+      tasks.spawn(shared.clone().fetch_segment(index));
+    }
+    let mut result = Ok(());
+    while let Some(segment_result) = tasks.join_next().await {
+      if let Err(err) = segment_result.map_err(anyhow::Error::from).and_then(|r| r) {
+        result = Err(err);
+        break;
+      }
+    }
+    if let Err(err) = result {
+      tasks.abort_all();
+      while tasks.join_next().await.is_some() {}
+      if let Some(DownloadError::Cancelled) = err.downcast_ref::<DownloadError>() {
         trace!("Download canceled");
-        fs::remove_file(&partial_path).await?;
-        return Err(anyhow!("Download canceled"));
+        self.remove_partial().await;
+      } else {
+        shared.save_state(true).await?;
+      }
+      return Err(err);
+    }
+
+    segments_file.sync_all().await?;
+    drop(segments_file);
+    fs::rename(&self.segments_path, &self.partial_path).await?;
+    let _ = fs::remove_file(&self.validator_path).await;
+    let _ = fs::remove_file(&self.segments_state_path).await;
+    self.finish().await
+  }
+
+  /// Waits for `future` with [wait_or_cancel], and removes the partial file when the download is
+  /// cancelled in the meantime.
+  async fn wait<F: Future>(&self, future: F) -> Result<F::Output, anyhow::Error> {
+    let cancel_token = self.options.cancel_token.as_ref();
+    match wait_or_cancel(future, self.options.read_timeout(), cancel_token).await {
+      Err(DownloadError::Cancelled) => {
+        trace!("Download canceled");
+        self.remove_partial().await;
+        Err(DownloadError::Cancelled.into())
+      },
+      result => Ok(result?),
+    }
+  }
+
+  /// Sleeps for `duration`, or removes the partial file and fails with
+  /// [DownloadError::Cancelled] when the download is cancelled in the meantime.
+  async fn sleep(&self, duration: Duration) -> Result<(), anyhow::Error> {
+    let result = sleep_or_cancel(duration, self.options.cancel_token.as_ref()).await;
+    if result.is_err() {
+      trace!("Download canceled");
+      self.remove_partial().await;
+    }
+    Ok(result?)
+  }
+
+  async fn remove_partial(&self) {
+    let _ = fs::remove_file(&self.partial_path).await;
+    let _ = fs::remove_file(&self.validator_path).await;
+    self.remove_segments().await;
+  }
+
+  async fn remove_segments(&self) {
+    let _ = fs::remove_file(&self.segments_path).await;
+    let _ = fs::remove_file(&self.segments_state_path).await;
+  }
+
+  /// Verifies the complete partial file and moves it to the final path.
+  async fn finish(&self) -> Result<PathBuf, anyhow::Error> {
+    if let Some(expected_sha256) = &self.options.expected_sha256 {
+      if let Err(err) = verify_file_checksum(&self.partial_path, expected_sha256).await {
+        // Start over next time instead of resuming a corrupted file
+        self.remove_partial().await;
+        return Err(err);
+      }
+    }
+
+    // Move the temporary file to the final destination
+    fs::rename(&self.partial_path, &self.final_path).await?;
+    let _ = fs::remove_file(&self.validator_path).await;
+    trace!("Plugin downloaded to {:?}", self.final_path);
+    Ok(self.final_path.clone())
+  }
+}
+
+/// Segments of at least this size are downloaded in parallel, see
+/// [DownloadOptions::connections].
+const MIN_SEGMENT_SIZE: u64 = 8 * 1024 * 1024;
+
+/// How often the progress of a segmented download is written to its state file
+const SEGMENTS_STATE_SAVE_INTERVAL: Duration = Duration::from_millis(500);
+
+struct RemoteFile {
+  size: u64,
+  validator: Option<String>,
+}
+
+/// The progress of a segmented download, stored next to its segments file. A segment only counts
+/// bytes that were written to the segments file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SegmentsState {
+  total_size: u64,
+  /// The `ETag` or `Last-Modified` header of the remote file, see [response_validator]
+  validator: Option<String>,
+  segments: Vec<SegmentState>,
+}
+
+/// The bytes `start..=end` of a segmented download, of which `done` bytes are downloaded
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct SegmentState {
+  start: u64,
+  end: u64,
+  done: u64,
+}
+
+impl SegmentsState {
+  fn new(remote: RemoteFile, segments: usize) -> Self {
+    let segment_size = remote.size.div_ceil(segments as u64);
+    let segments = (0..remote.size)
+      .step_by(segment_size as usize)
+      .map(|start| SegmentState {
+        start,
+        end: (start + segment_size).min(remote.size) - 1,
+        done: 0,
+      })
+      .collect();
+    Self {
+      total_size: remote.size,
+      validator: remote.validator,
+      segments,
+    }
+  }
+
+  fn downloaded(&self) -> u64 {
+    self.segments.iter().map(|segment| segment.done).sum()
+  }
+
+  fn unfinished(&self) -> Vec<usize> {
+    (0..self.segments.len())
+      .filter(|index| {
+        let segment = &self.segments[*index];
+        segment.start + segment.done <= segment.end
+      })
+      .collect()
+  }
+}
+
+/// The parts of a segmented download shared by its segments
+struct SegmentedDownload {
+  client: Client,
+  url: String,
+  segments_path: PathBuf,
+  state_path: PathBuf,
+  cancel_token: Option<CancellationToken>,
+  read_timeout: Duration,
+  progress: Progress,
+  throttle: Option<Throttle>,
+  state: Mutex<SegmentsState>,
+  last_save: tokio::sync::Mutex<Option<Instant>>,
+}
+
+impl SegmentedDownload {
+  async fn fetch_segment(self: Arc<Self>, index: usize) -> Result<(), anyhow::Error> {
+    let (segment, validator) = {
+      let state = self.state.lock();
+      (state.segments[index], state.validator.clone())
+    };
+    let start = segment.start + segment.done;
+    let mut request = self
+      .client
+      .get(&self.url)
+      .header(RANGE, format!("bytes={}-{}", start, segment.end));
+    if let Some(validator) = &validator {
+      request = request.header(IF_RANGE, validator.as_str());
+    }
+    let cancel_token = self.cancel_token.as_ref();
+    let response = wait_or_cancel(request.send(), self.read_timeout, cancel_token).await??;
+    if !response.status().is_success() {
+      return Err(DownloadError::HttpStatus(response.status().as_u16()).into());
+    }
+    if response.status() != StatusCode::PARTIAL_CONTENT
+      || content_range_start(&response) != Some(start)
+    {
+      return Err(anyhow!(
+        "Server ignored the range of segment {}-{}",
+        start,
+        segment.end
+      ));
+    }
+
+    let mut segments_file = OpenOptions::new()
+      .write(true)
+      .open(&self.segments_path)
+      .await?;
+    segments_file.seek(SeekFrom::Start(start)).await?;
+    let mut remaining = segment.end - start + 1;
+    let mut stream = response.bytes_stream();
+    while let Some(chunk) = wait_or_cancel(stream.next(), self.read_timeout, cancel_token).await? {
+      let bytes = chunk?;
+      let len = (bytes.len() as u64).min(remaining);
+      segments_file.write_all(&bytes[..len as usize]).await?;
+      // Only count bytes that reached the file
+      segments_file.flush().await?;
+      remaining -= len;
+      self.state.lock().segments[index].done += len;
+      self.progress.add(len);
+      self.save_state(false).await?;
+      if let Some(delay) = self.throttle.as_ref().and_then(|t| t.delay(len)) {
+        sleep_or_cancel(delay, cancel_token).await?;
       }
     }
+    if remaining > 0 {
+      return Err(anyhow!(
+        "Segment {}-{} ended {} bytes early",
+        start,
+        segment.end,
+        remaining
+      ));
+    }
+    Ok(())
+  }
 
-    let bytes = chunk?;
-    part_file.write_all(&bytes).await?;
-    downloaded += bytes.len() as u64;
+  /// Writes the state file, at most once per [SEGMENTS_STATE_SAVE_INTERVAL] unless `force`. The
+  /// file is replaced atomically, so a crash leaves the previous state.
+  async fn save_state(&self, force: bool) -> Result<(), anyhow::Error> {
+    let mut last_save = self.last_save.lock().await;
+    if !force && last_save.map_or(false, |last| last.elapsed() < SEGMENTS_STATE_SAVE_INTERVAL) {
+      return Ok(());
+    }
+    let state = serde_json::to_vec(&*self.state.lock())?;
+    let tmp_path = self.state_path.with_extension("json.tmp");
+    fs::write(&tmp_path, state).await?;
+    fs::rename(&tmp_path, &self.state_path).await?;
+    *last_save = Some(Instant::now());
+    Ok(())
+  }
+}
+
+/// Reports the progress of a download to [DownloadOptions::progress_callback], at most once per
+/// [DownloadOptions::callback_debounce]. Shared by the segments of a segmented download.
+struct Progress {
+  progress_callback: Option<ProgressCallback>,
+  debounce_duration: Duration,
+  total_size: u64,
+  downloaded: AtomicU64,
+  last_update: Mutex<Option<Instant>>,
+}
+
+impl Progress {
+  fn new(options: &DownloadOptions, downloaded: u64, total_size: u64) -> Self {
+    Self {
+      progress_callback: options.progress_callback.clone(),
+      debounce_duration: options
+        .callback_debounce
+        .unwrap_or_else(|| Duration::from_millis(500)),
+      total_size,
+      downloaded: AtomicU64::new(downloaded),
+      last_update: Mutex::new(None),
+    }
+  }
 
-    // Call the progress callback
-    if let Some(progress_callback) = &progress_callback {
+  fn add(&self, len: u64) {
+    let downloaded = self.downloaded.fetch_add(len, Ordering::Relaxed) + len;
+    if let Some(progress_callback) = &self.progress_callback {
       let now = Instant::now();
-      if now.duration_since(last_update) >= debounce_duration {
-        progress_callback(downloaded, total_size);
-        last_update = now;
+      let mut last_update = self.last_update.lock();
+      if last_update.map_or(true, |last| {
+        now.duration_since(last) >= self.debounce_duration
+      }) {
+        *last_update = Some(now);
+        drop(last_update);
+        progress_callback(downloaded, self.total_size);
       }
     }
   }
+}
+
+/// Keeps the average speed of a download at [DownloadOptions::max_bytes_per_sec]. Shared by the
+/// segments of a segmented download, so the limit applies to all connections together.
+struct Throttle {
+  max_bytes_per_sec: u64,
+  started_at: Instant,
+  received: AtomicU64,
+}
 
-  // Ensure all data is written to disk
-  part_file.sync_all().await?;
+impl Throttle {
+  fn new(options: &DownloadOptions) -> Option<Self> {
+    let max_bytes_per_sec = options.max_bytes_per_sec.filter(|max| *max > 0)?;
+    Some(Self {
+      max_bytes_per_sec,
+      started_at: Instant::now(),
+      received: AtomicU64::new(0),
+    })
+  }
 
-  // Move the temporary file to the final destination
-  fs::rename(&partial_path, &final_path).await?;
-  trace!("Plugin downloaded to {:?}", final_path);
-  Ok(final_path)
+  /// Counts `len` received bytes and returns how long to wait until the average speed is back at
+  /// the limit.
+  fn delay(&self, len: u64) -> Option<Duration> {
+    let received = self.received.fetch_add(len, Ordering::Relaxed) + len;
+    let expected = Duration::from_secs_f64(received as f64 / self.max_bytes_per_sec as f64);
+    expected.checked_sub(self.started_at.elapsed())
+  }
+}
+
+/// Waits for `future`, e.g. the next chunk of a response, but fails with
+/// [DownloadError::Timeout] if it takes longer than `timeout`, and with
+/// [DownloadError::Cancelled] when the download is cancelled in the meantime.
+async fn wait_or_cancel<F: Future>(
+  future: F,
+  timeout: Duration,
+  cancel_token: Option<&CancellationToken>,
+) -> Result<F::Output, DownloadError> {
+  let cancelled = async {
+    match cancel_token {
+      Some(cancel_token) => cancel_token.cancelled().await,
+      None => std::future::pending().await,
+    }
+  };
+  tokio::select! {
+    _ = cancelled => Err(DownloadError::Cancelled),
+    output = tokio::time::timeout(timeout, future) => {
+      output.map_err(|_| DownloadError::Timeout(timeout))
+    },
+  }
+}
+
+async fn sleep_or_cancel(
+  duration: Duration,
+  cancel_token: Option<&CancellationToken>,
+) -> Result<(), DownloadError> {
+  match cancel_token {
+    Some(cancel_token) => tokio::select! {
+      _ = cancel_token.cancelled() => Err(DownloadError::Cancelled),
+      _ = tokio::time::sleep(duration) => Ok(()),
+    },
+    None => {
+      tokio::time::sleep(duration).await;
+      Ok(())
+    },
+  }
+}
+
+/// Whether `err` may go away when the download is tried again: network errors, timeouts and 5xx
+/// responses.
+fn is_transient(err: &anyhow::Error) -> bool {
+  match err.downcast_ref::<DownloadError>() {
+    Some(DownloadError::HttpStatus(status)) => *status >= 500,
+    Some(DownloadError::Timeout(_)) => true,
+    Some(_) => false,
+    None => err.downcast_ref::<reqwest::Error>().is_some(),
+  }
+}
+
+/// The header to send in `If-Range` when resuming a download of `response`: a strong `ETag`, or
+/// else `Last-Modified`. Weak `ETag`s can't be used in `If-Range`.
+fn response_validator(response: &Response) -> Option<String> {
+  let header = |name| {
+    response
+      .headers()
+      .get(name)
+      .and_then(|value| value.to_str().ok())
+      .map(str::to_string)
+  };
+  header(ETAG)
+    .filter(|etag| !etag.starts_with("W/"))
+    .or_else(|| header(LAST_MODIFIED))
+}
+
+fn content_range_start(response: &Response) -> Option<u64> {
+  let range = response.headers().get(CONTENT_RANGE)?.to_str().ok()?;
+  parse_content_range_start(range)
+}
+
+fn content_range_total(response: &Response) -> Option<u64> {
+  let range = response.headers().get(CONTENT_RANGE)?.to_str().ok()?;
+  parse_content_range_total(range)
+}
+
+/// The first byte of a `Content-Range: bytes <start>-<end>/<total>` header
+fn parse_content_range_start(range: &str) -> Option<u64> {
+  let (start, _) = range.strip_prefix("bytes ")?.split_once('-')?;
+  start.trim().parse().ok()
+}
+
+/// The total size of a `Content-Range: bytes <range>/<total>` header
+fn parse_content_range_total(range: &str) -> Option<u64> {
+  let (_, total) = range.strip_prefix("bytes ")?.split_once('/')?;
+  total.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse_content_range_test() {
+    assert_eq!(parse_content_range_start("bytes 100-199/1000"), Some(100));
+    assert_eq!(parse_content_range_total("bytes 100-199/1000"), Some(1000));
+    // The range of a 416 response
+    assert_eq!(parse_content_range_start("bytes */1000"), None);
+    assert_eq!(parse_content_range_total("bytes */1000"), Some(1000));
+    // Unknown total size
+    assert_eq!(parse_content_range_start("bytes 0-9/*"), Some(0));
+    assert_eq!(parse_content_range_total("bytes 0-9/*"), None);
+    assert_eq!(parse_content_range_start("items 0-9/10"), None);
+    assert_eq!(parse_content_range_total(""), None);
+  }
 }
@@ -0,0 +1,145 @@
+use crate::plugin_request::{
+  download_and_extract_from, download_plugin, DownloadError, DownloadOptions,
+};
+use anyhow::anyhow;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ed25519_dalek::{Signature, VerifyingKey};
+use reqwest::Client;
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Describes the latest release of a plugin, published as a signed JSON document so the download
+/// urls, checksums and requirements can change without shipping a new host.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ReleaseManifest {
+  /// Semantic version of the plugin, e.g. `0.2.1`
+  pub version: String,
+  /// The oldest host version that can run this release
+  #[serde(default)]
+  pub min_host_version: Option<String>,
+  /// Keyed by platform, see [current_platform]
+  pub artifacts: HashMap<String, ReleaseArtifact>,
+  #[serde(default)]
+  pub release_notes: Option<String>,
+}
+
+/// The download of a release for one platform
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ReleaseArtifact {
+  /// Mirrors of the artifact, tried in order
+  pub urls: Vec<String>,
+  /// Hex encoded SHA-256 checksum of the artifact
+  pub sha256: String,
+  #[serde(default)]
+  pub size: Option<u64>,
+}
+
+/// The document served at a manifest url: the manifest JSON, base64 encoded so the signature
+/// covers its exact bytes, and the base64 encoded Ed25519 signature of those bytes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignedReleaseManifest {
+  pub manifest: String,
+  pub signature: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ReleaseManifestError {
+  /// The manifest wasn't signed with the private key of the given public key, e.g. because it
+  /// was tampered with.
+  #[error("invalid release manifest signature")]
+  InvalidSignature,
+}
+
+/// The platform key of this build in [ReleaseManifest::artifacts], e.g. `macos-aarch64` or
+/// `windows-x86_64`.
+pub fn current_platform() -> String {
+  format!("{}-{}", std::env::consts::OS, std::env::consts::ARCH)
+}
+
+/// Downloads the release manifest at `url` and verifies its signature with `public_key`, the
+/// 32 byte Ed25519 public key of the release pipeline.
+pub async fn fetch_release_manifest(
+  url: &str,
+  public_key: &[u8; 32],
+) -> Result<ReleaseManifest, anyhow::Error> {
+  let response = Client::new().get(url).send().await?;
+  if !response.status().is_success() {
+    return Err(DownloadError::HttpStatus(response.status().as_u16()).into());
+  }
+  let bytes = response.bytes().await?;
+  parse_release_manifest(&bytes, public_key)
+}
+
+/// Verifies and parses a [SignedReleaseManifest] document, e.g. one that was cached on disk.
+pub fn parse_release_manifest(
+  bytes: &[u8],
+  public_key: &[u8; 32],
+) -> Result<ReleaseManifest, anyhow::Error> {
+  let signed: SignedReleaseManifest = serde_json::from_slice(bytes)?;
+  let manifest = STANDARD.decode(signed.manifest.trim())?;
+  let signature = STANDARD.decode(signed.signature.trim())?;
+  let signature =
+    Signature::from_slice(&signature).map_err(|_| ReleaseManifestError::InvalidSignature)?;
+  VerifyingKey::from_bytes(public_key)?
+    .verify_strict(&manifest, &signature)
+    .map_err(|_| ReleaseManifestError::InvalidSignature)?;
+  Ok(serde_json::from_slice(&manifest)?)
+}
+
+impl ReleaseManifest {
+  /// The artifact for this build, see [current_platform].
+  pub fn current_artifact(&self) -> Option<&ReleaseArtifact> {
+    self.artifacts.get(&current_platform())
+  }
+
+  /// Whether this release can run in a host of `host_version`.
+  pub fn supports_host(&self, host_version: &str) -> Result<bool, anyhow::Error> {
+    match &self.min_host_version {
+      None => Ok(true),
+      Some(min_host_version) => {
+        Ok(Version::parse(host_version)? >= Version::parse(min_host_version)?)
+      },
+    }
+  }
+
+  /// Whether this release is newer than `installed_version`.
+  pub fn is_newer_than(&self, installed_version: &str) -> Result<bool, anyhow::Error> {
+    Ok(Version::parse(&self.version)? > Version::parse(installed_version)?)
+  }
+}
+
+impl ReleaseArtifact {
+  /// Downloads the artifact to `file_name` in `dir` from its urls, see [download_plugin]. The
+  /// checksum of the manifest replaces [DownloadOptions::expected_sha256].
+  pub async fn download(
+    &self,
+    dir: &Path,
+    file_name: &str,
+    options: DownloadOptions,
+  ) -> Result<PathBuf, anyhow::Error> {
+    if self.urls.is_empty() {
+      return Err(anyhow!("Release artifact has no download url"));
+    }
+    let urls = self.urls.iter().map(String::as_str).collect::<Vec<_>>();
+    let options = options.with_expected_sha256(&self.sha256);
+    download_plugin(&urls, dir, file_name, options).await
+  }
+
+  /// Downloads the artifact, a zip archive, and extracts it into `dest`, see
+  /// [download_and_extract](crate::plugin_request::download_and_extract).
+  pub async fn download_and_extract(
+    &self,
+    dest: &Path,
+    options: DownloadOptions,
+  ) -> Result<PathBuf, anyhow::Error> {
+    if self.urls.is_empty() {
+      return Err(anyhow!("Release artifact has no download url"));
+    }
+    let urls = self.urls.iter().map(String::as_str).collect::<Vec<_>>();
+    let options = options.with_expected_sha256(&self.sha256);
+    download_and_extract_from(&urls, dest, options).await
+  }
+}
@@ -0,0 +1,139 @@
+use appflowy_plugin::core::plugin::{RunningState, RunningStateSender};
+use parking_lot::RwLock;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::{Stream, StreamExt};
+
+/// Limits the number of requests that are processed by a plugin at the same time. Requests that
+/// exceed the limit wait in a queue, and the queue depth is published as [RunningState::Queued]
+/// on the plugin's running state channel.
+pub struct RequestLimiter {
+  semaphore: RwLock<Option<Arc<Semaphore>>>,
+  queue_depth: AtomicUsize,
+  running_state: RunningStateSender,
+}
+
+impl RequestLimiter {
+  pub fn new(running_state: RunningStateSender) -> Self {
+    Self {
+      semaphore: RwLock::new(None),
+      queue_depth: AtomicUsize::new(0),
+      running_state,
+    }
+  }
+
+  /// Sets the maximum number of concurrent requests. `None` removes the limit.
+  pub fn set_max_concurrency(&self, max_concurrency: Option<usize>) {
+    *self.semaphore.write() = max_concurrency.map(|n| Arc::new(Semaphore::new(n.max(1))));
+  }
+
+  /// Number of requests currently waiting for a free slot.
+  pub fn queue_depth(&self) -> usize {
+    self.queue_depth.load(Ordering::Acquire)
+  }
+
+  /// Waits until a request slot is available. The slot is released when the returned permit is
+  /// dropped. Returns `None` when no limit is configured.
+  pub async fn acquire(&self) -> Option<OwnedSemaphorePermit> {
+    let semaphore = self.semaphore.read().clone()?;
+    if let Ok(permit) = semaphore.clone().try_acquire_owned() {
+      return Some(permit);
+    }
+
+    let queue_depth = self.queue_depth.fetch_add(1, Ordering::AcqRel) + 1;
+    self.notify_queue_depth(queue_depth);
+    let permit = semaphore.acquire_owned().await.ok();
+    let queue_depth = self.queue_depth.fetch_sub(1, Ordering::AcqRel) - 1;
+    self.notify_queue_depth(queue_depth);
+    permit
+  }
+
+  fn notify_queue_depth(&self, queue_depth: usize) {
+    // Checked and updated in one step, so a plugin that stops meanwhile isn't reported as running
+    self.running_state.send_if_modified(|state| {
+      let plugin_id = match state {
+        RunningState::Running { plugin_id } | RunningState::Queued { plugin_id, .. } => *plugin_id,
+        _ => return false,
+      };
+      *state = if queue_depth == 0 {
+        RunningState::Running { plugin_id }
+      } else {
+        RunningState::Queued {
+          plugin_id,
+          queue_depth,
+        }
+      };
+      true
+    });
+  }
+}
+
+/// Keeps the permit alive until the stream is finished or the receiver is dropped. Dropping the
+/// receiver drops `stream`, which cancels a plugin request stream.
+pub fn stream_with_permit<T, S>(
+  mut stream: S,
+  permit: Option<OwnedSemaphorePermit>,
+) -> ReceiverStream<T>
+where
+  T: Send + 'static,
+  S: Stream<Item = T> + Unpin + Send + 'static,
+{
+  let (tx, rx) = tokio::sync::mpsc::channel(100);
+  tokio::spawn(async move {
+    let _permit = permit;
+    loop {
+      tokio::select! {
+        item = stream.next() => match item {
+          Some(item) => {
+            if tx.send(item).await.is_err() {
+              break;
+            }
+          },
+          None => break,
+        },
+        _ = tx.closed() => break,
+      }
+    }
+  });
+  ReceiverStream::new(rx)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::Duration;
+  use tokio::sync::watch;
+
+  async fn queue_one_request(initial_state: RunningState) -> RunningState {
+    let (running_state, rx) = watch::channel(initial_state);
+    let limiter = Arc::new(RequestLimiter::new(Arc::new(running_state)));
+    limiter.set_max_concurrency(Some(1));
+    let _permit = limiter.acquire().await;
+    let waiting = tokio::spawn({
+      let limiter = limiter.clone();
+      async move { limiter.acquire().await }
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let state = rx.borrow().clone();
+    waiting.abort();
+    state
+  }
+
+  #[tokio::test]
+  async fn queued_request_updates_running_state_test() {
+    let plugin_id = 1.into();
+    let state = queue_one_request(RunningState::Running { plugin_id }).await;
+    assert!(matches!(state, RunningState::Queued { queue_depth: 1, .. }));
+  }
+
+  #[tokio::test]
+  async fn queued_request_keeps_stopped_state_test() {
+    let plugin_id = 1.into();
+    let state = queue_one_request(RunningState::Stopped { plugin_id }).await;
+    assert!(matches!(state, RunningState::Stopped { .. }));
+    let state = queue_one_request(RunningState::Unhealthy { plugin_id }).await;
+    assert!(matches!(state, RunningState::Unhealthy { .. }));
+  }
+}
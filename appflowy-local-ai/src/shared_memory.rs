@@ -0,0 +1,150 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::hash_map::RandomState;
+use std::fs::{DirBuilder, File, OpenOptions};
+use std::hash::BuildHasher;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+use tracing::trace;
+
+/// The capability an embedding plugin reports in its handshake if it can write embeddings to
+/// shared memory, and the key of the region in its `initialize` params:
+/// `"shared_memory": {"path": <file>, "size": <bytes>}`.
+///
+/// Embedding requests then carry `"output": "shared_memory"`. The plugin maps the file, writes
+/// the vectors as little endian values in the requested precision, one after the other, and
+/// responds with `{"data": {"shared_memory": {"offset": o, "length": l, "dimensions": d}}}`. Once
+/// the app read them, it sends a `shared_memory_release` notification with `{"offset": o}`, so
+/// the plugin can reuse the space. Batches that don't fit are returned as JSON as usual.
+pub const SHARED_MEMORY: &str = "shared_memory";
+
+/// Passes embeddings from the plugin through a memory backed file instead of serializing them as
+/// JSON, which is slow for large batches.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct SharedMemoryConfig {
+  /// Size of the region of each plugin process in bytes
+  pub size: usize,
+}
+
+impl Default for SharedMemoryConfig {
+  fn default() -> Self {
+    Self {
+      size: 64 * 1024 * 1024,
+    }
+  }
+}
+
+/// Where the directory of the regions is created. tmpfs on Linux, so the files are never written
+/// to disk.
+fn base_dir() -> PathBuf {
+  let dev_shm = Path::new("/dev/shm");
+  if cfg!(target_os = "linux") && dev_shm.is_dir() {
+    dev_shm.to_path_buf()
+  } else {
+    std::env::temp_dir()
+  }
+}
+
+/// The directory that holds the regions of this process. It has an unpredictable name and only
+/// the current user can access it, so a sandboxed plugin can be allowed to write to it instead of
+/// the whole temp directory. Created by [SharedMemory::create].
+pub(crate) fn dir() -> PathBuf {
+  static DIR: OnceLock<PathBuf> = OnceLock::new();
+  DIR
+    .get_or_init(|| {
+      let random = RandomState::new().hash_one(std::process::id());
+      base_dir().join(format!("appflowy-embedding-{:016x}", random))
+    })
+    .clone()
+}
+
+fn create_dir(dir: &Path) -> io::Result<()> {
+  let mut builder = DirBuilder::new();
+  #[cfg(unix)]
+  {
+    use std::os::unix::fs::DirBuilderExt;
+    builder.mode(0o700);
+  }
+  match builder.create(dir) {
+    // Created by an earlier region of this process, no one else knows the name
+    Err(err) if err.kind() == io::ErrorKind::AlreadyExists => Ok(()),
+    result => result,
+  }
+}
+
+/// The region of one plugin process. The file is removed when dropped.
+pub(crate) struct SharedMemory {
+  path: PathBuf,
+  size: usize,
+}
+
+impl SharedMemory {
+  pub(crate) fn create(config: &SharedMemoryConfig) -> io::Result<Self> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let dir = dir();
+    create_dir(&dir)?;
+    let path = dir.join(format!(
+      "region-{}",
+      COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let mut options = OpenOptions::new();
+    options.read(true).write(true).create_new(true);
+    #[cfg(unix)]
+    {
+      use std::os::unix::fs::OpenOptionsExt;
+      options.mode(0o600);
+    }
+    let file = options.open(&path)?;
+    if let Err(err) = file.set_len(config.size as u64) {
+      let _ = std::fs::remove_file(&path);
+      return Err(err);
+    }
+    trace!("[Embedding Plugin] created shared memory {:?}", path);
+    Ok(Self {
+      path,
+      size: config.size,
+    })
+  }
+
+  pub(crate) fn init_param(&self) -> Value {
+    json!({ "path": self.path, "size": self.size })
+  }
+}
+
+impl Drop for SharedMemory {
+  fn drop(&mut self) {
+    // The plugin may still have the file mapped, which keeps the memory alive until it exits
+    let _ = std::fs::remove_file(&self.path);
+    // Fails while other regions are left
+    if let Some(dir) = self.path.parent() {
+      let _ = std::fs::remove_dir(dir);
+    }
+  }
+}
+
+/// The part of the region that holds the embeddings of a response.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub(crate) struct SharedMemorySlice {
+  pub offset: u64,
+  pub length: usize,
+  pub dimensions: usize,
+}
+
+/// Copies the slice out of the region. Positioned reads instead of a mapping, since the plugin
+/// keeps writing other slices while the app reads this one.
+pub(crate) fn read(path: &Path, slice: &SharedMemorySlice) -> io::Result<Vec<u8>> {
+  let mut file = File::open(path)?;
+  let end = slice.offset.saturating_add(slice.length as u64);
+  if end > file.metadata()?.len() {
+    return Err(io::Error::new(
+      io::ErrorKind::InvalidData,
+      format!("{:?} is outside of the shared memory", slice),
+    ));
+  }
+  file.seek(SeekFrom::Start(slice.offset))?;
+  let mut bytes = vec![0; slice.length];
+  file.read_exact(&mut bytes)?;
+  Ok(bytes)
+}
@@ -0,0 +1,334 @@
+use crate::chat_plugin::AppFlowyLocalAI;
+use crate::plugin_request::DownloadOptions;
+use crate::release_manifest::{current_platform, fetch_release_manifest, ReleaseManifest};
+use anyhow::anyhow;
+use std::path::PathBuf;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tokio::fs;
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+use tokio_stream::wrappers::BroadcastStream;
+use tracing::{error, info, trace};
+
+/// Settings of [PluginUpdater].
+#[derive(Clone)]
+pub struct UpdaterConfig {
+  /// Url of the signed release manifest, see [fetch_release_manifest]
+  pub manifest_url: String,
+  /// Ed25519 public key the manifest is signed with
+  pub public_key: [u8; 32],
+  /// Version of the host, compared with [ReleaseManifest::min_host_version]
+  pub host_version: String,
+  /// Every release is extracted into `<install_dir>/<version>`. The archive is extracted into a
+  /// staging directory first, which replaces `<install_dir>/<version>` once it's complete.
+  pub install_dir: PathBuf,
+  /// Path of the plugin binary inside the release archive, e.g. `appflowy_ai_plugin`
+  pub binary_path: PathBuf,
+  /// Checks for updates in the background at this interval. Only [PluginUpdater::update] checks
+  /// when `None`.
+  pub check_interval: Option<Duration>,
+  /// How the release is downloaded. The checksum comes from the manifest and the progress is
+  /// reported through [UpdateEvent::Progress].
+  pub download: DownloadOptions,
+}
+
+impl UpdaterConfig {
+  pub fn new<T: Into<PathBuf>>(
+    manifest_url: &str,
+    public_key: [u8; 32],
+    host_version: &str,
+    install_dir: T,
+    binary_path: T,
+  ) -> Self {
+    Self {
+      manifest_url: manifest_url.to_string(),
+      public_key,
+      host_version: host_version.to_string(),
+      install_dir: install_dir.into(),
+      binary_path: binary_path.into(),
+      check_interval: None,
+      download: DownloadOptions::default(),
+    }
+  }
+
+  pub fn with_check_interval(mut self, check_interval: Duration) -> Self {
+    self.check_interval = Some(check_interval);
+    self
+  }
+
+  pub fn with_download_options(mut self, download: DownloadOptions) -> Self {
+    self.download = download;
+    self
+  }
+}
+
+/// What [PluginUpdater] is doing, e.g. to show the progress of an update in the UI.
+#[derive(Debug, Clone, PartialEq)]
+pub enum UpdateEvent {
+  Checking,
+  UpToDate {
+    version: String,
+  },
+  UpdateAvailable {
+    installed: String,
+    latest: String,
+  },
+  /// The latest release needs a newer host, so it's not installed.
+  IncompatibleHost {
+    version: String,
+    min_host_version: String,
+  },
+  /// Bytes of the download and extraction of the release, see
+  /// [download_and_extract](crate::plugin_request::download_and_extract).
+  Progress {
+    done: u64,
+    total: u64,
+  },
+  /// The release was verified and extracted, and the plugin is being reloaded.
+  Installing {
+    version: String,
+  },
+  /// The plugin failed to start from the release, so it was started again from the binary it
+  /// ran before. Followed by [UpdateEvent::Failed].
+  RolledBack {
+    version: String,
+  },
+  Updated {
+    version: String,
+  },
+  Failed {
+    error: String,
+  },
+}
+
+struct UpdaterInner {
+  config: UpdaterConfig,
+  event_tx: broadcast::Sender<UpdateEvent>,
+  /// Held while an update runs, so a background check doesn't download the same release again
+  update_lock: tokio::sync::Mutex<()>,
+}
+
+impl UpdaterInner {
+  fn send_event(&self, event: UpdateEvent) {
+    let _ = self.event_tx.send(event);
+  }
+}
+
+/// Keeps the chat plugin up to date. Compares the version the running plugin reported when it was
+/// initialized with the latest release in the manifest at [UpdaterConfig::manifest_url], downloads
+/// and verifies a newer release, and switches the plugin to it with
+/// [AppFlowyLocalAI::reload_binary] without restarting the app.
+pub struct PluginUpdater {
+  local_ai: Weak<AppFlowyLocalAI>,
+  inner: Arc<UpdaterInner>,
+  worker: Option<JoinHandle<()>>,
+}
+
+impl PluginUpdater {
+  pub fn new(local_ai: &Arc<AppFlowyLocalAI>, config: UpdaterConfig) -> Self {
+    let (event_tx, _) = broadcast::channel(100);
+    let check_interval = config.check_interval;
+    let inner = Arc::new(UpdaterInner {
+      config,
+      event_tx,
+      update_lock: tokio::sync::Mutex::new(()),
+    });
+    let worker = check_interval.map(|check_interval| {
+      tokio::spawn(run_checks(
+        Arc::downgrade(local_ai),
+        inner.clone(),
+        check_interval,
+      ))
+    });
+    Self {
+      local_ai: Arc::downgrade(local_ai),
+      inner,
+      worker,
+    }
+  }
+
+  /// Returns the latest release if it's newer than the running plugin and supports this host.
+  pub async fn check_for_update(&self) -> Result<Option<ReleaseManifest>, anyhow::Error> {
+    let local_ai = self
+      .local_ai
+      .upgrade()
+      .ok_or_else(|| anyhow!("local AI was dropped"))?;
+    check_for_update(&local_ai, &self.inner).await
+  }
+
+  /// Installs the latest release if there is one, see [Self::check_for_update]. Returns the
+  /// version the plugin was updated to.
+  pub async fn update(&self) -> Result<Option<String>, anyhow::Error> {
+    let local_ai = self
+      .local_ai
+      .upgrade()
+      .ok_or_else(|| anyhow!("local AI was dropped"))?;
+    update(&local_ai, &self.inner).await
+  }
+
+  pub fn subscribe_events(&self) -> BroadcastStream<UpdateEvent> {
+    BroadcastStream::new(self.inner.event_tx.subscribe())
+  }
+}
+
+impl Drop for PluginUpdater {
+  fn drop(&mut self) {
+    if let Some(worker) = self.worker.take() {
+      worker.abort();
+    }
+  }
+}
+
+async fn run_checks(
+  local_ai: Weak<AppFlowyLocalAI>,
+  inner: Arc<UpdaterInner>,
+  check_interval: Duration,
+) {
+  loop {
+    tokio::time::sleep(check_interval).await;
+    let local_ai = match local_ai.upgrade() {
+      Some(local_ai) => local_ai,
+      None => return,
+    };
+    if let Err(err) = update(&local_ai, &inner).await {
+      error!("[Updater] update failed: {:?}", err);
+    }
+  }
+}
+
+async fn check_for_update(
+  local_ai: &AppFlowyLocalAI,
+  inner: &UpdaterInner,
+) -> Result<Option<ReleaseManifest>, anyhow::Error> {
+  let config = &inner.config;
+  inner.send_event(UpdateEvent::Checking);
+  let installed = local_ai
+    .plugin_version()
+    .await
+    .ok_or_else(|| anyhow!("chat plugin is not running"))?;
+  let manifest = fetch_release_manifest(&config.manifest_url, &config.public_key).await?;
+  if !manifest.is_newer_than(&installed)? {
+    trace!("[Updater] plugin {} is up to date", installed);
+    inner.send_event(UpdateEvent::UpToDate { version: installed });
+    return Ok(None);
+  }
+  if !manifest.supports_host(&config.host_version)? {
+    info!(
+      "[Updater] plugin {} needs host {:?}, running {}",
+      manifest.version, manifest.min_host_version, config.host_version
+    );
+    inner.send_event(UpdateEvent::IncompatibleHost {
+      version: manifest.version.clone(),
+      min_host_version: manifest.min_host_version.clone().unwrap_or_default(),
+    });
+    return Ok(None);
+  }
+  inner.send_event(UpdateEvent::UpdateAvailable {
+    installed,
+    latest: manifest.version.clone(),
+  });
+  Ok(Some(manifest))
+}
+
+async fn update(
+  local_ai: &AppFlowyLocalAI,
+  inner: &Arc<UpdaterInner>,
+) -> Result<Option<String>, anyhow::Error> {
+  let _guard = inner.update_lock.lock().await;
+  let result = install_update(local_ai, inner).await;
+  if let Err(err) = &result {
+    inner.send_event(UpdateEvent::Failed {
+      error: err.to_string(),
+    });
+  }
+  result
+}
+
+async fn install_update(
+  local_ai: &AppFlowyLocalAI,
+  inner: &Arc<UpdaterInner>,
+) -> Result<Option<String>, anyhow::Error> {
+  let installed = local_ai.plugin_version().await;
+  let manifest = match check_for_update(local_ai, inner).await? {
+    Some(manifest) => manifest,
+    None => return Ok(None),
+  };
+  let config = &inner.config;
+  let artifact = manifest.current_artifact().ok_or_else(|| {
+    anyhow!(
+      "release {} has no {} build",
+      manifest.version,
+      current_platform()
+    )
+  })?;
+  let previous_binary_path = local_ai
+    .plugin_config()
+    .await
+    .map(|config| config.chat_bin_path)
+    .ok_or_else(|| anyhow!("chat plugin is not initialized"))?;
+
+  let event_inner = inner.clone();
+  let options = config
+    .download
+    .clone()
+    .with_progress_callback(Arc::new(move |done, total| {
+      event_inner.send_event(UpdateEvent::Progress { done, total })
+    }));
+  // Leftovers of an interrupted update must not end up in the release
+  let staging_dir = config
+    .install_dir
+    .join(format!(".{}.staging", manifest.version));
+  let _ = fs::remove_dir_all(&staging_dir).await;
+  artifact.download_and_extract(&staging_dir, options).await?;
+  if !staging_dir.join(&config.binary_path).is_file() {
+    let _ = fs::remove_dir_all(&staging_dir).await;
+    return Err(anyhow!(
+      "release {} does not contain {:?}",
+      manifest.version,
+      config.binary_path
+    ));
+  }
+  let release_dir = config.install_dir.join(&manifest.version);
+  if fs::metadata(&release_dir).await.is_ok() {
+    fs::remove_dir_all(&release_dir).await?;
+  }
+  fs::rename(&staging_dir, &release_dir).await?;
+  let binary_path = release_dir.join(&config.binary_path);
+
+  info!(
+    "[Updater] switching chat plugin to {} at {:?}",
+    manifest.version, binary_path
+  );
+  inner.send_event(UpdateEvent::Installing {
+    version: manifest.version.clone(),
+  });
+  if let Err(err) = local_ai.reload_binary(binary_path).await {
+    error!(
+      "[Updater] plugin {} failed to start, rolling back to {:?}: {:?}",
+      manifest.version, previous_binary_path, err
+    );
+    if let Err(rollback_err) = rollback(local_ai, previous_binary_path).await {
+      return Err(anyhow!("{}, rolling back failed: {}", err, rollback_err));
+    }
+    inner.send_event(UpdateEvent::RolledBack {
+      version: installed.unwrap_or_default(),
+    });
+    return Err(err.into());
+  }
+  inner.send_event(UpdateEvent::Updated {
+    version: manifest.version.clone(),
+  });
+  Ok(Some(manifest.version))
+}
+
+/// Starts the plugin again from `binary_path`. The plugin is initialized from scratch, as the old
+/// process was already stopped when the new one failed to start.
+async fn rollback(local_ai: &AppFlowyLocalAI, binary_path: PathBuf) -> Result<(), anyhow::Error> {
+  let mut config = local_ai
+    .plugin_config()
+    .await
+    .ok_or_else(|| anyhow!("chat plugin is not initialized"))?;
+  config.chat_bin_path = binary_path;
+  local_ai.init_chat_plugin(config).await
+}
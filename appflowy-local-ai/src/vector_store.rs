@@ -0,0 +1,332 @@
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::info;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+/// Name of the manifest entry stored at the root of an exported vector store archive.
+pub const VECTOR_STORE_MANIFEST_FILE: &str = "manifest.json";
+const VECTOR_STORE_ARCHIVE_VERSION: u32 = 1;
+const VECTOR_STORE_DATA_DIR: &str = "data";
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChunkSplitter {
+  BySentence,
+  ByTokens,
+  ByMarkdownHeading,
+}
+
+/// Controls how documents are split into chunks before they are embedded. Smaller chunks give
+/// more precise retrieval, larger chunks give the chat model more context per hit.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ChunkingConfig {
+  pub chunk_size: usize,
+  /// Number of units shared by two consecutive chunks
+  pub overlap: usize,
+  pub splitter: ChunkSplitter,
+}
+
+impl Default for ChunkingConfig {
+  fn default() -> Self {
+    Self {
+      chunk_size: 1000,
+      overlap: 200,
+      splitter: ChunkSplitter::BySentence,
+    }
+  }
+}
+
+/// Usage of the local vector store, as reported by the embedding plugin.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct VectorStoreStats {
+  pub document_count: u64,
+  pub chunk_count: u64,
+  pub disk_bytes: u64,
+  pub model_dimension: usize,
+  /// Number of chunks that were not stored because an identical chunk already existed. Only
+  /// counted when deduplication is enabled.
+  #[serde(default)]
+  pub deduplicated_chunks: u64,
+}
+
+const WORKSPACES_DIR: &str = "workspaces";
+/// Holds the store of earlier versions, see [VectorStorePaths::adopt_legacy_store]
+const LEGACY_DIR: &str = "legacy";
+/// Longer directory names are replaced by a hash, see [sanitize_workspace_id]
+const MAX_DIR_NAME_LEN: usize = 128;
+
+/// Lays out one persist directory per workspace under a common root:
+/// `<root>/workspaces/<workspace_id>`.
+///
+/// Earlier versions persisted a single store directly in `root`, without recording the workspace
+/// it belongs to. Preparing any workspace moves that store aside to `<root>/legacy`, and the host
+/// hands it to its workspace with [Self::adopt_legacy_store].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct VectorStorePaths {
+  root: PathBuf,
+}
+
+impl VectorStorePaths {
+  pub fn new<T: Into<PathBuf>>(root: T) -> Self {
+    Self { root: root.into() }
+  }
+
+  pub fn root(&self) -> &Path {
+    &self.root
+  }
+
+  /// Returns the persist directory of `workspace_id` without touching the file system.
+  pub fn workspace_dir(&self, workspace_id: &str) -> PathBuf {
+    self
+      .root
+      .join(WORKSPACES_DIR)
+      .join(sanitize_workspace_id(workspace_id))
+  }
+
+  /// Returns the persist directory of `workspace_id`, creating it if needed. A legacy
+  /// single-directory store is moved aside, see [Self::legacy_store_dir].
+  pub fn prepare_workspace_dir(&self, workspace_id: &str) -> Result<PathBuf, anyhow::Error> {
+    self.move_legacy_store()?;
+    let dir = self.workspace_dir(workspace_id);
+    std::fs::create_dir_all(&dir)?;
+    Ok(dir)
+  }
+
+  /// The store of an earlier version that no workspace adopted yet.
+  pub fn legacy_store_dir(&self) -> Option<PathBuf> {
+    let dir = self.root.join(LEGACY_DIR);
+    dir.is_dir().then_some(dir)
+  }
+
+  /// Makes the legacy store the persist directory of `workspace_id`, e.g. the workspace that was
+  /// open when the app was updated. Returns false if there is no legacy store. Fails if the
+  /// workspace already has a store of its own.
+  pub fn adopt_legacy_store(&self, workspace_id: &str) -> Result<bool, anyhow::Error> {
+    self.move_legacy_store()?;
+    let legacy_dir = match self.legacy_store_dir() {
+      Some(legacy_dir) => legacy_dir,
+      None => return Ok(false),
+    };
+    let dir = self.workspace_dir(workspace_id);
+    if dir.exists() {
+      if std::fs::read_dir(&dir)?.next().is_some() {
+        return Err(anyhow!(
+          "workspace {} already has a vector store in {:?}",
+          workspace_id,
+          dir
+        ));
+      }
+      std::fs::remove_dir(&dir)?;
+    }
+    std::fs::create_dir_all(self.root.join(WORKSPACES_DIR))?;
+    std::fs::rename(legacy_dir, &dir)?;
+    info!(
+      "[Vector Store] workspace {} adopted the legacy store, moved to {:?}",
+      workspace_id, dir
+    );
+    Ok(true)
+  }
+
+  /// Moves every entry that sits directly in `root` into the legacy directory. Returns true if a
+  /// legacy store was found.
+  fn move_legacy_store(&self) -> Result<bool, anyhow::Error> {
+    if !self.root.is_dir() {
+      return Ok(false);
+    }
+    let legacy_dir = self.root.join(LEGACY_DIR);
+    let mut migrated = false;
+    for entry in std::fs::read_dir(&self.root)? {
+      let entry = entry?;
+      if entry.file_name() == WORKSPACES_DIR || entry.file_name() == LEGACY_DIR {
+        continue;
+      }
+      std::fs::create_dir_all(&legacy_dir)?;
+      std::fs::rename(entry.path(), legacy_dir.join(entry.file_name()))?;
+      migrated = true;
+    }
+    if migrated {
+      info!(
+        "[Vector Store] moved legacy store in {:?} to {:?}",
+        self.root, legacy_dir
+      );
+    }
+    Ok(migrated)
+  }
+}
+
+/// Maps a workspace id to a directory name that can't escape the root. ASCII letters, digits and
+/// `-` are kept and every other byte is escaped as `_` followed by its hex value, so two ids never
+/// share a directory. Names longer than 128 characters are replaced by `__` and the SHA-256 of the
+/// id, which no escaped id starts with.
+pub fn sanitize_workspace_id(workspace_id: &str) -> String {
+  if workspace_id.is_empty() {
+    return "_".to_string();
+  }
+  let mut name = String::with_capacity(workspace_id.len());
+  for byte in workspace_id.bytes() {
+    if byte.is_ascii_alphanumeric() || byte == b'-' {
+      name.push(byte as char);
+    } else {
+      let _ = write!(name, "_{:02x}", byte);
+    }
+  }
+  if name.len() > MAX_DIR_NAME_LEN {
+    return format!("__{:x}", Sha256::digest(workspace_id.as_bytes()));
+  }
+  name
+}
+
+/// Describes an exported vector store so it can be validated before it replaces a persist
+/// directory on another machine.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct VectorStoreManifest {
+  pub version: u32,
+  /// File name of the embedding model the vectors were created with. Vectors produced by a
+  /// different model are not comparable, so importers should check this.
+  pub model_file_name: Option<String>,
+  pub chunking: Option<ChunkingConfig>,
+  /// Seconds since the unix epoch
+  pub created_at: u64,
+}
+
+impl VectorStoreManifest {
+  pub fn new(model_path: &Path, chunking: Option<ChunkingConfig>) -> Self {
+    let created_at = unix_timestamp(SystemTime::now());
+    Self {
+      version: VECTOR_STORE_ARCHIVE_VERSION,
+      model_file_name: model_path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string()),
+      chunking,
+      created_at,
+    }
+  }
+}
+
+/// Writes every file under `persist_directory`, plus `manifest`, into a single zip archive at
+/// `archive_path`.
+pub fn export_vector_store(
+  persist_directory: &Path,
+  manifest: &VectorStoreManifest,
+  archive_path: &Path,
+) -> Result<(), anyhow::Error> {
+  if !persist_directory.is_dir() {
+    return Err(anyhow!(
+      "persist directory does not exist: {:?}",
+      persist_directory
+    ));
+  }
+
+  let mut writer = ZipWriter::new(File::create(archive_path)?);
+  let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+  writer.start_file(VECTOR_STORE_MANIFEST_FILE, options)?;
+  writer.write_all(&serde_json::to_vec_pretty(manifest)?)?;
+
+  let mut pending_dirs = vec![persist_directory.to_path_buf()];
+  while let Some(current_dir) = pending_dirs.pop() {
+    for entry in std::fs::read_dir(&current_dir)? {
+      let path = entry?.path();
+      let relative_path = path.strip_prefix(persist_directory)?;
+      let name = Path::new(VECTOR_STORE_DATA_DIR)
+        .join(relative_path)
+        .to_string_lossy()
+        .replace('\\', "/");
+      if path.is_dir() {
+        writer.add_directory(name, options)?;
+        pending_dirs.push(path);
+      } else {
+        writer.start_file(name, options)?;
+        std::io::copy(&mut File::open(&path)?, &mut writer)?;
+      }
+    }
+  }
+  writer.finish()?;
+  Ok(())
+}
+
+/// Reads the manifest of an archive created by [export_vector_store] without extracting it.
+pub fn read_vector_store_manifest(
+  archive_path: &Path,
+) -> Result<VectorStoreManifest, anyhow::Error> {
+  let mut archive = ZipArchive::new(File::open(archive_path)?)?;
+  let mut content = String::new();
+  archive
+    .by_name(VECTOR_STORE_MANIFEST_FILE)
+    .map_err(|_| anyhow!("{:?} is not a vector store archive", archive_path))?
+    .read_to_string(&mut content)?;
+  let manifest: VectorStoreManifest = serde_json::from_str(&content)?;
+  if manifest.version > VECTOR_STORE_ARCHIVE_VERSION {
+    return Err(anyhow!(
+      "unsupported vector store archive version: {}",
+      manifest.version
+    ));
+  }
+  Ok(manifest)
+}
+
+/// Replaces the content of `persist_directory` with the store in `archive_path`. The archive is
+/// extracted next to the persist directory first, so a broken archive leaves the existing store
+/// untouched.
+pub fn import_vector_store(
+  archive_path: &Path,
+  persist_directory: &Path,
+) -> Result<VectorStoreManifest, anyhow::Error> {
+  let manifest = read_vector_store_manifest(archive_path)?;
+  let staging_dir = sibling_path(persist_directory, "importing");
+  if staging_dir.exists() {
+    std::fs::remove_dir_all(&staging_dir)?;
+  }
+
+  let mut archive = ZipArchive::new(File::open(archive_path)?)?;
+  if let Err(err) = archive.extract(&staging_dir) {
+    let _ = std::fs::remove_dir_all(&staging_dir);
+    return Err(err.into());
+  }
+
+  let data_dir = staging_dir.join(VECTOR_STORE_DATA_DIR);
+  if !data_dir.is_dir() {
+    std::fs::create_dir_all(&data_dir)?;
+  }
+
+  // Left behind by an import that was interrupted after the swap
+  let backup_dir = sibling_path(persist_directory, "backup");
+  if backup_dir.exists() {
+    std::fs::remove_dir_all(&backup_dir)?;
+  }
+  if persist_directory.exists() {
+    std::fs::rename(persist_directory, &backup_dir)?;
+  }
+  if let Err(err) = std::fs::rename(&data_dir, persist_directory) {
+    if backup_dir.exists() {
+      std::fs::rename(&backup_dir, persist_directory)?;
+    }
+    return Err(err.into());
+  }
+  let _ = std::fs::remove_dir_all(&staging_dir);
+  let _ = std::fs::remove_dir_all(&backup_dir);
+  Ok(manifest)
+}
+
+fn sibling_path(path: &Path, suffix: &str) -> PathBuf {
+  let mut name = path
+    .file_name()
+    .map(|name| name.to_os_string())
+    .unwrap_or_default();
+  name.push(format!(".{}", suffix));
+  path.with_file_name(name)
+}
+
+/// Seconds since the unix epoch, which is how the plugin stores times such as `expires_at`.
+pub fn unix_timestamp(time: SystemTime) -> u64 {
+  time
+    .duration_since(UNIX_EPOCH)
+    .map(|duration| duration.as_secs())
+    .unwrap_or_default()
+}
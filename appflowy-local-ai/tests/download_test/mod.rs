@@ -0,0 +1,281 @@
+pub mod server;
+
+use appflowy_local_ai::plugin_request::{
+  download_and_extract, download_plugin, DownloadError, DownloadOptions, DownloadRetryConfig,
+};
+use server::{test_content, TestServer};
+use std::fs;
+use std::io::{Cursor, Write};
+use std::time::Duration;
+use zip::write::SimpleFileOptions;
+use zip::ZipWriter;
+
+#[tokio::test]
+async fn resume_partial_download_test() {
+  let server = TestServer::start().await;
+  let content = test_content(100_000, 1);
+  let url = server.serve("/plugin.zip", content.clone(), Some("\"v1\""));
+  let dir = tempfile::tempdir().unwrap();
+  fs::write(dir.path().join("plugin.zip.part"), &content[..40_000]).unwrap();
+  fs::write(dir.path().join("plugin.zip.part.validator"), "\"v1\"").unwrap();
+
+  let path = download_plugin(&[&url], dir.path(), "plugin.zip", Default::default())
+    .await
+    .unwrap();
+  assert_eq!(fs::read(path).unwrap(), content);
+  let requests = server.requests();
+  assert_eq!(requests.len(), 1);
+  assert_eq!(requests[0].range.as_deref(), Some("bytes=40000-"));
+  assert_eq!(requests[0].if_range.as_deref(), Some("\"v1\""));
+  assert!(!dir.path().join("plugin.zip.part.validator").exists());
+}
+
+#[tokio::test]
+async fn changed_remote_file_is_not_spliced_test() {
+  let server = TestServer::start().await;
+  let old_content = test_content(100_000, 1);
+  let content = test_content(100_000, 2);
+  let url = server.serve("/plugin.zip", content.clone(), Some("\"v2\""));
+  let dir = tempfile::tempdir().unwrap();
+  fs::write(dir.path().join("plugin.zip.part"), &old_content[..40_000]).unwrap();
+  fs::write(dir.path().join("plugin.zip.part.validator"), "\"v1\"").unwrap();
+
+  let path = download_plugin(&[&url], dir.path(), "plugin.zip", Default::default())
+    .await
+    .unwrap();
+  assert_eq!(fs::read(path).unwrap(), content);
+}
+
+#[tokio::test]
+async fn partial_download_without_validator_starts_over_test() {
+  let server = TestServer::start().await;
+  let content = test_content(100_000, 1);
+  let url = server.serve("/plugin.zip", content.clone(), Some("\"v1\""));
+  let dir = tempfile::tempdir().unwrap();
+  fs::write(dir.path().join("plugin.zip.part"), vec![0; 100_000]).unwrap();
+
+  let path = download_plugin(&[&url], dir.path(), "plugin.zip", Default::default())
+    .await
+    .unwrap();
+  assert_eq!(fs::read(path).unwrap(), content);
+  assert_eq!(server.requests()[0].range, None);
+}
+
+#[tokio::test]
+async fn mismatched_partial_response_starts_over_test() {
+  let server = TestServer::start().await;
+  let content = test_content(100_000, 1);
+  let url = server.serve("/plugin.zip", content.clone(), Some("\"v1\""));
+  server.state.lock().wrong_range = true;
+  let dir = tempfile::tempdir().unwrap();
+  fs::write(dir.path().join("plugin.zip.part"), &content[..40_000]).unwrap();
+  fs::write(dir.path().join("plugin.zip.part.validator"), "\"v1\"").unwrap();
+
+  let path = download_plugin(
+    &[&url],
+    dir.path(),
+    "plugin.zip",
+    DownloadOptions::default(),
+  )
+  .await
+  .unwrap();
+  assert_eq!(fs::read(path).unwrap(), content);
+  let requests = server.requests();
+  assert_eq!(requests.len(), 2);
+  assert_eq!(requests[1].range, None);
+}
+
+#[tokio::test]
+async fn next_mirror_starts_over_without_checksum_test() {
+  let server = TestServer::start().await;
+  let mirror_content = test_content(100_000, 1);
+  let content = test_content(100_000, 2);
+  let broken_url = server.serve("/a/plugin.zip", mirror_content, Some("\"v1\""));
+  let url = server.serve("/b/plugin.zip", content.clone(), Some("\"v1\""));
+  {
+    let mut state = server.state.lock();
+    state.truncate_responses = 1;
+    state.truncate_at = 40_000;
+  }
+  let dir = tempfile::tempdir().unwrap();
+  let options = DownloadOptions::default().with_retry(DownloadRetryConfig {
+    max_retries: 0,
+    ..Default::default()
+  });
+
+  let path = download_plugin(&[&broken_url, &url], dir.path(), "plugin.zip", options)
+    .await
+    .unwrap();
+  assert_eq!(fs::read(path).unwrap(), content);
+  let requests = server.requests();
+  assert_eq!(requests.len(), 2);
+  assert_eq!(requests[1].range, None);
+}
+
+#[tokio::test]
+async fn stalled_download_is_retried_test() {
+  let server = TestServer::start().await;
+  let content = test_content(100_000, 1);
+  let url = server.serve("/plugin.zip", content.clone(), Some("\"v1\""));
+  server.state.lock().stall_responses = 1;
+  let dir = tempfile::tempdir().unwrap();
+  let options = DownloadOptions::default()
+    .with_read_timeout(Duration::from_millis(200))
+    .with_retry(DownloadRetryConfig {
+      max_retries: 1,
+      initial_backoff: Duration::from_millis(10),
+      ..Default::default()
+    });
+
+  let path = download_plugin(&[&url], dir.path(), "plugin.zip", options)
+    .await
+    .unwrap();
+  assert_eq!(fs::read(path).unwrap(), content);
+  assert_eq!(server.requests().len(), 2);
+}
+
+#[tokio::test]
+async fn interrupted_segmented_download_resumes_segments_test() {
+  let server = TestServer::start().await;
+  let content = test_content(20 * 1024 * 1024, 1);
+  let url = server.serve("/model.bin", content.clone(), Some("\"v1\""));
+  server.state.lock().chunk_delay = Some(Duration::from_millis(5));
+  let dir = tempfile::tempdir().unwrap();
+  let state_path = dir.path().join("model.bin.segments.json");
+
+  // Kill the download once every segment made progress, like the app being quit
+  let download = {
+    let (url, dir) = (url.clone(), dir.path().to_path_buf());
+    tokio::spawn(async move {
+      let options = DownloadOptions::default().with_connections(3);
+      download_plugin(&[&url], &dir, "model.bin", options).await
+    })
+  };
+  let state = loop {
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let state = fs::read(&state_path)
+      .ok()
+      .and_then(|state| serde_json::from_slice::<serde_json::Value>(&state).ok());
+    if let Some(state) = state {
+      let segments = state["segments"].as_array().unwrap();
+      if segments
+        .iter()
+        .all(|segment| segment["done"].as_u64() > Some(0))
+      {
+        break state;
+      }
+    }
+  };
+  download.abort();
+  let _ = download.await;
+  assert!(!dir.path().join("model.bin").exists());
+  assert!(!dir.path().join("model.bin.part").exists());
+
+  server.state.lock().chunk_delay = None;
+  let requests_before = server.requests().len();
+  let options = DownloadOptions::default().with_connections(3);
+  let path = download_plugin(&[&url], dir.path(), "model.bin", options)
+    .await
+    .unwrap();
+  assert!(fs::read(path).unwrap() == content);
+  assert!(!state_path.exists());
+  assert!(!dir.path().join("model.bin.segments").exists());
+
+  // Every segment resumed past the bytes written before the kill
+  let resumed = &server.requests()[requests_before..];
+  for segment in state["segments"].as_array().unwrap() {
+    let start = segment["start"].as_u64().unwrap();
+    let done = segment["done"].as_u64().unwrap();
+    let end = segment["end"].as_u64().unwrap();
+    let range = resumed
+      .iter()
+      .filter_map(|request| request.range.as_deref())
+      .find(|range| range.ends_with(&format!("-{}", end)) && !range.starts_with("bytes=0-0"))
+      .unwrap();
+    let resumed_at: u64 = range["bytes=".len()..range.find('-').unwrap()]
+      .parse()
+      .unwrap();
+    assert!(resumed_at >= start + done);
+  }
+}
+
+/// Builds a zip archive of `(name, content, mode)` entries, directories end with `/`
+pub fn zip_archive(entries: &[(&str, &[u8], u32)]) -> Vec<u8> {
+  let mut writer = ZipWriter::new(Cursor::new(vec![]));
+  for (name, content, mode) in entries {
+    let options = SimpleFileOptions::default().unix_permissions(*mode);
+    if name.ends_with('/') {
+      writer.add_directory(*name, options).unwrap();
+    } else {
+      writer.start_file(*name, options).unwrap();
+      writer.write_all(content).unwrap();
+    }
+  }
+  writer.finish().unwrap().into_inner()
+}
+
+/// Sets extra mode bits of an entry in the central directory, which `ZipWriter` masks out
+fn add_mode_bits(archive: &mut [u8], name: &str, bits: u32) {
+  let mut offset = 0;
+  while let Some(pos) = archive[offset..]
+    .windows(4)
+    .position(|w| w == b"PK\x01\x02")
+  {
+    let header = offset + pos;
+    let name_len = u16::from_le_bytes([archive[header + 28], archive[header + 29]]) as usize;
+    if &archive[header + 46..header + 46 + name_len] == name.as_bytes() {
+      let attrs = header + 38;
+      let value = u32::from_le_bytes(archive[attrs..attrs + 4].try_into().unwrap()) | (bits << 16);
+      archive[attrs..attrs + 4].copy_from_slice(&value.to_le_bytes());
+      return;
+    }
+    offset = header + 4;
+  }
+  panic!("no entry {}", name);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn extract_archive_permissions_test() {
+  use std::os::unix::fs::PermissionsExt;
+
+  let server = TestServer::start().await;
+  let mut archive = zip_archive(&[
+    ("bin/plugin", b"binary", 0o755),
+    ("readonly/", b"", 0o555),
+    ("readonly/model.bin", b"model", 0o644),
+  ]);
+  add_mode_bits(&mut archive, "bin/plugin", 0o4000);
+  let url = server.serve("/plugin.zip", archive, None);
+  let dir = tempfile::tempdir().unwrap();
+  let dest = dir.path().join("plugin");
+
+  download_and_extract(&url, &dest, Default::default())
+    .await
+    .unwrap();
+  let mode = |path: &str| fs::metadata(dest.join(path)).unwrap().permissions().mode() & 0o7777;
+  assert_eq!(mode("bin/plugin"), 0o755);
+  assert_eq!(mode("readonly"), 0o555);
+  assert_eq!(fs::read(dest.join("readonly/model.bin")).unwrap(), b"model");
+  assert!(!dir.path().join("plugin.zip").exists());
+  fs::set_permissions(dest.join("readonly"), fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+#[tokio::test]
+async fn extract_archive_rejects_zip_slip_test() {
+  let server = TestServer::start().await;
+  let archive = zip_archive(&[("../evil.txt", b"evil", 0o644)]);
+  let url = server.serve("/plugin.zip", archive, None);
+  let dir = tempfile::tempdir().unwrap();
+  let dest = dir.path().join("install/plugin");
+
+  let err = download_and_extract(&url, &dest, Default::default())
+    .await
+    .unwrap_err();
+  assert_eq!(
+    err.downcast_ref::<DownloadError>(),
+    Some(&DownloadError::UnsafeArchivePath("../evil.txt".to_string()))
+  );
+  assert!(!dir.path().join("install/evil.txt").exists());
+  assert!(!dir.path().join("evil.txt").exists());
+}
@@ -0,0 +1,246 @@
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// The headers of a request the [TestServer] received that matter for downloads
+#[derive(Debug, Clone, Default)]
+pub struct RecordedRequest {
+  pub path: String,
+  pub range: Option<String>,
+  pub if_range: Option<String>,
+}
+
+#[derive(Default)]
+pub struct ServerState {
+  pub files: HashMap<String, ServedFile>,
+  pub requests: Vec<RecordedRequest>,
+  /// Drops the connection of this many responses after [Self::truncate_at] body bytes
+  pub truncate_responses: usize,
+  pub truncate_at: usize,
+  /// Answers every range request with a range that starts at 0
+  pub wrong_range: bool,
+  /// Sends the headers of this many responses and then nothing
+  pub stall_responses: usize,
+  /// Waits this long after every body chunk, to interrupt a download halfway
+  pub chunk_delay: Option<Duration>,
+}
+
+#[derive(Clone)]
+pub struct ServedFile {
+  pub content: Vec<u8>,
+  pub etag: Option<String>,
+}
+
+/// A minimal HTTP/1.1 server with `Range` and `If-Range` support, to test downloads without a
+/// network.
+pub struct TestServer {
+  pub base_url: String,
+  pub state: Arc<Mutex<ServerState>>,
+}
+
+impl TestServer {
+  pub async fn start() -> Self {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let base_url = format!("http://{}", listener.local_addr().unwrap());
+    let state = Arc::new(Mutex::new(ServerState::default()));
+    let accept_state = state.clone();
+    tokio::spawn(async move {
+      while let Ok((stream, _)) = listener.accept().await {
+        tokio::spawn(handle_connection(stream, accept_state.clone()));
+      }
+    });
+    Self { base_url, state }
+  }
+
+  /// Serves `content` at `path` and returns its url
+  pub fn serve(&self, path: &str, content: Vec<u8>, etag: Option<&str>) -> String {
+    self.state.lock().files.insert(
+      path.to_string(),
+      ServedFile {
+        content,
+        etag: etag.map(str::to_string),
+      },
+    );
+    format!("{}{}", self.base_url, path)
+  }
+
+  pub fn requests(&self) -> Vec<RecordedRequest> {
+    self.state.lock().requests.clone()
+  }
+}
+
+async fn handle_connection(mut stream: TcpStream, state: Arc<Mutex<ServerState>>) {
+  let mut buf = vec![];
+  let mut chunk = [0u8; 1024];
+  while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+    match stream.read(&mut chunk).await {
+      Ok(0) | Err(_) => return,
+      Ok(n) => buf.extend_from_slice(&chunk[..n]),
+    }
+  }
+  let head = String::from_utf8_lossy(&buf).to_string();
+  let mut lines = head.split("\r\n");
+  let path = lines
+    .next()
+    .and_then(|line| line.split(' ').nth(1))
+    .unwrap_or("/")
+    .to_string();
+  let mut headers = HashMap::new();
+  for line in lines {
+    if let Some((name, value)) = line.split_once(':') {
+      headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+    }
+  }
+  let request = RecordedRequest {
+    path: path.clone(),
+    range: headers.get("range").cloned(),
+    if_range: headers.get("if-range").cloned(),
+  };
+
+  let response = {
+    let mut state = state.lock();
+    state.requests.push(request.clone());
+    state
+      .files
+      .get(&path)
+      .cloned()
+      .map(|file| respond(&mut state, &request, file))
+  };
+  let (status, mut response_headers, body, truncate, stall, chunk_delay) = match response {
+    Some(response) => response,
+    None => {
+      let _ = stream
+        .write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+        .await;
+      return;
+    },
+  };
+  write_response(
+    stream,
+    status,
+    &mut response_headers,
+    body,
+    truncate,
+    stall,
+    chunk_delay,
+  )
+  .await;
+}
+
+type Response = (
+  &'static str,
+  Vec<String>,
+  Vec<u8>,
+  Option<usize>,
+  bool,
+  Option<Duration>,
+);
+
+fn respond(state: &mut ServerState, request: &RecordedRequest, file: ServedFile) -> Response {
+  let len = file.content.len();
+  let validator_matches = match (&request.if_range, &file.etag) {
+    (None, _) => true,
+    (Some(if_range), Some(etag)) => if_range == etag,
+    (Some(_), None) => false,
+  };
+  let range = request
+    .range
+    .as_deref()
+    .filter(|_| validator_matches)
+    .and_then(parse_range);
+  let (status, headers, body) = match range {
+    Some((start, _)) if start >= len => (
+      "416 Range Not Satisfiable",
+      vec![format!("Content-Range: bytes */{}", len)],
+      vec![],
+    ),
+    Some((start, end)) => {
+      let start = if state.wrong_range { 0 } else { start };
+      let end = end.unwrap_or(len - 1).min(len - 1);
+      (
+        "206 Partial Content",
+        vec![format!("Content-Range: bytes {}-{}/{}", start, end, len)],
+        file.content[start..=end].to_vec(),
+      )
+    },
+    None => ("200 OK", vec![], file.content.clone()),
+  };
+  let mut headers = headers;
+  if let Some(etag) = &file.etag {
+    headers.push(format!("ETag: {}", etag));
+  }
+
+  let truncate = if state.truncate_responses > 0 && body.len() > state.truncate_at {
+    state.truncate_responses -= 1;
+    Some(state.truncate_at)
+  } else {
+    None
+  };
+  let stall = if state.stall_responses > 0 {
+    state.stall_responses -= 1;
+    true
+  } else {
+    false
+  };
+  (status, headers, body, truncate, stall, state.chunk_delay)
+}
+
+async fn write_response(
+  mut stream: TcpStream,
+  status: &str,
+  response_headers: &mut Vec<String>,
+  body: Vec<u8>,
+  truncate: Option<usize>,
+  stall: bool,
+  chunk_delay: Option<Duration>,
+) {
+  response_headers.push(format!("Content-Length: {}", body.len()));
+  response_headers.push("Connection: close".to_string());
+  let head = format!(
+    "HTTP/1.1 {}\r\n{}\r\n\r\n",
+    status,
+    response_headers.join("\r\n")
+  );
+  if stream.write_all(head.as_bytes()).await.is_err() {
+    return;
+  }
+  if stall {
+    tokio::time::sleep(Duration::from_secs(3600)).await;
+    return;
+  }
+  let body = match truncate {
+    Some(len) => &body[..len],
+    None => &body[..],
+  };
+  for chunk in body.chunks(16 * 1024) {
+    if stream.write_all(chunk).await.is_err() {
+      return;
+    }
+    if let Some(delay) = chunk_delay {
+      tokio::time::sleep(delay).await;
+    }
+  }
+  let _ = stream.flush().await;
+  let _ = stream.shutdown().await;
+}
+
+/// Parses `bytes=<start>-[<end>]`
+fn parse_range(range: &str) -> Option<(usize, Option<usize>)> {
+  let (start, end) = range.strip_prefix("bytes=")?.split_once('-')?;
+  let end = if end.is_empty() {
+    None
+  } else {
+    Some(end.parse().ok()?)
+  };
+  Some((start.parse().ok()?, end))
+}
+
+/// Deterministic content of `len` bytes
+pub fn test_content(len: usize, seed: u8) -> Vec<u8> {
+  (0..len)
+    .map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed))
+    .collect()
+}
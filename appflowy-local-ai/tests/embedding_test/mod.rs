@@ -1,4 +1,5 @@
 use crate::util::LocalAITest;
+use appflowy_local_ai::embedding_ops::{SimilaritySearchOptions, DEFAULT_COLLECTION};
 use serde_json::json;
 use std::collections::HashMap;
 
@@ -11,11 +12,34 @@ async fn ci_generate_embedding_test() {
   let mut metadata = HashMap::new();
   metadata.insert("id".to_string(), json!(id));
 
-  test.embedding_manager.index("AppFlowy is an AI collaborative workspace where you achieve more without losing control of your data", metadata.clone()).await.unwrap();
+  test.embedding_manager.index(DEFAULT_COLLECTION, "AppFlowy is an AI collaborative workspace where you achieve more without losing control of your data", metadata.clone(), None).await.unwrap();
   let resp = test
     .embedding_manager
-    .similarity_search("AppFlowy", metadata)
+    .similarity_search(
+      DEFAULT_COLLECTION,
+      "AppFlowy",
+      metadata,
+      SimilaritySearchOptions::new(1),
+    )
     .await
     .unwrap();
   eprintln!("embedding response: {:?}", resp);
+  assert_eq!(resp[0].metadata.get("id"), Some(&json!(id)));
+}
+
+#[tokio::test]
+async fn ci_generate_embeddings_batch_test() {
+  let test = LocalAITest::new().unwrap();
+  test.init_embedding_plugin().await;
+
+  let texts = vec!["AppFlowy".to_string(), "Local AI".to_string()];
+  let embeddings = test
+    .embedding_manager
+    .generate_embeddings(texts.clone())
+    .await
+    .unwrap();
+  assert_eq!(embeddings.len(), texts.len());
+
+  let single = test.generate_embedding(&texts[0]).await;
+  assert_eq!(embeddings[0].len(), single[0].len());
 }
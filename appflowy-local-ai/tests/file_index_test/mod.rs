@@ -0,0 +1,113 @@
+use appflowy_local_ai::file_index::{
+  collect_index_files, html_to_text, IndexFileOptions, IndexFileType, IndexOptions,
+};
+use std::fs;
+
+#[test]
+fn collect_index_files_test() {
+  let dir = tempfile::tempdir().unwrap();
+  let root = dir.path();
+  fs::create_dir_all(root.join("notes/nested")).unwrap();
+  fs::write(root.join("a.md"), "# a").unwrap();
+  fs::write(root.join("notes/b.txt"), "b").unwrap();
+  fs::write(root.join("notes/nested/c.pdf"), "c").unwrap();
+  fs::write(root.join("notes/image.png"), "png").unwrap();
+  fs::write(root.join("notes/large.txt"), "x".repeat(1024)).unwrap();
+
+  let files = collect_index_files(root, &IndexOptions::default()).unwrap();
+  assert_eq!(
+    files,
+    vec![
+      root.join("a.md"),
+      root.join("notes/b.txt"),
+      root.join("notes/large.txt"),
+      root.join("notes/nested/c.pdf"),
+    ]
+  );
+
+  let options = IndexOptions {
+    globs: vec!["notes/**/*".to_string()],
+    max_file_size: Some(100),
+  };
+  let files = collect_index_files(root, &options).unwrap();
+  assert_eq!(
+    files,
+    vec![root.join("notes/b.txt"), root.join("notes/nested/c.pdf")]
+  );
+}
+
+#[test]
+fn detect_index_file_type_test() {
+  let dir = tempfile::tempdir().unwrap();
+  let root = dir.path();
+  fs::write(root.join("page.HTML"), "<html></html>").unwrap();
+  fs::write(root.join("book.epub"), "").unwrap();
+  fs::write(root.join("no_extension"), "%PDF-1.7").unwrap();
+  fs::write(root.join("unknown.bin"), "%PDF-1.7").unwrap();
+
+  assert_eq!(
+    IndexFileType::from_path(&root.join("page.HTML")),
+    Some(IndexFileType::Html)
+  );
+  assert_eq!(
+    IndexFileType::from_path(&root.join("book.epub")),
+    Some(IndexFileType::Epub)
+  );
+  assert_eq!(
+    IndexFileType::from_path(&root.join("no_extension")),
+    Some(IndexFileType::Pdf)
+  );
+  assert_eq!(IndexFileType::from_path(&root.join("unknown.bin")), None);
+}
+
+#[test]
+fn html_to_text_test() {
+  let html = r#"<html><head><title>AppFlowy</title><style>p { color: red; }</style></head>
+<body>
+  <nav><a href="/">Home</a></nav>
+  <h1>AppFlowy &amp; Local AI</h1>
+  <p>Run <b>AI</b> models
+     on your own machine.</p>
+  <script>console.log("ignored")</script>
+  <!-- a comment -->
+  <ul><li>Private</li><li>Offline</li></ul>
+  <footer>Copyright</footer>
+</body></html>"#;
+  assert_eq!(
+    html_to_text(html),
+    "AppFlowy\nAppFlowy & Local AI\nRun AI models on your own machine.\nPrivate\nOffline"
+  );
+}
+
+#[test]
+fn html_to_text_bare_less_than_test() {
+  assert_eq!(
+    html_to_text("<p>1 < 2 and 3 > 2</p><p>x<3</p>"),
+    "1 < 2 and 3 > 2\nx<3"
+  );
+  assert_eq!(html_to_text("<p>a <</p>"), "a <");
+  assert_eq!(html_to_text("<script>if (a < b) {}</script>text"), "text");
+}
+
+#[test]
+fn html_to_text_entities_test() {
+  assert_eq!(
+    html_to_text("&#169; 2024 &#x41;&#X42; &amp;lt; &lt;b&gt;"),
+    "\u{a9} 2024 AB &lt; <b>"
+  );
+  assert_eq!(
+    html_to_text("Tom & Jerry &unknown; &#xD800; &#;"),
+    "Tom & Jerry &unknown; &#xD800; &#;"
+  );
+}
+
+#[test]
+fn index_file_options_debug_redacts_password_test() {
+  let options = IndexFileOptions::default()
+    .with_pages(0..2)
+    .with_password("secret");
+  let debug = format!("{:?}", options);
+  assert!(!debug.contains("secret"));
+  assert!(debug.contains("<redacted>"));
+  assert!(debug.contains("0..2"));
+}
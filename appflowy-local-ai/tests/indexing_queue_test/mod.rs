@@ -0,0 +1,64 @@
+use appflowy_local_ai::chat_plugin::AppFlowyLocalAI;
+use appflowy_local_ai::indexing_queue::{
+  IndexJob, IndexJobEvent, IndexingQueue, IndexingQueueStatus,
+};
+use appflowy_plugin::manager::PluginManager;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_stream::StreamExt;
+
+fn local_ai() -> Arc<AppFlowyLocalAI> {
+  Arc::new(AppFlowyLocalAI::new(Arc::new(PluginManager::new())))
+}
+
+#[tokio::test]
+async fn cancel_pending_job_test() {
+  let local_ai = local_ai();
+  let queue = IndexingQueue::new(&local_ai);
+  let mut events = queue.subscribe_events();
+
+  // The plugin never starts, so both jobs stay pending
+  let first = queue.enqueue(IndexJob::text("chat", "first"));
+  let second = queue.enqueue(IndexJob::text("chat", "second"));
+  tokio::time::sleep(Duration::from_millis(50)).await;
+  assert_eq!(
+    queue.queue_status(),
+    IndexingQueueStatus {
+      pending: 2,
+      running: None,
+      completed: 0,
+      failed: 0,
+    }
+  );
+
+  assert!(queue.cancel(second));
+  assert!(!queue.cancel(second));
+  assert!(queue.cancel(first));
+  assert_eq!(queue.queue_status(), IndexingQueueStatus::default());
+
+  let mut received = vec![];
+  for _ in 0..4 {
+    received.push(events.next().await.unwrap().unwrap());
+  }
+  assert_eq!(
+    received,
+    vec![
+      IndexJobEvent::Queued { job_id: first },
+      IndexJobEvent::Queued { job_id: second },
+      IndexJobEvent::Cancelled { job_id: second },
+      IndexJobEvent::Cancelled { job_id: first },
+    ]
+  );
+}
+
+#[tokio::test]
+async fn waiting_queue_does_not_keep_local_ai_alive_test() {
+  let local_ai = local_ai();
+  let weak_local_ai = Arc::downgrade(&local_ai);
+  let queue = IndexingQueue::new(&local_ai);
+  queue.enqueue(IndexJob::text("chat", "content"));
+  tokio::time::sleep(Duration::from_millis(50)).await;
+
+  drop(local_ai);
+  assert!(weak_local_ai.upgrade().is_none());
+}
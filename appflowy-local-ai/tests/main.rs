@@ -1,3 +1,9 @@
 pub mod chat_test;
+pub mod download_test;
 pub mod embedding_test;
+pub mod file_index_test;
+pub mod indexing_queue_test;
+pub mod release_manifest_test;
+pub mod updater_test;
 pub mod util;
+pub mod vector_store_test;
@@ -0,0 +1,50 @@
+use appflowy_local_ai::release_manifest::{
+  current_platform, parse_release_manifest, ReleaseManifestError, SignedReleaseManifest,
+};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ed25519_dalek::{Signer, SigningKey};
+use serde_json::json;
+
+pub fn sign_manifest(signing_key: &SigningKey, manifest: &serde_json::Value) -> Vec<u8> {
+  let manifest = serde_json::to_vec(manifest).unwrap();
+  let signed = SignedReleaseManifest {
+    manifest: STANDARD.encode(&manifest),
+    signature: STANDARD.encode(signing_key.sign(&manifest).to_bytes()),
+  };
+  serde_json::to_vec(&signed).unwrap()
+}
+
+#[test]
+fn parse_signed_release_manifest_test() {
+  let signing_key = SigningKey::from_bytes(&[7; 32]);
+  let public_key = signing_key.verifying_key().to_bytes();
+  let bytes = sign_manifest(
+    &signing_key,
+    &json!({
+      "version": "0.2.1",
+      "min_host_version": "0.6.0",
+      "artifacts": {
+        current_platform(): {
+          "urls": ["https://example.com/AppFlowyAI.zip"],
+          "sha256": "abc",
+        },
+      },
+    }),
+  );
+
+  let manifest = parse_release_manifest(&bytes, &public_key).unwrap();
+  assert_eq!(manifest.version, "0.2.1");
+  assert_eq!(manifest.current_artifact().unwrap().sha256, "abc");
+  assert!(manifest.is_newer_than("0.2.0").unwrap());
+  assert!(!manifest.is_newer_than("0.2.1").unwrap());
+  assert!(manifest.supports_host("0.6.0").unwrap());
+  assert!(!manifest.supports_host("0.5.9").unwrap());
+
+  let other_key = SigningKey::from_bytes(&[8; 32]).verifying_key().to_bytes();
+  let err = parse_release_manifest(&bytes, &other_key).unwrap_err();
+  assert_eq!(
+    err.downcast_ref::<ReleaseManifestError>(),
+    Some(&ReleaseManifestError::InvalidSignature)
+  );
+}
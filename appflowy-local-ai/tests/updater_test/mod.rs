@@ -0,0 +1,203 @@
+#![cfg(unix)]
+
+use crate::download_test::server::TestServer;
+use crate::download_test::zip_archive;
+use crate::release_manifest_test::sign_manifest;
+use appflowy_local_ai::chat_plugin::{AIPluginConfig, AppFlowyLocalAI};
+use appflowy_local_ai::release_manifest::current_platform;
+use appflowy_local_ai::updater::{PluginUpdater, UpdateEvent, UpdaterConfig};
+use appflowy_plugin::manager::PluginManager;
+use ed25519_dalek::SigningKey;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::StreamExt;
+
+/// A chat plugin that answers every request with a handshake of `version`
+fn fake_plugin(version: &str) -> String {
+  format!(
+    r#"#!/bin/sh
+while IFS= read -r line; do
+  id=$(printf '%s' "$line" | sed -n 's/.*"id":\([0-9][0-9]*\).*/\1/p')
+  [ -z "$id" ] && continue
+  printf '{{"id":%s,"result":{{"data":{{"version":"{}","capabilities":[]}}}}}}\n' "$id"
+  case "$line" in *'"method":"shutdown"'*) exit 0;; esac
+done
+"#,
+    version
+  )
+}
+
+/// A plugin binary that exits right away, like a release built for the wrong platform
+const BROKEN_PLUGIN: &str = "#!/bin/sh\nexit 1\n";
+
+struct UpdaterTest {
+  dir: TempDir,
+  server: TestServer,
+  signing_key: SigningKey,
+  local_ai: Arc<AppFlowyLocalAI>,
+}
+
+impl UpdaterTest {
+  /// Runs the fake plugin of version `0.1.0`
+  async fn start() -> Self {
+    let dir = tempfile::tempdir().unwrap();
+    let binary_path = dir.path().join("installed/plugin");
+    fs::create_dir_all(binary_path.parent().unwrap()).unwrap();
+    write_executable(&binary_path, &fake_plugin("0.1.0"));
+    let model_path = dir.path().join("model.gguf");
+    fs::write(&model_path, "model").unwrap();
+
+    let local_ai = Arc::new(AppFlowyLocalAI::new(Arc::new(PluginManager::new())));
+    let config = AIPluginConfig::new(binary_path, model_path).unwrap();
+    local_ai.init_chat_plugin(config).await.unwrap();
+    assert_eq!(local_ai.plugin_version().await.as_deref(), Some("0.1.0"));
+    Self {
+      dir,
+      server: TestServer::start().await,
+      signing_key: SigningKey::from_bytes(&[7; 32]),
+      local_ai,
+    }
+  }
+
+  /// Publishes a release whose archive contains `plugin` as the plugin binary
+  fn publish(&self, version: &str, min_host_version: &str, plugin: &str) -> UpdaterConfig {
+    let archive = zip_archive(&[("plugin", plugin.as_bytes(), 0o755)]);
+    let sha256 = format!("{:x}", Sha256::digest(&archive));
+    let archive_url = self.server.serve("/plugin.zip", archive, None);
+    let manifest = json!({
+      "version": version,
+      "min_host_version": min_host_version,
+      "artifacts": {
+        current_platform(): { "urls": [archive_url], "sha256": sha256 },
+      },
+    });
+    let manifest_url = self.server.serve(
+      "/manifest.json",
+      sign_manifest(&self.signing_key, &manifest),
+      None,
+    );
+    UpdaterConfig::new(
+      &manifest_url,
+      self.signing_key.verifying_key().to_bytes(),
+      "1.0.0",
+      self.dir.path().join("releases"),
+      "plugin".into(),
+    )
+  }
+}
+
+fn write_executable(path: &Path, content: &str) {
+  fs::write(path, content).unwrap();
+  fs::set_permissions(path, fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+/// The events sent so far, without the progress of the download
+async fn received_events(events: &mut BroadcastStream<UpdateEvent>) -> Vec<UpdateEvent> {
+  let mut received = vec![];
+  while let Ok(Some(event)) = tokio::time::timeout(Duration::from_millis(200), events.next()).await
+  {
+    let event = event.unwrap();
+    if !matches!(event, UpdateEvent::Progress { .. }) {
+      received.push(event);
+    }
+  }
+  received
+}
+
+#[tokio::test]
+async fn update_installs_newer_release_test() {
+  let test = UpdaterTest::start().await;
+  let config = test.publish("0.2.0", "1.0.0", &fake_plugin("0.2.0"));
+  let install_dir = config.install_dir.clone();
+  // Leftovers of an interrupted update
+  fs::create_dir_all(install_dir.join(".0.2.0.staging")).unwrap();
+  fs::write(install_dir.join(".0.2.0.staging/stale"), "stale").unwrap();
+  let updater = PluginUpdater::new(&test.local_ai, config);
+  let mut events = updater.subscribe_events();
+
+  assert_eq!(updater.update().await.unwrap().as_deref(), Some("0.2.0"));
+  assert_eq!(
+    test.local_ai.plugin_version().await.as_deref(),
+    Some("0.2.0")
+  );
+  assert!(install_dir.join("0.2.0/plugin").is_file());
+  assert!(!install_dir.join("0.2.0/stale").exists());
+  assert!(!install_dir.join(".0.2.0.staging").exists());
+  assert_eq!(
+    received_events(&mut events).await,
+    vec![
+      UpdateEvent::Checking,
+      UpdateEvent::UpdateAvailable {
+        installed: "0.1.0".to_string(),
+        latest: "0.2.0".to_string(),
+      },
+      UpdateEvent::Installing {
+        version: "0.2.0".to_string(),
+      },
+      UpdateEvent::Updated {
+        version: "0.2.0".to_string(),
+      },
+    ]
+  );
+
+  // Nothing newer is published
+  assert_eq!(updater.update().await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn update_rolls_back_when_release_fails_to_start_test() {
+  let test = UpdaterTest::start().await;
+  let config = test.publish("0.2.0", "1.0.0", BROKEN_PLUGIN);
+  let updater = PluginUpdater::new(&test.local_ai, config);
+  let mut events = updater.subscribe_events();
+
+  assert!(updater.update().await.is_err());
+  assert_eq!(
+    test.local_ai.plugin_version().await.as_deref(),
+    Some("0.1.0")
+  );
+  let events = received_events(&mut events).await;
+  assert_eq!(
+    events[events.len() - 2..events.len() - 1],
+    [UpdateEvent::RolledBack {
+      version: "0.1.0".to_string(),
+    }]
+  );
+  assert!(matches!(events.last(), Some(UpdateEvent::Failed { .. })));
+}
+
+#[tokio::test]
+async fn update_skips_release_for_newer_host_test() {
+  let test = UpdaterTest::start().await;
+  let config = test.publish("0.2.0", "9.0.0", &fake_plugin("0.2.0"));
+  let updater = PluginUpdater::new(&test.local_ai, config);
+  let mut events = updater.subscribe_events();
+
+  assert_eq!(updater.update().await.unwrap(), None);
+  assert_eq!(
+    received_events(&mut events).await,
+    vec![
+      UpdateEvent::Checking,
+      UpdateEvent::IncompatibleHost {
+        version: "0.2.0".to_string(),
+        min_host_version: "9.0.0".to_string(),
+      },
+    ]
+  );
+  assert!(test
+    .server
+    .requests()
+    .iter()
+    .all(|request| request.path != "/plugin.zip"));
+  assert_eq!(
+    test.local_ai.plugin_version().await.as_deref(),
+    Some("0.1.0")
+  );
+}
@@ -47,7 +47,11 @@ impl LocalAITest {
 
     let persist_dir = tempfile::tempdir().unwrap().path().to_path_buf();
     config
-      .set_rag_enabled(&self.config.embedding_model_absolute_path(), &persist_dir)
+      .set_rag_enabled(
+        &self.config.embedding_model_absolute_path(),
+        &persist_dir,
+        None,
+      )
       .unwrap();
 
     self.local_ai.init_chat_plugin(config).await.unwrap();
@@ -60,7 +64,8 @@ impl LocalAITest {
       self.config.embedding_model_absolute_path(),
       Some(temp_dir),
     )
-    .unwrap();
+    .unwrap()
+    .with_normalize(true);
     self
       .embedding_manager
       .init_embedding_plugin(config)
@@ -106,8 +111,10 @@ impl LocalAITest {
 
     let actual_embedding_flat = flatten_vec(left);
     let expected_embedding_flat = flatten_vec(right);
-    let distance = f64::cosine(&actual_embedding_flat, &expected_embedding_flat)
+    // Embeddings are normalized, so their dot product is the cosine similarity
+    let similarity = f64::dot(&actual_embedding_flat, &expected_embedding_flat)
       .expect("Vectors must be of the same length");
+    let distance = 1.0 - similarity;
 
     distance.cos()
   }
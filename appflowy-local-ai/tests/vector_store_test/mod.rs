@@ -0,0 +1,98 @@
+use appflowy_local_ai::vector_store::{
+  export_vector_store, import_vector_store, read_vector_store_manifest, sanitize_workspace_id,
+  VectorStoreManifest, VectorStorePaths,
+};
+use std::fs;
+use std::path::Path;
+
+#[test]
+fn export_import_vector_store_test() {
+  let dir = tempfile::tempdir().unwrap();
+  let source = dir.path().join("source");
+  fs::create_dir_all(source.join("index")).unwrap();
+  fs::write(source.join("chroma.sqlite3"), "db").unwrap();
+  fs::write(source.join("index/data.bin"), "vectors").unwrap();
+
+  let archive = dir.path().join("backup.zip");
+  let manifest = VectorStoreManifest::new(Path::new("/models/all-MiniLM-L12-v2.gguf"), None);
+  export_vector_store(&source, &manifest, &archive).unwrap();
+  assert_eq!(read_vector_store_manifest(&archive).unwrap(), manifest);
+
+  let target = dir.path().join("target");
+  fs::create_dir_all(&target).unwrap();
+  fs::write(target.join("stale.bin"), "stale").unwrap();
+  // Left behind by an interrupted import
+  fs::create_dir_all(dir.path().join("target.backup")).unwrap();
+  fs::write(dir.path().join("target.backup/old.bin"), "old").unwrap();
+  let imported = import_vector_store(&archive, &target).unwrap();
+  assert_eq!(
+    imported.model_file_name.as_deref(),
+    Some("all-MiniLM-L12-v2.gguf")
+  );
+  assert_eq!(
+    fs::read_to_string(target.join("chroma.sqlite3")).unwrap(),
+    "db"
+  );
+  assert_eq!(
+    fs::read_to_string(target.join("index/data.bin")).unwrap(),
+    "vectors"
+  );
+  assert!(!target.join("stale.bin").exists());
+  assert!(!dir.path().join("target.backup").exists());
+  assert!(!target.join("manifest.json").exists());
+}
+
+#[test]
+fn workspace_store_paths_test() {
+  assert_eq!(sanitize_workspace_id("../a b/c"), "_2e_2e_2fa_20b_2fc");
+  assert_eq!(sanitize_workspace_id("a_b"), "a_5fb");
+  assert_ne!(sanitize_workspace_id("a/b"), sanitize_workspace_id("a_b"));
+  assert_ne!(sanitize_workspace_id("a_2fb"), sanitize_workspace_id("a/b"));
+  assert_eq!(sanitize_workspace_id(""), "_");
+  let long_id = "w".repeat(200);
+  assert!(sanitize_workspace_id(&long_id).starts_with("__"));
+  assert_ne!(
+    sanitize_workspace_id(&long_id),
+    sanitize_workspace_id(&"w".repeat(201))
+  );
+
+  let dir = tempfile::tempdir().unwrap();
+  fs::write(dir.path().join("chroma.sqlite3"), "legacy").unwrap();
+  let paths = VectorStorePaths::new(dir.path());
+
+  // Neither workspace takes the legacy store on its own
+  let w1 = paths.prepare_workspace_dir("w1").unwrap();
+  assert_eq!(w1, dir.path().join("workspaces/w1"));
+  assert_eq!(fs::read_dir(&w1).unwrap().count(), 0);
+  assert!(!dir.path().join("chroma.sqlite3").exists());
+  let legacy = paths.legacy_store_dir().unwrap();
+  assert_eq!(
+    fs::read_to_string(legacy.join("chroma.sqlite3")).unwrap(),
+    "legacy"
+  );
+
+  let w2 = paths.prepare_workspace_dir("w2").unwrap();
+  assert!(w2.is_dir());
+  assert_eq!(fs::read_dir(&w2).unwrap().count(), 0);
+
+  assert!(paths.adopt_legacy_store("w2").unwrap());
+  assert_eq!(
+    fs::read_to_string(w2.join("chroma.sqlite3")).unwrap(),
+    "legacy"
+  );
+  assert_eq!(paths.legacy_store_dir(), None);
+  assert!(!paths.adopt_legacy_store("w1").unwrap());
+}
+
+#[test]
+fn adopt_legacy_store_keeps_existing_store_test() {
+  let dir = tempfile::tempdir().unwrap();
+  fs::write(dir.path().join("chroma.sqlite3"), "legacy").unwrap();
+  let paths = VectorStorePaths::new(dir.path());
+  let w1 = paths.prepare_workspace_dir("w1").unwrap();
+  fs::write(w1.join("chroma.sqlite3"), "w1").unwrap();
+
+  assert!(paths.adopt_legacy_store("w1").is_err());
+  assert_eq!(fs::read_to_string(w1.join("chroma.sqlite3")).unwrap(), "w1");
+  assert!(paths.legacy_store_dir().is_some());
+}
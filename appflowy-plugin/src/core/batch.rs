@@ -0,0 +1,44 @@
+use crate::core::rpc_object::RpcObject;
+use serde_json::{json, Value as JsonValue};
+use tracing::error;
+
+/// The capability a plugin reports in its handshake if it accepts batches. A batch is a JSON
+/// object with a single `batch` field, which holds an array of requests that each carry their
+/// own id. The plugin answers every request of the batch as usual, either one by one or in a
+/// batch of responses.
+pub(crate) const BATCH: &str = "batch";
+
+/// Wraps `requests` into a batch.
+pub(crate) fn envelope(requests: Vec<JsonValue>) -> JsonValue {
+  json!({ BATCH: requests })
+}
+
+/// Splits a batch into its messages. Other messages are returned as is.
+pub(crate) fn split(object: RpcObject) -> Vec<RpcObject> {
+  let is_batch = match object.0.as_object() {
+    Some(map) => map.len() == 1 && map.get(BATCH).map_or(false, |batch| batch.is_array()),
+    None => false,
+  };
+  if !is_batch {
+    return vec![object];
+  }
+
+  let messages = match object.0 {
+    JsonValue::Object(mut map) => match map.remove(BATCH) {
+      Some(JsonValue::Array(messages)) => messages,
+      _ => vec![],
+    },
+    _ => vec![],
+  };
+  messages
+    .into_iter()
+    .map(|message| {
+      if message.is_object() {
+        RpcObject(message)
+      } else {
+        error!("[RPC] batched message is not a JSON object: {}", message);
+        RpcObject(json!({ "message": message.to_string() }))
+      }
+    })
+    .collect()
+}
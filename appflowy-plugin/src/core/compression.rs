@@ -0,0 +1,130 @@
+use crate::core::parser::find_request_id;
+use crate::core::rpc_object::RpcObject;
+use crate::error::ReadError;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde_json::{json, Value as JsonValue};
+use std::io::{self, Read};
+use tracing::error;
+
+/// The capability a plugin reports in its handshake if it accepts compressed messages. The host
+/// always accepts them and says so with a top level `"compression": ["zstd"]` field in the
+/// `initialize` request.
+pub(crate) const ZSTD: &str = "zstd";
+
+/// Compresses messages to the plugin that are larger than [Self::threshold], e.g. indexed
+/// documents or embedding batches. Only used if the plugin reports the `zstd` capability.
+///
+/// A compressed message is a JSON object with a single `zstd` field, which holds the base64
+/// encoded zstd frame of the original message.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct CompressionConfig {
+  /// Size of the serialized message in bytes from which on it's compressed
+  pub threshold: usize,
+  /// zstd compression level, 1 (fastest) to 22 (smallest)
+  pub level: i32,
+}
+
+impl Default for CompressionConfig {
+  fn default() -> Self {
+    Self {
+      threshold: 16 * 1024,
+      level: 3,
+    }
+  }
+}
+
+impl CompressionConfig {
+  /// Returns the payload to send instead of `message`, if it's worth compressing.
+  pub(crate) fn compress(&self, message: &[u8]) -> io::Result<Option<Vec<u8>>> {
+    if message.len() < self.threshold {
+      return Ok(None);
+    }
+    let compressed = zstd::encode_all(message, self.level)?;
+    let envelope = json!({ ZSTD: STANDARD.encode(compressed) });
+    Ok(Some(serde_json::to_vec(&envelope)?))
+  }
+}
+
+/// Replaces a compressed message by the original message. Other messages are returned as is.
+///
+/// The message is decompressed as a stream and given up once it exceeds `max_size` bytes, so a
+/// small frame that expands to gigabytes can't exhaust the memory of the host.
+pub(crate) fn decompress(object: RpcObject, max_size: usize) -> Result<RpcObject, ReadError> {
+  let encoded = match object.0.as_object() {
+    Some(map) if map.len() == 1 => match map.get(ZSTD).and_then(|v| v.as_str()) {
+      Some(encoded) => encoded,
+      None => return Ok(object),
+    },
+    _ => return Ok(object),
+  };
+
+  let message = STANDARD
+    .decode(encoded)
+    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    .and_then(|compressed| {
+      let mut message = vec![];
+      zstd::stream::Decoder::new(compressed.as_slice())?
+        .take(max_size as u64 + 1)
+        .read_to_end(&mut message)?;
+      Ok(message)
+    });
+  let message = match message {
+    Ok(message) if message.len() > max_size => {
+      return Err(ReadError::MessageTooLarge {
+        id: find_request_id(&message),
+        size: message.len(),
+      });
+    },
+    Ok(message) => serde_json::from_slice::<JsonValue>(&message).map_err(io::Error::from),
+    Err(err) => Err(err),
+  };
+  Ok(match message {
+    Ok(message) if message.is_object() => message.into(),
+    Ok(message) => {
+      error!("[RPC] compressed message is not a JSON object: {}", message);
+      RpcObject(json!({ "message": message.to_string() }))
+    },
+    Err(err) => {
+      error!("[RPC] failed to decompress message: {:?}", err);
+      RpcObject(json!({ "message": format!("invalid compressed message: {}", err) }))
+    },
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn compressed(message: &JsonValue) -> RpcObject {
+    let payload = serde_json::to_vec(message).unwrap();
+    let config = CompressionConfig {
+      threshold: 0,
+      level: 3,
+    };
+    let envelope = config.compress(&payload).unwrap().unwrap();
+    RpcObject(serde_json::from_slice(&envelope).unwrap())
+  }
+
+  #[test]
+  fn decompress_test() {
+    let message = json!({ "id": 1, "result": { "data": "a".repeat(1000) } });
+    let object = decompress(compressed(&message), 64 * 1024).unwrap();
+    assert_eq!(object.0, message);
+  }
+
+  #[test]
+  fn decompress_rejects_oversized_message_test() {
+    // Compresses to a few hundred bytes
+    let message = json!({ "id": 4, "result": { "data": "a".repeat(1024 * 1024) } });
+    let object = compressed(&message);
+    assert!(object.0.to_string().len() < 1024);
+    match decompress(object, 64 * 1024) {
+      Err(ReadError::MessageTooLarge { id, size }) => {
+        assert_eq!(id, Some(4));
+        assert_eq!(size, 64 * 1024 + 1);
+      },
+      other => panic!("unexpected result: {:?}", other.map(|object| object.0)),
+    }
+  }
+}
@@ -0,0 +1,131 @@
+use crate::error::ReadError;
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::process::Child;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Number of stderr lines kept for a [PluginCrashReport]
+const STDERR_TAIL_LINES: usize = 50;
+/// How long to wait for the process to exit after its stdout was closed
+const EXIT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How a plugin process ended, attached to [RunningState::UnexpectedStop].
+///
+/// [RunningState::UnexpectedStop]: super::plugin::RunningState::UnexpectedStop
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PluginCrashReport {
+  /// `None` if the process was terminated by a signal or didn't exit in time
+  pub exit_code: Option<i32>,
+  /// The signal that terminated the process. Always `None` on Windows.
+  pub signal: Option<i32>,
+  /// The last lines the plugin wrote to stderr, oldest first
+  pub stderr_tail: Vec<String>,
+  /// The error that ended the connection, `None` if the plugin closed it
+  pub error: Option<String>,
+}
+
+impl Display for PluginCrashReport {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    match (self.exit_code, self.signal) {
+      (Some(code), _) => write!(f, "exit code: {}", code)?,
+      (None, Some(signal)) => write!(f, "terminated by signal: {}", signal)?,
+      (None, None) => write!(f, "exit status unknown")?,
+    }
+    if let Some(error) = &self.error {
+      write!(f, ", error: {}", error)?;
+    }
+    for line in &self.stderr_tail {
+      write!(f, "\n  {}", line)?;
+    }
+    Ok(())
+  }
+}
+
+/// Forwards the stderr of a plugin to the stderr of the app and keeps its last lines.
+pub(crate) struct StderrTail {
+  lines: Arc<Mutex<VecDeque<String>>>,
+  done: mpsc::Receiver<()>,
+}
+
+impl StderrTail {
+  pub(crate) fn spawn<R: Read + Send + 'static>(name: &str, stderr: R) -> Self {
+    let lines = Arc::new(Mutex::new(VecDeque::with_capacity(STDERR_TAIL_LINES)));
+    let (tx, done) = mpsc::channel();
+    let thread_lines = lines.clone();
+    let result = thread::Builder::new()
+      .name(format!("<{}> stderr", name))
+      .spawn(move || {
+        let mut reader = BufReader::new(stderr);
+        let mut buf = vec![];
+        while matches!(reader.read_until(b'\n', &mut buf), Ok(n) if n > 0) {
+          let _ = std::io::stderr().write_all(&buf);
+          let line = String::from_utf8_lossy(&buf).trim_end().to_string();
+          buf.clear();
+
+          let mut lines = thread_lines.lock();
+          if lines.len() == STDERR_TAIL_LINES {
+            lines.pop_front();
+          }
+          lines.push_back(line);
+        }
+        let _ = tx.send(());
+      });
+    if let Err(err) = result {
+      tracing::warn!("[RPC] failed to read stderr of {}: {:?}", name, err);
+    }
+    Self { lines, done }
+  }
+}
+
+/// The report of a remote plugin, which only knows how the connection ended.
+pub(crate) fn disconnected(result: &Result<(), ReadError>) -> PluginCrashReport {
+  PluginCrashReport {
+    error: result.as_ref().err().map(|err| err.to_string()),
+    ..Default::default()
+  }
+}
+
+/// Waits briefly for the plugin process to exit and collects its exit status and stderr.
+pub(crate) fn collect(
+  process: &Mutex<Child>,
+  stderr: Option<StderrTail>,
+  result: &Result<(), ReadError>,
+) -> PluginCrashReport {
+  let deadline = Instant::now() + EXIT_TIMEOUT;
+  let status = loop {
+    match process.lock().try_wait() {
+      Ok(None) if Instant::now() < deadline => {},
+      Ok(status) => break status,
+      Err(_) => break None,
+    }
+    thread::sleep(Duration::from_millis(20));
+  };
+
+  let stderr_tail = stderr
+    .map(|stderr| {
+      // The process may have exited before its last lines were read
+      let _ = stderr.done.recv_timeout(Duration::from_millis(200));
+      let lines = stderr.lines.lock();
+      lines.iter().cloned().collect()
+    })
+    .unwrap_or_default();
+
+  #[cfg(unix)]
+  let signal = {
+    use std::os::unix::process::ExitStatusExt;
+    status.and_then(|status| status.signal())
+  };
+  #[cfg(not(unix))]
+  let signal = None;
+
+  PluginCrashReport {
+    exit_code: status.and_then(|status| status.code()),
+    signal,
+    stderr_tail,
+    error: result.as_ref().err().map(|err| err.to_string()),
+  }
+}
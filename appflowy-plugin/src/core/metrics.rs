@@ -0,0 +1,122 @@
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Upper bounds of the latency buckets of [MethodMetrics::histogram]. Slower requests fall into
+/// a last bucket bounded by [Duration::MAX].
+const LATENCY_BUCKETS: [Duration; 12] = [
+  Duration::from_millis(5),
+  Duration::from_millis(10),
+  Duration::from_millis(25),
+  Duration::from_millis(50),
+  Duration::from_millis(100),
+  Duration::from_millis(250),
+  Duration::from_millis(500),
+  Duration::from_secs(1),
+  Duration::from_millis(2500),
+  Duration::from_secs(5),
+  Duration::from_secs(10),
+  Duration::from_secs(30),
+];
+
+/// Counters and latencies of the requests of one method, returned by
+/// [PluginManager::rpc_metrics](crate::manager::PluginManager::rpc_metrics). Latencies are
+/// measured from sending the request to its (final) response, so requests waiting for a free
+/// slot of the request queue aren't counted until they're sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MethodMetrics {
+  /// The [PluginInfo::name](crate::core::plugin::PluginInfo::name) of the plugin
+  pub plugin: String,
+  /// The RPC method, or for `handle` requests the method they run in the plugin, e.g. `answer`
+  pub method: String,
+  /// Number of completed requests, including failed ones
+  pub count: u64,
+  /// Requests that failed, timed out or were cancelled
+  pub errors: u64,
+  pub total: Duration,
+  pub max: Duration,
+  /// Number of requests per latency bucket as `(upper bound, count)`
+  pub histogram: Vec<(Duration, u64)>,
+}
+
+impl MethodMetrics {
+  pub fn mean(&self) -> Duration {
+    match self.count {
+      0 => Duration::ZERO,
+      count => self.total / count as u32,
+    }
+  }
+
+  /// Estimates the latency below which `quantile` (0 to 1) of the requests completed, as the
+  /// upper bound of the bucket that contains it.
+  pub fn quantile(&self, quantile: f64) -> Duration {
+    let rank = (self.count as f64 * quantile.clamp(0.0, 1.0)).ceil() as u64;
+    let mut seen = 0;
+    for (bound, count) in &self.histogram {
+      seen += count;
+      if seen >= rank.max(1) {
+        return (*bound).min(self.max);
+      }
+    }
+    self.max
+  }
+}
+
+#[derive(Debug, Default)]
+struct MethodStats {
+  count: u64,
+  errors: u64,
+  total: Duration,
+  max: Duration,
+  buckets: [u64; LATENCY_BUCKETS.len() + 1],
+}
+
+/// The metrics of the requests sent to the plugins with the same name. Kept by the
+/// [PluginManager](crate::manager::PluginManager), so they survive restarts of the plugin.
+#[derive(Debug, Default)]
+pub(crate) struct RpcMetrics {
+  methods: Mutex<HashMap<String, MethodStats>>,
+}
+
+impl RpcMetrics {
+  pub(crate) fn record(&self, method: &str, latency: Duration, is_error: bool) {
+    let mut methods = self.methods.lock();
+    let stats = match methods.get_mut(method) {
+      Some(stats) => stats,
+      None => methods.entry(method.to_string()).or_default(),
+    };
+    stats.count += 1;
+    if is_error {
+      stats.errors += 1;
+    }
+    stats.total += latency;
+    stats.max = stats.max.max(latency);
+    let bucket = LATENCY_BUCKETS
+      .iter()
+      .position(|bound| latency <= *bound)
+      .unwrap_or(LATENCY_BUCKETS.len());
+    stats.buckets[bucket] += 1;
+  }
+
+  pub(crate) fn snapshot(&self, plugin: &str) -> Vec<MethodMetrics> {
+    self
+      .methods
+      .lock()
+      .iter()
+      .map(|(method, stats)| MethodMetrics {
+        plugin: plugin.to_string(),
+        method: method.clone(),
+        count: stats.count,
+        errors: stats.errors,
+        total: stats.total,
+        max: stats.max,
+        histogram: LATENCY_BUCKETS
+          .iter()
+          .copied()
+          .chain(std::iter::once(Duration::MAX))
+          .zip(stats.buckets.iter().copied())
+          .collect(),
+      })
+      .collect()
+  }
+}
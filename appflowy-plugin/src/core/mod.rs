@@ -1,5 +1,16 @@
+mod batch;
+pub mod compression;
+pub mod crash_report;
+pub mod metrics;
 pub mod parser;
+pub(crate) mod pid_file;
 pub mod plugin;
+mod process_group;
+pub mod recording;
+pub mod resource_limit;
 pub mod rpc_loop;
 mod rpc_object;
 pub mod rpc_peer;
+pub mod sandbox;
+pub mod schema;
+pub mod transport;
@@ -1,51 +1,341 @@
 use crate::core::rpc_object::RpcObject;
 
 use crate::error::{ReadError, RemoteError};
-use serde_json::{json, Value as JsonValue};
-use std::io::BufRead;
-use tracing::error;
+use serde::de::DeserializeOwned;
+use serde_json::Value as JsonValue;
+use std::io::{self, BufRead, Read};
+use std::marker::PhantomData;
+use tracing::{error, warn};
 
-#[derive(Debug, Default)]
-pub struct MessageReader(String);
+/// Number of messages in a row that aren't JSON objects, e.g. stray prints of a Python library to
+/// stdout, that are skipped before the plugin is considered broken. Any valid message resets the
+/// count.
+pub const MAX_MALFORMED_MESSAGES: usize = 256;
+
+/// How much of a malformed message is logged
+const MALFORMED_LOG_LEN: usize = 200;
+
+/// Largest message read from a plugin unless [PluginInfo::max_message_size] says otherwise.
+///
+/// [PluginInfo::max_message_size]: crate::core::plugin::PluginInfo::max_message_size
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 * 1024 * 1024;
+
+/// How much of an oversized message is searched for its request id
+const TOO_LARGE_ID_PREFIX: usize = 256;
+
+/// How messages are delimited on the stdio channel.
+///
+/// Plugins start with [Framing::JsonLines]. The host offers [Framing::LengthPrefixed] with a top
+/// level `"framing": ["length_prefixed"]` field in the `initialize` request, and the plugin
+/// accepts it with a top level `"framing": "length_prefixed"` field in its response. Both sides
+/// use length-prefixed frames for every message after that response. Plugins that don't know the
+/// field keep using JSON lines.
+///
+/// Messages the host sent before it processed the response still arrive as JSON lines, so after
+/// the switch a reader takes a message that starts with [FRAME_MAGIC] as a frame and anything
+/// else as a line. The same rule skips stray output between frames, e.g. a print of a library.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Framing {
+  /// One JSON object per line
+  #[default]
+  JsonLines,
+  /// [FRAME_MAGIC], a 4 byte big-endian length and a JSON object of that many bytes. Unlike JSON
+  /// lines, stray newlines in the plugin's output can't split a message.
+  LengthPrefixed,
+}
+
+/// Starts every length-prefixed frame. `0xFF` never occurs in UTF-8, so text can't be mistaken for
+/// the start of a frame.
+pub const FRAME_MAGIC: [u8; 2] = [0xFF, 0xAF];
+
+impl Framing {
+  pub(crate) const LENGTH_PREFIXED: &'static str = "length_prefixed";
+
+  /// Frames the serialized JSON object `payload` as one message.
+  pub(crate) fn encode(&self, mut payload: Vec<u8>) -> Vec<u8> {
+    match self {
+      Framing::JsonLines => {
+        payload.push(b'\n');
+        payload
+      },
+      Framing::LengthPrefixed => {
+        let mut frame = Vec::with_capacity(FRAME_MAGIC.len() + 4 + payload.len());
+        frame.extend_from_slice(&FRAME_MAGIC);
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&payload);
+        frame
+      },
+    }
+  }
+}
+
+#[derive(Debug)]
+pub struct MessageReader {
+  buf: Vec<u8>,
+  framing: Framing,
+  malformed: usize,
+  max_size: usize,
+}
+
+impl Default for MessageReader {
+  fn default() -> Self {
+    Self {
+      buf: Vec::new(),
+      framing: Framing::default(),
+      malformed: 0,
+      max_size: DEFAULT_MAX_MESSAGE_SIZE,
+    }
+  }
+}
 
 impl MessageReader {
-  /// Attempts to read the next line from the stream and parse it as
-  /// an RPC object.
+  /// Reads the following messages with `framing`.
+  pub(crate) fn set_framing(&mut self, framing: Framing) {
+    self.framing = framing;
+  }
+
+  /// Skips messages larger than `max_size` bytes instead of buffering them.
+  pub(crate) fn set_max_size(&mut self, max_size: usize) {
+    self.max_size = max_size;
+  }
+
+  pub(crate) fn max_size(&self) -> usize {
+    self.max_size
+  }
+
+  /// Reads the next message from the stream and parses it as an RPC object. Messages that aren't
+  /// JSON objects are logged and skipped.
   ///
   /// # Errors
   ///
   /// This function will return an error if there is an underlying
-  /// I/O error, if the stream is closed, or if the message is not
-  /// a valid JSON object.
+  /// I/O error, if the stream is closed, or if more than [MAX_MALFORMED_MESSAGES] messages in a
+  /// row are not valid JSON objects. A message larger than the maximum size is skipped and
+  /// returned as [ReadError::MessageTooLarge], after which the reader can be used again.
   pub fn next<R: BufRead>(&mut self, reader: &mut R) -> Result<RpcObject, ReadError> {
-    self.0.clear();
-    let _ = reader.read_line(&mut self.0)?;
-    if self.0.is_empty() {
-      Err(ReadError::Disconnect(
+    loop {
+      let too_large = match self.framing {
+        Framing::JsonLines => self.next_line(reader)?,
+        Framing::LengthPrefixed => self.next_frame(reader)?,
+      };
+      if let Some(size) = too_large {
+        let id = find_request_id(&self.buf);
+        // Don't hold on to the memory of the oversized message
+        self.buf = Vec::new();
+        return Err(ReadError::MessageTooLarge { id, size });
+      }
+      // E.g. a bare `print()`
+      if self.buf.iter().all(u8::is_ascii_whitespace) {
+        continue;
+      }
+      match self.parse(&self.buf) {
+        Ok(object) => {
+          self.malformed = 0;
+          return Ok(object);
+        },
+        Err(_) => {
+          self.malformed += 1;
+          let message = String::from_utf8_lossy(&self.buf);
+          let message = message.trim_end();
+          if self.malformed > MAX_MALFORMED_MESSAGES {
+            error!(
+              "[RPC] giving up after {} malformed messages",
+              MAX_MALFORMED_MESSAGES
+            );
+            return Err(ReadError::Malformed(message.to_string()));
+          }
+          match message.char_indices().nth(MALFORMED_LOG_LEN) {
+            None => warn!("[RPC] skipping malformed message: {}", message),
+            Some((end, _)) => warn!("[RPC] skipping malformed message: {}...", &message[..end]),
+          }
+        },
+      }
+    }
+  }
+
+  /// Reads bytes instead of a `String`, so output that isn't UTF-8 is skipped like any other
+  /// malformed line.
+  ///
+  /// Returns the size of the line if it's larger than the maximum size. Only the start of such a
+  /// line is kept in the buffer, the rest is read and dropped.
+  fn next_line<R: BufRead>(&mut self, reader: &mut R) -> Result<Option<usize>, ReadError> {
+    self.buf.clear();
+    let limit = self.max_size as u64 + 1;
+    if (&mut *reader)
+      .take(limit)
+      .read_until(b'\n', &mut self.buf)?
+      == 0
+    {
+      return Err(ReadError::Disconnect(
         "stdout return empty line".to_string(),
-      ))
-    } else {
-      self.parse(&self.0)
+      ));
+    }
+    if self.buf.len() <= self.max_size || self.buf.ends_with(b"\n") {
+      return Ok(None);
     }
+    let mut size = self.buf.len();
+    loop {
+      let available = reader.fill_buf()?;
+      if available.is_empty() {
+        break;
+      }
+      match available.iter().position(|b| *b == b'\n') {
+        Some(pos) => {
+          size += pos + 1;
+          reader.consume(pos + 1);
+          break;
+        },
+        None => {
+          let len = available.len();
+          size += len;
+          reader.consume(len);
+        },
+      }
+    }
+    Ok(Some(size))
   }
 
-  /// Attempts to parse a &str as an RPC Object.
+  /// Returns the size of the frame if it's larger than the maximum size. Only the start of such a
+  /// frame is kept in the buffer, the rest is read and dropped.
+  ///
+  /// Anything before the next [FRAME_MAGIC] is read up to the end of its line instead, and parsed
+  /// like a JSON line, see [Framing].
+  fn next_frame<R: BufRead>(&mut self, reader: &mut R) -> Result<Option<usize>, ReadError> {
+    self.buf.clear();
+    if !self.read_until_frame(reader)? {
+      return Ok(None);
+    }
+    let mut header = [0; FRAME_MAGIC.len() + 4];
+    if let Err(err) = reader.read_exact(&mut header) {
+      return Err(match err.kind() {
+        io::ErrorKind::UnexpectedEof => ReadError::Disconnect("stdout closed".to_string()),
+        _ => err.into(),
+      });
+    }
+    if header[..FRAME_MAGIC.len()] != FRAME_MAGIC {
+      // Output that happens to contain `0xFF`, skipped as a malformed message
+      self.buf.extend_from_slice(&header);
+      return Ok(None);
+    }
+    let len: [u8; 4] = header[FRAME_MAGIC.len()..].try_into().unwrap();
+    let size = u32::from_be_bytes(len) as usize;
+    if size > self.max_size {
+      self.buf.resize(TOO_LARGE_ID_PREFIX.min(size), 0);
+      reader.read_exact(&mut self.buf)?;
+      let rest = (size - self.buf.len()) as u64;
+      if io::copy(&mut (&mut *reader).take(rest), &mut io::sink())? < rest {
+        return Err(ReadError::Disconnect("stdout closed".to_string()));
+      }
+      return Ok(Some(size));
+    }
+    self.buf.resize(size, 0);
+    reader.read_exact(&mut self.buf)?;
+    Ok(None)
+  }
+
+  /// Reads what comes before the next frame into the buffer, up to the end of its line. Returns
+  /// whether a frame starts next, i.e. nothing was read.
+  fn read_until_frame<R: BufRead>(&mut self, reader: &mut R) -> Result<bool, ReadError> {
+    loop {
+      let available = reader.fill_buf()?;
+      if available.is_empty() {
+        if self.buf.is_empty() {
+          return Err(ReadError::Disconnect("stdout closed".to_string()));
+        }
+        return Ok(false);
+      }
+      match available
+        .iter()
+        .position(|b| *b == FRAME_MAGIC[0] || *b == b'\n')
+      {
+        Some(0) if available[0] == FRAME_MAGIC[0] => return Ok(self.buf.is_empty()),
+        Some(pos) if available[pos] == FRAME_MAGIC[0] => {
+          self.buf.extend_from_slice(&available[..pos]);
+          reader.consume(pos);
+          return Ok(false);
+        },
+        Some(pos) => {
+          self.buf.extend_from_slice(&available[..=pos]);
+          reader.consume(pos + 1);
+          return Ok(false);
+        },
+        None => {
+          let len = available.len();
+          self.buf.extend_from_slice(available);
+          reader.consume(len);
+          // Don't buffer endless output without a newline
+          if self.buf.len() > self.max_size {
+            return Ok(false);
+          }
+        },
+      }
+    }
+  }
+
+  /// Attempts to parse a message as an RPC Object.
   ///
   /// This should not be called directly unless you are writing tests.
   #[doc(hidden)]
-  pub fn parse(&self, s: &str) -> Result<RpcObject, ReadError> {
-    match serde_json::from_str::<JsonValue>(s) {
-      Ok(val) => {
-        if val.is_object() {
-          Ok(val.into())
-        } else {
-          error!("Expected JSON object, found: {}", s);
-          Ok(RpcObject(json!({"message": s.to_string()})))
+  pub fn parse(&self, message: &[u8]) -> Result<RpcObject, ReadError> {
+    let val = serde_json::from_slice::<JsonValue>(message)?;
+    if val.is_object() {
+      Ok(val.into())
+    } else {
+      Err(ReadError::NotObject(
+        String::from_utf8_lossy(message).into_owned(),
+      ))
+    }
+  }
+}
+
+/// Looks for the top level `"id"` of a response at the start of an oversized message that can't
+/// be parsed as a whole. Plugins write the id before the result, so it's usually among the first
+/// bytes. Keys of nested objects and the content of strings are skipped.
+pub(crate) fn find_request_id(message: &[u8]) -> Option<RequestId> {
+  let prefix = &message[..message.len().min(TOO_LARGE_ID_PREFIX)];
+  let mut depth = 0usize;
+  let mut i = 0;
+  while i < prefix.len() {
+    match prefix[i] {
+      b'{' | b'[' => depth += 1,
+      b'}' | b']' => depth = depth.saturating_sub(1),
+      b'"' => {
+        let end = i + 1 + string_len(&prefix[i + 1..])?;
+        let is_id = depth == 1 && &prefix[i + 1..end] == b"id";
+        i = end + 1;
+        if is_id {
+          if let Some(rest) = skip_whitespace(&prefix[i..]).strip_prefix(b":") {
+            let rest = skip_whitespace(rest);
+            let digits = rest.iter().take_while(|b| b.is_ascii_digit()).count();
+            return std::str::from_utf8(&rest[..digits]).ok()?.parse().ok();
+          }
         }
+        continue;
       },
-      Err(_) => Ok(RpcObject(json!({"message": s.to_string()}))),
+      _ => {},
     }
+    i += 1;
   }
+  None
+}
+
+/// The length of the JSON string that ends with the first unescaped quote in `bytes`
+fn string_len(bytes: &[u8]) -> Option<usize> {
+  let mut escaped = false;
+  for (i, b) in bytes.iter().enumerate() {
+    match b {
+      _ if escaped => escaped = false,
+      b'\\' => escaped = true,
+      b'"' => return Some(i),
+      _ => {},
+    }
+  }
+  None
+}
+
+fn skip_whitespace(bytes: &[u8]) -> &[u8] {
+  let n = bytes.iter().take_while(|b| b.is_ascii_whitespace()).count();
+  &bytes[n..]
 }
 
 pub type RequestId = u64;
@@ -53,6 +343,8 @@ pub type RequestId = u64;
 /// An RPC call, which may be either a notification or a request.
 pub enum Call<R> {
   Message(JsonValue),
+  /// A method and params without an id, pushed by the plugin on its own
+  Notification(String, JsonValue),
   /// An id and an RPC Request
   Request(RequestId, R),
   /// A malformed request: the request contained an id, but could
@@ -73,3 +365,85 @@ impl ResponseParser for DefaultResponseParser {
     Ok(())
   }
 }
+
+/// Deserializes the `data` field of the response into `T`, a missing field as `null`. Used by
+/// [Plugin::typed_request](crate::core::plugin::Plugin::typed_request).
+pub struct TypedResponseParser<T>(PhantomData<T>);
+impl<T> ResponseParser for TypedResponseParser<T>
+where
+  T: DeserializeOwned + Send + Sync + 'static,
+{
+  type ValueType = T;
+
+  fn parse_json(payload: JsonValue) -> Result<Self::ValueType, RemoteError> {
+    let data = payload.get("data").unwrap_or(&JsonValue::Null);
+    T::deserialize(data).map_err(|err| {
+      error!("Failed to deserialize response: {}", err);
+      RemoteError::ParseResponse(payload.clone())
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn find_request_id_test() {
+    assert_eq!(find_request_id(br#"{"id": 12, "result": {"#), Some(12));
+    assert_eq!(find_request_id(br#"{"result": {"id": 3}, "id":7"#), Some(7));
+    assert_eq!(
+      find_request_id(br#"{"result": [{"id": 3}], "x": "\"id\": 4""#),
+      None
+    );
+    assert_eq!(
+      find_request_id(br#"{"result": "{\"id\": 4", "id": 5}"#),
+      Some(5)
+    );
+    assert_eq!(find_request_id(br#"{"result": {"data": "aaaa"#), None);
+  }
+
+  fn frame(message: &str) -> Vec<u8> {
+    Framing::LengthPrefixed.encode(message.as_bytes().to_vec())
+  }
+
+  fn length_prefixed_reader() -> MessageReader {
+    let mut reader = MessageReader::default();
+    reader.set_framing(Framing::LengthPrefixed);
+    reader
+  }
+
+  #[test]
+  fn stray_output_between_frames_is_skipped_test() {
+    let mut input = frame(r#"{"id": 1, "result": {}}"#);
+    input.extend_from_slice(b"loading model...\n");
+    input.extend_from_slice(b"progress: 50%");
+    input.extend_from_slice(&frame(r#"{"id": 2, "result": {}}"#));
+    input.extend_from_slice(b"\xFF not a frame\n");
+    input.extend_from_slice(&frame(r#"{"id": 3, "result": {}}"#));
+    let mut input = io::BufReader::new(&input[..]);
+
+    let mut reader = length_prefixed_reader();
+    for id in 1..=3 {
+      assert_eq!(reader.next(&mut input).unwrap().get_id(), Some(id));
+    }
+    assert!(matches!(
+      reader.next(&mut input),
+      Err(ReadError::Disconnect(_))
+    ));
+  }
+
+  #[test]
+  fn json_lines_sent_before_the_switch_are_read_test() {
+    let mut input = br#"{"id": 1, "result": {}}"#.to_vec();
+    input.push(b'\n');
+    input.extend_from_slice(&frame(r#"{"id": 2, "result": {}}"#));
+    input.extend_from_slice(b"{\"method\": \"ping\", \"params\": {}}\n");
+    let mut input = io::BufReader::new(&input[..]);
+
+    let mut reader = length_prefixed_reader();
+    assert_eq!(reader.next(&mut input).unwrap().get_id(), Some(1));
+    assert_eq!(reader.next(&mut input).unwrap().get_id(), Some(2));
+    assert_eq!(reader.next(&mut input).unwrap().get_method(), Some("ping"));
+  }
+}
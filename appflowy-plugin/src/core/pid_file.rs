@@ -0,0 +1,130 @@
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use sysinfo::{Pid, ProcessRefreshKind, System, UpdateKind};
+use tracing::{info, trace, warn};
+
+/// Written for every running plugin process and removed when the plugin stops. A file that's
+/// still there after its host exited belongs to a plugin the host couldn't stop, e.g. because it
+/// crashed.
+#[derive(Debug, Serialize, Deserialize)]
+struct PidRecord {
+  pid: u32,
+  exec_path: PathBuf,
+  host_pid: u32,
+  /// Distinguishes the host from a later process that reuses its pid
+  host_start_time: u64,
+}
+
+/// Removes the pid file of the plugin when dropped.
+#[derive(Debug)]
+pub(crate) struct PidFile {
+  path: PathBuf,
+}
+
+impl PidFile {
+  pub(crate) fn create(dir: &Path, pid: u32, exec_path: &Path) -> io::Result<Self> {
+    let (host_pid, host_start_time) = host();
+    let record = PidRecord {
+      pid,
+      exec_path: exec_path
+        .canonicalize()
+        .unwrap_or_else(|_| exec_path.to_path_buf()),
+      host_pid,
+      host_start_time,
+    };
+    std::fs::create_dir_all(dir)?;
+    let path = dir.join(format!("{}.json", pid));
+    std::fs::write(&path, serde_json::to_vec(&record)?)?;
+    Ok(Self { path })
+  }
+}
+
+impl Drop for PidFile {
+  fn drop(&mut self) {
+    if let Err(err) = std::fs::remove_file(&self.path) {
+      trace!("[RPC] failed to remove pid file {:?}: {:?}", self.path, err);
+    }
+  }
+}
+
+fn host() -> (u32, u64) {
+  static HOST: OnceLock<(u32, u64)> = OnceLock::new();
+  *HOST.get_or_init(|| {
+    let pid = std::process::id();
+    (pid, start_time(&mut System::new(), pid).unwrap_or_default())
+  })
+}
+
+fn start_time(system: &mut System, pid: u32) -> Option<u64> {
+  let pid = Pid::from_u32(pid);
+  system.refresh_process_specifics(pid, ProcessRefreshKind::new());
+  system.process(pid).map(|process| process.start_time())
+}
+
+/// Terminates the plugin processes of hosts that are no longer running and removes their pid
+/// files. Returns the number of terminated processes.
+pub(crate) fn cleanup_orphans(dir: &Path) -> io::Result<usize> {
+  let entries = match std::fs::read_dir(dir) {
+    Ok(entries) => entries,
+    Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+    Err(err) => return Err(err),
+  };
+
+  let mut system = System::new();
+  let mut terminated = 0;
+  for entry in entries {
+    let path = entry?.path();
+    if path.extension().map_or(true, |ext| ext != "json") {
+      continue;
+    }
+    let record = match std::fs::read(&path)
+      .ok()
+      .and_then(|content| serde_json::from_slice::<PidRecord>(&content).ok())
+    {
+      Some(record) => record,
+      None => {
+        warn!("[RPC] remove invalid pid file {:?}", path);
+        let _ = std::fs::remove_file(&path);
+        continue;
+      },
+    };
+
+    if start_time(&mut system, record.host_pid) == Some(record.host_start_time) {
+      // The host is still running and stops its plugins itself
+      continue;
+    }
+
+    let pid = Pid::from_u32(record.pid);
+    system.refresh_process_specifics(pid, ProcessRefreshKind::new().with_exe(UpdateKind::Always));
+    // Only touch the process if the pid wasn't reused by an unrelated program
+    if let Some(process) = system
+      .process(pid)
+      .filter(|process| process.exe() == Some(record.exec_path.as_path()))
+    {
+      info!(
+        "[RPC] terminating orphaned plugin {:?}, pid: {}",
+        record.exec_path, record.pid
+      );
+      kill_group(record.pid);
+      process.kill();
+      terminated += 1;
+    }
+    let _ = std::fs::remove_file(&path);
+  }
+  Ok(terminated)
+}
+
+/// Plugins are the leaders of their process group, so the processes they spawned are stopped
+/// too. See [ProcessGroup](super::process_group::ProcessGroup).
+fn kill_group(pid: u32) {
+  #[cfg(unix)]
+  // Safety: kill has no memory safety requirements. A negative pid addresses the whole group.
+  unsafe {
+    libc::kill(-(pid as libc::pid_t), libc::SIGKILL);
+  }
+
+  #[cfg(not(unix))]
+  let _ = pid;
+}
@@ -1,23 +1,51 @@
-use crate::error::PluginError;
+use crate::error::{PluginError, RemoteError};
 use crate::manager::WeakPluginState;
-use std::fmt::{Display, Formatter};
+use std::fmt::{Debug, Display, Formatter};
 
-use crate::core::parser::ResponseParser;
+use crate::core::batch;
+use crate::core::compression::{self, CompressionConfig};
+use crate::core::crash_report::{self, PluginCrashReport, StderrTail};
+use crate::core::parser::{DefaultResponseParser, ResponseParser, TypedResponseParser};
+use crate::core::pid_file::PidFile;
+use crate::core::process_group::ProcessGroup;
+use crate::core::recording::RpcRecorder;
+use crate::core::resource_limit::{
+  apply_after_spawn, apply_before_spawn, ProcessPriority, ResourceLimitGuard, ResourceLimits,
+};
 use crate::core::rpc_loop::RpcLoop;
-use crate::core::rpc_peer::{CloneableCallback, OneShotCallback};
+use crate::core::rpc_peer::{
+  CloneableCallback, IdleCallback, OnSent, OneShotCallback, RequestPriority, RequestQueueConfig,
+  StalledRequest,
+};
+use crate::core::sandbox::{self, SandboxPolicy};
+use crate::core::transport::{self, LocalListener, PluginTransport, Reader, Writer, SOCKET_ENV};
 use anyhow::anyhow;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use parking_lot::{Mutex, RwLock};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value as JsonValue};
-use std::io::BufReader;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::{self, BufReader, BufWriter};
+use std::net::{Shutdown, TcpStream};
 use std::path::PathBuf;
+use std::pin::Pin;
 use std::process::{Child, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::task::{Context, Poll};
 use std::thread;
-use std::time::Instant;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::sync::mpsc::error::TrySendError;
 use tokio::sync::watch;
-use tokio_stream::wrappers::{ReceiverStream, WatchStream};
+use tokio::task::JoinHandle;
+use tokio_stream::wrappers::{ReceiverStream, UnboundedReceiverStream, WatchStream};
+use tokio_stream::{Stream, StreamExt};
 
-use tracing::{error, info, trace};
+use tracing::{error, info, trace, warn};
 
 #[derive(
   Default, Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize,
@@ -39,19 +67,83 @@ pub trait Peer: Send + Sync + 'static {
   /// Sends an RPC notification to the peer with the specified method and parameters.
   fn send_rpc_notification(&self, method: &str, params: &JsonValue);
 
-  fn stream_rpc_request(&self, method: &str, params: &JsonValue, f: CloneableCallback);
+  /// Returns the id of the request, see [Self::fail_request].
+  ///
+  /// With a `window`, the peer sends at most that many stream messages that weren't acknowledged
+  /// with a `stream_ack` notification, see [RequestStream]. `on_sent` fires once the request was
+  /// written, which is later than this returns if the request waits in the request queue.
+  fn stream_rpc_request(
+    &self,
+    method: &str,
+    params: &JsonValue,
+    window: Option<usize>,
+    priority: RequestPriority,
+    f: CloneableCallback,
+    on_sent: Option<OnSent>,
+  ) -> usize;
 
-  fn async_send_rpc_request(&self, method: &str, params: &JsonValue, f: Box<dyn OneShotCallback>);
-  /// Sends a synchronous RPC request to the peer and waits for the result.
+  /// Returns the id of the request, see [Self::fail_request].
+  fn async_send_rpc_request(
+    &self,
+    method: &str,
+    params: &JsonValue,
+    priority: RequestPriority,
+    f: Box<dyn OneShotCallback>,
+  ) -> usize;
+  /// Sends several requests at once. Returns the ids of the requests in the same order.
+  fn async_send_rpc_batch(
+    &self,
+    requests: Vec<(String, JsonValue, Box<dyn OneShotCallback>)>,
+    priority: RequestPriority,
+  ) -> Vec<usize>;
+
+  /// Sends a synchronous RPC request to the peer and waits for the result, at most for `timeout`.
   /// Returns the result of the request or an error.
-  fn send_rpc_request(&self, method: &str, params: &JsonValue) -> Result<JsonValue, PluginError>;
+  fn send_rpc_request(
+    &self,
+    method: &str,
+    params: &JsonValue,
+    timeout: Option<Duration>,
+  ) -> Result<JsonValue, PluginError>;
 
   /// Checks if there is an incoming request pending, intended to reduce latency for bulk operations done in the background.
   fn request_is_pending(&self) -> bool;
 
+  /// Number of requests sent to the peer that haven't received their (final) response yet.
+  fn pending_request_count(&self) -> usize;
+
+  /// Pending requests that didn't receive a response or stream message for at least `threshold`.
+  fn stalled_requests(&self, threshold: Duration) -> Vec<StalledRequest>;
+
+  /// Completes a pending or queued request with `error`. Its response is ignored if it still
+  /// arrives.
+  fn fail_request(&self, request_id: usize, error: PluginError);
+
+  /// Completes a pending or queued request with [PluginError::Cancelled]. If the request was sent
+  /// already, tells the plugin to stop working on it, see [AbortHandle].
+  fn cancel_request(&self, request_id: usize);
+
+  /// Compresses large messages to the peer from now on.
+  fn enable_compression(&self, config: CompressionConfig);
+
+  /// Sends the requests of [Self::async_send_rpc_batch] in a single message from now on.
+  fn enable_batching(&self);
+
+  /// The protocol revision agreed on with the peer in `initialize`, see
+  /// [PROTOCOL_VERSION](crate::core::rpc_peer::PROTOCOL_VERSION).
+  fn protocol_version(&self) -> u32;
+
+  /// Returns the notifications with `method` the peer pushes from now on. The stream ends when
+  /// the peer disconnects.
+  fn subscribe_notifications(&self, method: &str) -> ReceiverStream<JsonValue>;
+
   /// Schedules a timer to execute the handler's `idle` function after the specified `Instant`.
   /// Note: This is not a high-fidelity timer. Regular RPC messages will always take priority over idle tasks.
   fn schedule_timer(&self, after: Instant, token: usize);
+
+  /// Runs `callback` once `after` elapsed and neither messages from the peer are waiting to be
+  /// handled nor requests to the peer are in flight.
+  fn schedule_idle(&self, after: Duration, callback: IdleCallback);
 }
 
 /// The `Peer` trait object.
@@ -69,10 +161,25 @@ pub enum RunningState {
   Connected { plugin_id: PluginId },
   /// The plugin is currently running
   Running { plugin_id: PluginId },
+  /// The plugin is running, but requests are waiting for a free slot because the maximum number
+  /// of concurrent requests has been reached
+  Queued {
+    plugin_id: PluginId,
+    queue_depth: usize,
+  },
   /// The plugin has been stopped intentionally
   Stopped { plugin_id: PluginId },
-  /// The plugin stopped unexpectedly
-  UnexpectedStop { plugin_id: PluginId },
+  /// The connection to the plugin was lost, e.g. because the process crashed. Sent instead of
+  /// [RunningState::Stopped] when the plugin didn't exit cleanly.
+  UnexpectedStop {
+    plugin_id: PluginId,
+    report: PluginCrashReport,
+  },
+  /// The plugin process is still alive, but stopped answering health check pings
+  Unhealthy { plugin_id: PluginId },
+  /// The plugin process is paused by [crate::manager::PluginManager::suspend]. Requests are
+  /// answered once it's resumed.
+  Suspended { plugin_id: PluginId },
 }
 
 impl RunningState {
@@ -81,13 +188,27 @@ impl RunningState {
       RunningState::Connecting => None,
       RunningState::Connected { plugin_id } => Some(*plugin_id),
       RunningState::Running { plugin_id } => Some(*plugin_id),
+      RunningState::Queued { plugin_id, .. } => Some(*plugin_id),
       RunningState::Stopped { plugin_id } => Some(*plugin_id),
-      RunningState::UnexpectedStop { plugin_id } => Some(*plugin_id),
+      RunningState::UnexpectedStop { plugin_id, .. } => Some(*plugin_id),
+      RunningState::Unhealthy { plugin_id } => Some(*plugin_id),
+      RunningState::Suspended { plugin_id } => Some(*plugin_id),
     }
   }
 
   pub fn is_ready(&self) -> bool {
-    matches!(self, RunningState::Running { .. })
+    matches!(
+      self,
+      RunningState::Running { .. } | RunningState::Queued { .. }
+    )
+  }
+
+  pub fn is_unhealthy(&self) -> bool {
+    matches!(self, RunningState::Unhealthy { .. })
+  }
+
+  pub fn is_suspended(&self) -> bool {
+    matches!(self, RunningState::Suspended { .. })
   }
 
   pub fn is_loading(&self) -> bool {
@@ -106,75 +227,534 @@ pub struct Plugin {
   peer: RpcPeer,
   pub(crate) id: PluginId,
   pub(crate) name: String,
-  #[allow(dead_code)]
-  pub(crate) process: Arc<Child>,
+  /// `None` for plugins that run on another machine, see [PluginTransport::Tcp]
+  pub(crate) process: Option<Arc<Mutex<Child>>>,
+  process_group: Option<Arc<ProcessGroup>>,
+  pub(crate) pid: Option<u32>,
+  /// The connection to a [PluginTransport::Tcp] plugin
+  remote: Option<Arc<TcpStream>>,
+  pub(crate) started_at: Instant,
   pub(crate) running_state: RunningStateSender,
+  handshake: Arc<RwLock<PluginHandshake>>,
+  pub(crate) info: PluginInfo,
+  init_params: Arc<RwLock<Option<JsonValue>>>,
+  last_active: Arc<Mutex<Instant>>,
+  pub(crate) pid_file: Option<Arc<PidFile>>,
+}
+
+/// Version and features reported by the plugin in its response to `initialize`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PluginHandshake {
+  /// Empty for plugins that predate the handshake
+  #[serde(default)]
+  pub version: String,
+  /// Features the plugin supports, e.g. `rag`, `complete_text` or `vision`
+  #[serde(default)]
+  pub capabilities: Vec<String>,
 }
 
 impl Display for Plugin {
   fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-    write!(
-      f,
-      "{}, plugin id: {:?}, process id: {}",
-      self.name,
-      self.id,
-      self.process.id()
-    )
+    match self.pid {
+      Some(pid) => write!(
+        f,
+        "{}, plugin id: {:?}, process id: {}",
+        self.name, self.id, pid
+      ),
+      None => match &self.info.transport {
+        PluginTransport::Tcp { addr, .. } => write!(
+          f,
+          "{}, plugin id: {:?}, address: {}",
+          self.name, self.id, addr
+        ),
+        _ => write!(f, "{}, plugin id: {:?}", self.name, self.id),
+      },
+    }
   }
 }
 
 impl Plugin {
-  pub fn initialize(&self, value: JsonValue) -> Result<(), PluginError> {
-    self.peer.send_rpc_request("initialize", &value)?;
+  pub fn initialize(&self, mut value: JsonValue) -> Result<(), PluginError> {
+    // Not part of the init params, so it doesn't end up where they're logged or stored
+    if let PluginTransport::Tcp { auth_token, .. } = &self.info.transport {
+      if let Some(params) = value.as_object_mut() {
+        params.insert("auth_token".to_string(), json!(auth_token));
+      }
+    }
+    let resp = self.peer.send_rpc_request("initialize", &value, None)?;
+    let data = resp.get("data").cloned().unwrap_or(resp);
+    let data_is_null = data.is_null();
+    let handshake = match serde_json::from_value::<PluginHandshake>(data) {
+      Ok(handshake) => handshake,
+      // Plugins that predate the handshake don't return anything
+      Err(_) if data_is_null => PluginHandshake::default(),
+      Err(err) => {
+        warn!("plugin {} returned an invalid handshake: {:?}", self, err);
+        PluginHandshake::default()
+      },
+    };
+    info!(
+      "plugin {} version: {}, capabilities: {:?}",
+      self, handshake.version, handshake.capabilities
+    );
+    if let Some(compression) = self.info.compression {
+      if handshake
+        .capabilities
+        .iter()
+        .any(|c| c == compression::ZSTD)
+      {
+        self.peer.enable_compression(compression);
+      }
+    }
+    if handshake.capabilities.iter().any(|c| c == batch::BATCH) {
+      self.peer.enable_batching();
+    }
+    *self.handshake.write() = handshake;
     Ok(())
   }
 
-  pub fn request(&self, method: &str, params: &JsonValue) -> Result<JsonValue, PluginError> {
-    self.peer.send_rpc_request(method, params)
+  /// Queries the JSON Schema of the `initialize` params. Returns `None` for plugins that don't
+  /// implement `get_init_schema`.
+  pub async fn init_schema(&self) -> Result<Option<JsonValue>, PluginError> {
+    let params = json!({});
+    let (_, request) = self.send_async_request::<InitSchemaParser>(
+      "get_init_schema",
+      &params,
+      Some(INIT_SCHEMA_TIMEOUT),
+      RequestPriority::Interactive,
+    );
+    match request.await {
+      Ok(schema) => Ok(schema),
+      Err(PluginError::RemoteError(err)) => {
+        trace!("plugin {} has no init schema: {:?}", self, err);
+        Ok(None)
+      },
+      // Older plugins may not answer unknown requests at all
+      Err(PluginError::Timeout) => {
+        warn!("plugin {} did not answer get_init_schema", self);
+        Ok(None)
+      },
+      Err(err) => Err(err),
+    }
+  }
+
+  pub(crate) fn set_init_params(&self, params: JsonValue) {
+    *self.init_params.write() = Some(params);
+  }
+
+  /// The params of the last `initialize` request.
+  pub fn init_params(&self) -> Option<JsonValue> {
+    self.init_params.read().clone()
+  }
+
+  pub fn handshake(&self) -> PluginHandshake {
+    self.handshake.read().clone()
+  }
+
+  pub fn version(&self) -> String {
+    self.handshake.read().version.clone()
+  }
+
+  /// The protocol revision agreed on in `initialize`, see
+  /// [PROTOCOL_VERSION](crate::core::rpc_peer::PROTOCOL_VERSION).
+  pub fn protocol_version(&self) -> u32 {
+    self.peer.protocol_version()
   }
 
+  /// Returns the params of the notifications with `method` the plugin pushes from now on, e.g.
+  /// to learn that the plugin unloaded its model. A subscriber that falls behind misses
+  /// notifications. The stream ends when the plugin disconnects.
+  pub fn subscribe_notifications(&self, method: &str) -> ReceiverStream<JsonValue> {
+    self.peer.subscribe_notifications(method)
+  }
+
+  /// Whether the plugin reported `capability` when it was initialized.
+  pub fn supports(&self, capability: &str) -> bool {
+    self
+      .handshake
+      .read()
+      .capabilities
+      .iter()
+      .any(|c| c == capability)
+  }
+
+  /// Sends a request and blocks until the response arrives. Fails with [PluginError::Timeout] if
+  /// the plugin doesn't respond within `timeout`.
+  pub fn request(
+    &self,
+    method: &str,
+    params: &JsonValue,
+    timeout: Option<Duration>,
+  ) -> Result<JsonValue, PluginError> {
+    self.touch();
+    self.peer.send_rpc_request(method, params, timeout)
+  }
+
+  /// Fails with [PluginError::Timeout] if the plugin doesn't respond within `timeout`.
   pub async fn async_request<P: ResponseParser>(
     &self,
     method: &str,
     params: &JsonValue,
+    timeout: Option<Duration>,
   ) -> Result<P::ValueType, PluginError> {
-    let (tx, rx) = tokio::sync::oneshot::channel();
-    self.peer.async_send_rpc_request(
+    self.touch();
+    let (_, request) =
+      self.send_async_request::<P>(method, params, timeout, RequestPriority::Interactive);
+    request.await
+  }
+
+  /// Like [Self::async_request], but waiting interactive requests are sent first, see
+  /// [RequestPriority]. Meant for work the user doesn't wait for, like indexing.
+  pub async fn background_request<P: ResponseParser>(
+    &self,
+    method: &str,
+    params: &JsonValue,
+    timeout: Option<Duration>,
+  ) -> Result<P::ValueType, PluginError> {
+    self.touch();
+    let (_, request) =
+      self.send_async_request::<P>(method, params, timeout, RequestPriority::Background);
+    request.await
+  }
+
+  /// Like [Self::async_request], but serializes `request` as the params and deserializes the
+  /// `data` field of the response into `Resp`, so the two types define the contract with the
+  /// plugin instead of hand-built JSON. A response that doesn't match `Resp` fails with
+  /// [RemoteError::ParseResponse]; use [serde::de::IgnoredAny] if the response doesn't matter.
+  pub async fn typed_request<Req, Resp>(
+    &self,
+    method: &str,
+    request: &Req,
+    timeout: Option<Duration>,
+  ) -> Result<Resp, PluginError>
+  where
+    Req: Serialize + ?Sized,
+    Resp: DeserializeOwned + Send + Sync + 'static,
+  {
+    let params = serde_json::to_value(request)
+      .map_err(|err| PluginError::Internal(anyhow!("failed to serialize {}: {}", method, err)))?;
+    self
+      .async_request::<TypedResponseParser<Resp>>(method, &params, timeout)
+      .await
+  }
+
+  /// Like [Self::typed_request], but sent with [RequestPriority::Background].
+  pub async fn typed_background_request<Req, Resp>(
+    &self,
+    method: &str,
+    request: &Req,
+    timeout: Option<Duration>,
+  ) -> Result<Resp, PluginError>
+  where
+    Req: Serialize + ?Sized,
+    Resp: DeserializeOwned + Send + Sync + 'static,
+  {
+    let params = serde_json::to_value(request)
+      .map_err(|err| PluginError::Internal(anyhow!("failed to serialize {}: {}", method, err)))?;
+    self
+      .background_request::<TypedResponseParser<Resp>>(method, &params, timeout)
+      .await
+  }
+
+  /// Like [Self::async_request], but also returns an [AbortHandle] to cancel the request from
+  /// elsewhere. The request is sent immediately, the future only waits for the response.
+  pub fn abortable_request<P: ResponseParser>(
+    &self,
+    method: &str,
+    params: &JsonValue,
+    timeout: Option<Duration>,
+  ) -> (
+    AbortHandle,
+    impl Future<Output = Result<P::ValueType, PluginError>>,
+  ) {
+    self.touch();
+    self.send_async_request::<P>(method, params, timeout, RequestPriority::Interactive)
+  }
+
+  /// Sends all `requests` as `(method, params)` in a single message if the plugin reports the
+  /// `batch` capability, which saves the per-message overhead of e.g. indexing hundreds of
+  /// chunks. Otherwise, or if a [RequestQueueConfig] limits the requests in flight, they're sent
+  /// one by one. The responses are in the same order as `requests`.
+  pub async fn batch_request(
+    &self,
+    requests: Vec<(String, JsonValue)>,
+    priority: RequestPriority,
+  ) -> Vec<Result<JsonValue, PluginError>> {
+    self.touch();
+    let (requests, receivers): (Vec<_>, Vec<_>) = requests
+      .into_iter()
+      .map(|(method, params)| {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let callback: Box<dyn OneShotCallback> = Box::new(move |result| {
+          let _ = tx.send(result);
+        });
+        ((method, params, callback), rx)
+      })
+      .unzip();
+    let mut guards = self
+      .peer
+      .async_send_rpc_batch(requests, priority)
+      .into_iter()
+      .map(|request_id| {
+        CancelOnDrop(Some(AbortHandle {
+          peer: self.peer.clone(),
+          request_id,
+        }))
+      })
+      .collect::<Vec<_>>();
+
+    let mut responses = Vec::with_capacity(receivers.len());
+    for (rx, guard) in receivers.into_iter().zip(guards.iter_mut()) {
+      let response = rx.await.map_err(|err| {
+        PluginError::Internal(anyhow!("error waiting for async response: {:?}", err))
+      });
+      guard.0 = None;
+      responses.push(response.and_then(|response| response));
+    }
+    responses
+  }
+
+  /// Sends the bytes of `reader` to the plugin, e.g. a file it can't open itself because it's
+  /// sandboxed or runs on another machine. Only plugins that report the [UPLOAD] capability
+  /// understand it.
+  ///
+  /// The bytes go out in `upload_chunk` requests with the `stream_id`, the `offset` and up to
+  /// [UPLOAD_CHUNK_SIZE] base64 encoded bytes of `data`, followed by an `upload_end` request with
+  /// the total `size`. Each chunk waits for the previous one to be answered, so neither side
+  /// holds the whole file in memory. If reading or sending fails, an `upload_abort` notification
+  /// tells the plugin to drop what it received. Later requests refer to the content by
+  /// `stream_id`. Returns the number of bytes sent.
+  pub async fn upload<R>(&self, stream_id: &str, mut reader: R) -> Result<u64, PluginError>
+  where
+    R: AsyncRead + Unpin,
+  {
+    let result = async {
+      let mut buf = vec![0; UPLOAD_CHUNK_SIZE];
+      let mut offset = 0u64;
+      loop {
+        let mut len = 0;
+        while len < buf.len() {
+          match reader.read(&mut buf[len..]).await? {
+            0 => break,
+            n => len += n,
+          }
+        }
+        if len == 0 {
+          break;
+        }
+        let params = json!({
+          "stream_id": stream_id,
+          "offset": offset,
+          "data": STANDARD.encode(&buf[..len]),
+        });
+        self
+          .background_request::<DefaultResponseParser>("upload_chunk", &params, None)
+          .await?;
+        offset += len as u64;
+      }
+      let params = json!({ "stream_id": stream_id, "size": offset });
+      self
+        .background_request::<DefaultResponseParser>("upload_end", &params, None)
+        .await?;
+      Ok(offset)
+    }
+    .await;
+    if result.is_err() {
+      self.notify("upload_abort", &json!({ "stream_id": stream_id }));
+    }
+    result
+  }
+
+  /// Sends a `ping` request. Unlike other requests, pings don't count as activity for
+  /// [Self::idle_duration].
+  pub(crate) async fn ping(&self, timeout: Option<Duration>) -> Result<(), PluginError> {
+    let (_, request) = self.send_async_request::<DefaultResponseParser>(
+      "ping",
+      &json!({}),
+      timeout,
+      RequestPriority::Interactive,
+    );
+    request.await.map(|_| ())
+  }
+
+  fn send_async_request<P: ResponseParser>(
+    &self,
+    method: &str,
+    params: &JsonValue,
+    timeout: Option<Duration>,
+    priority: RequestPriority,
+  ) -> (
+    AbortHandle,
+    impl Future<Output = Result<P::ValueType, PluginError>>,
+  ) {
+    let (tx, mut rx) = tokio::sync::oneshot::channel();
+    let request_id = self.peer.async_send_rpc_request(
       method,
       params,
+      priority,
       Box::new(move |result| {
         let _ = tx.send(result);
       }),
     );
-    let value = rx.await.map_err(|err| {
-      PluginError::Internal(anyhow!("error waiting for async response: {:?}", err))
-    })??;
-    let value = P::parse_json(value)?;
-    Ok(value)
+    let abort_handle = AbortHandle {
+      peer: self.peer.clone(),
+      request_id,
+    };
+    let mut guard = CancelOnDrop(Some(abort_handle.clone()));
+    let peer = self.peer.clone();
+    let name = self.to_string();
+    let method = method.to_string();
+    let request = async move {
+      let result = match timeout {
+        None => (&mut rx).await,
+        Some(timeout) => match tokio::time::timeout(timeout, &mut rx).await {
+          Ok(result) => result,
+          Err(_) => {
+            warn!(
+              "{} did not answer {} (request id: {}) within {:?}",
+              name, method, request_id, timeout
+            );
+            // Completes `rx` with the timeout error, unless the response arrived in the meantime
+            fail_request(peer, request_id, PluginError::Timeout);
+            rx.await
+          },
+        },
+      };
+      // Nothing to cancel anymore
+      guard.0 = None;
+      let value = result.map_err(|err| {
+        PluginError::Internal(anyhow!("error waiting for async response: {:?}", err))
+      })??;
+      let value = P::parse_json(value)?;
+      Ok(value)
+    };
+    (abort_handle, request)
   }
 
+  /// Ends the stream with [PluginError::Timeout] if it didn't finish within `timeout`. The timeout
+  /// requires a tokio runtime. Dropping the stream cancels the request.
   pub fn stream_request<P: ResponseParser>(
     &self,
     method: &str,
     params: &JsonValue,
-  ) -> Result<ReceiverStream<Result<P::ValueType, PluginError>>, PluginError> {
-    let (tx, stream) = tokio::sync::mpsc::channel(100);
-    let stream = ReceiverStream::new(stream);
+    timeout: Option<Duration>,
+  ) -> Result<RequestStream<Result<P::ValueType, PluginError>>, PluginError> {
+    self.send_stream_request::<P>(method, params, timeout, RequestPriority::Interactive, None)
+  }
+
+  /// Like [Self::stream_request], but waiting interactive requests are sent first, see
+  /// [RequestPriority].
+  pub fn background_stream_request<P: ResponseParser>(
+    &self,
+    method: &str,
+    params: &JsonValue,
+    timeout: Option<Duration>,
+  ) -> Result<RequestStream<Result<P::ValueType, PluginError>>, PluginError> {
+    self.send_stream_request::<P>(method, params, timeout, RequestPriority::Background, None)
+  }
+
+  /// Like [Self::stream_request], but `input` streams to the plugin while the response streams
+  /// back, e.g. audio going up while its transcription comes down. Only plugins that report the
+  /// [DUPLEX] capability understand it.
+  ///
+  /// The request id is the stream id: every item of `input` is sent as a `stream_input`
+  /// notification, `{"id": <request id>, "data": <item>}`, and the end of `input` as a
+  /// `stream_input_end` notification, `{"id": <request id>}`. Input is only sent once the request
+  /// was written, so a request that waits in the request queue doesn't receive input for a stream
+  /// the plugin doesn't know yet. Sending stops when the returned stream is dropped.
+  pub fn duplex_request<P, S>(
+    &self,
+    method: &str,
+    params: &JsonValue,
+    mut input: S,
+    timeout: Option<Duration>,
+  ) -> Result<RequestStream<Result<P::ValueType, PluginError>>, PluginError>
+  where
+    P: ResponseParser,
+    S: Stream<Item = JsonValue> + Send + Unpin + 'static,
+  {
+    if !self.supports(DUPLEX) {
+      return Err(PluginError::Internal(anyhow!(
+        "plugin {} doesn't support duplex requests",
+        self
+      )));
+    }
+    let (on_sent, sent) = tokio::sync::oneshot::channel();
+    let mut stream = self.send_stream_request::<P>(
+      method,
+      params,
+      timeout,
+      RequestPriority::Interactive,
+      Some(on_sent),
+    )?;
+    let peer = self.peer.clone();
+    let request_id = stream.abort_handle.request_id;
+    stream.input_task = Some(tokio::spawn(async move {
+      // The request failed before it was written
+      if sent.await.is_err() {
+        return;
+      }
+      while let Some(data) = input.next().await {
+        peer.send_rpc_notification("stream_input", &json!({ "id": request_id, "data": data }));
+      }
+      peer.send_rpc_notification("stream_input_end", &json!({ "id": request_id }));
+    }));
+    Ok(stream)
+  }
+
+  fn send_stream_request<P: ResponseParser>(
+    &self,
+    method: &str,
+    params: &JsonValue,
+    timeout: Option<Duration>,
+    priority: RequestPriority,
+    on_sent: Option<OnSent>,
+  ) -> Result<RequestStream<Result<P::ValueType, PluginError>>, PluginError> {
+    self.touch();
+    let window = self.supports(STREAM_WINDOW).then_some(STREAM_WINDOW_SIZE);
+    let (tx, stream) = stream_channel(window.is_some());
     let callback = CloneableCallback::new(move |result| match result {
-      Ok(json) => {
-        let result = P::parse_json(json).map_err(PluginError::from);
-        let _ = tx.blocking_send(result);
-      },
-      Err(err) => {
-        let _ = tx.blocking_send(Err(err));
-      },
+      Ok(json) => tx.send(P::parse_json(json).map_err(PluginError::from)),
+      Err(err) => tx.send(Err(err)),
     });
-    self.peer.stream_rpc_request(method, params, callback);
-    Ok(stream)
+    let request_id = self
+      .peer
+      .stream_rpc_request(method, params, window, priority, callback, on_sent);
+    if let Some(timeout) = timeout {
+      let peer = self.peer.clone();
+      tokio::spawn(async move {
+        tokio::time::sleep(timeout).await;
+        // Does nothing if the stream finished in time
+        fail_request(peer, request_id, PluginError::Timeout);
+      });
+    }
+    Ok(RequestStream {
+      inner: stream,
+      abort_handle: AbortHandle {
+        peer: self.peer.clone(),
+        request_id,
+      },
+      window,
+      unacked: 0,
+      finished: Default::default(),
+      input_task: None,
+    })
+  }
+
+  /// Sends a notification, which the plugin doesn't answer.
+  pub fn notify(&self, method: &str, params: &JsonValue) {
+    self.peer.send_rpc_notification(method, params);
   }
 
   pub fn shutdown(&self) {
-    match self.peer.send_rpc_request("shutdown", &json!({})) {
+    // A plugin that stopped answering pings would never respond to the shutdown request either
+    if self.running_state.borrow().is_unhealthy() {
+      info!("shutting down unhealthy plugin {}", self);
+      self.peer.send_rpc_notification("shutdown", &json!({}));
+      return;
+    }
+
+    match self.peer.send_rpc_request("shutdown", &json!({}), None) {
       Ok(_) => {
         info!("shutting down plugin {}", self);
       },
@@ -184,15 +764,218 @@ impl Plugin {
     }
   }
 
+  /// Terminates the plugin process and all processes it spawned immediately, and waits for the
+  /// plugin process to exit. Remote plugins are disconnected instead.
+  pub fn kill(&self) -> Result<(), PluginError> {
+    let mut process = match &self.process {
+      Some(process) => process.lock(),
+      None => {
+        if let Some(remote) = &self.remote {
+          remote.shutdown(Shutdown::Both)?;
+        }
+        return Ok(());
+      },
+    };
+    match &self.process_group {
+      Some(process_group) => process_group.kill()?,
+      None => process.kill()?,
+    }
+    process.wait()?;
+    Ok(())
+  }
+
+  /// Asks the processes spawned by the plugin to exit, after the plugin itself was shut down.
+  pub(crate) fn terminate_process_group(&self) {
+    if let Some(process_group) = &self.process_group {
+      if let Err(err) = process_group.terminate() {
+        warn!("failed to terminate process group of {}: {:?}", self, err);
+      }
+    }
+  }
+
+  /// Pauses the plugin process without unloading its model.
+  pub fn suspend(&self) -> Result<(), PluginError> {
+    self.signal_process(true)?;
+    let _ = self
+      .running_state
+      .send(RunningState::Suspended { plugin_id: self.id });
+    Ok(())
+  }
+
+  pub fn resume(&self) -> Result<(), PluginError> {
+    self.signal_process(false)?;
+    let _ = self
+      .running_state
+      .send(RunningState::Running { plugin_id: self.id });
+    Ok(())
+  }
+
+  #[cfg(unix)]
+  fn signal_process(&self, suspend: bool) -> Result<(), PluginError> {
+    let signal = if suspend {
+      libc::SIGSTOP
+    } else {
+      libc::SIGCONT
+    };
+    if let Some(process_group) = &self.process_group {
+      process_group.signal(signal)?;
+      return Ok(());
+    }
+
+    let pid = self.pid.ok_or_else(|| suspend_unsupported(self))?;
+    // Safety: kill has no memory safety requirements
+    if unsafe { libc::kill(pid as libc::pid_t, signal) } != 0 {
+      return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+  }
+
+  #[cfg(windows)]
+  fn signal_process(&self, suspend: bool) -> Result<(), PluginError> {
+    use std::os::windows::io::AsRawHandle;
+    use windows_sys::Win32::Foundation::HANDLE;
+
+    #[link(name = "ntdll")]
+    extern "system" {
+      fn NtSuspendProcess(process: HANDLE) -> i32;
+      fn NtResumeProcess(process: HANDLE) -> i32;
+    }
+
+    let process = self
+      .process
+      .as_ref()
+      .ok_or_else(|| suspend_unsupported(self))?
+      .lock()
+      .as_raw_handle() as HANDLE;
+    // Safety: the handle belongs to the child process, which is alive while the plugin exists
+    let status = unsafe {
+      if suspend {
+        NtSuspendProcess(process)
+      } else {
+        NtResumeProcess(process)
+      }
+    };
+    if status < 0 {
+      return Err(PluginError::Internal(anyhow!(
+        "failed to suspend or resume plugin {}: NTSTATUS {:#x}",
+        self,
+        status
+      )));
+    }
+    Ok(())
+  }
+
+  #[cfg(not(any(unix, windows)))]
+  fn signal_process(&self, _suspend: bool) -> Result<(), PluginError> {
+    Err(PluginError::Internal(anyhow!(
+      "suspending plugins is not supported on this platform"
+    )))
+  }
+
   pub fn subscribe_running_state(&self) -> WatchStream<RunningState> {
     WatchStream::new(self.running_state.subscribe())
   }
+
+  fn touch(&self) {
+    *self.last_active.lock() = Instant::now();
+  }
+
+  /// Time since the last request was sent to the plugin.
+  pub fn idle_duration(&self) -> Duration {
+    self.last_active.lock().elapsed()
+  }
+
+  /// Runs `callback` once, as soon as `after` elapsed and there is no RPC traffic with the plugin,
+  /// i.e. no requests in flight and no messages waiting to be handled. Meant for housekeeping like
+  /// vector store compaction or cache eviction that shouldn't compete with user requests.
+  ///
+  /// The callback runs on the thread that handles the plugin's messages, so it must not block.
+  /// Async work can be spawned on a [tokio::runtime::Handle] captured beforehand. If the plugin
+  /// stops before it became idle, the callback is dropped without running.
+  pub fn on_idle<F>(&self, after: Duration, callback: F)
+  where
+    F: FnOnce() + Send + 'static,
+  {
+    self.peer.schedule_idle(after, Box::new(callback));
+  }
+
+  /// Number of requests in flight, including streams that haven't finished.
+  pub fn pending_requests(&self) -> usize {
+    self.peer.pending_request_count()
+  }
+
+  pub(crate) fn stalled_requests(&self, threshold: Duration) -> Vec<StalledRequest> {
+    self.peer.stalled_requests(threshold)
+  }
+
+  pub(crate) fn fail_request(&self, request_id: usize, error: PluginError) {
+    fail_request(self.peer.clone(), request_id, error)
+  }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct PluginInfo {
   pub name: String,
   pub exec_path: PathBuf,
+  pub resource_limits: ResourceLimits,
+  pub priority: ProcessPriority,
+  /// Environment variables set for the plugin process, in addition to the inherited ones
+  pub env: HashMap<String, String>,
+  /// Command line arguments passed to the plugin binary
+  pub args: Vec<String>,
+  /// Restricts what the plugin process can access. `None` runs it unrestricted.
+  pub sandbox: Option<SandboxPolicy>,
+  /// How long [PluginManager::create_plugin](crate::manager::PluginManager::create_plugin) waits
+  /// for the plugin to answer its first ping. `None` returns as soon as the process is spawned.
+  pub startup_timeout: Option<Duration>,
+  /// Limits the number of requests sent to the plugin at the same time. `None` sends all
+  /// requests right away, regardless of their [RequestPriority].
+  pub request_queue: Option<RequestQueueConfig>,
+  /// Compresses large messages to plugins that support it. `None` sends every message as is.
+  pub compression: Option<CompressionConfig>,
+  /// How messages are exchanged with the plugin. The plugin must connect, or accept the connection
+  /// of a [PluginTransport::Tcp] plugin, within [Self::startup_timeout], or 10 seconds without one.
+  pub transport: PluginTransport,
+  /// Names of the plugins that must be running and initialized before this plugin is created,
+  /// see [PluginManager::start_plugins](crate::manager::PluginManager::start_plugins).
+  pub depends_on: Vec<String>,
+  /// Largest message accepted from the plugin. A larger response fails its request with
+  /// [PluginError::MessageTooLarge]. `None` uses
+  /// [DEFAULT_MAX_MESSAGE_SIZE](crate::core::parser::DEFAULT_MAX_MESSAGE_SIZE).
+  pub max_message_size: Option<usize>,
+  /// Records every message exchanged with the plugin next to this file, in a new file per launch,
+  /// see [RpcRecorder::create]. Meant for reproducing protocol bugs, as the recording contains the
+  /// content of all requests.
+  pub recording: Option<PathBuf>,
+}
+
+const INIT_SCHEMA_TIMEOUT: Duration = Duration::from_secs(5);
+/// The capability of plugins that accept content through [Plugin::upload]
+pub const UPLOAD: &str = "upload";
+/// Maximum number of bytes in one `upload_chunk` request, see [Plugin::upload]
+pub const UPLOAD_CHUNK_SIZE: usize = 256 * 1024;
+/// The capability of plugins that accept input while a request streams, see
+/// [Plugin::duplex_request]
+pub const DUPLEX: &str = "duplex";
+/// The capability of plugins that hold back stream messages until they're acknowledged
+const STREAM_WINDOW: &str = "stream_window";
+/// Number of stream messages a plugin may send ahead of the consumer, see [RequestStream]
+const STREAM_WINDOW_SIZE: usize = 32;
+
+/// Remote plugins have no local process to suspend.
+#[cfg(any(unix, windows))]
+fn suspend_unsupported(plugin: &Plugin) -> PluginError {
+  PluginError::Internal(anyhow!("no local process for remote plugin {}", plugin))
+}
+
+struct InitSchemaParser;
+impl ResponseParser for InitSchemaParser {
+  type ValueType = Option<JsonValue>;
+
+  fn parse_json(payload: JsonValue) -> Result<Self::ValueType, RemoteError> {
+    let schema = payload.get("data").cloned().unwrap_or(payload);
+    Ok((!schema.is_null()).then_some(schema))
+  }
 }
 
 pub(crate) async fn start_plugin_process(
@@ -211,25 +994,66 @@ pub(crate) async fn start_plugin_process(
       // #[cfg(target_os = "macos")]
       // handle_macos_security_check(&plugin_info);
 
-      let child = std::process::Command::new(&plugin_info.exec_path)
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .spawn();
+      let connection = match &plugin_info.transport {
+        PluginTransport::Tcp { addr, .. } => Connection::remote(addr, plugin_info.startup_timeout),
+        _ => Connection::spawn(&plugin_info),
+      };
 
-      match child {
-        Ok(mut child) => {
-          let child_stdin = child.stdin.take().unwrap();
-          let child_stdout = child.stdout.take().unwrap();
-          let mut looper = RpcLoop::new(child_stdin, running_state.clone());
+      match connection {
+        Ok(connection) => {
+          let Connection {
+            reader,
+            writer,
+            process,
+            process_group,
+            stderr_tail,
+            remote,
+            _limit_guard,
+          } = connection;
+          let config = state.config();
+          let writer = BufWriter::with_capacity(config.write_buffer_size, writer);
+          let mut looper = RpcLoop::new(writer, running_state.clone());
+          looper
+            .get_raw_peer()
+            .0
+            .set_flush_policy(config.flush_policy);
+          if let Some(max_message_size) = plugin_info.max_message_size {
+            looper.set_max_message_size(max_message_size);
+          }
+          if let Some(request_queue) = plugin_info.request_queue {
+            looper.get_raw_peer().0.set_request_queue(request_queue);
+          }
+          if let Some(metrics) = state.rpc_metrics(&plugin_info.name) {
+            looper.get_raw_peer().0.set_metrics(metrics);
+          }
+          if let Some(path) = &plugin_info.recording {
+            match RpcRecorder::create(path) {
+              Ok(recorder) => {
+                info!("[RPC] recording messages to {:?}", recorder.path());
+                looper.get_raw_peer().0.set_recorder(Arc::new(recorder))
+              },
+              Err(err) => warn!("[RPC] failed to create recording {:?}: {}", path, err),
+            }
+          }
           let _ = running_state.send(RunningState::Connecting);
 
           let peer: RpcPeer = Arc::new(looper.get_raw_peer());
           let name = plugin_info.name.clone();
           peer.send_rpc_notification("ping", &JsonValue::Array(Vec::new()));
 
+          let pid = process.as_ref().map(|process| process.lock().id());
           let plugin = Plugin {
             peer,
-            process: Arc::new(child),
+            pid,
+            process: process.clone(),
+            process_group: process_group.clone(),
+            remote,
+            started_at: Instant::now(),
+            handshake: Default::default(),
+            info: plugin_info.clone(),
+            init_params: Default::default(),
+            last_active: Arc::new(Mutex::new(Instant::now())),
+            pid_file: None,
             name,
             id,
             running_state: running_state.clone(),
@@ -247,10 +1071,30 @@ pub(crate) async fn start_plugin_process(
           let err = looper.mainloop(
             &plugin_info.name,
             &plugin_id,
-            || BufReader::new(child_stdout),
+            || BufReader::with_capacity(config.read_buffer_size, reader),
             &mut state,
           );
-          let _ = running_state.send(RunningState::Stopped { plugin_id });
+          let (report, clean_exit) = match process {
+            Some(process) => {
+              let report = crash_report::collect(&process, stderr_tail, &err);
+              let clean_exit = report.exit_code == Some(0);
+              (report, clean_exit)
+            },
+            None => (crash_report::disconnected(&err), err.is_ok()),
+          };
+          if !clean_exit {
+            warn!("[RPC] plugin {} exited, {}", plugin_info.name, report);
+          }
+          // Don't leave processes spawned by the plugin behind, e.g. when it crashed
+          if let Some(process_group) = process_group {
+            let _ = process_group.terminate();
+          }
+          // The final state of the process, either a clean exit or a crash
+          if clean_exit {
+            let _ = running_state.send(RunningState::Stopped { plugin_id });
+          } else {
+            let _ = running_state.send(RunningState::UnexpectedStop { plugin_id, report });
+          }
           state.plugin_exit(id, err);
         },
         Err(err) => {
@@ -269,6 +1113,315 @@ pub(crate) async fn start_plugin_process(
   Ok(())
 }
 
+/// How the host thread talks to a started plugin, and what it cleans up after the plugin exited.
+struct Connection {
+  reader: Reader,
+  writer: Writer,
+  /// `None` for remote plugins
+  process: Option<Arc<Mutex<Child>>>,
+  process_group: Option<Arc<ProcessGroup>>,
+  stderr_tail: Option<StderrTail>,
+  /// The connection to a [PluginTransport::Tcp] plugin
+  remote: Option<Arc<TcpStream>>,
+  _limit_guard: Option<ResourceLimitGuard>,
+}
+
+impl Connection {
+  /// Starts the plugin binary and connects to it over stdio or a local socket.
+  fn spawn(plugin_info: &PluginInfo) -> io::Result<Self> {
+    let listener = match plugin_info.transport {
+      PluginTransport::LocalSocket => Some(LocalListener::bind()?),
+      _ => None,
+    };
+    let resource_limits = plugin_info
+      .resource_limits
+      .clone()
+      .with_priority(plugin_info.priority);
+    let mut command = sandbox::command(
+      &plugin_info.exec_path,
+      plugin_info.sandbox.as_ref(),
+      listener.as_ref().map(|listener| listener.path()),
+    );
+    command
+      .args(&plugin_info.args)
+      .envs(&plugin_info.env)
+      .stdout(Stdio::piped())
+      .stderr(Stdio::piped());
+    match &listener {
+      None => command.stdin(Stdio::piped()),
+      Some(listener) => command
+        .env(SOCKET_ENV, listener.path())
+        .stdin(Stdio::null()),
+    };
+    ProcessGroup::configure(&mut command);
+    apply_before_spawn(&mut command, &plugin_info.name, &resource_limits);
+    let mut child = {
+      let _sandbox_guard = sandbox::apply(&mut command, plugin_info.sandbox.as_ref());
+      command.spawn()?
+    };
+
+    let limit_guard = apply_after_spawn(&child, &plugin_info.name, &resource_limits);
+    let process_group = match ProcessGroup::attach(&child) {
+      Ok(process_group) => Some(Arc::new(process_group)),
+      Err(err) => {
+        warn!(
+          "failed to create process group for {}: {:?}",
+          plugin_info.name, err
+        );
+        None
+      },
+    };
+    let stderr_tail = child
+      .stderr
+      .take()
+      .map(|stderr| StderrTail::spawn(&plugin_info.name, stderr));
+    let connection = match listener {
+      None => {
+        let reader: Reader = Box::new(child.stdout.take().unwrap());
+        let writer: Writer = Box::new(child.stdin.take().unwrap());
+        Ok((reader, writer))
+      },
+      Some(listener) => {
+        if let Some(stdout) = child.stdout.take() {
+          transport::log_stdout(&plugin_info.name, stdout);
+        }
+        listener.accept(&mut child, plugin_info.startup_timeout)
+      },
+    };
+    let (reader, writer) = match connection {
+      Ok(connection) => connection,
+      Err(err) => {
+        error!("plugin {} did not connect: {:?}", plugin_info.name, err);
+        match &process_group {
+          Some(process_group) => drop(process_group.kill()),
+          None => drop(child.kill()),
+        }
+        let _ = child.wait();
+        return Err(err);
+      },
+    };
+
+    Ok(Self {
+      reader,
+      writer,
+      process: Some(Arc::new(Mutex::new(child))),
+      process_group,
+      stderr_tail,
+      remote: None,
+      _limit_guard: Some(limit_guard),
+    })
+  }
+
+  /// Connects to a plugin that runs on another machine.
+  fn remote(addr: &str, timeout: Option<Duration>) -> io::Result<Self> {
+    let stream = transport::connect_tcp(addr, timeout)?;
+    info!("[RPC] connected to remote plugin at {}", addr);
+    Ok(Self {
+      reader: Box::new(stream.try_clone()?),
+      writer: Box::new(stream.try_clone()?),
+      process: None,
+      process_group: None,
+      stderr_tail: None,
+      remote: Some(Arc::new(stream)),
+      _limit_guard: None,
+    })
+  }
+}
+
+/// Cancels a request sent with [Plugin::abortable_request] or [Plugin::stream_request].
+///
+/// The request completes with [PluginError::Cancelled]. If it was sent to the plugin already, the
+/// plugin receives a `cancel` notification with the id of the request, `{"id": <request id>}`,
+/// so it can stop working on it. Plugins that don't handle `cancel` just finish the request and
+/// the response is ignored.
+#[derive(Clone)]
+pub struct AbortHandle {
+  peer: RpcPeer,
+  request_id: usize,
+}
+
+impl AbortHandle {
+  /// Does nothing if the request finished already.
+  pub fn abort(&self) {
+    let peer = self.peer.clone();
+    let request_id = self.request_id;
+    off_runtime(move || peer.cancel_request(request_id));
+  }
+}
+
+impl Debug for AbortHandle {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("AbortHandle")
+      .field("request_id", &self.request_id)
+      .finish()
+  }
+}
+
+/// Cancels the request when a request future is dropped before it completed.
+struct CancelOnDrop(Option<AbortHandle>);
+
+impl Drop for CancelOnDrop {
+  fn drop(&mut self) {
+    if let Some(handle) = self.0.take() {
+      handle.abort();
+    }
+  }
+}
+
+/// The stream of a [Plugin::stream_request]. Dropping it before the plugin finished the stream
+/// cancels the request, see [AbortHandle].
+///
+/// Plugins that report the `stream_window` capability receive a top level `"stream_window": <n>`
+/// field in the stream request and may send at most `n` messages ahead of the consumer. The stream
+/// acknowledges consumed messages with a `stream_ack` notification,
+/// `{"id": <request id>, "count": <messages consumed since the last ack>}`. Their messages are
+/// buffered per stream, so a slow consumer doesn't hold up other requests. Other plugins are
+/// throttled by a bounded buffer, which blocks reading from the plugin while it's full.
+pub struct RequestStream<T> {
+  inner: StreamReceiver<T>,
+  abort_handle: AbortHandle,
+  window: Option<usize>,
+  unacked: usize,
+  /// Set once the stream was read to its end
+  finished: Arc<AtomicBool>,
+  /// Forwards the input of a [Plugin::duplex_request]
+  input_task: Option<JoinHandle<()>>,
+}
+
+impl<T> RequestStream<T> {
+  pub fn abort_handle(&self) -> AbortHandle {
+    self.abort_handle.clone()
+  }
+
+  /// Becomes true once the stream was read to its end, e.g. to skip stopping work the plugin
+  /// already finished after the stream was handed off.
+  pub fn finished_flag(&self) -> Arc<AtomicBool> {
+    self.finished.clone()
+  }
+}
+
+impl<T> Stream for RequestStream<T> {
+  type Item = T;
+
+  fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+    let poll = Pin::new(&mut self.inner).poll_next(cx);
+    match &poll {
+      Poll::Ready(Some(_)) => {
+        if let Some(window) = self.window {
+          self.unacked += 1;
+          // Ack in batches, but early enough that the plugin doesn't run dry
+          if self.unacked >= (window / 2).max(1) {
+            let params = json!({ "id": self.abort_handle.request_id, "count": self.unacked });
+            let peer = self.abort_handle.peer.clone();
+            // Writing to the plugin may block
+            off_runtime(move || peer.send_rpc_notification("stream_ack", &params));
+            self.unacked = 0;
+          }
+        }
+      },
+      Poll::Ready(None) => self.finished.store(true, Ordering::Release),
+      Poll::Pending => {},
+    }
+    poll
+  }
+
+  fn size_hint(&self) -> (usize, Option<usize>) {
+    self.inner.size_hint()
+  }
+}
+
+impl<T> Drop for RequestStream<T> {
+  fn drop(&mut self) {
+    if let Some(input_task) = self.input_task.take() {
+      input_task.abort();
+    }
+    if !self.finished.load(Ordering::Acquire) {
+      self.abort_handle.abort();
+    }
+  }
+}
+
+/// Number of messages buffered for a [RequestStream] of a plugin without a stream window
+const STREAM_BUFFER: usize = 100;
+
+fn stream_channel<T>(windowed: bool) -> (StreamSender<T>, StreamReceiver<T>) {
+  if windowed {
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    (
+      StreamSender::Unbounded(tx),
+      StreamReceiver::Unbounded(UnboundedReceiverStream::new(rx)),
+    )
+  } else {
+    let (tx, rx) = tokio::sync::mpsc::channel(STREAM_BUFFER);
+    (
+      StreamSender::Bounded(tx),
+      StreamReceiver::Bounded(ReceiverStream::new(rx)),
+    )
+  }
+}
+
+/// Sends the messages of a [RequestStream], from the reader thread.
+enum StreamSender<T> {
+  /// The plugin is held back by the stream window, so the reader never waits for the consumer
+  Unbounded(tokio::sync::mpsc::UnboundedSender<T>),
+  /// Blocks the reader while the consumer is behind
+  Bounded(tokio::sync::mpsc::Sender<T>),
+}
+
+impl<T> StreamSender<T> {
+  fn send(&self, value: T) {
+    match self {
+      StreamSender::Unbounded(tx) => {
+        let _ = tx.send(value);
+      },
+      StreamSender::Bounded(tx) => {
+        // Only waits when the buffer is full, which never happens for the error of a request
+        // that failed before it was sent
+        if let Err(TrySendError::Full(value)) = tx.try_send(value) {
+          let _ = tx.blocking_send(value);
+        }
+      },
+    }
+  }
+}
+
+enum StreamReceiver<T> {
+  Unbounded(UnboundedReceiverStream<T>),
+  Bounded(ReceiverStream<T>),
+}
+
+impl<T> Stream for StreamReceiver<T> {
+  type Item = T;
+
+  fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+    match self.get_mut() {
+      StreamReceiver::Unbounded(rx) => Pin::new(rx).poll_next(cx),
+      StreamReceiver::Bounded(rx) => Pin::new(rx).poll_next(cx),
+    }
+  }
+
+  fn size_hint(&self) -> (usize, Option<usize>) {
+    match self {
+      StreamReceiver::Unbounded(rx) => rx.size_hint(),
+      StreamReceiver::Bounded(rx) => rx.size_hint(),
+    }
+  }
+}
+
+fn fail_request(peer: RpcPeer, request_id: usize, error: PluginError) {
+  off_runtime(move || peer.fail_request(request_id, error));
+}
+
+/// Stream callbacks block while their buffer is full, which isn't allowed on the async runtime.
+fn off_runtime(f: impl FnOnce() + Send + 'static) {
+  match tokio::runtime::Handle::try_current() {
+    Ok(handle) => {
+      handle.spawn_blocking(f);
+    },
+    Err(_) => f(),
+  }
+}
+
 #[allow(dead_code)]
 #[cfg(unix)]
 async fn ensure_executable(exec_path: &std::path::Path) -> Result<(), anyhow::Error> {
@@ -0,0 +1,155 @@
+use std::io;
+use std::process::{Child, Command};
+
+/// The plugin process together with all processes it spawns, e.g. the workers of a model
+/// runtime. Stopping the group makes sure no grandchildren outlive the plugin.
+///
+/// * Unix: the plugin is the leader of its own process group.
+/// * Windows: the plugin is assigned to a job object that terminates its processes when closed.
+///   Processes the plugin spawns before it's assigned to the job aren't part of the group.
+pub(crate) struct ProcessGroup {
+  #[cfg(unix)]
+  pgid: libc::pid_t,
+  #[cfg(windows)]
+  job: windows_sys::Win32::Foundation::HANDLE,
+}
+
+impl ProcessGroup {
+  /// Makes the process started by `command` the leader of a new process group.
+  pub(crate) fn configure(command: &mut Command) {
+    #[cfg(unix)]
+    {
+      use std::os::unix::process::CommandExt;
+      command.process_group(0);
+    }
+
+    #[cfg(not(unix))]
+    let _ = command;
+  }
+
+  pub(crate) fn attach(child: &Child) -> io::Result<Self> {
+    #[cfg(unix)]
+    {
+      Ok(Self {
+        pgid: child.id() as libc::pid_t,
+      })
+    }
+
+    #[cfg(windows)]
+    {
+      windows::create_kill_on_close_job(child).map(|job| Self { job })
+    }
+
+    #[cfg(not(any(unix, windows)))]
+    {
+      let _ = child;
+      Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "process groups are not supported on this platform",
+      ))
+    }
+  }
+
+  /// Asks all processes of the group to exit. On Windows, they're terminated right away.
+  pub(crate) fn terminate(&self) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+      self.signal(libc::SIGTERM)
+    }
+
+    #[cfg(not(unix))]
+    {
+      self.kill()
+    }
+  }
+
+  /// Terminates all processes of the group immediately.
+  pub(crate) fn kill(&self) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+      self.signal(libc::SIGKILL)
+    }
+
+    #[cfg(windows)]
+    {
+      windows::terminate_job(self.job)
+    }
+
+    #[cfg(not(any(unix, windows)))]
+    {
+      Ok(())
+    }
+  }
+
+  #[cfg(unix)]
+  pub(crate) fn signal(&self, signal: libc::c_int) -> io::Result<()> {
+    // Safety: kill has no memory safety requirements. A negative pid addresses the whole group.
+    if unsafe { libc::kill(-self.pgid, signal) } != 0 {
+      let err = io::Error::last_os_error();
+      // The group is already gone
+      if err.raw_os_error() == Some(libc::ESRCH) {
+        return Ok(());
+      }
+      return Err(err);
+    }
+    Ok(())
+  }
+}
+
+#[cfg(windows)]
+impl Drop for ProcessGroup {
+  fn drop(&mut self) {
+    // Closing the last handle terminates all processes that are still in the job
+    unsafe {
+      windows_sys::Win32::Foundation::CloseHandle(self.job);
+    }
+  }
+}
+
+#[cfg(windows)]
+mod windows {
+  use std::io;
+  use std::os::windows::io::AsRawHandle;
+  use std::process::Child;
+  use windows_sys::Win32::Foundation::{CloseHandle, HANDLE};
+  use windows_sys::Win32::System::JobObjects::{
+    AssignProcessToJobObject, CreateJobObjectW, JobObjectExtendedLimitInformation,
+    SetInformationJobObject, TerminateJobObject, JOBOBJECT_EXTENDED_LIMIT_INFORMATION,
+    JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE,
+  };
+
+  pub(super) fn create_kill_on_close_job(child: &Child) -> io::Result<HANDLE> {
+    // Safety: all pointers passed to the job object functions are valid for the duration of the
+    // calls, and the process handle belongs to the child.
+    unsafe {
+      let job = CreateJobObjectW(std::ptr::null(), std::ptr::null());
+      if job == 0 {
+        return Err(io::Error::last_os_error());
+      }
+
+      let mut info: JOBOBJECT_EXTENDED_LIMIT_INFORMATION = std::mem::zeroed();
+      info.BasicLimitInformation.LimitFlags = JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE;
+      if SetInformationJobObject(
+        job,
+        JobObjectExtendedLimitInformation,
+        &info as *const _ as *const std::ffi::c_void,
+        std::mem::size_of::<JOBOBJECT_EXTENDED_LIMIT_INFORMATION>() as u32,
+      ) == 0
+        || AssignProcessToJobObject(job, child.as_raw_handle() as HANDLE) == 0
+      {
+        let err = io::Error::last_os_error();
+        CloseHandle(job);
+        return Err(err);
+      }
+      Ok(job)
+    }
+  }
+
+  pub(super) fn terminate_job(job: HANDLE) -> io::Result<()> {
+    // Safety: the job handle stays open until the ProcessGroup is dropped
+    if unsafe { TerminateJobObject(job, 1) } == 0 {
+      return Err(io::Error::last_os_error());
+    }
+    Ok(())
+  }
+}
@@ -0,0 +1,294 @@
+use crate::core::batch;
+use crate::core::plugin::{PluginId, RunningState};
+use crate::core::rpc_loop::{Handler, RpcLoop};
+use crate::core::rpc_object::RpcObject;
+use crate::core::rpc_peer::CloneableCallback;
+use crate::error::{PluginError, ReadError};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value as JsonValue};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Cursor, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::watch;
+use tracing::warn;
+
+/// Whether a [RecordedMessage] was read from or written to the plugin
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+  Inbound,
+  Outbound,
+}
+
+/// One line of a recording, see [RpcRecorder]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordedMessage {
+  /// Milliseconds since the recording started
+  pub ts_ms: u64,
+  pub direction: Direction,
+  pub message: JsonValue,
+}
+
+/// Writes every message exchanged with a plugin to a file, to reproduce protocol bugs reported by
+/// users. Enabled with [PluginInfo::recording](crate::core::plugin::PluginInfo::recording).
+///
+/// The file holds one [RecordedMessage] per line. Messages are recorded as the JSON objects the
+/// host sends and receives, i.e. before compression and framing, and each line is written right
+/// away so a recording survives a crash. Feed a recording back into a handler with [replay].
+///
+/// The values of [REDACTED_KEYS] are never written, and the file is only readable by the current
+/// user, as the other messages still contain the content of the user's documents and chats.
+pub struct RpcRecorder {
+  started_at: Instant,
+  path: PathBuf,
+  file: Mutex<File>,
+}
+
+/// Keys whose values are replaced with `<redacted>` in a recording, at any depth of a message
+pub const REDACTED_KEYS: &[&str] = &["password", "api_key", "auth_token"];
+
+impl RpcRecorder {
+  /// Creates a new recording next to `path`, named after it and the current time, e.g.
+  /// `chat-1718000000000.jsonl` for `chat.jsonl`. Every launch of a plugin gets its own
+  /// recording, so a restart doesn't replace the recording of the session that failed.
+  pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+    let path = timestamped_path(path.as_ref());
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+      use std::os::unix::fs::OpenOptionsExt;
+      options.mode(0o600);
+    }
+    let file = options.open(&path)?;
+    Ok(Self {
+      started_at: Instant::now(),
+      path,
+      file: Mutex::new(file),
+    })
+  }
+
+  /// The file the messages are written to
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+
+  pub(crate) fn record(&self, direction: Direction, message: &JsonValue) {
+    let mut message = message.clone();
+    redact(&mut message);
+    let entry = RecordedMessage {
+      ts_ms: self.started_at.elapsed().as_millis() as u64,
+      direction,
+      message,
+    };
+    let result = serde_json::to_vec(&entry)
+      .map_err(io::Error::from)
+      .and_then(|mut line| {
+        line.push(b'\n');
+        self.file.lock().write_all(&line)
+      });
+    if let Err(err) = result {
+      warn!("[RPC] failed to record message: {}", err);
+    }
+  }
+}
+
+fn timestamped_path(path: &Path) -> PathBuf {
+  let millis = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|elapsed| elapsed.as_millis())
+    .unwrap_or_default();
+  let stem = path
+    .file_stem()
+    .map(|stem| stem.to_string_lossy().to_string())
+    .unwrap_or_else(|| "recording".to_string());
+  let file_name = match path.extension() {
+    Some(extension) => format!("{}-{}.{}", stem, millis, extension.to_string_lossy()),
+    None => format!("{}-{}", stem, millis),
+  };
+  path.with_file_name(file_name)
+}
+
+/// Replaces the values of [REDACTED_KEYS] in `message`
+fn redact(message: &mut JsonValue) {
+  match message {
+    JsonValue::Object(map) => {
+      for (key, value) in map.iter_mut() {
+        if REDACTED_KEYS.contains(&key.as_str()) {
+          *value = json!("<redacted>");
+        } else {
+          redact(value);
+        }
+      }
+    },
+    JsonValue::Array(values) => values.iter_mut().for_each(redact),
+    _ => {},
+  }
+}
+
+/// Reads the messages of a recording written by [RpcRecorder].
+pub fn read_recording<P: AsRef<Path>>(path: P) -> io::Result<Vec<RecordedMessage>> {
+  let reader = BufReader::new(File::open(path)?);
+  let mut messages = vec![];
+  for line in reader.lines() {
+    let line = line?;
+    if line.trim().is_empty() {
+      continue;
+    }
+    messages.push(serde_json::from_str(&line)?);
+  }
+  Ok(messages)
+}
+
+/// A response or stream message of a request the host sent in a replayed recording
+#[derive(Debug)]
+pub struct ReplayedResponse {
+  pub id: usize,
+  pub method: String,
+  pub result: Result<JsonValue, PluginError>,
+}
+
+/// Feeds the messages the plugin sent in a recording back into `handler`, in their original order
+/// but without the original delays. Requests and notifications go to the handler as usual, and
+/// whatever the handler sends is dropped. Responses are delivered to the requests the host sent
+/// in the recording, and returned in the order they were handled.
+///
+/// Returns once the whole recording was handled.
+pub fn replay<P, H>(path: P, handler: &mut H) -> Result<Vec<ReplayedResponse>, ReadError>
+where
+  P: AsRef<Path>,
+  H: Handler,
+{
+  let (running_state, _) = watch::channel(RunningState::Connecting);
+  let mut looper = RpcLoop::new(io::sink(), Arc::new(running_state));
+  let peer = looper.get_raw_peer();
+  let responses = Arc::new(Mutex::new(vec![]));
+
+  let mut input = vec![];
+  for recorded in read_recording(path)? {
+    for object in batch::split(RpcObject::from(recorded.message)) {
+      match recorded.direction {
+        Direction::Inbound => {
+          serde_json::to_writer(&mut input, &object.0)?;
+          input.push(b'\n');
+        },
+        Direction::Outbound => {
+          // Requests of the host, and not the responses of the host to requests of the plugin
+          if let (Some(id), Some(method)) = (object.get_id(), object.get_method()) {
+            let (id, method) = (id as usize, method.to_string());
+            let responses = responses.clone();
+            let callback_method = method.clone();
+            let callback = CloneableCallback::new(move |result| {
+              responses.lock().push(ReplayedResponse {
+                id,
+                method: callback_method.clone(),
+                result,
+              });
+            });
+            peer.expect_response(id, &method, callback);
+          }
+        },
+      }
+    }
+  }
+
+  looper.mainloop("replay", &PluginId::from(0), || Cursor::new(input), handler)?;
+  let responses = std::mem::take(&mut *responses.lock());
+  Ok(responses)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::core::plugin::RpcCtx;
+  use crate::core::rpc_peer::ResponsePayload;
+  use crate::error::RemoteError;
+
+  #[derive(Default)]
+  struct RecordingHandler {
+    requests: Vec<JsonValue>,
+  }
+
+  impl Handler for RecordingHandler {
+    type Request = JsonValue;
+
+    fn handle_request(
+      &mut self,
+      _ctx: &RpcCtx,
+      rpc: Self::Request,
+    ) -> Result<ResponsePayload, RemoteError> {
+      self.requests.push(rpc);
+      Ok(ResponsePayload::empty_json())
+    }
+  }
+
+  fn record_session(dir: &Path) -> PathBuf {
+    let recorder = RpcRecorder::create(dir.join("rpc.jsonl")).unwrap();
+    let params = json!({ "file": "a.pdf", "options": { "password": "secret" } });
+    recorder.record(
+      Direction::Outbound,
+      &json!({ "id": 0, "method": "index_file", "params": params }),
+    );
+    recorder.record(
+      Direction::Inbound,
+      &json!({ "id": 7, "method": "read_file", "params": {} }),
+    );
+    recorder.record(Direction::Outbound, &json!({ "id": 7, "result": {} }));
+    recorder.record(
+      Direction::Inbound,
+      &json!({ "id": 0, "result": { "stream": { "has_more": true, "data": "a" } } }),
+    );
+    recorder.record(
+      Direction::Inbound,
+      &json!({ "id": 0, "result": { "stream": { "has_more": false, "data": {} } } }),
+    );
+    recorder.path().to_path_buf()
+  }
+
+  #[test]
+  fn record_test() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = record_session(dir.path());
+    let file_name = path.file_name().unwrap().to_string_lossy().to_string();
+    assert!(file_name.starts_with("rpc-") && file_name.ends_with(".jsonl"));
+    #[cfg(unix)]
+    {
+      use std::os::unix::fs::PermissionsExt;
+      let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+      assert_eq!(mode & 0o777, 0o600);
+    }
+    assert!(!std::fs::read_to_string(&path).unwrap().contains("secret"));
+
+    let messages = read_recording(&path).unwrap();
+    assert_eq!(messages.len(), 5);
+    assert_eq!(messages[0].direction, Direction::Outbound);
+    assert_eq!(
+      messages[0].message.pointer("/params/options/password"),
+      Some(&json!("<redacted>"))
+    );
+
+    // A second launch doesn't replace the first recording
+    std::thread::sleep(std::time::Duration::from_millis(2));
+    let second = record_session(dir.path());
+    assert_ne!(second, path);
+    assert_eq!(read_recording(&path).unwrap().len(), 5);
+  }
+
+  #[test]
+  fn replay_test() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = record_session(dir.path());
+    let mut handler = RecordingHandler::default();
+
+    let responses = replay(&path, &mut handler).unwrap();
+    assert_eq!(handler.requests.len(), 1);
+    assert_eq!(handler.requests[0]["method"], "read_file");
+    assert_eq!(responses.len(), 1);
+    assert_eq!(responses[0].id, 0);
+    assert_eq!(responses[0].method, "index_file");
+    assert_eq!(responses[0].result.as_ref().unwrap(), &json!("a"));
+  }
+}
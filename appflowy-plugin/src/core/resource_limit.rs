@@ -0,0 +1,299 @@
+use std::process::{Child, Command};
+#[cfg(any(target_os = "linux", windows))]
+use tracing::warn;
+
+/// Limits applied to a plugin process when it's started.
+///
+/// * Linux: the memory limit is enforced by moving the process into its own cgroup (cgroup v2).
+/// * macOS: the memory limit is applied with `setrlimit(RLIMIT_AS)`, unless it's above the hard
+///   limit the app runs with, which is stricter already.
+/// * Windows: the process is assigned to a job object with a process memory limit.
+///
+/// Limits that can't be applied, e.g. because cgroups aren't delegated to the current user, are
+/// logged and ignored, so the plugin still starts.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct ResourceLimits {
+  /// Maximum memory the plugin process may use, in bytes
+  pub max_memory_bytes: Option<u64>,
+  /// Added to the niceness of the plugin process. Positive values lower its CPU priority. On
+  /// Windows, the value is mapped to the closest priority class.
+  pub nice: Option<i32>,
+}
+
+/// CPU priority of a plugin process relative to the app, e.g. [ProcessPriority::Low] for
+/// background indexing so the editor stays responsive.
+///
+/// * Unix: mapped to niceness 10 (low) or -5 (high). Raising the priority usually requires
+///   privileges and is ignored otherwise.
+/// * Windows: mapped to the idle or above normal priority class.
+///
+/// [ResourceLimits::nice] takes precedence if it's set.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum ProcessPriority {
+  Low,
+  #[default]
+  Normal,
+  High,
+}
+
+impl ProcessPriority {
+  fn nice(self) -> Option<i32> {
+    match self {
+      ProcessPriority::Low => Some(10),
+      ProcessPriority::Normal => None,
+      ProcessPriority::High => Some(-5),
+    }
+  }
+}
+
+impl ResourceLimits {
+  pub fn with_max_memory_bytes(mut self, max_memory_bytes: u64) -> Self {
+    self.max_memory_bytes = Some(max_memory_bytes);
+    self
+  }
+
+  pub fn with_nice(mut self, nice: i32) -> Self {
+    self.nice = Some(nice);
+    self
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.max_memory_bytes.is_none() && self.nice.is_none()
+  }
+
+  /// Returns the limits with the niceness of `priority`, unless a niceness is set already.
+  pub(crate) fn with_priority(mut self, priority: ProcessPriority) -> Self {
+    self.nice = self.nice.or(priority.nice());
+    self
+  }
+}
+
+/// Keeps the resources that enforce the limits of a running plugin. Dropped once the plugin
+/// exits.
+#[derive(Default)]
+pub(crate) struct ResourceLimitGuard {
+  #[cfg(target_os = "linux")]
+  cgroup_dir: Option<std::path::PathBuf>,
+}
+
+impl Drop for ResourceLimitGuard {
+  fn drop(&mut self) {
+    #[cfg(target_os = "linux")]
+    if let Some(cgroup_dir) = self.cgroup_dir.take() {
+      // Only succeeds once the process has exited, otherwise the cgroup is left behind
+      let _ = std::fs::remove_dir(cgroup_dir);
+    }
+  }
+}
+
+/// Applies the limits that must be set up in the child process before the plugin binary runs.
+pub(crate) fn apply_before_spawn(command: &mut Command, name: &str, limits: &ResourceLimits) {
+  #[cfg(unix)]
+  {
+    use std::os::unix::process::CommandExt;
+
+    let nice = limits.nice;
+    #[cfg(target_os = "macos")]
+    let max_memory_bytes = limits
+      .max_memory_bytes
+      .filter(|max_memory_bytes| macos::can_limit_memory(name, *max_memory_bytes));
+    if limits.is_empty() {
+      return;
+    }
+
+    // Safety: the closure runs between fork and exec and only calls async-signal-safe functions.
+    unsafe {
+      command.pre_exec(move || {
+        // -1 is also a valid niceness, so failures can't be told apart and are ignored
+        if let Some(nice) = nice {
+          libc::nice(nice);
+        }
+
+        // The child can't log, failures the parent didn't foresee are ignored like above
+        #[cfg(target_os = "macos")]
+        if let Some(max_memory_bytes) = max_memory_bytes {
+          let limit = libc::rlimit {
+            rlim_cur: max_memory_bytes as libc::rlim_t,
+            rlim_max: max_memory_bytes as libc::rlim_t,
+          };
+          libc::setrlimit(libc::RLIMIT_AS, &limit);
+        }
+        Ok(())
+      });
+    }
+  }
+
+  #[cfg(not(target_os = "macos"))]
+  let _ = (command, name, limits);
+}
+
+/// Applies the limits that are attached to an already running plugin process.
+pub(crate) fn apply_after_spawn(
+  child: &Child,
+  name: &str,
+  limits: &ResourceLimits,
+) -> ResourceLimitGuard {
+  #[cfg(target_os = "linux")]
+  {
+    let mut guard = ResourceLimitGuard::default();
+    if let Some(max_memory_bytes) = limits.max_memory_bytes {
+      match linux::move_to_cgroup(child.id(), name, max_memory_bytes) {
+        Ok(cgroup_dir) => guard.cgroup_dir = Some(cgroup_dir),
+        Err(err) => warn!(
+          "[RPC] failed to apply memory limit to plugin {}: {:?}",
+          name, err
+        ),
+      }
+    }
+    guard
+  }
+
+  #[cfg(windows)]
+  {
+    if let Err(err) = windows::apply_limits(child, limits) {
+      warn!(
+        "[RPC] failed to apply resource limits to plugin {}: {:?}",
+        name, err
+      );
+    }
+    ResourceLimitGuard::default()
+  }
+
+  #[cfg(not(any(target_os = "linux", windows)))]
+  {
+    let _ = (child, name, limits);
+    ResourceLimitGuard::default()
+  }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+  use std::io;
+  use std::path::{Path, PathBuf};
+
+  const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+  /// Creates a cgroup next to the cgroup of the current process, sets its memory limit and moves
+  /// the plugin process into it. A sibling is used because cgroup v2 doesn't allow enabling
+  /// controllers in a cgroup that contains processes.
+  pub(super) fn move_to_cgroup(pid: u32, name: &str, max_memory_bytes: u64) -> io::Result<PathBuf> {
+    let current = current_cgroup()?;
+    let parent = current
+      .parent()
+      .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "current cgroup has no parent"))?;
+    let cgroup_dir = parent.join(format!("appflowy-{}-{}", name, pid));
+    std::fs::create_dir_all(&cgroup_dir)?;
+
+    let result = std::fs::write(cgroup_dir.join("memory.max"), max_memory_bytes.to_string())
+      .and_then(|_| std::fs::write(cgroup_dir.join("cgroup.procs"), pid.to_string()));
+    if let Err(err) = result {
+      let _ = std::fs::remove_dir(&cgroup_dir);
+      return Err(err);
+    }
+    Ok(cgroup_dir)
+  }
+
+  fn current_cgroup() -> io::Result<PathBuf> {
+    let content = std::fs::read_to_string("/proc/self/cgroup")?;
+    let path = content
+      .lines()
+      .find_map(|line| line.strip_prefix("0::"))
+      .ok_or_else(|| io::Error::new(io::ErrorKind::Unsupported, "cgroup v2 is not available"))?;
+    Ok(Path::new(CGROUP_ROOT).join(path.trim_start_matches('/')))
+  }
+}
+
+#[cfg(target_os = "macos")]
+mod macos {
+  use tracing::warn;
+
+  /// Whether `setrlimit` would accept the memory limit in the plugin process, which can't log
+  /// why it was rejected. Raising the hard limit needs privileges.
+  pub(super) fn can_limit_memory(name: &str, max_memory_bytes: u64) -> bool {
+    let mut current = libc::rlimit {
+      rlim_cur: 0,
+      rlim_max: 0,
+    };
+    // Safety: current is valid for the duration of the call
+    if unsafe { libc::getrlimit(libc::RLIMIT_AS, &mut current) } != 0 {
+      warn!(
+        "[RPC] failed to read the memory limit for plugin {}: {:?}",
+        name,
+        std::io::Error::last_os_error()
+      );
+      return false;
+    }
+    if max_memory_bytes as libc::rlim_t > current.rlim_max {
+      warn!(
+        "[RPC] memory limit of plugin {} is above the hard limit {}, ignored",
+        name, current.rlim_max
+      );
+      return false;
+    }
+    true
+  }
+}
+
+#[cfg(windows)]
+mod windows {
+  use super::ResourceLimits;
+  use std::io;
+  use std::os::windows::io::AsRawHandle;
+  use std::process::Child;
+  use windows_sys::Win32::Foundation::{CloseHandle, HANDLE};
+  use windows_sys::Win32::System::JobObjects::{
+    AssignProcessToJobObject, CreateJobObjectW, JobObjectExtendedLimitInformation,
+    SetInformationJobObject, JOBOBJECT_EXTENDED_LIMIT_INFORMATION, JOB_OBJECT_LIMIT_PROCESS_MEMORY,
+  };
+  use windows_sys::Win32::System::Threading::{
+    SetPriorityClass, ABOVE_NORMAL_PRIORITY_CLASS, BELOW_NORMAL_PRIORITY_CLASS,
+    IDLE_PRIORITY_CLASS, NORMAL_PRIORITY_CLASS,
+  };
+
+  pub(super) fn apply_limits(child: &Child, limits: &ResourceLimits) -> io::Result<()> {
+    let process = child.as_raw_handle() as HANDLE;
+    if let Some(nice) = limits.nice {
+      let priority_class = match nice {
+        n if n >= 10 => IDLE_PRIORITY_CLASS,
+        n if n > 0 => BELOW_NORMAL_PRIORITY_CLASS,
+        0 => NORMAL_PRIORITY_CLASS,
+        _ => ABOVE_NORMAL_PRIORITY_CLASS,
+      };
+      // Safety: the handle belongs to the child process, which outlives this call.
+      if unsafe { SetPriorityClass(process, priority_class) } == 0 {
+        return Err(io::Error::last_os_error());
+      }
+    }
+
+    if let Some(max_memory_bytes) = limits.max_memory_bytes {
+      // Safety: all pointers passed to the job object functions are valid for the duration of the
+      // calls. The job object stays alive as long as the process is assigned to it, so the handle
+      // can be closed right away.
+      unsafe {
+        let job = CreateJobObjectW(std::ptr::null(), std::ptr::null());
+        if job == 0 {
+          return Err(io::Error::last_os_error());
+        }
+
+        let mut info: JOBOBJECT_EXTENDED_LIMIT_INFORMATION = std::mem::zeroed();
+        info.BasicLimitInformation.LimitFlags = JOB_OBJECT_LIMIT_PROCESS_MEMORY;
+        info.ProcessMemoryLimit = max_memory_bytes as usize;
+        let result = if SetInformationJobObject(
+          job,
+          JobObjectExtendedLimitInformation,
+          &info as *const _ as *const std::ffi::c_void,
+          std::mem::size_of::<JOBOBJECT_EXTENDED_LIMIT_INFORMATION>() as u32,
+        ) == 0
+          || AssignProcessToJobObject(job, process) == 0
+        {
+          Err(io::Error::last_os_error())
+        } else {
+          Ok(())
+        };
+        CloseHandle(job);
+        result?;
+      }
+    }
+    Ok(())
+  }
+}
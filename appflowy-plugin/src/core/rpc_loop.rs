@@ -1,7 +1,10 @@
+use crate::core::batch;
+use crate::core::compression;
 use crate::core::parser::{Call, MessageReader};
 use crate::core::plugin::{PluginId, RpcCtx, RunningStateSender};
+use crate::core::recording::Direction;
 use crate::core::rpc_object::RpcObject;
-use crate::core::rpc_peer::{RawPeer, ResponsePayload, RpcState};
+use crate::core::rpc_peer::{RawPeer, ResponsePayload, RpcState, TimerTask, KEEPALIVE};
 use crate::error::{PluginError, ReadError, RemoteError};
 use serde::de::DeserializeOwned;
 
@@ -9,7 +12,7 @@ use std::io::{BufRead, Write};
 use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
-use tracing::{error, trace};
+use tracing::{error, trace, warn};
 
 const MAX_IDLE_WAIT: Duration = Duration::from_millis(5);
 
@@ -67,6 +70,12 @@ impl<W: Write + Send> RpcLoop<W> {
     }
   }
 
+  /// Skips inbound messages larger than `max_size` bytes. Defaults to
+  /// [DEFAULT_MAX_MESSAGE_SIZE](crate::core::parser::DEFAULT_MAX_MESSAGE_SIZE).
+  pub fn set_max_message_size(&mut self, max_size: usize) {
+    self.reader.set_max_size(max_size);
+  }
+
   /// Gets a reference to the peer.
   pub fn get_raw_peer(&self) -> RawPeer<W> {
     self.peer.clone()
@@ -167,8 +176,25 @@ impl<W: Write + Send> RpcLoop<W> {
             trace!("read loop exit");
             break;
           }
-          let json = match self.reader.next(&mut stream) {
-            Ok(json) => json,
+          let max_size = self.reader.max_size();
+          let next = self
+            .reader
+            .next(&mut stream)
+            .and_then(|json| compression::decompress(json, max_size));
+          let messages = match next {
+            Ok(json) => {
+              self.peer.0.record(Direction::Inbound, &json.0);
+              batch::split(json)
+            },
+            Err(ReadError::MessageTooLarge { id, size }) => {
+              warn!("[RPC] skipping message of {} bytes, too large", size);
+              if let Some(request_id) = id {
+                self
+                  .peer
+                  .handle_response(request_id, Err(PluginError::MessageTooLarge(size)));
+              }
+              continue;
+            },
             Err(err) => {
               if self.peer.0.is_blocking() {
                 self.peer.unexpected_disconnect(plugin_id, &err);
@@ -178,22 +204,38 @@ impl<W: Write + Send> RpcLoop<W> {
             },
           };
           self.peer.notify_running(*plugin_id);
-          if json.is_response() {
-            let request_id = json.get_id().unwrap();
-            match json.into_response() {
-              Ok(resp) => {
-                let resp = resp.map_err(PluginError::from);
-                self.peer.handle_response(request_id, resp);
-              },
-              Err(msg) => {
-                error!("[RPC] failed to parse response: {}", msg);
-                self
-                  .peer
-                  .handle_response(request_id, Err(PluginError::InvalidResponse));
-              },
+          for json in messages {
+            if json.is_response() {
+              let request_id = json.get_id().unwrap();
+              // The plugin switches right after this response, so the next read must already use
+              // the new framing
+              if let Some(framing) = self.peer.negotiate_framing(request_id, &json) {
+                self.reader.set_framing(framing);
+              }
+              if let Err(err) = self.peer.negotiate_protocol(request_id, &json) {
+                self.peer.handle_response(request_id, Err(err));
+                continue;
+              }
+              match json.into_response() {
+                Ok(resp) => {
+                  let resp = resp.map_err(PluginError::from);
+                  self.peer.handle_response(request_id, resp);
+                },
+                Err(msg) => {
+                  error!("[RPC] failed to parse response: {}", msg);
+                  self
+                    .peer
+                    .handle_response(request_id, Err(PluginError::InvalidResponse));
+                },
+              }
+            } else if json.get_method() == Some(KEEPALIVE) {
+              match json.0.pointer("/params/id").and_then(|id| id.as_u64()) {
+                Some(request_id) => self.peer.keepalive(request_id),
+                None => warn!("[RPC] keepalive without request id: {:?}", json),
+              }
+            } else {
+              self.peer.put_rpc_object(Ok(json));
             }
-          } else {
-            self.peer.put_rpc_object(Ok(json));
           }
         }
       });
@@ -208,7 +250,7 @@ impl<W: Write + Send> RpcLoop<W> {
           peer: &peer,
           plugin_id,
         };
-        let read_result = next_read(&peer, &ctx);
+        let read_result = next_read(&peer, &ctx, handler);
         let json = match read_result {
           Ok(json) => json,
           Err(err) => {
@@ -229,8 +271,12 @@ impl<W: Write + Send> RpcLoop<W> {
             peer.respond(Err(err), id)
           },
           Err(err) => {
-            peer.unexpected_disconnect(plugin_id, &err);
-            return ReadError::UnknownRequest(err);
+            // A JSON object that is neither a request, a notification nor a log message
+            warn!("[RPC] skipping unknown message: {}", err);
+          },
+          Ok(Call::Notification(method, params)) => {
+            trace!("[RPC] received notification: {}", method);
+            peer.dispatch_notification(&method, params);
           },
           Ok(Call::Message(_msg)) => {
             #[cfg(feature = "verbose")]
@@ -251,9 +297,10 @@ impl<W: Write + Send> RpcLoop<W> {
 
 /// retrieves the next available read result from a peer, performing idle work if no result is
 /// immediately available.
-fn next_read<W>(peer: &RawPeer<W>, _ctx: &RpcCtx) -> Result<RpcObject, ReadError>
+fn next_read<W, H>(peer: &RawPeer<W>, ctx: &RpcCtx, handler: &mut H) -> Result<RpcObject, ReadError>
 where
   W: Write + Send,
+  H: Handler,
 {
   loop {
     // Continuously checks if there is a result available from the peer using
@@ -262,11 +309,20 @@ where
     }
 
     let time_to_next_timer = match peer.check_timers() {
-      Some(Ok(_token)) => continue,
+      Some(Ok(TimerTask::Token(token))) => {
+        handler.idle(ctx, token);
+        continue;
+      },
+      Some(Ok(TimerTask::Idle(callback))) => {
+        peer.run_idle(callback);
+        continue;
+      },
       Some(Err(duration)) => Some(duration),
       None => None,
     };
 
+    peer.flush_idle();
+
     // Ensures the function does not block indefinitely by setting a maximum wait time
     let idle_timeout = time_to_next_timer
       .unwrap_or(MAX_IDLE_WAIT)
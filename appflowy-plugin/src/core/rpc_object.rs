@@ -96,9 +96,15 @@ impl RpcObject {
         Ok(resp) => Ok(Call::Request(id, resp)),
         Err(err) => Ok(Call::InvalidRequest(id, err.into())),
       },
-      None => match self.0.get("message").and_then(|value| value.as_str()) {
-        None => Err(serde_json::Error::missing_field("message")),
-        Some(s) => Ok(Call::Message(s.to_string().into())),
+      None => {
+        if let Some(method) = self.get_method() {
+          let params = self.0.get("params").cloned().unwrap_or(Value::Null);
+          return Ok(Call::Notification(method.to_string(), params));
+        }
+        match self.0.get("message").and_then(|value| value.as_str()) {
+          None => Err(serde_json::Error::missing_field("message")),
+          Some(s) => Ok(Call::Message(s.to_string().into())),
+        }
       },
     }
   }
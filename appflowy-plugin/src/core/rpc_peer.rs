@@ -1,19 +1,27 @@
+use crate::core::batch;
+use crate::core::compression::{self, CompressionConfig};
+use crate::core::metrics::RpcMetrics;
+use crate::core::parser::Framing;
 use crate::core::plugin::{Peer, PluginId, RunningState, RunningStateSender};
+use crate::core::recording::{Direction, RpcRecorder};
 use crate::core::rpc_object::RpcObject;
 use crate::error::{PluginError, ReadError, RemoteError};
 use parking_lot::{Condvar, Mutex};
 use serde::{de, ser, Deserialize, Deserializer, Serialize, Serializer};
 use serde_json::{json, Value as JsonValue};
-use std::collections::{BTreeMap, BinaryHeap, VecDeque};
+use std::collections::{BTreeMap, BinaryHeap, HashMap, VecDeque};
 use std::fmt::{Debug, Display};
 use std::io::Write;
 
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
 use std::sync::{mpsc, Arc};
 use std::time::{Duration, Instant};
 use std::{cmp, io};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::Sender as NotificationSender;
+use tokio_stream::wrappers::ReceiverStream;
 use tokio_stream::Stream;
-use tracing::{error, trace, warn};
+use tracing::{debug_span, error, trace, warn, Span};
 
 pub struct PluginCommand<T> {
   pub plugin_id: PluginId,
@@ -49,12 +57,94 @@ impl<'de, T: Deserialize<'de>> Deserialize<'de> for PluginCommand<T> {
   }
 }
 
+/// The protocol revision spoken by the host. The host offers it with a top level
+/// `"protocol_version": {"min": ..., "max": ...}` field in the `initialize` request, and the
+/// plugin answers with the revision it picked in a top level `"protocol_version"` field of its
+/// response, or with the oldest revision it speaks if that's outside of the range. Plugins that
+/// don't answer speak revision 1.
+///
+/// Optional features are negotiated with capabilities instead. Only changes that every plugin
+/// has to follow raise the revision, and the host checks [Plugin::protocol_version] before using
+/// them.
+///
+/// [Plugin::protocol_version]: crate::core::plugin::Plugin::protocol_version
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// The oldest protocol revision the host still speaks. Plugins that pick a revision outside of
+/// `MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION` fail to initialize with
+/// [PluginError::IncompatibleProtocol].
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// The capability, and the method of the notification, of plugins that send keepalives. The host
+/// asks for them with a top level `"keepalive_interval_ms": <n>` field in the `initialize`
+/// request. While such a plugin works on a request without sending anything else, e.g. during the
+/// prefill of a huge prompt, it sends `{"method": "keepalive", "params": {"id": <request id>}}`
+/// every `n` milliseconds. Keepalives count as progress for
+/// [WatchdogConfig::keepalive_threshold](crate::manager::WatchdogConfig::keepalive_threshold).
+pub const KEEPALIVE: &str = "keepalive";
+
+/// How often plugins that support [KEEPALIVE] send keepalives for a busy request
+pub const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Number of notifications buffered per subscriber. Further notifications are dropped until the
+/// subscriber catches up.
+const NOTIFICATION_BUFFER: usize = 64;
+
+/// How long idle work waits for the plugin to become idle again once it found it busy
+const IDLE_RETRY: Duration = Duration::from_millis(100);
+
+/// Requests that are never held back by the request queue, see [RequestQueueConfig]
+pub const CONTROL_METHODS: &[&str] = &["ping", "initialize", "get_init_schema", "shutdown"];
+
+/// Methods sent through `handle` that cancel or stop work the plugin is doing. Like the
+/// [CONTROL_METHODS], they bypass the request queue, since they free the slots others wait for.
+pub const STOP_METHODS: &[&str] = &["stop_complete_text"];
+
+/// The method a request runs in the plugin: the `method` of the params for `handle` requests,
+/// which carry almost all the work, and the RPC method otherwise. Metrics and stalled requests are
+/// reported by this method, so e.g. `answer` and `index_file` aren't lumped together.
+fn handled_method<'a>(method: &'a str, params: &'a JsonValue) -> &'a str {
+  if method != "handle" {
+    return method;
+  }
+  params
+    .get("method")
+    .and_then(JsonValue::as_str)
+    .unwrap_or(method)
+}
+
+/// Whether a request bypasses the request queue
+fn is_control_request(method: &str, params: &JsonValue) -> bool {
+  if CONTROL_METHODS.contains(&method) {
+    return true;
+  }
+  method == "handle"
+    && params
+      .get("method")
+      .and_then(JsonValue::as_str)
+      .map_or(false, |method| STOP_METHODS.contains(&method))
+}
+
 pub struct RpcState<W: Write> {
   rx_queue: Mutex<VecDeque<Result<RpcObject, ReadError>>>,
   rx_cvar: Condvar,
   writer: Mutex<W>,
+  framing: Mutex<Framing>,
+  /// The id of the `initialize` request that offered [Framing::LengthPrefixed]
+  framing_offer: Mutex<Option<usize>>,
+  /// The id of the `initialize` request that offered the [PROTOCOL_VERSION] range
+  protocol_offer: Mutex<Option<usize>>,
+  protocol_version: AtomicU32,
+  /// Subscribers of the notifications pushed by the plugin, by method
+  notification_subscribers: Mutex<HashMap<String, Vec<NotificationSender<JsonValue>>>>,
+  compression: Mutex<Option<CompressionConfig>>,
+  batching: AtomicBool,
+  metrics: Mutex<Option<Arc<RpcMetrics>>>,
+  recorder: Mutex<Option<Arc<RpcRecorder>>>,
+  flush_policy: Mutex<FlushPolicy>,
   request_id_counter: AtomicUsize,
-  pending: Mutex<BTreeMap<usize, ResponseHandler>>,
+  pending: Mutex<BTreeMap<usize, PendingRequest>>,
+  request_queue: Mutex<Option<RequestQueue>>,
   timers: Mutex<BinaryHeap<Timer>>,
   needs_exit: AtomicBool,
   is_blocking: AtomicBool,
@@ -76,8 +166,19 @@ impl<W: Write> RpcState<W> {
       rx_queue: Mutex::new(VecDeque::new()),
       rx_cvar: Condvar::new(),
       writer: Mutex::new(writer),
+      framing: Mutex::new(Framing::default()),
+      framing_offer: Mutex::new(None),
+      protocol_offer: Mutex::new(None),
+      protocol_version: AtomicU32::new(MIN_PROTOCOL_VERSION),
+      notification_subscribers: Mutex::new(HashMap::new()),
+      compression: Mutex::new(None),
+      batching: AtomicBool::new(false),
+      metrics: Mutex::new(None),
+      recorder: Mutex::new(None),
+      flush_policy: Mutex::new(FlushPolicy::default()),
       request_id_counter: AtomicUsize::new(0),
       pending: Mutex::new(BTreeMap::new()),
+      request_queue: Mutex::new(None),
       timers: Mutex::new(BinaryHeap::new()),
       needs_exit: AtomicBool::new(false),
       is_blocking: Default::default(),
@@ -88,8 +189,170 @@ impl<W: Write> RpcState<W> {
   pub fn is_blocking(&self) -> bool {
     self.is_blocking.load(Ordering::Acquire)
   }
+
+  /// Limits the number of requests in flight, see [RequestQueueConfig].
+  pub fn set_request_queue(&self, config: RequestQueueConfig) {
+    *self.request_queue.lock() = Some(RequestQueue {
+      config,
+      in_flight: 0,
+      waiting: VecDeque::new(),
+    });
+  }
+
+  /// Records the latency of every completed request in `metrics`.
+  pub(crate) fn set_metrics(&self, metrics: Arc<RpcMetrics>) {
+    *self.metrics.lock() = Some(metrics);
+  }
+
+  /// Writes every message sent and received from now on to `recorder`.
+  pub fn set_recorder(&self, recorder: Arc<RpcRecorder>) {
+    *self.recorder.lock() = Some(recorder);
+  }
+
+  pub(crate) fn record(&self, direction: Direction, message: &JsonValue) {
+    if let Some(recorder) = self.recorder.lock().as_ref() {
+      recorder.record(direction, message);
+    }
+  }
+
+  /// Controls when buffered writes reach the plugin, see [FlushPolicy].
+  pub fn set_flush_policy(&self, flush_policy: FlushPolicy) {
+    *self.flush_policy.lock() = flush_policy;
+  }
+}
+
+/// When messages written to a buffered writer are flushed to the plugin. Only matters if the
+/// writer buffers, see [PluginManagerConfig::write_buffer_size].
+///
+/// [PluginManagerConfig::write_buffer_size]: crate::manager::PluginManagerConfig::write_buffer_size
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum FlushPolicy {
+  /// Flushes after every message, for the lowest latency
+  #[default]
+  EveryMessage,
+  /// Flushes when the buffer is full or the RPC loop runs out of messages to read, at most a few
+  /// milliseconds after the write. Saves system calls when many small messages are sent in a
+  /// burst, e.g. stream acks while tokens stream in.
+  Idle,
+}
+
+/// Limits the number of requests sent to a plugin at the same time, to protect plugins that
+/// process requests one by one from floods of RPCs. The [CONTROL_METHODS] bypass the limit, so
+/// health checks measure whether the plugin is responsive rather than how busy it is, and a
+/// plugin can always be initialized, stopped and shut down.
+///
+/// The host only schedules requests by [RequestPriority] while they wait in this queue, so
+/// without one, background requests are sent right away and only the plugin can put them behind
+/// interactive requests.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct RequestQueueConfig {
+  /// Maximum number of requests that haven't received their (final) response yet
+  pub max_in_flight: usize,
+  /// Maximum number of requests waiting for a free slot. Further requests fail with
+  /// [PluginError::Busy]. With 0, requests fail as soon as `max_in_flight` is reached.
+  pub max_queued: usize,
+  /// How many of the `max_queued` slots only interactive requests can take, so a flood of
+  /// background requests doesn't make interactive requests fail with [PluginError::Busy].
+  pub reserved_interactive: usize,
+  /// A background request that waited this long is sent before waiting interactive requests, so
+  /// constant interactive traffic doesn't starve background requests. `None` lets interactive
+  /// requests always go first.
+  pub max_background_wait: Option<Duration>,
+}
+
+impl RequestQueueConfig {
+  pub fn new(max_in_flight: usize, max_queued: usize) -> Self {
+    Self {
+      max_in_flight,
+      max_queued,
+      reserved_interactive: 0,
+      max_background_wait: None,
+    }
+  }
+
+  pub fn with_reserved_interactive(mut self, reserved_interactive: usize) -> Self {
+    self.reserved_interactive = reserved_interactive;
+    self
+  }
+
+  pub fn with_max_background_wait(mut self, max_background_wait: Duration) -> Self {
+    self.max_background_wait = Some(max_background_wait);
+    self
+  }
 }
 
+/// The lane of a request in the request queue. Waiting [RequestPriority::Interactive] requests
+/// are sent before waiting background requests, so e.g. chat answers aren't stuck behind
+/// indexing. Background requests carry a top level `"priority": "background"` field, so plugins
+/// that process requests concurrently can schedule them as well.
+///
+/// The host only reorders requests if the plugin has a [RequestQueueConfig], see
+/// [PluginInfo::request_queue](crate::core::plugin::PluginInfo::request_queue).
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RequestPriority {
+  #[default]
+  Interactive,
+  Background,
+}
+
+struct RequestQueue {
+  config: RequestQueueConfig,
+  in_flight: usize,
+  waiting: VecDeque<QueuedRequest>,
+}
+
+impl RequestQueue {
+  /// Whether a request of `priority` can wait for a free slot, see
+  /// [RequestQueueConfig::reserved_interactive].
+  fn has_room(&self, priority: RequestPriority) -> bool {
+    let max_queued = match priority {
+      RequestPriority::Interactive => self.config.max_queued,
+      RequestPriority::Background => self
+        .config
+        .max_queued
+        .saturating_sub(self.config.reserved_interactive),
+    };
+    self.waiting.len() < max_queued
+  }
+
+  /// The waiting request to send next. Interactive requests overtake the background requests
+  /// waiting before them, unless the oldest background request waited longer than
+  /// [RequestQueueConfig::max_background_wait].
+  fn next_index(&self) -> Option<usize> {
+    let background = self
+      .waiting
+      .iter()
+      .position(|request| request.priority == RequestPriority::Background);
+    if let (Some(index), Some(max_wait)) = (background, self.config.max_background_wait) {
+      if self.waiting[index].queued_at.elapsed() >= max_wait {
+        return Some(index);
+      }
+    }
+    self
+      .waiting
+      .iter()
+      .position(|request| request.priority == RequestPriority::Interactive)
+      .or_else(|| (!self.waiting.is_empty()).then_some(0))
+  }
+}
+
+struct QueuedRequest {
+  id: usize,
+  method: String,
+  params: JsonValue,
+  stream_window: Option<usize>,
+  priority: RequestPriority,
+  queued_at: Instant,
+  handler: ResponseHandler,
+  on_sent: Option<OnSent>,
+  span: Span,
+}
+
+/// Fired once a request was written to the plugin, see [Peer::stream_rpc_request]. Dropped
+/// without firing if the request fails before.
+pub type OnSent = tokio::sync::oneshot::Sender<()>;
+
 pub struct RawPeer<W: Write + 'static>(pub(crate) Arc<RpcState<W>>);
 
 impl<W: Write + Send + 'static> Peer for RawPeer<W> {
@@ -108,19 +371,109 @@ impl<W: Write + Send + 'static> Peer for RawPeer<W> {
     }
   }
 
-  fn stream_rpc_request(&self, method: &str, params: &JsonValue, f: CloneableCallback) {
-    self.send_rpc(method, params, ResponseHandler::StreamCallback(Arc::new(f)));
+  fn stream_rpc_request(
+    &self,
+    method: &str,
+    params: &JsonValue,
+    window: Option<usize>,
+    priority: RequestPriority,
+    f: CloneableCallback,
+    on_sent: Option<OnSent>,
+  ) -> usize {
+    let handler = ResponseHandler::StreamCallback(Arc::new(f));
+    self.send_rpc(method, params, window, priority, handler, on_sent)
   }
 
-  fn async_send_rpc_request(&self, method: &str, params: &JsonValue, f: Box<dyn OneShotCallback>) {
-    self.send_rpc(method, params, ResponseHandler::Callback(f));
+  fn async_send_rpc_request(
+    &self,
+    method: &str,
+    params: &JsonValue,
+    priority: RequestPriority,
+    f: Box<dyn OneShotCallback>,
+  ) -> usize {
+    let handler = ResponseHandler::Callback(f);
+    self.send_rpc(method, params, None, priority, handler, None)
+  }
+
+  fn async_send_rpc_batch(
+    &self,
+    requests: Vec<(String, JsonValue, Box<dyn OneShotCallback>)>,
+    priority: RequestPriority,
+  ) -> Vec<usize> {
+    // The request queue limits every request of the batch on its own
+    if !self.0.batching.load(Ordering::Acquire) || self.0.request_queue.lock().is_some() {
+      return requests
+        .into_iter()
+        .map(|(method, params, f)| {
+          let handler = ResponseHandler::Callback(f);
+          self.send_rpc(&method, &params, None, priority, handler, None)
+        })
+        .collect();
+    }
+
+    let mut ids = Vec::with_capacity(requests.len());
+    let mut messages = Vec::with_capacity(requests.len());
+    for (method, params, f) in requests {
+      let id = self.0.request_id_counter.fetch_add(1, Ordering::Relaxed);
+      let span = debug_span!("rpc_request", id, method = method.as_str());
+      let _enter = span.enter();
+      trace!("[RPC] batch method: {} params: {:?}", method, params);
+      self.insert_pending(
+        id,
+        handled_method(&method, &params),
+        ResponseHandler::Callback(f),
+        false,
+        span.clone(),
+      );
+      let mut message = json!({ "id": id, "method": method, "params": params });
+      if priority == RequestPriority::Background {
+        message["priority"] = json!(priority);
+      }
+      messages.push(message);
+      ids.push(id);
+    }
+    if let Err(err) = self.send(&batch::envelope(messages)) {
+      for id in &ids {
+        let error = io::Error::new(err.kind(), err.to_string());
+        self.complete_with_error(*id, PluginError::Io(error));
+      }
+    }
+    ids
   }
 
-  fn send_rpc_request(&self, method: &str, params: &JsonValue) -> Result<JsonValue, PluginError> {
+  fn send_rpc_request(
+    &self,
+    method: &str,
+    params: &JsonValue,
+    timeout: Option<Duration>,
+  ) -> Result<JsonValue, PluginError> {
     let (tx, rx) = mpsc::channel();
     self.0.is_blocking.store(true, Ordering::Release);
-    self.send_rpc(method, params, ResponseHandler::Chan(tx));
-    rx.recv().unwrap_or(Err(PluginError::PeerDisconnect))
+    let id = self.send_rpc(
+      method,
+      params,
+      None,
+      RequestPriority::Interactive,
+      ResponseHandler::Chan(tx),
+      None,
+    );
+    let timeout = match timeout {
+      Some(timeout) => timeout,
+      None => return rx.recv().unwrap_or(Err(PluginError::PeerDisconnect)),
+    };
+    match rx.recv_timeout(timeout) {
+      Ok(result) => result,
+      Err(mpsc::RecvTimeoutError::Timeout) => {
+        warn!(
+          "[RPC] method {} (request id: {}) timed out after {:?}",
+          method, id, timeout
+        );
+        self.fail_request(id, PluginError::Timeout);
+        // The response may have arrived in the meantime
+        rx.recv().unwrap_or(Err(PluginError::Timeout))
+      },
+      Err(mpsc::RecvTimeoutError::Disconnected) => Err(PluginError::PeerDisconnect),
+    }
   }
 
   fn request_is_pending(&self) -> bool {
@@ -128,15 +481,119 @@ impl<W: Write + Send + 'static> Peer for RawPeer<W> {
     !queue.is_empty()
   }
 
+  fn pending_request_count(&self) -> usize {
+    let waiting = self
+      .0
+      .request_queue
+      .lock()
+      .as_ref()
+      .map_or(0, |queue| queue.waiting.len());
+    self.0.pending.lock().len() + waiting
+  }
+
+  fn stalled_requests(&self, threshold: Duration) -> Vec<StalledRequest> {
+    let now = Instant::now();
+    self
+      .0
+      .pending
+      .lock()
+      .iter()
+      .filter_map(|(id, request)| {
+        let idle = now.saturating_duration_since(request.last_activity);
+        (idle >= threshold).then(|| StalledRequest {
+          id: *id,
+          method: request.method.clone(),
+          idle,
+        })
+      })
+      .collect()
+  }
+
+  fn fail_request(&self, request_id: usize, error: PluginError) {
+    self.complete_with_error(request_id, error);
+  }
+
+  fn enable_compression(&self, config: CompressionConfig) {
+    *self.0.compression.lock() = Some(config);
+  }
+
+  fn enable_batching(&self) {
+    self.0.batching.store(true, Ordering::Release);
+  }
+
+  fn protocol_version(&self) -> u32 {
+    self.0.protocol_version.load(Ordering::Acquire)
+  }
+
+  fn subscribe_notifications(&self, method: &str) -> ReceiverStream<JsonValue> {
+    let (tx, rx) = tokio::sync::mpsc::channel(NOTIFICATION_BUFFER);
+    self
+      .0
+      .notification_subscribers
+      .lock()
+      .entry(method.to_string())
+      .or_default()
+      .push(tx);
+    ReceiverStream::new(rx)
+  }
+
+  fn cancel_request(&self, request_id: usize) {
+    if self.complete_with_error(request_id, PluginError::Cancelled) == Some(true) {
+      trace!("[RPC] cancel request: {}", request_id);
+      self.send_rpc_notification("cancel", &json!({ "id": request_id }));
+    }
+  }
+
   fn schedule_timer(&self, after: Instant, token: usize) {
     self.0.timers.lock().push(Timer {
       fire_after: after,
-      token,
+      task: TimerTask::Token(token),
+    });
+  }
+
+  fn schedule_idle(&self, after: Duration, callback: IdleCallback) {
+    self.0.timers.lock().push(Timer {
+      fire_after: Instant::now() + after,
+      task: TimerTask::Idle(callback),
     });
   }
 }
 
 impl<W: Write> RawPeer<W> {
+  /// Completes a pending or queued request with `error`. Returns whether the request was sent to
+  /// the plugin already, or `None` if there is no such request (anymore).
+  fn complete_with_error(&self, request_id: usize, error: PluginError) -> Option<bool> {
+    let (request, queued) = {
+      // Lock the queue first, so the request can't move from the queue to the pending requests
+      // in between
+      let mut request_queue = self.0.request_queue.lock();
+      let queued = request_queue.as_mut().and_then(|queue| {
+        let index = queue
+          .waiting
+          .iter()
+          .position(|request| request.id == request_id)?;
+        queue.waiting.remove(index)
+      });
+      let request = self.0.pending.lock().remove(&request_id);
+      (request, queued)
+    };
+    if let Some(queued) = queued {
+      let _enter = queued.span.enter();
+      queued.handler.invoke(Err(error));
+      Some(false)
+    } else if let Some(request) = request {
+      let _enter = request.span.enter();
+      self.record_metrics(&request.method, request.sent_at, true);
+      request.handler.invoke(Err(error));
+      if request.counted {
+        self.release_slot();
+      }
+      Some(true)
+    } else {
+      None
+    }
+  }
+
   /// Sends a JSON value to the peer.
   ///
   /// # Arguments
@@ -149,11 +606,129 @@ impl<W: Write> RawPeer<W> {
   ///
   /// # Notes
   ///
-  /// This function serializes the JSON value, appends a newline, and writes it to the underlying writer.
+  /// This function serializes the JSON value, compresses it if it's large, see
+  /// [CompressionConfig], and writes it to the underlying writer with the current [Framing].
   fn send(&self, json: &JsonValue) -> Result<(), io::Error> {
-    let mut s = serde_json::to_string(json).unwrap();
-    s.push('\n');
-    self.0.writer.lock().write_all(s.as_bytes())
+    self.0.record(Direction::Outbound, json);
+    let mut payload = serde_json::to_vec(json)?;
+    let compression = *self.0.compression.lock();
+    if let Some(compressed) = compression
+      .map(|compression| compression.compress(&payload))
+      .transpose()?
+      .flatten()
+    {
+      trace!(
+        "[RPC] compressed message from {} to {} bytes",
+        payload.len(),
+        compressed.len()
+      );
+      payload = compressed;
+    }
+    let mut writer = self.0.writer.lock();
+    let message = self.0.framing.lock().encode(payload);
+    writer.write_all(&message)?;
+    if *self.0.flush_policy.lock() == FlushPolicy::EveryMessage {
+      writer.flush()?;
+    }
+    Ok(())
+  }
+
+  /// Flushes messages held back by [FlushPolicy::Idle].
+  pub(crate) fn flush_idle(&self) {
+    if *self.0.flush_policy.lock() != FlushPolicy::Idle {
+      return;
+    }
+    if let Err(err) = self.0.writer.lock().flush() {
+      error!("[RPC] failed to flush messages: {}", err);
+    }
+  }
+
+  /// Switches to the framing the plugin accepted in its response to `initialize`, see [Framing].
+  /// Returns the framing the reader must use for the following messages if it changed.
+  pub(crate) fn negotiate_framing(&self, request_id: u64, response: &RpcObject) -> Option<Framing> {
+    {
+      let mut offer = self.0.framing_offer.lock();
+      if *offer != Some(request_id as usize) {
+        return None;
+      }
+      *offer = None;
+    }
+    let accepted = response
+      .0
+      .get("framing")
+      .and_then(|framing| framing.as_str());
+    if accepted != Some(Framing::LENGTH_PREFIXED) {
+      trace!("[RPC] plugin does not support length-prefixed framing");
+      return None;
+    }
+    trace!("[RPC] switch to length-prefixed framing");
+    *self.0.framing.lock() = Framing::LengthPrefixed;
+    Some(Framing::LengthPrefixed)
+  }
+
+  /// Passes a notification pushed by the plugin to the subscribers of its method, see
+  /// [Peer::subscribe_notifications].
+  pub(crate) fn dispatch_notification(&self, method: &str, params: JsonValue) {
+    let mut subscribers = self.0.notification_subscribers.lock();
+    let senders = match subscribers.get_mut(method) {
+      Some(senders) => senders,
+      None => {
+        trace!("[RPC] no subscriber for notification: {}", method);
+        return;
+      },
+    };
+    senders.retain(|sender| match sender.try_send(params.clone()) {
+      Ok(()) => true,
+      Err(TrySendError::Full(_)) => {
+        warn!(
+          "[RPC] subscriber of {} is lagging, drop notification",
+          method
+        );
+        true
+      },
+      Err(TrySendError::Closed(_)) => false,
+    });
+    if senders.is_empty() {
+      subscribers.remove(method);
+    }
+  }
+
+  /// Checks the protocol revision the plugin picked in its response to `initialize`, see
+  /// [PROTOCOL_VERSION]. Older revisions are accepted as long as the host still speaks them.
+  pub(crate) fn negotiate_protocol(
+    &self,
+    request_id: u64,
+    response: &RpcObject,
+  ) -> Result<(), PluginError> {
+    {
+      let mut offer = self.0.protocol_offer.lock();
+      if *offer != Some(request_id as usize) {
+        return Ok(());
+      }
+      *offer = None;
+    }
+    let version = match response.0.get("protocol_version") {
+      None => MIN_PROTOCOL_VERSION,
+      Some(version) => version
+        .as_u64()
+        .and_then(|version| u32::try_from(version).ok())
+        .ok_or(PluginError::InvalidResponse)?,
+    };
+    if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+      error!(
+        "[RPC] plugin speaks protocol version {}, supported: {}..={}",
+        version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+      );
+      return Err(PluginError::IncompatibleProtocol(version));
+    }
+    if version < PROTOCOL_VERSION {
+      warn!(
+        "[RPC] plugin speaks protocol version {}, downgrade from {}",
+        version, PROTOCOL_VERSION
+      );
+    }
+    self.0.protocol_version.store(version, Ordering::Release);
+    Ok(())
   }
 
   /// Sends a response to a previous RPC request.
@@ -189,31 +764,196 @@ impl<W: Write> RawPeer<W> {
   ///
   /// * `method` - The name of the RPC method to be called.
   /// * `params` - The parameters for the RPC call.
+  /// * `stream_window` - The number of unacknowledged stream messages the plugin may send, see
+  ///   [Peer::stream_rpc_request].
+  /// * `priority` - The lane of the request in the request queue, see [RequestPriority].
   /// * `response_handler` - A `ResponseHandler` to handle the response.
+  /// * `on_sent` - Fired once the request was written, which may be later if it's queued, see
+  ///   [Peer::stream_rpc_request].
   ///
   /// # Notes
   ///
   /// This function generates a unique ID for the request, stores the response handler,
   /// and sends the RPC request. If sending fails, it immediately invokes the response handler with an error.
-  fn send_rpc(&self, method: &str, params: &JsonValue, response_handler: ResponseHandler) {
-    trace!("[RPC] call method: {} params: {:?}", method, params);
+  /// Returns the ID, which is assigned before the request is queued.
+  fn send_rpc(
+    &self,
+    method: &str,
+    params: &JsonValue,
+    stream_window: Option<usize>,
+    priority: RequestPriority,
+    response_handler: ResponseHandler,
+    on_sent: Option<OnSent>,
+  ) -> usize {
     let id = self.0.request_id_counter.fetch_add(1, Ordering::Relaxed);
-    {
-      let mut pending = self.0.pending.lock();
-      pending.insert(id, response_handler);
+    // Child of the caller's span. Entered again whenever the request is sent, answered or
+    // failed, so the logs of concurrent requests can be told apart by their id.
+    let span = debug_span!("rpc_request", id, method);
+    let _enter = span.enter();
+    let mut counted = false;
+    if !is_control_request(method, params) {
+      let mut request_queue = self.0.request_queue.lock();
+      if let Some(queue) = request_queue.as_mut() {
+        if queue.in_flight >= queue.config.max_in_flight {
+          if queue.has_room(priority) {
+            trace!("[RPC] queue method: {}", method);
+            queue.waiting.push_back(QueuedRequest {
+              id,
+              method: method.to_string(),
+              params: params.clone(),
+              stream_window,
+              priority,
+              queued_at: Instant::now(),
+              handler: response_handler,
+              on_sent,
+              span: span.clone(),
+            });
+          } else {
+            drop(request_queue);
+            warn!(
+              "[RPC] too many requests in flight, reject method: {}",
+              method
+            );
+            response_handler.invoke(Err(PluginError::Busy));
+          }
+          return id;
+        }
+        queue.in_flight += 1;
+        counted = true;
+      }
     }
+    let handled_method = handled_method(method, params);
+    self.insert_pending(id, handled_method, response_handler, counted, span.clone());
+    self.write_request(id, method, params, stream_window, priority, on_sent);
+    id
+  }
 
-    // Call the ResponseHandler if the send fails. Otherwise, the response will be
-    // called in handle_response.
-    if let Err(e) = self.send(&json!({
+  /// Registers a request that was sent by someone else, so its responses reach `callback`. Used
+  /// to replay a recording, see [replay](crate::core::recording::replay).
+  pub(crate) fn expect_response(&self, id: usize, method: &str, callback: CloneableCallback) {
+    let span = debug_span!("rpc_request", id, method);
+    let handler = ResponseHandler::StreamCallback(Arc::new(callback));
+    self.insert_pending(id, method, handler, false, span);
+  }
+
+  /// Registers a request that isn't held back by the request queue (anymore). `counted` requests
+  /// occupy a slot of the queue until they're completed. `method` is the method the plugin runs,
+  /// see [handled_method].
+  fn insert_pending(
+    &self,
+    id: usize,
+    method: &str,
+    response_handler: ResponseHandler,
+    counted: bool,
+    span: Span,
+  ) {
+    self.0.pending.lock().insert(
+      id,
+      PendingRequest {
+        handler: response_handler,
+        method: method.to_string(),
+        sent_at: Instant::now(),
+        last_activity: Instant::now(),
+        counted,
+        span,
+      },
+    );
+  }
+
+  /// Sends a request registered with [Self::insert_pending]. Called within the span of the
+  /// request.
+  fn write_request(
+    &self,
+    id: usize,
+    method: &str,
+    params: &JsonValue,
+    stream_window: Option<usize>,
+    priority: RequestPriority,
+    on_sent: Option<OnSent>,
+  ) {
+    trace!("[RPC] call method: {} params: {:?}", method, params);
+    let mut request = json!({
         "id": id,
         "method": method,
         "params": params,
-    })) {
-      let mut pending = self.0.pending.lock();
-      if let Some(rh) = pending.remove(&id) {
-        rh.invoke(Err(PluginError::Io(e)));
+    });
+    if method == "initialize" && *self.0.framing.lock() == Framing::JsonLines {
+      // Recorded before sending, the response may arrive before send returns
+      *self.0.framing_offer.lock() = Some(id);
+      request["framing"] = json!([Framing::LENGTH_PREFIXED]);
+    }
+    if method == "initialize" {
+      *self.0.protocol_offer.lock() = Some(id);
+      request["protocol_version"] = json!({ "min": MIN_PROTOCOL_VERSION, "max": PROTOCOL_VERSION });
+      request["compression"] = json!([compression::ZSTD]);
+      request["keepalive_interval_ms"] = json!(KEEPALIVE_INTERVAL.as_millis() as u64);
+    }
+    if let Some(stream_window) = stream_window {
+      request["stream_window"] = json!(stream_window);
+    }
+    if priority == RequestPriority::Background {
+      request["priority"] = json!(priority);
+    }
+
+    // Call the ResponseHandler if the send fails. Otherwise, the response will be
+    // called in handle_response.
+    match self.send(&request) {
+      Ok(()) => {
+        if let Some(on_sent) = on_sent {
+          let _ = on_sent.send(());
+        }
+      },
+      Err(e) => {
+        let request = self.0.pending.lock().remove(&id);
+        if let Some(request) = request {
+          request.handler.invoke(Err(PluginError::Io(e)));
+          if request.counted {
+            self.release_slot();
+          }
+        }
+      },
+    }
+  }
+
+  /// Called when a counted request completed. Sends the next waiting request, which takes over
+  /// the slot, or frees the slot.
+  fn release_slot(&self) {
+    let next = {
+      let mut request_queue = self.0.request_queue.lock();
+      let queue = match request_queue.as_mut() {
+        Some(queue) => queue,
+        None => return,
+      };
+      let next = queue
+        .next_index()
+        .and_then(|index| queue.waiting.remove(index));
+      match next {
+        Some(next) => {
+          let QueuedRequest {
+            id,
+            method,
+            params,
+            stream_window,
+            priority,
+            handler,
+            on_sent,
+            span,
+            ..
+          } = next;
+          // Register the request before releasing the queue, see [Peer::fail_request]
+          let handled_method = handled_method(&method, &params);
+          self.insert_pending(id, handled_method, handler, true, span.clone());
+          Some((id, method, params, stream_window, priority, on_sent, span))
+        },
+        None => {
+          queue.in_flight = queue.in_flight.saturating_sub(1);
+          None
+        },
       }
+    };
+    if let Some((id, method, params, stream_window, priority, on_sent, span)) = next {
+      let _enter = span.enter();
+      self.write_request(id, &method, &params, stream_window, priority, on_sent);
     }
   }
 
@@ -255,13 +995,22 @@ impl<W: Write> RawPeer<W> {
     resp: Result<ResponsePayload, PluginError>,
   ) {
     let request_id = request_id as usize;
-    let handler = {
+    let request = {
       let mut pending = self.0.pending.lock();
       pending.remove(&request_id)
     };
     let is_stream = resp.as_ref().map(|resp| resp.is_stream()).unwrap_or(false);
-    match handler {
-      Some(response_handler) => {
+    match request {
+      Some(PendingRequest {
+        handler: response_handler,
+        method,
+        sent_at,
+        counted,
+        span,
+        ..
+      }) => {
+        let _enter = span.enter();
+        let mut is_completed = true;
         if is_stream {
           let is_stream_end = resp
             .as_ref()
@@ -272,12 +1021,26 @@ impl<W: Write> RawPeer<W> {
             // receive the next stream message.
             if let Some(callback) = response_handler.get_stream_callback() {
               let mut pending = self.0.pending.lock();
-              pending.insert(request_id, ResponseHandler::StreamCallback(callback));
+              pending.insert(
+                request_id,
+                PendingRequest {
+                  handler: ResponseHandler::StreamCallback(callback),
+                  method: method.clone(),
+                  sent_at,
+                  last_activity: Instant::now(),
+                  counted,
+                  span: span.clone(),
+                },
+              );
+              is_completed = false;
             }
           } else {
             trace!("[RPC] {} stream end", request_id);
           }
         }
+        if is_completed {
+          self.record_metrics(&method, sent_at, resp.is_err());
+        }
         let json = resp.map(|resp| resp.into_json());
         match json {
           Ok(Some(json)) => {
@@ -292,11 +1055,20 @@ impl<W: Write> RawPeer<W> {
             response_handler.invoke(Err(err));
           },
         }
+        if counted && is_completed {
+          self.release_slot();
+        }
       },
       None => error!("[RPC] id {}'s handle not found", request_id),
     }
   }
 
+  fn record_metrics(&self, method: &str, sent_at: Instant, is_error: bool) {
+    if let Some(metrics) = self.0.metrics.lock().as_ref() {
+      metrics.record(method, sent_at.elapsed(), is_error);
+    }
+  }
+
   /// Get a message from the receive queue if available.
   pub(crate) fn try_get_rx(&self) -> Option<Result<RpcObject, ReadError>> {
     let mut queue = self.0.rx_queue.lock();
@@ -329,7 +1101,7 @@ impl<W: Write> RawPeer<W> {
   /// - `Some(Ok(usize))`: If the most imminent timer has expired, returns its token.
   /// - `Some(Err(Duration))`: If the most imminent timer has not yet expired, returns the time until it expires.
   /// - `None`: If no timers are registered.
-  pub(crate) fn check_timers(&self) -> Option<Result<usize, Duration>> {
+  pub(crate) fn check_timers(&self) -> Option<Result<TimerTask, Duration>> {
     let mut timers = self.0.timers.lock();
     match timers.peek() {
       None => return None,
@@ -340,22 +1112,62 @@ impl<W: Write> RawPeer<W> {
         }
       },
     }
-    Some(Ok(timers.pop().unwrap().token))
+    Some(Ok(timers.pop().unwrap().task))
+  }
+
+  /// Runs `callback` if no messages are waiting to be handled and no requests are in flight.
+  /// Otherwise, tries again after [IDLE_RETRY].
+  pub(crate) fn run_idle(&self, callback: IdleCallback) {
+    let is_busy = !self.0.rx_queue.lock().is_empty()
+      || !self.0.pending.lock().is_empty()
+      || self
+        .0
+        .request_queue
+        .lock()
+        .as_ref()
+        .map_or(false, |queue| !queue.waiting.is_empty());
+    if is_busy {
+      self.0.timers.lock().push(Timer {
+        fire_after: Instant::now() + IDLE_RETRY,
+        task: TimerTask::Idle(callback),
+      });
+      return;
+    }
+    callback();
   }
 
   /// send disconnect error to pending requests.
   pub(crate) fn unexpected_disconnect<E: Debug>(&self, plugin_id: &PluginId, error: &E) {
-    trace!("[RPC] disconnecting peer: {:?}", error);
-    let _ = self.0.running_state.send(RunningState::UnexpectedStop {
-      plugin_id: *plugin_id,
-    });
+    // The host thread reports RunningState::UnexpectedStop once it collected the crash report
+    trace!("[RPC] disconnecting peer {:?}: {:?}", plugin_id, error);
+
+    // Requests still waiting in the queue would never be sent
+    let waiting = self
+      .0
+      .request_queue
+      .lock()
+      .as_mut()
+      .map(|queue| {
+        queue.in_flight = 0;
+        std::mem::take(&mut queue.waiting)
+      })
+      .unwrap_or_default();
+    for request in waiting {
+      let _enter = request.span.enter();
+      request.handler.invoke(Err(PluginError::PeerDisconnect));
+    }
 
     let mut pending = self.0.pending.lock();
     let ids = pending.keys().cloned().collect::<Vec<_>>();
     for id in &ids {
-      let callback = pending.remove(id).unwrap();
-      callback.invoke(Err(PluginError::PeerDisconnect));
+      let request = pending.remove(id).unwrap();
+      let _enter = request.span.enter();
+      self.record_metrics(&request.method, request.sent_at, true);
+      request.handler.invoke(Err(PluginError::PeerDisconnect));
     }
+    drop(pending);
+    // Ends the notification streams
+    self.0.notification_subscribers.lock().clear();
     self.0.needs_exit.store(true, Ordering::Relaxed);
   }
 
@@ -368,9 +1180,17 @@ impl<W: Write> RawPeer<W> {
     self.0.needs_exit.store(false, Ordering::SeqCst);
   }
 
+  /// Records a [KEEPALIVE] of the plugin for `request_id` as progress of the request.
+  pub(crate) fn keepalive(&self, request_id: u64) {
+    match self.0.pending.lock().get_mut(&(request_id as usize)) {
+      Some(request) => request.last_activity = Instant::now(),
+      None => trace!("[RPC] keepalive for finished request {}", request_id),
+    }
+  }
+
   pub(crate) fn notify_running(&self, plugin_id: PluginId) {
-    // if current running state is not equal to Running, we need to notify the plugin to start running.
-    let is_running = matches!(*self.0.running_state.borrow(), RunningState::Running { .. });
+    // if current running state is not Running (or Queued), we need to notify the plugin to start running.
+    let is_running = self.0.running_state.borrow().is_ready();
     if !is_running {
       let _ = self
         .0
@@ -434,6 +1254,29 @@ pub trait ResponseStream: Stream<Item = Result<JsonValue, PluginError>> + Unpin
 
 impl<T> ResponseStream for T where T: Stream<Item = Result<JsonValue, PluginError>> + Unpin + Send {}
 
+/// A request that was sent to the peer and hasn't received its (final) response yet.
+struct PendingRequest {
+  handler: ResponseHandler,
+  /// See [handled_method]
+  method: String,
+  sent_at: Instant,
+  /// When the request was sent or its last stream message was received
+  last_activity: Instant,
+  /// Whether the request occupies a slot of the request queue
+  counted: bool,
+  /// The `rpc_request` span created when the request was sent
+  span: Span,
+}
+
+/// A request without any response or stream message for a while, see [Peer::stalled_requests].
+#[derive(Debug, Clone)]
+pub struct StalledRequest {
+  pub id: usize,
+  pub method: String,
+  /// Time since the request was sent or its last stream message was received
+  pub idle: Duration,
+}
+
 enum ResponseHandler {
   Chan(mpsc::Sender<Result<JsonValue, PluginError>>),
   Callback(Box<dyn OneShotCallback>),
@@ -498,12 +1341,29 @@ impl ResponseHandler {
     }
   }
 }
-#[derive(Debug, PartialEq, Eq)]
+/// Work scheduled with [Peer::schedule_idle]
+pub type IdleCallback = Box<dyn FnOnce() + Send>;
+
+/// What to do when a [Timer] fires.
+pub(crate) enum TimerTask {
+  /// Passed to [Handler::idle](crate::core::rpc_loop::Handler::idle)
+  Token(usize),
+  Idle(IdleCallback),
+}
+
 struct Timer {
   fire_after: Instant,
-  token: usize,
+  task: TimerTask,
+}
+
+impl PartialEq for Timer {
+  fn eq(&self, other: &Timer) -> bool {
+    self.fire_after == other.fire_after
+  }
 }
 
+impl Eq for Timer {}
+
 impl Ord for Timer {
   fn cmp(&self, other: &Timer) -> cmp::Ordering {
     other.fire_after.cmp(&self.fire_after)
@@ -515,3 +1375,89 @@ impl PartialOrd for Timer {
     Some(self.cmp(other))
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn is_control_request_test() {
+    assert!(is_control_request("shutdown", &json!({})));
+    assert!(is_control_request("initialize", &json!({})));
+    assert!(is_control_request(
+      "handle",
+      &json!({ "method": "stop_complete_text", "params": {} })
+    ));
+    assert!(!is_control_request(
+      "handle",
+      &json!({ "method": "complete_text", "params": {} })
+    ));
+    assert!(!is_control_request("index_file", &json!({})));
+  }
+
+  #[test]
+  fn handled_method_test() {
+    let params = json!({ "method": "answer", "params": {} });
+    assert_eq!(handled_method("handle", &params), "answer");
+    assert_eq!(handled_method("handle", &json!({})), "handle");
+    assert_eq!(handled_method("ping", &params), "ping");
+  }
+
+  fn queue_with(
+    config: RequestQueueConfig,
+    waiting: &[(RequestPriority, Duration)],
+  ) -> RequestQueue {
+    let waiting = waiting
+      .iter()
+      .enumerate()
+      .map(|(id, (priority, waited))| {
+        let (tx, _rx) = mpsc::channel();
+        QueuedRequest {
+          id,
+          method: "handle".to_string(),
+          params: json!({}),
+          stream_window: None,
+          priority: *priority,
+          queued_at: Instant::now() - *waited,
+          handler: ResponseHandler::Chan(tx),
+          on_sent: None,
+          span: Span::none(),
+        }
+      })
+      .collect();
+    RequestQueue {
+      config,
+      in_flight: config.max_in_flight,
+      waiting,
+    }
+  }
+
+  #[test]
+  fn reserved_interactive_slots_test() {
+    use RequestPriority::*;
+    let config = RequestQueueConfig::new(1, 3).with_reserved_interactive(1);
+    let queue = queue_with(config, &[(Background, Duration::ZERO)]);
+    assert!(queue.has_room(Background));
+    let queue = queue_with(config, &[(Background, Duration::ZERO); 2]);
+    assert!(!queue.has_room(Background));
+    assert!(queue.has_room(Interactive));
+    let queue = queue_with(config, &[(Background, Duration::ZERO); 3]);
+    assert!(!queue.has_room(Interactive));
+  }
+
+  #[test]
+  fn background_request_aging_test() {
+    use RequestPriority::*;
+    let waiting = [
+      (Background, Duration::from_secs(5)),
+      (Interactive, Duration::from_secs(1)),
+    ];
+    let queue = queue_with(RequestQueueConfig::new(1, 10), &waiting);
+    assert_eq!(queue.next_index(), Some(1));
+    let config = RequestQueueConfig::new(1, 10).with_max_background_wait(Duration::from_secs(10));
+    assert_eq!(queue_with(config, &waiting).next_index(), Some(1));
+    let config = RequestQueueConfig::new(1, 10).with_max_background_wait(Duration::from_secs(2));
+    assert_eq!(queue_with(config, &waiting).next_index(), Some(0));
+    assert_eq!(queue_with(config, &[]).next_index(), None);
+  }
+}
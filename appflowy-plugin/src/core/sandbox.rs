@@ -0,0 +1,402 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Restrictions for a plugin process, which handles untrusted user documents.
+///
+/// Reading files is always allowed, so the plugin can load its libraries and models, but writes
+/// are limited to the temp directory and [Self::writable_paths].
+///
+/// * Linux: writes are restricted with landlock (kernel 5.13+) and network access with a seccomp
+///   filter that rejects IPv4 and IPv6 sockets.
+/// * macOS: the plugin is started through `sandbox-exec`.
+/// * Windows: not supported, the plugin runs unrestricted.
+///
+/// Restrictions the system doesn't support, or a container doesn't allow, are logged and skipped,
+/// so the plugin still starts.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct SandboxPolicy {
+  pub allow_network: bool,
+  /// Directories the plugin may write to in addition to the temp directory, e.g. the directory
+  /// of its vector store
+  pub writable_paths: Vec<PathBuf>,
+}
+
+impl SandboxPolicy {
+  pub fn with_network(mut self, allow_network: bool) -> Self {
+    self.allow_network = allow_network;
+    self
+  }
+
+  pub fn with_writable_path<T: Into<PathBuf>>(mut self, path: T) -> Self {
+    self.writable_paths.push(path.into());
+    self
+  }
+
+  #[cfg(any(target_os = "linux", target_os = "macos"))]
+  fn writable_dirs(&self) -> Vec<PathBuf> {
+    let mut dirs = vec![std::env::temp_dir()];
+    dirs.extend(self.writable_paths.iter().cloned());
+    dirs
+  }
+}
+
+/// Keeps the resources needed to set up the sandbox alive until the plugin process is spawned.
+#[derive(Default)]
+pub(crate) struct SandboxGuard {
+  #[cfg(target_os = "linux")]
+  _ruleset: Option<std::os::fd::OwnedFd>,
+}
+
+/// Creates the command that starts the plugin binary, wrapped in `sandbox-exec` on macOS.
+/// `socket` is the Unix domain socket the plugin connects to, which stays reachable without
+/// network access.
+pub(crate) fn command(
+  exec_path: &Path,
+  policy: Option<&SandboxPolicy>,
+  socket: Option<&Path>,
+) -> Command {
+  #[cfg(target_os = "macos")]
+  if let Some(policy) = policy {
+    let mut command = Command::new("/usr/bin/sandbox-exec");
+    command
+      .arg("-p")
+      .arg(macos::profile(policy, socket))
+      .arg(exec_path);
+    return command;
+  }
+
+  #[cfg(not(target_os = "macos"))]
+  let _ = (policy, socket);
+  Command::new(exec_path)
+}
+
+/// Applies the parts of the sandbox that are set up in the child process before the plugin
+/// binary runs.
+pub(crate) fn apply(command: &mut Command, policy: Option<&SandboxPolicy>) -> SandboxGuard {
+  #[cfg(target_os = "linux")]
+  if let Some(policy) = policy {
+    return linux::apply(command, policy);
+  }
+
+  #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+  if policy.is_some() {
+    tracing::warn!("[RPC] sandboxing plugins is not supported on this platform");
+  }
+
+  let _ = (command, policy);
+  SandboxGuard::default()
+}
+
+#[cfg(target_os = "macos")]
+mod macos {
+  use super::SandboxPolicy;
+  use std::path::Path;
+
+  /// Builds a sandbox profile (SBPL) that allows everything except what the policy restricts.
+  pub(super) fn profile(policy: &SandboxPolicy, socket: Option<&Path>) -> String {
+    let mut profile = String::from("(version 1)\n(allow default)\n");
+    if !policy.allow_network {
+      profile.push_str("(deny network*)\n");
+      if let Some(socket) = socket {
+        profile.push_str(&format!(
+          "(allow network-outbound (remote unix-socket (path-literal {})))\n",
+          quote(&canonical(socket))
+        ));
+      }
+    }
+    profile.push_str("(deny file-write*)\n(allow file-write* (literal \"/dev/null\")");
+    for dir in policy.writable_dirs() {
+      profile.push_str(&format!(" (subpath {})", quote(&canonical(&dir))));
+    }
+    profile.push_str(")\n");
+    profile
+  }
+
+  /// The sandbox matches resolved paths, e.g. /private/var instead of /var.
+  fn canonical(path: &Path) -> String {
+    path
+      .canonicalize()
+      .unwrap_or_else(|_| path.to_path_buf())
+      .to_string_lossy()
+      .to_string()
+  }
+
+  fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+  }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+  use super::{SandboxGuard, SandboxPolicy};
+  use std::fs::OpenOptions;
+  use std::io;
+  use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+  use std::os::unix::fs::OpenOptionsExt;
+  use std::os::unix::process::CommandExt;
+  use std::process::Command;
+  use tracing::warn;
+
+  const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1;
+  const LANDLOCK_RULE_PATH_BENEATH: u32 = 1;
+
+  const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+  const ACCESS_FS_REMOVE_DIR: u64 = 1 << 4;
+  const ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+  const ACCESS_FS_MAKE_CHAR: u64 = 1 << 6;
+  const ACCESS_FS_MAKE_DIR: u64 = 1 << 7;
+  const ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+  const ACCESS_FS_MAKE_SOCK: u64 = 1 << 9;
+  const ACCESS_FS_MAKE_FIFO: u64 = 1 << 10;
+  const ACCESS_FS_MAKE_BLOCK: u64 = 1 << 11;
+  const ACCESS_FS_MAKE_SYM: u64 = 1 << 12;
+  /// Landlock ABI 2
+  const ACCESS_FS_REFER: u64 = 1 << 13;
+  /// Landlock ABI 3
+  const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+
+  #[repr(C)]
+  struct RulesetAttr {
+    handled_access_fs: u64,
+  }
+
+  #[repr(C, packed)]
+  struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+  }
+
+  pub(super) fn apply(command: &mut Command, policy: &SandboxPolicy) -> SandboxGuard {
+    // Both landlock and seccomp filters need it
+    // Safety: PR_GET_NO_NEW_PRIVS doesn't take pointers
+    if unsafe { libc::prctl(libc::PR_GET_NO_NEW_PRIVS, 0, 0, 0, 0) } < 0 {
+      warn!(
+        "[RPC] sandboxing plugins is not supported by this kernel: {:?}",
+        io::Error::last_os_error()
+      );
+      return SandboxGuard::default();
+    }
+    let ruleset = match write_ruleset(policy) {
+      Ok(ruleset) => Some(ruleset),
+      Err(err) => {
+        warn!("[RPC] failed to restrict plugin file writes: {:?}", err);
+        None
+      },
+    };
+    let ruleset_fd = ruleset.as_ref().map(|fd| fd.as_raw_fd());
+    let network_filter = if policy.allow_network {
+      None
+    } else {
+      let filter = seccomp::deny_ip_sockets();
+      if filter.is_none() {
+        warn!("[RPC] restricting plugin network access is not supported on this architecture");
+      }
+      filter.filter(|_| match seccomp::check_filter_support() {
+        Ok(_) => true,
+        Err(err) => {
+          warn!("[RPC] failed to restrict plugin network access: {:?}", err);
+          false
+        },
+      })
+    };
+
+    // The steps were checked in the parent, which can log and skip them. A failure here is
+    // unexpected and fails the spawn rather than running the plugin less restricted.
+    // Safety: the closure runs between fork and exec and only calls async-signal-safe functions.
+    unsafe {
+      command.pre_exec(move || {
+        if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+          return Err(io::Error::last_os_error());
+        }
+        if let Some(ruleset_fd) = ruleset_fd {
+          if libc::syscall(libc::SYS_landlock_restrict_self, ruleset_fd, 0) != 0 {
+            return Err(io::Error::last_os_error());
+          }
+        }
+        if let Some(filter) = network_filter.as_ref() {
+          let prog = libc::sock_fprog {
+            len: filter.len() as u16,
+            filter: filter.as_ptr() as *mut libc::sock_filter,
+          };
+          if libc::prctl(
+            libc::PR_SET_SECCOMP,
+            libc::SECCOMP_MODE_FILTER,
+            &prog as *const libc::sock_fprog,
+          ) != 0
+          {
+            return Err(io::Error::last_os_error());
+          }
+        }
+        Ok(())
+      });
+    }
+
+    SandboxGuard { _ruleset: ruleset }
+  }
+
+  /// Creates a landlock ruleset in the parent process that only allows writes below the
+  /// writable directories. The child only has to enforce it.
+  fn write_ruleset(policy: &SandboxPolicy) -> io::Result<OwnedFd> {
+    // Safety: querying the ABI version doesn't read the attribute pointer
+    let abi = unsafe {
+      libc::syscall(
+        libc::SYS_landlock_create_ruleset,
+        std::ptr::null::<RulesetAttr>(),
+        0,
+        LANDLOCK_CREATE_RULESET_VERSION,
+      )
+    };
+    if abi < 1 {
+      return Err(io::Error::last_os_error());
+    }
+
+    let mut write_access = ACCESS_FS_WRITE_FILE
+      | ACCESS_FS_REMOVE_DIR
+      | ACCESS_FS_REMOVE_FILE
+      | ACCESS_FS_MAKE_CHAR
+      | ACCESS_FS_MAKE_DIR
+      | ACCESS_FS_MAKE_REG
+      | ACCESS_FS_MAKE_SOCK
+      | ACCESS_FS_MAKE_FIFO
+      | ACCESS_FS_MAKE_BLOCK
+      | ACCESS_FS_MAKE_SYM;
+    if abi >= 2 {
+      write_access |= ACCESS_FS_REFER;
+    }
+    if abi >= 3 {
+      write_access |= ACCESS_FS_TRUNCATE;
+    }
+
+    let attr = RulesetAttr {
+      handled_access_fs: write_access,
+    };
+    // Safety: attr is valid for the duration of the call
+    let fd = unsafe {
+      libc::syscall(
+        libc::SYS_landlock_create_ruleset,
+        &attr as *const RulesetAttr,
+        std::mem::size_of::<RulesetAttr>(),
+        0,
+      )
+    };
+    if fd < 0 {
+      return Err(io::Error::last_os_error());
+    }
+    // Safety: the syscall returned a new file descriptor that nothing else owns
+    let ruleset = unsafe { OwnedFd::from_raw_fd(fd as i32) };
+
+    // /dev/null and friends are opened for writing by many runtimes
+    let mut dirs = policy.writable_dirs();
+    dirs.push("/dev".into());
+    for dir in dirs {
+      let dir_file = match OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_PATH | libc::O_CLOEXEC)
+        .open(&dir)
+      {
+        Ok(file) => file,
+        Err(err) => {
+          warn!("[RPC] skip writable path {:?}: {:?}", dir, err);
+          continue;
+        },
+      };
+      let rule = PathBeneathAttr {
+        allowed_access: write_access,
+        parent_fd: dir_file.as_raw_fd(),
+      };
+      // Safety: rule is valid for the duration of the call
+      let result = unsafe {
+        libc::syscall(
+          libc::SYS_landlock_add_rule,
+          ruleset.as_raw_fd(),
+          LANDLOCK_RULE_PATH_BENEATH,
+          &rule as *const PathBeneathAttr,
+          0,
+        )
+      };
+      if result != 0 {
+        warn!(
+          "[RPC] failed to allow writes to {:?}: {:?}",
+          dir,
+          io::Error::last_os_error()
+        );
+      }
+    }
+    Ok(ruleset)
+  }
+
+  mod seccomp {
+    /// BPF_LD | BPF_W | BPF_ABS
+    const BPF_LD_W_ABS: u16 = 0x20;
+    /// BPF_JMP | BPF_JEQ | BPF_K
+    const BPF_JMP_JEQ_K: u16 = 0x15;
+    /// BPF_RET | BPF_K
+    const BPF_RET_K: u16 = 0x06;
+    const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+    const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+
+    // Offsets in struct seccomp_data
+    const OFFSET_NR: u32 = 0;
+    const OFFSET_ARCH: u32 = 4;
+    const OFFSET_ARG0: u32 = 16;
+
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: Option<u32> = Some(0xc000_003e);
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: Option<u32> = Some(0xc000_00b7);
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    const AUDIT_ARCH: Option<u32> = None;
+
+    fn stmt(code: u16, k: u32) -> libc::sock_filter {
+      libc::sock_filter {
+        code,
+        jt: 0,
+        jf: 0,
+        k,
+      }
+    }
+
+    fn jump(code: u16, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+      libc::sock_filter { code, jt, jf, k }
+    }
+
+    /// Checks that seccomp filters can be installed, e.g. not rejected by the seccomp profile of
+    /// a container. A null filter passes the checks for filter support and then fails to be
+    /// copied with EFAULT, so nothing is installed.
+    pub(super) fn check_filter_support() -> std::io::Result<()> {
+      // Safety: the kernel rejects the null pointer without dereferencing it
+      let result = unsafe {
+        libc::prctl(
+          libc::PR_SET_SECCOMP,
+          libc::SECCOMP_MODE_FILTER,
+          std::ptr::null::<libc::sock_fprog>(),
+        )
+      };
+      let err = std::io::Error::last_os_error();
+      if result == 0 || err.raw_os_error() == Some(libc::EFAULT) {
+        Ok(())
+      } else {
+        Err(err)
+      }
+    }
+
+    /// A filter that makes `socket(AF_INET | AF_INET6, ..)` fail with EPERM. Syscalls of other
+    /// architectures are rejected, since their syscall numbers differ.
+    pub(super) fn deny_ip_sockets() -> Option<Vec<libc::sock_filter>> {
+      let arch = AUDIT_ARCH?;
+      let deny = SECCOMP_RET_ERRNO | libc::EPERM as u32;
+      Some(vec![
+        stmt(BPF_LD_W_ABS, OFFSET_ARCH),
+        jump(BPF_JMP_JEQ_K, arch, 1, 0),
+        stmt(BPF_RET_K, deny),
+        stmt(BPF_LD_W_ABS, OFFSET_NR),
+        jump(BPF_JMP_JEQ_K, libc::SYS_socket as u32, 0, 4),
+        stmt(BPF_LD_W_ABS, OFFSET_ARG0),
+        jump(BPF_JMP_JEQ_K, libc::AF_INET as u32, 1, 0),
+        jump(BPF_JMP_JEQ_K, libc::AF_INET6 as u32, 0, 1),
+        stmt(BPF_RET_K, deny),
+        stmt(BPF_RET_K, SECCOMP_RET_ALLOW),
+      ])
+    }
+  }
+}
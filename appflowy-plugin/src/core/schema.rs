@@ -0,0 +1,260 @@
+use serde_json::{Map, Value as JsonValue};
+
+/// Validates `value` against a JSON Schema and returns one message per violation, prefixed with
+/// the JSON pointer of the offending value.
+///
+/// Only the keywords plugins use to describe their `initialize` params are supported: `type`,
+/// `enum`, `const`, `properties`, `required`, `additionalProperties`, `items`, `minimum` and
+/// `maximum`. Other keywords are ignored.
+pub fn validate(schema: &JsonValue, value: &JsonValue) -> Vec<String> {
+  let mut violations = vec![];
+  validate_at("", schema, value, &mut violations);
+  violations
+}
+
+fn validate_at(path: &str, schema: &JsonValue, value: &JsonValue, violations: &mut Vec<String>) {
+  let schema = match schema {
+    JsonValue::Object(schema) => schema,
+    // `true` and `{}` accept everything, `false` nothing
+    JsonValue::Bool(false) => {
+      violations.push(format!("{}: not allowed", display_path(path)));
+      return;
+    },
+    _ => return,
+  };
+
+  if let Some(expected) = schema.get("type") {
+    let types = match expected {
+      JsonValue::String(ty) => vec![ty.as_str()],
+      JsonValue::Array(types) => types.iter().filter_map(|ty| ty.as_str()).collect(),
+      _ => vec![],
+    };
+    if !types.is_empty() && !types.iter().any(|ty| has_type(value, ty)) {
+      violations.push(format!(
+        "{}: expected {}, found {}",
+        display_path(path),
+        types.join(" or "),
+        type_name(value)
+      ));
+      // The other keywords would only report follow-up errors
+      return;
+    }
+  }
+
+  if let Some(JsonValue::Array(allowed)) = schema.get("enum") {
+    if !allowed.contains(value) {
+      violations.push(format!(
+        "{}: expected one of {}, found {}",
+        display_path(path),
+        JsonValue::Array(allowed.clone()),
+        value
+      ));
+    }
+  }
+  if let Some(expected) = schema.get("const") {
+    if expected != value {
+      violations.push(format!(
+        "{}: expected {}, found {}",
+        display_path(path),
+        expected,
+        value
+      ));
+    }
+  }
+
+  if let Some(number) = value.as_f64() {
+    if let Some(minimum) = schema.get("minimum").and_then(|v| v.as_f64()) {
+      if number < minimum {
+        violations.push(format!(
+          "{}: {} is less than the minimum {}",
+          display_path(path),
+          value,
+          minimum
+        ));
+      }
+    }
+    if let Some(maximum) = schema.get("maximum").and_then(|v| v.as_f64()) {
+      if number > maximum {
+        violations.push(format!(
+          "{}: {} is greater than the maximum {}",
+          display_path(path),
+          value,
+          maximum
+        ));
+      }
+    }
+  }
+
+  match value {
+    JsonValue::Object(object) => validate_object(path, schema, object, violations),
+    JsonValue::Array(items) => {
+      if let Some(item_schema) = schema.get("items") {
+        for (index, item) in items.iter().enumerate() {
+          validate_at(
+            &format!("{}/{}", path, index),
+            item_schema,
+            item,
+            violations,
+          );
+        }
+      }
+    },
+    _ => {},
+  }
+}
+
+fn validate_object(
+  path: &str,
+  schema: &Map<String, JsonValue>,
+  object: &Map<String, JsonValue>,
+  violations: &mut Vec<String>,
+) {
+  if let Some(JsonValue::Array(required)) = schema.get("required") {
+    for key in required.iter().filter_map(|key| key.as_str()) {
+      if !object.contains_key(key) {
+        violations.push(format!(
+          "{}: missing required property `{}`",
+          display_path(path),
+          key
+        ));
+      }
+    }
+  }
+
+  let properties = schema.get("properties").and_then(|v| v.as_object());
+  let additional = schema.get("additionalProperties");
+  for (key, value) in object {
+    let property_path = format!("{}/{}", path, escape(key));
+    match properties.and_then(|properties| properties.get(key)) {
+      Some(property_schema) => validate_at(&property_path, property_schema, value, violations),
+      None => match additional {
+        Some(JsonValue::Bool(false)) => violations.push(format!(
+          "{}: unknown property, the plugin may be older than the app",
+          display_path(&property_path)
+        )),
+        Some(additional) => validate_at(&property_path, additional, value, violations),
+        None => {},
+      },
+    }
+  }
+}
+
+fn has_type(value: &JsonValue, ty: &str) -> bool {
+  match ty {
+    "null" => value.is_null(),
+    "boolean" => value.is_boolean(),
+    "string" => value.is_string(),
+    "number" => value.is_number(),
+    "integer" => {
+      value.is_i64() || value.is_u64() || value.as_f64().map_or(false, |n| n.fract() == 0.0)
+    },
+    "array" => value.is_array(),
+    "object" => value.is_object(),
+    _ => true,
+  }
+}
+
+fn type_name(value: &JsonValue) -> &'static str {
+  match value {
+    JsonValue::Null => "null",
+    JsonValue::Bool(_) => "boolean",
+    JsonValue::Number(_) => "number",
+    JsonValue::String(_) => "string",
+    JsonValue::Array(_) => "array",
+    JsonValue::Object(_) => "object",
+  }
+}
+
+/// Escapes a property name for use in a JSON pointer (RFC 6901).
+fn escape(key: &str) -> String {
+  key.replace('~', "~0").replace('/', "~1")
+}
+
+fn display_path(path: &str) -> &str {
+  if path.is_empty() {
+    "/"
+  } else {
+    path
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  fn init_schema() -> JsonValue {
+    json!({
+      "type": "object",
+      "required": ["model", "threads"],
+      "properties": {
+        "model": { "type": "string" },
+        "threads": { "type": "integer", "minimum": 1, "maximum": 64 },
+        "device": { "enum": ["cpu", "gpu"] },
+        "stop": { "type": "array", "items": { "type": "string" } },
+        "a/b": { "type": "boolean" }
+      },
+      "additionalProperties": false
+    })
+  }
+
+  #[test]
+  fn validate_valid_params_test() {
+    let params = json!({
+      "model": "llama",
+      "threads": 4,
+      "device": "gpu",
+      "stop": ["\n"],
+      "a/b": true
+    });
+    assert!(validate(&init_schema(), &params).is_empty());
+    assert!(validate(&json!(true), &params).is_empty());
+    assert!(validate(&json!({}), &params).is_empty());
+  }
+
+  #[test]
+  fn validate_invalid_params_test() {
+    let params = json!({
+      "model": 1,
+      "threads": 0,
+      "device": "tpu",
+      "stop": ["\n", 2],
+      "a/b": "yes",
+      "context_size": 2048
+    });
+    assert_eq!(
+      validate(&init_schema(), &params),
+      vec![
+        "/a~1b: expected boolean, found string",
+        "/context_size: unknown property, the plugin may be older than the app",
+        "/device: expected one of [\"cpu\",\"gpu\"], found \"tpu\"",
+        "/model: expected string, found number",
+        "/stop/1: expected string, found number",
+        "/threads: 0 is less than the minimum 1",
+      ]
+    );
+    assert_eq!(
+      validate(&init_schema(), &json!([])),
+      vec!["/: expected object, found array"]
+    );
+    assert_eq!(validate(&json!(false), &json!({})), vec!["/: not allowed"]);
+  }
+
+  #[test]
+  fn validate_missing_required_params_test() {
+    assert_eq!(
+      validate(&init_schema(), &json!({ "threads": 65 })),
+      vec![
+        "/: missing required property `model`",
+        "/threads: 65 is greater than the maximum 64",
+      ]
+    );
+    assert_eq!(
+      validate(&init_schema(), &json!({})),
+      vec![
+        "/: missing required property `model`",
+        "/: missing required property `threads`",
+      ]
+    );
+  }
+}
@@ -0,0 +1,569 @@
+use socket2::{SockRef, TcpKeepalive};
+use std::collections::hash_map::RandomState;
+use std::fmt::{Debug, Formatter};
+use std::hash::BuildHasher;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::process::Child;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// The environment variable that holds the address a [PluginTransport::LocalSocket] plugin connects to
+pub const SOCKET_ENV: &str = "APPFLOWY_PLUGIN_SOCKET";
+
+/// How long to wait for the connection to a [PluginTransport::LocalSocket] or
+/// [PluginTransport::Tcp] plugin if the plugin has no
+/// [startup_timeout](super::plugin::PluginInfo::startup_timeout)
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a [PluginTransport::Tcp] connection is idle before TCP keepalive probes check that the
+/// other machine is still there
+const TCP_KEEPALIVE_TIME: Duration = Duration::from_secs(30);
+const TCP_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+/// How long a write to a [PluginTransport::Tcp] plugin may block, e.g. when the other machine
+/// stopped reading
+const TCP_WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How the app and a plugin exchange messages.
+#[derive(Clone, Default, Eq, PartialEq)]
+pub enum PluginTransport {
+  /// Over the stdin and stdout of the plugin process
+  #[default]
+  Stdio,
+  /// The plugin connects to the Unix domain socket (a named pipe on Windows) in the
+  /// [SOCKET_ENV] environment variable. Whatever it writes to stdout is logged.
+  LocalSocket,
+  /// Connects to a plugin that already runs on another machine, e.g. a home server with a GPU,
+  /// and listens on `addr` (`host:port`). The plugin isn't started or stopped by the app, so
+  /// [PluginInfo::exec_path](super::plugin::PluginInfo::exec_path), the sandbox and resource
+  /// limits don't apply. Messages aren't encrypted, so only use it within a trusted network or
+  /// through a tunnel.
+  ///
+  /// `auth_token` is a secret shared with the plugin, e.g. generated when the plugin was set up
+  /// on the other machine. It's sent as a top level `"auth_token"` field of the `initialize`
+  /// params, and the plugin must refuse any other request of a connection that didn't present
+  /// it. A dead connection is noticed by TCP keepalive probes.
+  Tcp { addr: String, auth_token: String },
+}
+
+impl Debug for PluginTransport {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    match self {
+      PluginTransport::Stdio => write!(f, "Stdio"),
+      PluginTransport::LocalSocket => write!(f, "LocalSocket"),
+      PluginTransport::Tcp { addr, .. } => f
+        .debug_struct("Tcp")
+        .field("addr", addr)
+        .field("auth_token", &"<redacted>")
+        .finish(),
+    }
+  }
+}
+
+pub(crate) type Reader = Box<dyn Read + Send>;
+pub(crate) type Writer = Box<dyn Write + Send>;
+
+/// Waits for a [PluginTransport::LocalSocket] plugin to connect. Created before the plugin is spawned.
+pub(crate) struct LocalListener {
+  #[cfg(unix)]
+  inner: unix::Listener,
+  #[cfg(windows)]
+  inner: windows::Listener,
+}
+
+impl LocalListener {
+  pub(crate) fn bind() -> io::Result<Self> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    // Unpredictable, so another process can't create the socket first and pose as the app
+    let random = RandomState::new().hash_one(COUNTER.fetch_add(1, Ordering::Relaxed));
+    let name = format!("appflowy-plugin-{}-{:016x}", std::process::id(), random);
+
+    #[cfg(unix)]
+    {
+      unix::Listener::bind(&name).map(|inner| Self { inner })
+    }
+
+    #[cfg(windows)]
+    {
+      windows::Listener::bind(&name).map(|inner| Self { inner })
+    }
+
+    #[cfg(not(any(unix, windows)))]
+    {
+      let _ = name;
+      Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "local sockets are not supported on this platform",
+      ))
+    }
+  }
+
+  /// The address passed to the plugin in [SOCKET_ENV]
+  pub(crate) fn path(&self) -> &Path {
+    #[cfg(any(unix, windows))]
+    {
+      &self.inner.path
+    }
+
+    #[cfg(not(any(unix, windows)))]
+    unreachable!()
+  }
+
+  /// Waits until `child` connected. Gives up if it exits before, or after `timeout`, which
+  /// defaults to [CONNECT_TIMEOUT].
+  pub(crate) fn accept(
+    self,
+    child: &mut Child,
+    timeout: Option<Duration>,
+  ) -> io::Result<(Reader, Writer)> {
+    let deadline = Instant::now() + timeout.unwrap_or(CONNECT_TIMEOUT);
+    let mut gave_up = || -> io::Result<()> {
+      if let Some(status) = child.try_wait()? {
+        return Err(io::Error::new(
+          io::ErrorKind::ConnectionRefused,
+          format!("plugin exited before connecting, {}", status),
+        ));
+      }
+      if Instant::now() >= deadline {
+        return Err(io::Error::new(
+          io::ErrorKind::TimedOut,
+          "plugin did not connect in time",
+        ));
+      }
+      Ok(())
+    };
+
+    #[cfg(any(unix, windows))]
+    {
+      self.inner.accept(&mut gave_up)
+    }
+
+    #[cfg(not(any(unix, windows)))]
+    {
+      let _ = gave_up;
+      unreachable!()
+    }
+  }
+}
+
+/// Connects to a [PluginTransport::Tcp] plugin, trying every address `addr` resolves to.
+pub(crate) fn connect_tcp(addr: &str, timeout: Option<Duration>) -> io::Result<TcpStream> {
+  let timeout = timeout.unwrap_or(CONNECT_TIMEOUT);
+  let mut last_err = None;
+  for socket_addr in addr.to_socket_addrs()? {
+    match TcpStream::connect_timeout(&socket_addr, timeout) {
+      Ok(stream) => {
+        // Requests are small and latency bound
+        stream.set_nodelay(true)?;
+        // Reads block until the plugin sends something, which may be never if the other machine
+        // went away without closing the connection
+        let keepalive = TcpKeepalive::new()
+          .with_time(TCP_KEEPALIVE_TIME)
+          .with_interval(TCP_KEEPALIVE_INTERVAL);
+        SockRef::from(&stream).set_tcp_keepalive(&keepalive)?;
+        stream.set_write_timeout(Some(TCP_WRITE_TIMEOUT))?;
+        return Ok(stream);
+      },
+      Err(err) => last_err = Some(err),
+    }
+  }
+  Err(last_err.unwrap_or_else(|| {
+    io::Error::new(
+      io::ErrorKind::InvalidInput,
+      format!("{} did not resolve to any address", addr),
+    )
+  }))
+}
+
+/// Logs the lines a [PluginTransport::LocalSocket] plugin writes to stdout.
+pub(crate) fn log_stdout<R: Read + Send + 'static>(name: &str, stdout: R) {
+  let plugin_name = name.to_string();
+  let result = thread::Builder::new()
+    .name(format!("<{}> stdout", name))
+    .spawn(move || {
+      let mut reader = BufReader::new(stdout);
+      let mut buf = vec![];
+      while matches!(reader.read_until(b'\n', &mut buf), Ok(n) if n > 0) {
+        info!(
+          "[{}] {}",
+          plugin_name,
+          String::from_utf8_lossy(&buf).trim_end()
+        );
+        buf.clear();
+      }
+    });
+  if let Err(err) = result {
+    warn!("[RPC] failed to read stdout of {}: {:?}", name, err);
+  }
+}
+
+#[cfg(unix)]
+mod unix {
+  use super::{Reader, Writer};
+  use std::io;
+  use std::os::unix::fs::DirBuilderExt;
+  use std::os::unix::net::UnixListener;
+  use std::path::PathBuf;
+  use std::thread;
+  use std::time::Duration;
+
+  pub(super) struct Listener {
+    listener: UnixListener,
+    dir: PathBuf,
+    pub(super) path: PathBuf,
+  }
+
+  impl Listener {
+    /// The socket is created in a directory only the current user can access, so other users
+    /// can't connect before the plugin does.
+    pub(super) fn bind(name: &str) -> io::Result<Self> {
+      let dir = std::env::temp_dir().join(name);
+      std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+      let path = dir.join("rpc.sock");
+      let listener = UnixListener::bind(&path).and_then(|listener| {
+        listener.set_nonblocking(true)?;
+        Ok(listener)
+      });
+      match listener {
+        Ok(listener) => Ok(Self {
+          listener,
+          dir,
+          path,
+        }),
+        Err(err) => {
+          let _ = std::fs::remove_dir_all(&dir);
+          Err(err)
+        },
+      }
+    }
+
+    pub(super) fn accept(
+      self,
+      gave_up: &mut dyn FnMut() -> io::Result<()>,
+    ) -> io::Result<(Reader, Writer)> {
+      loop {
+        match self.listener.accept() {
+          Ok((stream, _)) => {
+            stream.set_nonblocking(false)?;
+            return Ok((Box::new(stream.try_clone()?), Box::new(stream)));
+          },
+          Err(err) if err.kind() == io::ErrorKind::WouldBlock => {},
+          Err(err) => return Err(err),
+        }
+        gave_up()?;
+        thread::sleep(Duration::from_millis(10));
+      }
+    }
+  }
+
+  impl Drop for Listener {
+    fn drop(&mut self) {
+      // The connection stays open without the socket file
+      let _ = std::fs::remove_dir_all(&self.dir);
+    }
+  }
+}
+
+#[cfg(windows)]
+mod windows {
+  use super::{Reader, Writer};
+  use std::io::{self, Read, Write};
+  use std::os::windows::ffi::OsStrExt;
+  use std::os::windows::io::{AsRawHandle, FromRawHandle, OwnedHandle};
+  use std::path::PathBuf;
+  use std::sync::Arc;
+  use windows_sys::Win32::Foundation::{
+    ERROR_BROKEN_PIPE, ERROR_IO_PENDING, ERROR_PIPE_CONNECTED, FALSE, HANDLE, INVALID_HANDLE_VALUE,
+    TRUE, WAIT_OBJECT_0,
+  };
+  use windows_sys::Win32::Security::{
+    AddAccessAllowedAce, GetLengthSid, GetTokenInformation, InitializeAcl,
+    InitializeSecurityDescriptor, SetSecurityDescriptorDacl, TokenUser, ACCESS_ALLOWED_ACE, ACL,
+    ACL_REVISION, SECURITY_ATTRIBUTES, SECURITY_DESCRIPTOR, TOKEN_QUERY, TOKEN_USER,
+  };
+  use windows_sys::Win32::Storage::FileSystem::{
+    ReadFile, WriteFile, FILE_ALL_ACCESS, FILE_FLAG_FIRST_PIPE_INSTANCE, FILE_FLAG_OVERLAPPED,
+    PIPE_ACCESS_DUPLEX,
+  };
+  use windows_sys::Win32::System::Pipes::{
+    ConnectNamedPipe, CreateNamedPipeW, PIPE_READMODE_BYTE, PIPE_REJECT_REMOTE_CLIENTS,
+    PIPE_TYPE_BYTE, PIPE_WAIT,
+  };
+  use windows_sys::Win32::System::Threading::{
+    CreateEventW, GetCurrentProcess, OpenProcessToken, WaitForSingleObject,
+  };
+  use windows_sys::Win32::System::IO::{CancelIoEx, GetOverlappedResult, OVERLAPPED};
+
+  const BUFFER_SIZE: u32 = 64 * 1024;
+  /// `SECURITY_DESCRIPTOR_REVISION` of `Win32::System::SystemServices`
+  const SECURITY_DESCRIPTOR_REVISION: u32 = 1;
+
+  pub(super) struct Listener {
+    pipe: OwnedHandle,
+    pub(super) path: PathBuf,
+  }
+
+  impl Listener {
+    /// The pipe accepts a single local client of the current user and fails to bind if another
+    /// process already created a pipe with the same name.
+    pub(super) fn bind(name: &str) -> io::Result<Self> {
+      let path = PathBuf::from(format!(r"\\.\pipe\{}", name));
+      let wide = path
+        .as_os_str()
+        .encode_wide()
+        .chain(Some(0))
+        .collect::<Vec<_>>();
+      let mut security = CurrentUserOnly::new()?;
+      let attributes = security.attributes();
+      // Safety: `wide` is a null terminated string and `attributes` points into `security`, both
+      // outlive the call.
+      let handle = unsafe {
+        CreateNamedPipeW(
+          wide.as_ptr(),
+          PIPE_ACCESS_DUPLEX | FILE_FLAG_OVERLAPPED | FILE_FLAG_FIRST_PIPE_INSTANCE,
+          PIPE_TYPE_BYTE | PIPE_READMODE_BYTE | PIPE_WAIT | PIPE_REJECT_REMOTE_CLIENTS,
+          1,
+          BUFFER_SIZE,
+          BUFFER_SIZE,
+          0,
+          &attributes,
+        )
+      };
+      if handle == INVALID_HANDLE_VALUE {
+        return Err(io::Error::last_os_error());
+      }
+      // Safety: the handle was just created and isn't owned by anything else.
+      let pipe = unsafe { OwnedHandle::from_raw_handle(handle as _) };
+      Ok(Self { pipe, path })
+    }
+
+    pub(super) fn accept(
+      self,
+      gave_up: &mut dyn FnMut() -> io::Result<()>,
+    ) -> io::Result<(Reader, Writer)> {
+      let handle = self.pipe.as_raw_handle() as HANDLE;
+      let mut io = Overlapped::new()?;
+      // Safety: `io` outlives the operation, it's either completed or cancelled below.
+      if unsafe { ConnectNamedPipe(handle, io.as_mut_ptr()) } == FALSE {
+        match io::Error::last_os_error() {
+          err if err.raw_os_error() == Some(ERROR_PIPE_CONNECTED as i32) => {},
+          err if err.raw_os_error() == Some(ERROR_IO_PENDING as i32) => loop {
+            // Safety: the event is owned by `io`.
+            if unsafe { WaitForSingleObject(io.event(), 10) } == WAIT_OBJECT_0 {
+              io.result(handle)?;
+              break;
+            }
+            if let Err(err) = gave_up() {
+              io.cancel(handle);
+              return Err(err);
+            }
+          },
+          err => return Err(err),
+        }
+      }
+
+      let pipe = Arc::new(self.pipe);
+      Ok((Box::new(Pipe(pipe.clone())), Box::new(Pipe(pipe))))
+    }
+  }
+
+  /// A security descriptor whose DACL only grants the current user access, so processes of other
+  /// users can't connect to the pipe. The default DACL of a pipe lets everyone read it.
+  struct CurrentUserOnly {
+    descriptor: Box<SECURITY_DESCRIPTOR>,
+    // Referenced by `descriptor`. u64 for the alignment of the structs they hold.
+    _acl: Vec<u64>,
+    _token_user: Vec<u64>,
+  }
+
+  impl CurrentUserOnly {
+    fn new() -> io::Result<Self> {
+      let check = |ok| {
+        if ok == FALSE {
+          Err(io::Error::last_os_error())
+        } else {
+          Ok(())
+        }
+      };
+      // Safety: every buffer is large enough for what's written to it, as reported by the API,
+      // and outlives the calls that use it.
+      unsafe {
+        let mut token: HANDLE = 0;
+        check(OpenProcessToken(
+          GetCurrentProcess(),
+          TOKEN_QUERY,
+          &mut token,
+        ))?;
+        let owned_token = OwnedHandle::from_raw_handle(token as _);
+        let token = owned_token.as_raw_handle() as HANDLE;
+        let mut len = 0;
+        GetTokenInformation(token, TokenUser, std::ptr::null_mut(), 0, &mut len);
+        let mut token_user = vec![0u64; (len as usize).div_ceil(8)];
+        check(GetTokenInformation(
+          token,
+          TokenUser,
+          token_user.as_mut_ptr().cast(),
+          len,
+          &mut len,
+        ))?;
+        let sid = (*token_user.as_ptr().cast::<TOKEN_USER>()).User.Sid;
+
+        let acl_len = std::mem::size_of::<ACL>()
+          + std::mem::size_of::<ACCESS_ALLOWED_ACE>()
+          + GetLengthSid(sid) as usize;
+        let mut acl = vec![0u64; acl_len.div_ceil(8)];
+        let acl_ptr = acl.as_mut_ptr().cast::<ACL>();
+        check(InitializeAcl(acl_ptr, acl_len as u32, ACL_REVISION))?;
+        check(AddAccessAllowedAce(
+          acl_ptr,
+          ACL_REVISION,
+          FILE_ALL_ACCESS,
+          sid,
+        ))?;
+
+        let mut descriptor = Box::new(std::mem::zeroed::<SECURITY_DESCRIPTOR>());
+        let descriptor_ptr = (&mut *descriptor as *mut SECURITY_DESCRIPTOR).cast();
+        check(InitializeSecurityDescriptor(
+          descriptor_ptr,
+          SECURITY_DESCRIPTOR_REVISION,
+        ))?;
+        check(SetSecurityDescriptorDacl(
+          descriptor_ptr,
+          TRUE,
+          acl_ptr,
+          FALSE,
+        ))?;
+        Ok(Self {
+          descriptor,
+          _acl: acl,
+          _token_user: token_user,
+        })
+      }
+    }
+
+    fn attributes(&mut self) -> SECURITY_ATTRIBUTES {
+      SECURITY_ATTRIBUTES {
+        nLength: std::mem::size_of::<SECURITY_ATTRIBUTES>() as u32,
+        lpSecurityDescriptor: (&mut *self.descriptor as *mut SECURITY_DESCRIPTOR).cast(),
+        bInheritHandle: FALSE,
+      }
+    }
+  }
+
+  /// A connected pipe. Reading and writing use overlapped I/O, because synchronous operations
+  /// on the same pipe would wait for each other.
+  struct Pipe(Arc<OwnedHandle>);
+
+  impl Pipe {
+    fn handle(&self) -> HANDLE {
+      self.0.as_raw_handle() as HANDLE
+    }
+  }
+
+  impl Read for Pipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+      let handle = self.handle();
+      let len = buf.len().min(u32::MAX as usize) as u32;
+      let mut io = Overlapped::new()?;
+      // Safety: `buf` and `io` outlive the operation, which is awaited by `wait`.
+      let started = unsafe {
+        ReadFile(
+          handle,
+          buf.as_mut_ptr(),
+          len,
+          std::ptr::null_mut(),
+          io.as_mut_ptr(),
+        )
+      };
+      match io.wait(handle, started) {
+        // The plugin closed its end
+        Err(err) if err.raw_os_error() == Some(ERROR_BROKEN_PIPE as i32) => Ok(0),
+        result => result,
+      }
+    }
+  }
+
+  impl Write for Pipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+      let handle = self.handle();
+      let len = buf.len().min(u32::MAX as usize) as u32;
+      let mut io = Overlapped::new()?;
+      // Safety: `buf` and `io` outlive the operation, which is awaited by `wait`.
+      let started = unsafe {
+        WriteFile(
+          handle,
+          buf.as_ptr(),
+          len,
+          std::ptr::null_mut(),
+          io.as_mut_ptr(),
+        )
+      };
+      io.wait(handle, started)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+      Ok(())
+    }
+  }
+
+  /// An `OVERLAPPED` with its own event, so concurrent operations on a pipe don't wake each
+  /// other up.
+  struct Overlapped {
+    overlapped: OVERLAPPED,
+    event: OwnedHandle,
+  }
+
+  impl Overlapped {
+    fn new() -> io::Result<Self> {
+      // Safety: creates a manual reset event without name or security attributes.
+      let event = unsafe { CreateEventW(std::ptr::null(), TRUE, FALSE, std::ptr::null()) };
+      if event == 0 {
+        return Err(io::Error::last_os_error());
+      }
+      // Safety: the event was just created and isn't owned by anything else.
+      let event = unsafe { OwnedHandle::from_raw_handle(event as _) };
+      // Safety: OVERLAPPED is a plain C struct, all zeros is its initial state.
+      let mut overlapped: OVERLAPPED = unsafe { std::mem::zeroed() };
+      overlapped.hEvent = event.as_raw_handle() as HANDLE;
+      Ok(Self { overlapped, event })
+    }
+
+    fn as_mut_ptr(&mut self) -> *mut OVERLAPPED {
+      &mut self.overlapped
+    }
+
+    fn event(&self) -> HANDLE {
+      self.event.as_raw_handle() as HANDLE
+    }
+
+    /// Waits for an operation that was `started` with this `OVERLAPPED`.
+    fn wait(&self, handle: HANDLE, started: i32) -> io::Result<usize> {
+      if started == FALSE {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(ERROR_IO_PENDING as i32) {
+          return Err(err);
+        }
+      }
+      self.result(handle)
+    }
+
+    fn result(&self, handle: HANDLE) -> io::Result<usize> {
+      let mut transferred = 0;
+      // Safety: the `OVERLAPPED` belongs to an operation on `handle`.
+      if unsafe { GetOverlappedResult(handle, &self.overlapped, &mut transferred, TRUE) } == FALSE {
+        return Err(io::Error::last_os_error());
+      }
+      Ok(transferred as usize)
+    }
+
+    /// Cancels the operation and waits until the system doesn't use the `OVERLAPPED` anymore.
+    fn cancel(&self, handle: HANDLE) {
+      // Safety: the `OVERLAPPED` belongs to an operation on `handle`.
+      unsafe { CancelIoEx(handle, &self.overlapped) };
+      let _ = self.result(handle);
+    }
+  }
+}
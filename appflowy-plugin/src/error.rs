@@ -21,6 +21,52 @@ pub enum PluginError {
   #[error("Plugin not connected.")]
   PluginNotConnected,
 
+  /// The plugin didn't answer its first ping within its startup timeout
+  #[error("Plugin startup timed out.")]
+  StartupTimeout,
+
+  /// The plugin didn't respond within the timeout of the request
+  #[error("Request timed out.")]
+  Timeout,
+
+  /// The request was cancelled with an [AbortHandle](crate::core::plugin::AbortHandle)
+  #[error("Request was cancelled.")]
+  Cancelled,
+
+  /// The plugin didn't make progress on the request for longer than the watchdog threshold
+  #[error("Plugin is unresponsive.")]
+  Unresponsive,
+
+  /// Too many requests are in flight and waiting, see
+  /// [RequestQueueConfig](crate::core::rpc_peer::RequestQueueConfig)
+  #[error("Plugin is busy.")]
+  Busy,
+
+  /// A plugin listed in [PluginInfo::depends_on](crate::core::plugin::PluginInfo::depends_on)
+  /// is not running
+  #[error("Plugin dependency is not running: {0}")]
+  MissingDependency(String),
+
+  /// The `initialize` params don't match the schema reported by the plugin, usually because the
+  /// app and the plugin binary are from different versions
+  #[error("Invalid initialize params: {0}")]
+  InvalidInitParams(String),
+
+  /// The plugin picked a protocol revision the host doesn't speak, see
+  /// [PROTOCOL_VERSION](crate::core::rpc_peer::PROTOCOL_VERSION)
+  #[error(
+    "Incompatible plugin protocol version {0}, supported: {}..={}",
+    crate::core::rpc_peer::MIN_PROTOCOL_VERSION,
+    crate::core::rpc_peer::PROTOCOL_VERSION
+  )]
+  IncompatibleProtocol(u32),
+
+  /// The plugin's response was larger than
+  /// [PluginInfo::max_message_size](crate::core::plugin::PluginInfo::max_message_size) and was
+  /// dropped unread
+  #[error("Message of {0} bytes exceeds the maximum message size.")]
+  MessageTooLarge(usize),
+
   #[error(transparent)]
   Internal(#[from] anyhow::Error),
 }
@@ -37,6 +83,12 @@ pub enum ReadError {
   UnknownRequest(serde_json::Error),
   /// The peer closed the connection.
   Disconnect(String),
+  /// The plugin wrote too many messages in a row that aren't JSON objects, see
+  /// [MAX_MALFORMED_MESSAGES](crate::core::parser::MAX_MALFORMED_MESSAGES). Holds the last one.
+  Malformed(String),
+  /// The plugin wrote a message larger than the maximum message size. It was skipped, `id` is the
+  /// request id found at its start, if any.
+  MessageTooLarge { id: Option<u64>, size: usize },
 }
 
 #[derive(Debug, Clone, thiserror::Error)]
@@ -83,6 +135,10 @@ impl fmt::Display for ReadError {
       ReadError::NotObject(s) => write!(f, "Expected JSON object, found: {}", s),
       ReadError::UnknownRequest(ref err) => write!(f, "Unknown request: {:?}", err),
       ReadError::Disconnect(reason) => write!(f, "Peer closed the connection, reason: {}", reason),
+      ReadError::Malformed(s) => write!(f, "Too many malformed messages, last: {}", s),
+      ReadError::MessageTooLarge { size, .. } => {
+        write!(f, "Message of {} bytes exceeds the maximum size", size)
+      },
     }
   }
 }
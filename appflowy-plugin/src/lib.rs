@@ -1,4 +1,5 @@
 pub mod core;
 pub mod error;
 pub mod manager;
+pub mod router;
 pub mod util;
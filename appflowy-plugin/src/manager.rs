@@ -1,24 +1,220 @@
+use crate::core::metrics::{MethodMetrics, RpcMetrics};
 use crate::core::parser::ResponseParser;
+use crate::core::pid_file::{cleanup_orphans, PidFile};
 use crate::core::plugin::{
-  start_plugin_process, Plugin, PluginId, PluginInfo, RpcCtx, RunningStateSender,
+  start_plugin_process, Plugin, PluginHandshake, PluginId, PluginInfo, RpcCtx, RunningState,
+  RunningStateSender,
 };
 use crate::core::rpc_loop::Handler;
-use crate::core::rpc_peer::{PluginCommand, ResponsePayload};
+use crate::core::rpc_peer::{
+  FlushPolicy, PluginCommand, ResponsePayload, KEEPALIVE, KEEPALIVE_INTERVAL,
+};
+use crate::core::schema::validate;
+use crate::core::transport::PluginTransport;
 use crate::error::{PluginError, ReadError, RemoteError};
 use anyhow::anyhow;
 use parking_lot::Mutex;
 use serde_json::Value;
+use std::collections::{HashMap, HashSet};
 use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use sysinfo::{ProcessRefreshKind, System};
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+use tokio_stream::wrappers::WatchStream;
+use tokio_stream::StreamExt;
 
 use crate::util::{get_operating_system, OperatingSystem};
 use std::sync::atomic::{AtomicI64, Ordering};
 use std::sync::{Arc, Weak};
 use tracing::{error, info, instrument, trace, warn};
 
+/// Controls the `ping` requests that [PluginManager::start_health_check] sends to a plugin.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct HealthCheckConfig {
+  /// Time between two pings
+  pub interval: Duration,
+  /// A ping that isn't answered within this time counts as failed
+  pub timeout: Duration,
+  /// The plugin is marked as [RunningState::Unhealthy] after this many consecutive failed pings
+  pub max_failures: usize,
+}
+
+impl Default for HealthCheckConfig {
+  fn default() -> Self {
+    Self {
+      interval: Duration::from_secs(10),
+      timeout: Duration::from_secs(5),
+      max_failures: 3,
+    }
+  }
+}
+
+/// Controls the watchdog started with [PluginManager::start_watchdog].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct WatchdogConfig {
+  /// A request counts as hung once it got neither a response nor a stream message for this long
+  pub threshold: Duration,
+  /// Time between two checks of the pending requests
+  pub interval: Duration,
+  /// When true, hung requests fail with [PluginError::Unresponsive] and the plugin is marked as
+  /// [RunningState::Unhealthy], so it's restarted like a plugin that failed its health check.
+  pub restart: bool,
+  /// Replaces [Self::threshold] for plugins that send keepalives, see
+  /// [KEEPALIVE](crate::core::rpc_peer::KEEPALIVE). Busy requests of such plugins make progress
+  /// every [KEEPALIVE_INTERVAL], so a dead plugin is noticed long before a slow prefill would
+  /// exceed [Self::threshold].
+  pub keepalive_threshold: Option<Duration>,
+}
+
+impl Default for WatchdogConfig {
+  fn default() -> Self {
+    Self {
+      threshold: Duration::from_secs(120),
+      interval: Duration::from_secs(5),
+      restart: false,
+      keepalive_threshold: Some(KEEPALIVE_INTERVAL * 3),
+    }
+  }
+}
+
+/// Settings shared by all plugins of a [PluginManager], set with [PluginManager::with_config].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PluginManagerConfig {
+  /// Capacity in bytes of the buffer messages from a plugin are read through, e.g. its stdout
+  pub read_buffer_size: usize,
+  /// Capacity in bytes of the buffer messages to a plugin are written through. 0 writes every
+  /// message straight to the connection, e.g. the plugin's stdin.
+  pub write_buffer_size: usize,
+  /// When buffered messages are sent, see [FlushPolicy]
+  pub flush_policy: FlushPolicy,
+}
+
+impl Default for PluginManagerConfig {
+  fn default() -> Self {
+    Self {
+      read_buffer_size: 8 * 1024,
+      write_buffer_size: 0,
+      flush_policy: FlushPolicy::EveryMessage,
+    }
+  }
+}
+
+/// Events reported by [PluginManager::subscribe_events].
+#[derive(Debug, Clone)]
+pub enum PluginEvent {
+  /// A request didn't make progress for longer than [WatchdogConfig::threshold]. Reported once
+  /// per request.
+  PluginUnresponsive {
+    plugin_id: PluginId,
+    method: String,
+    idle: Duration,
+  },
+}
+
+/// Resource usage of a plugin process, sampled by [PluginManager::plugin_metrics].
+#[derive(Debug, Clone, PartialEq)]
+pub struct PluginMetrics {
+  /// Resident set size in bytes
+  pub rss_bytes: u64,
+  /// CPU usage over the sampling interval. Can exceed 100 on multi-core machines.
+  pub cpu_percent: f32,
+  pub uptime: Duration,
+}
+
+/// Point-in-time view of a plugin, returned by [PluginManager::list_plugins].
+#[derive(Debug, Clone)]
+pub struct PluginSnapshot {
+  pub id: PluginId,
+  pub name: String,
+  pub state: RunningState,
+  /// 0 for plugins that run on another machine, see [Self::remote_addr]
+  pub pid: u32,
+  /// The address of a plugin that runs on another machine, see
+  /// [PluginTransport::Tcp]
+  pub remote_addr: Option<String>,
+  pub uptime: Duration,
+}
+
+/// A plugin to start with [PluginManager::start_plugins].
+pub struct PluginLaunch {
+  pub info: PluginInfo,
+  pub init_params: Value,
+  pub running_state: RunningStateSender,
+}
+
+/// Returns the indices of `plugins` so that every plugin comes after the plugins it depends on.
+fn start_order(plugins: &[PluginLaunch]) -> Result<Vec<usize>, PluginError> {
+  let mut order = Vec::with_capacity(plugins.len());
+  let mut remaining = (0..plugins.len()).collect::<Vec<_>>();
+  while !remaining.is_empty() {
+    // A plugin is ready once none of its dependencies is still waiting to be started
+    let (ready, blocked): (Vec<usize>, Vec<usize>) = remaining.iter().partition(|&&index| {
+      plugins[index].info.depends_on.iter().all(|dependency| {
+        !remaining
+          .iter()
+          .any(|&other| &plugins[other].info.name == dependency)
+      })
+    });
+    if ready.is_empty() {
+      let names = blocked
+        .iter()
+        .map(|&index| plugins[index].info.name.as_str())
+        .collect::<Vec<_>>();
+      return Err(PluginError::Internal(anyhow!(
+        "dependency cycle between plugins: {}",
+        names.join(", ")
+      )));
+    }
+    order.extend(ready);
+    remaining = blocked;
+  }
+  Ok(order)
+}
+
+/// How long [PluginManager::reload_binary] waits for requests in flight
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
 pub struct PluginManager {
   state: Arc<Mutex<PluginState>>,
   plugin_id_counter: Arc<AtomicI64>,
   operating_system: OperatingSystem,
+  health_checks: Arc<Mutex<HashMap<PluginId, HealthCheckTask>>>,
+  watchdogs: Arc<Mutex<HashMap<PluginId, WatchdogTask>>>,
+  events: broadcast::Sender<PluginEvent>,
+  idle_watchers: Mutex<HashMap<PluginId, IdleWatcherTask>>,
+  idle_stopped: Arc<Mutex<HashMap<PluginId, IdleStoppedPlugin>>>,
+  /// Maps the ids of plugins that were stopped for being idle to the ids of their relaunched
+  /// processes
+  relaunched: Mutex<HashMap<PluginId, PluginId>>,
+  relaunch_lock: tokio::sync::Mutex<()>,
+}
+
+struct HealthCheckTask {
+  config: HealthCheckConfig,
+  handle: JoinHandle<()>,
+}
+
+struct WatchdogTask {
+  config: WatchdogConfig,
+  handle: JoinHandle<()>,
+}
+
+struct IdleWatcherTask {
+  idle_timeout: Duration,
+  handle: JoinHandle<()>,
+}
+
+/// Everything needed to relaunch a plugin that was shut down by [PluginManager::set_idle_shutdown].
+struct IdleStoppedPlugin {
+  info: PluginInfo,
+  init_params: Value,
+  handshake: PluginHandshake,
+  running_state: RunningStateSender,
+  idle_timeout: Duration,
+  health_check: Option<HealthCheckConfig>,
+  watchdog: Option<WatchdogConfig>,
 }
 
 impl Default for PluginManager {
@@ -32,12 +228,52 @@ impl PluginManager {
     PluginManager {
       state: Arc::new(Mutex::new(PluginState {
         plugins: Vec::new(),
+        pid_dir: Some(std::env::temp_dir().join("appflowy_plugins")),
+        rpc_metrics: HashMap::new(),
+        config: PluginManagerConfig::default(),
       })),
       plugin_id_counter: Arc::new(Default::default()),
       operating_system: get_operating_system(),
+      health_checks: Arc::new(Mutex::new(HashMap::new())),
+      watchdogs: Arc::new(Mutex::new(HashMap::new())),
+      events: broadcast::channel(64).0,
+      idle_watchers: Mutex::new(HashMap::new()),
+      idle_stopped: Arc::new(Mutex::new(HashMap::new())),
+      relaunched: Mutex::new(HashMap::new()),
+      relaunch_lock: tokio::sync::Mutex::new(()),
     }
   }
 
+  /// Sets the directory where a pid file is kept for every running plugin, so
+  /// [Self::cleanup_orphans] can find the plugins of a host that crashed. Defaults to a directory
+  /// in the system's temp directory. `None` disables pid files.
+  pub fn with_pid_dir(self, pid_dir: Option<PathBuf>) -> Self {
+    self.state.lock().pid_dir = pid_dir;
+    self
+  }
+
+  /// Sets the buffers of the connections to plugins created from now on, e.g. a larger read
+  /// buffer for plugins that stream many tokens, see [PluginManagerConfig].
+  pub fn with_config(self, config: PluginManagerConfig) -> Self {
+    self.state.lock().config = config;
+    self
+  }
+
+  /// Terminates plugin processes left behind by a previous run that exited without stopping
+  /// them, e.g. because it crashed. Should be called on startup, before any plugin is created.
+  /// Plugins of other hosts that are still running are not touched. Returns the number of
+  /// terminated processes.
+  pub async fn cleanup_orphans(&self) -> Result<usize, PluginError> {
+    let pid_dir = match self.state.lock().pid_dir.clone() {
+      Some(pid_dir) => pid_dir,
+      None => return Ok(0),
+    };
+    let terminated = tokio::task::spawn_blocking(move || cleanup_orphans(&pid_dir))
+      .await
+      .map_err(|err| PluginError::Internal(err.into()))??;
+    Ok(terminated)
+  }
+
   pub async fn create_plugin(
     &self,
     plugin_info: PluginInfo,
@@ -48,13 +284,171 @@ impl PluginManager {
         "plugin not supported on this platform"
       )));
     }
+    self.check_dependencies(&plugin_info)?;
     let plugin_id = PluginId::from(self.plugin_id_counter.fetch_add(1, Ordering::SeqCst));
     let weak_state = WeakPluginState(Arc::downgrade(&self.state));
-    start_plugin_process(plugin_info, plugin_id, weak_state, running_state).await?;
+    let startup_timeout = plugin_info.startup_timeout;
+    let start = async {
+      start_plugin_process(plugin_info, plugin_id, weak_state, running_state).await?;
+      if startup_timeout.is_some() {
+        if let Some(plugin) = self.find_plugin(plugin_id)?.upgrade() {
+          // Any answer, even an error, means the plugin is processing messages
+          if let Err(err) = plugin.ping(None).await {
+            trace!("[RPC] startup ping of {} failed: {:?}", plugin, err);
+          }
+        }
+      }
+      Ok::<_, PluginError>(())
+    };
+
+    match startup_timeout {
+      None => start.await?,
+      Some(startup_timeout) => {
+        if tokio::time::timeout(startup_timeout, start).await.is_err() {
+          error!(
+            "[RPC] plugin {:?} did not start within {:?}",
+            plugin_id, startup_timeout
+          );
+          if let Err(err) = self.kill_plugin(plugin_id).await {
+            warn!("[RPC] failed to kill plugin {:?}: {:?}", plugin_id, err);
+          }
+          return Err(PluginError::StartupTimeout);
+        }
+      },
+    }
     Ok(plugin_id)
   }
 
+  /// Creates and initializes the plugins in the order given by their
+  /// [PluginInfo::depends_on], e.g. the embedding plugin before the chat plugin that uses it.
+  /// Dependencies that aren't part of `plugins` must be running already. If a plugin fails to
+  /// start, the plugins started by this call are removed again and the error is returned.
+  ///
+  /// Returns the ids of the plugins in the order of `plugins`.
+  pub async fn start_plugins(
+    &self,
+    plugins: Vec<PluginLaunch>,
+  ) -> Result<Vec<PluginId>, PluginError> {
+    let order = start_order(&plugins)?;
+    let mut plugins = plugins.into_iter().map(Some).collect::<Vec<_>>();
+    let mut started = Vec::with_capacity(plugins.len());
+    for index in order {
+      let launch = plugins[index].take().unwrap();
+      let name = launch.info.name.clone();
+      let result = async {
+        let plugin_id = self
+          .create_plugin(launch.info, launch.running_state)
+          .await?;
+        if let Err(err) = self.init_plugin(plugin_id, launch.init_params).await {
+          let _ = self.remove_plugin(plugin_id).await;
+          return Err(err);
+        }
+        Ok::<_, PluginError>(plugin_id)
+      }
+      .await;
+
+      match result {
+        Ok(plugin_id) => started.push((index, plugin_id)),
+        Err(err) => {
+          error!("[RPC] failed to start plugin {}: {:?}", name, err);
+          // Dependencies started for the failed plugin aren't needed anymore
+          for (_, plugin_id) in started.into_iter().rev() {
+            let _ = self.remove_plugin(plugin_id).await;
+          }
+          return Err(err);
+        },
+      }
+    }
+
+    started.sort_by_key(|(index, _)| *index);
+    Ok(
+      started
+        .into_iter()
+        .map(|(_, plugin_id)| plugin_id)
+        .collect(),
+    )
+  }
+
+  /// Fails with [PluginError::MissingDependency] if a dependency of the plugin isn't running and
+  /// initialized. Dependencies that were shut down for being idle count as running, since they're
+  /// relaunched on demand.
+  fn check_dependencies(&self, plugin_info: &PluginInfo) -> Result<(), PluginError> {
+    for dependency in &plugin_info.depends_on {
+      let is_running = self
+        .state
+        .lock()
+        .plugins
+        .iter()
+        .any(|plugin| &plugin.name == dependency && plugin.init_params().is_some());
+      let is_idle = self
+        .idle_stopped
+        .lock()
+        .values()
+        .any(|plugin| &plugin.info.name == dependency);
+      if !is_running && !is_idle {
+        error!(
+          "[RPC] plugin {} requires {}, which is not running",
+          plugin_info.name, dependency
+        );
+        return Err(PluginError::MissingDependency(dependency.clone()));
+      }
+    }
+    Ok(())
+  }
+
+  /// Starts one instance of the plugin for every running state sender, e.g. to run several
+  /// embedding workers in parallel. Requests can be spread over the instances with a
+  /// [PluginRouter](crate::router::PluginRouter). Each instance still has to be initialized with
+  /// [Self::init_plugin].
+  pub async fn create_plugin_instances(
+    &self,
+    plugin_info: PluginInfo,
+    running_states: Vec<RunningStateSender>,
+  ) -> Result<Vec<PluginId>, PluginError> {
+    let mut plugin_ids = Vec::with_capacity(running_states.len());
+    for (index, running_state) in running_states.into_iter().enumerate() {
+      let mut info = plugin_info.clone();
+      info.name = format!("{}-{}", plugin_info.name, index);
+      match self.create_plugin(info, running_state).await {
+        Ok(plugin_id) => plugin_ids.push(plugin_id),
+        Err(err) => {
+          // Don't leave the instances that already started behind
+          for plugin_id in plugin_ids {
+            let _ = self.remove_plugin(plugin_id).await;
+          }
+          return Err(err);
+        },
+      }
+    }
+    Ok(plugin_ids)
+  }
+
+  /// Returns the running plugin with the given id. Fails for a plugin that was shut down for
+  /// being idle, use [Self::acquire_plugin] to send requests to it.
   pub async fn get_plugin(&self, plugin_id: PluginId) -> Result<Weak<Plugin>, PluginError> {
+    self.find_plugin(plugin_id)
+  }
+
+  /// Returns the plugin with the given id to send requests to. A plugin that was shut down for
+  /// being idle is relaunched first.
+  pub async fn acquire_plugin(&self, plugin_id: PluginId) -> Result<Weak<Plugin>, PluginError> {
+    let err = match self.find_plugin(plugin_id) {
+      Ok(plugin) => return Ok(plugin),
+      Err(err) => err,
+    };
+    // The relaunched plugin may have been shut down for being idle again
+    let relaunched_id = self.relaunched.lock().get(&plugin_id).copied();
+    if let Some(relaunched_id) = relaunched_id {
+      return Box::pin(self.acquire_plugin(relaunched_id)).await;
+    }
+    if self.idle_stopped.lock().contains_key(&plugin_id) {
+      self.relaunch_idle_plugin(plugin_id).await
+    } else {
+      Err(err)
+    }
+  }
+
+  fn find_plugin(&self, plugin_id: PluginId) -> Result<Weak<Plugin>, PluginError> {
     let state = self.state.lock();
     let plugin = state
       .plugins
@@ -64,6 +458,217 @@ impl PluginManager {
     Ok(Arc::downgrade(plugin))
   }
 
+  /// The handshake of the plugin, also while it's shut down for being idle, so its version and
+  /// capabilities can be looked up without relaunching it.
+  pub fn handshake(&self, plugin_id: PluginId) -> Option<PluginHandshake> {
+    if let Some(idle_plugin) = self.idle_stopped.lock().get(&plugin_id) {
+      return Some(idle_plugin.handshake.clone());
+    }
+    let plugin = self.find_plugin(plugin_id).ok()?.upgrade()?;
+    Some(plugin.handshake())
+  }
+
+  /// Whether the plugin was shut down for being idle and not relaunched yet, see
+  /// [Self::set_idle_shutdown].
+  pub fn is_idle_stopped(&self, plugin_id: PluginId) -> bool {
+    self.idle_stopped.lock().contains_key(&plugin_id)
+  }
+
+  /// Shuts the plugin down once no request was sent to it for `idle_timeout`, to free the memory
+  /// of the loaded model. The plugin is relaunched with the same `initialize` params on the next
+  /// [Self::acquire_plugin] call.
+  pub fn set_idle_shutdown(&self, id: PluginId, idle_timeout: Duration) -> Result<(), PluginError> {
+    let plugin = self.find_plugin(id)?;
+    let state = Arc::downgrade(&self.state);
+    let health_checks = self.health_checks.clone();
+    let watchdogs = self.watchdogs.clone();
+    let idle_stopped = self.idle_stopped.clone();
+    let check_interval = idle_timeout.min(Duration::from_secs(60));
+    let handle = tokio::spawn(async move {
+      let mut interval = tokio::time::interval(check_interval);
+      loop {
+        interval.tick().await;
+        let plugin = match plugin.upgrade() {
+          Some(plugin) => plugin,
+          None => break,
+        };
+        // A suspended plugin wouldn't answer the shutdown request
+        if plugin.pending_requests() > 0
+          || plugin.idle_duration() < idle_timeout
+          || plugin.running_state.borrow().is_suspended()
+        {
+          continue;
+        }
+        let init_params = match plugin.init_params() {
+          Some(init_params) => init_params,
+          None => continue,
+        };
+        let state = match state.upgrade() {
+          Some(state) => state,
+          None => break,
+        };
+
+        let health_check = health_checks
+          .lock()
+          .get(&id)
+          .map(|task| task.config.clone());
+        let watchdog = watchdogs.lock().get(&id).map(|task| task.config.clone());
+        let removed = {
+          let mut state = state.lock();
+          // Checked again under the lock, as requests can't find the plugin once it's removed
+          if plugin.pending_requests() > 0 || plugin.idle_duration() < idle_timeout {
+            continue;
+          }
+          idle_stopped.lock().insert(
+            id,
+            IdleStoppedPlugin {
+              info: plugin.info.clone(),
+              init_params,
+              handshake: plugin.handshake(),
+              running_state: plugin.running_state.clone(),
+              idle_timeout,
+              health_check,
+              watchdog,
+            },
+          );
+          let idx = state.plugins.iter().position(|p| p.id == id);
+          idx.map(|idx| state.plugins.remove(idx))
+        };
+        info!(
+          "[RPC] plugin {} was idle for {:?}, shutting it down",
+          plugin, idle_timeout
+        );
+        drop(plugin);
+        if let Some(task) = health_checks.lock().remove(&id) {
+          task.handle.abort();
+        }
+        if let Some(task) = watchdogs.lock().remove(&id) {
+          task.handle.abort();
+        }
+        if let Some(plugin) = removed {
+          // Shutting down waits for the plugin to answer the shutdown request
+          let _ = tokio::task::spawn_blocking(move || {
+            plugin.shutdown();
+            plugin.terminate_process_group();
+          })
+          .await;
+        }
+        break;
+      }
+    });
+
+    let task = IdleWatcherTask {
+      idle_timeout,
+      handle,
+    };
+    if let Some(old) = self.idle_watchers.lock().insert(id, task) {
+      old.handle.abort();
+    }
+    Ok(())
+  }
+
+  async fn relaunch_idle_plugin(&self, id: PluginId) -> Result<Weak<Plugin>, PluginError> {
+    let guard = self.relaunch_lock.lock().await;
+    // The plugin may have been relaunched while waiting for the lock
+    let relaunched_id = self.relaunched.lock().get(&id).copied();
+    if let Some(relaunched_id) = relaunched_id {
+      drop(guard);
+      return Box::pin(self.acquire_plugin(relaunched_id)).await;
+    }
+
+    let idle_plugin = self
+      .idle_stopped
+      .lock()
+      .remove(&id)
+      .ok_or(PluginError::PluginNotConnected)?;
+    info!("[RPC] relaunching idle plugin {:?}", id);
+    let new_id = self
+      .create_plugin(idle_plugin.info, idle_plugin.running_state)
+      .await?;
+    let plugin = self.init_plugin(new_id, idle_plugin.init_params).await?;
+    self.relaunched.lock().insert(id, new_id);
+    if let Some(health_check) = idle_plugin.health_check {
+      self.start_health_check(new_id, health_check).await?;
+    }
+    if let Some(watchdog) = idle_plugin.watchdog {
+      self.start_watchdog(new_id, watchdog)?;
+    }
+    self.set_idle_shutdown(new_id, idle_plugin.idle_timeout)?;
+    Ok(Arc::downgrade(&plugin))
+  }
+
+  /// Returns all running plugins, in the order they were started.
+  pub fn list_plugins(&self) -> Vec<PluginSnapshot> {
+    self
+      .state
+      .lock()
+      .plugins
+      .iter()
+      .map(|plugin| PluginSnapshot {
+        id: plugin.id,
+        name: plugin.name.clone(),
+        state: plugin.running_state.borrow().clone(),
+        pid: plugin.pid.unwrap_or_default(),
+        remote_addr: match &plugin.info.transport {
+          PluginTransport::Tcp { addr, .. } => Some(addr.clone()),
+          _ => None,
+        },
+        uptime: plugin.started_at.elapsed(),
+      })
+      .collect()
+  }
+
+  /// Returns the number and latency of the requests sent to the plugins since the manager was
+  /// created, per plugin name and method. Restarted plugins keep adding to the same metrics.
+  pub fn rpc_metrics(&self) -> Vec<MethodMetrics> {
+    let state = self.state.lock();
+    let mut metrics = state
+      .rpc_metrics
+      .iter()
+      .flat_map(|(plugin, metrics)| metrics.snapshot(plugin))
+      .collect::<Vec<_>>();
+    metrics.sort_by(|a, b| (&a.plugin, &a.method).cmp(&(&b.plugin, &b.method)));
+    metrics
+  }
+
+  /// Samples the memory and CPU usage of the plugin process. Blocks for a short interval because
+  /// CPU usage is measured between two samples. Fails for remote plugins.
+  pub async fn plugin_metrics(&self, plugin_id: PluginId) -> Result<PluginMetrics, PluginError> {
+    let (pid, started_at) = {
+      let state = self.state.lock();
+      let plugin = state
+        .plugins
+        .iter()
+        .find(|p| p.id == plugin_id)
+        .ok_or(PluginError::PluginNotConnected)?;
+      let pid = plugin.pid.ok_or_else(|| {
+        PluginError::Internal(anyhow!("no local process for remote plugin {}", plugin))
+      })?;
+      (pid, plugin.started_at)
+    };
+
+    let (rss_bytes, cpu_percent) = tokio::task::spawn_blocking(move || {
+      let pid = sysinfo::Pid::from_u32(pid);
+      let refresh_kind = ProcessRefreshKind::new().with_cpu().with_memory();
+      let mut system = System::new();
+      system.refresh_process_specifics(pid, refresh_kind);
+      std::thread::sleep(sysinfo::MINIMUM_CPU_UPDATE_INTERVAL);
+      system.refresh_process_specifics(pid, refresh_kind);
+      system
+        .process(pid)
+        .map(|process| (process.memory(), process.cpu_usage()))
+    })
+    .await
+    .map_err(|err| PluginError::Internal(err.into()))?
+    .ok_or(PluginError::PluginNotConnected)?;
+
+    Ok(PluginMetrics {
+      rss_bytes,
+      cpu_percent,
+      uptime: started_at.elapsed(),
+    })
+  }
+
   #[instrument(skip(self), err)]
   pub async fn remove_plugin(&self, id: PluginId) -> Result<(), PluginError> {
     if self.operating_system.is_not_desktop() {
@@ -73,10 +678,350 @@ impl PluginManager {
     }
 
     info!("[RPC] removing plugin {:?}", id);
+    self.stop_health_check(id);
+    self.stop_watchdog(id);
+    self.stop_idle_shutdown(id);
     self.state.lock().plugin_disconnect(id, Ok(()));
     Ok(())
   }
 
+  /// Terminates the plugin process without sending the `shutdown` request first. Use it when the
+  /// plugin stopped responding and [Self::remove_plugin] would never complete.
+  #[instrument(skip(self), err)]
+  pub async fn kill_plugin(&self, id: PluginId) -> Result<(), PluginError> {
+    info!("[RPC] killing plugin {:?}", id);
+    self.stop_health_check(id);
+    self.stop_watchdog(id);
+    self.stop_idle_shutdown(id);
+    let plugin = {
+      let mut state = self.state.lock();
+      let idx = state
+        .plugins
+        .iter()
+        .position(|p| p.id == id)
+        .ok_or(PluginError::PluginNotConnected)?;
+      state.plugins.remove(idx)
+    };
+    // The RPC loop of the plugin sees its stdout closing and reports the plugin as stopped
+    plugin.kill()
+  }
+
+  /// Pauses the plugin process (SIGSTOP on Unix, NtSuspendProcess on Windows) while keeping its
+  /// model loaded, e.g. while the user runs something heavy. Requests sent in the meantime are
+  /// answered after [Self::resume].
+  pub async fn suspend(&self, id: PluginId) -> Result<(), PluginError> {
+    let plugin = self
+      .find_plugin(id)?
+      .upgrade()
+      .ok_or(PluginError::PluginNotConnected)?;
+    info!("[RPC] suspending plugin {}", plugin);
+    plugin.suspend()
+  }
+
+  pub async fn resume(&self, id: PluginId) -> Result<(), PluginError> {
+    let plugin = self
+      .find_plugin(id)?
+      .upgrade()
+      .ok_or(PluginError::PluginNotConnected)?;
+    info!("[RPC] resuming plugin {}", plugin);
+    plugin.resume()
+  }
+
+  /// Periodically pings the plugin. When `config.max_failures` consecutive pings fail or time
+  /// out, the plugin's running state becomes [RunningState::Unhealthy], so hosts can restart it
+  /// instead of waiting on requests that will never be answered. The state goes back to
+  /// [RunningState::Running] once a ping succeeds again.
+  pub async fn start_health_check(
+    &self,
+    id: PluginId,
+    config: HealthCheckConfig,
+  ) -> Result<(), PluginError> {
+    let plugin = self.find_plugin(id)?;
+    let task_config = config.clone();
+    let handle = tokio::spawn(async move {
+      let config = task_config;
+      let mut failures = 0;
+      loop {
+        tokio::time::sleep(config.interval).await;
+        let plugin = match plugin.upgrade() {
+          Some(plugin) => plugin,
+          None => break,
+        };
+        // A suspended plugin can't answer
+        if plugin.running_state.borrow().is_suspended() {
+          failures = 0;
+          continue;
+        }
+
+        match plugin.ping(Some(config.timeout)).await {
+          Ok(_) => {
+            failures = 0;
+            if plugin.running_state.borrow().is_unhealthy() {
+              info!("[RPC] plugin {} is healthy again", plugin);
+              let _ = plugin
+                .running_state
+                .send(RunningState::Running { plugin_id: id });
+            }
+          },
+          Err(PluginError::Timeout) => {
+            warn!("[RPC] ping plugin {} timeout", plugin);
+            failures += 1;
+          },
+          Err(err) => {
+            warn!("[RPC] ping plugin {} failed: {:?}", plugin, err);
+            failures += 1;
+          },
+        }
+
+        if failures >= config.max_failures && !plugin.running_state.borrow().is_unhealthy() {
+          error!(
+            "[RPC] plugin {} did not answer {} pings, mark it as unhealthy",
+            plugin, failures
+          );
+          let _ = plugin
+            .running_state
+            .send(RunningState::Unhealthy { plugin_id: id });
+        }
+      }
+    });
+
+    if let Some(old) = self
+      .health_checks
+      .lock()
+      .insert(id, HealthCheckTask { config, handle })
+    {
+      old.handle.abort();
+    }
+    Ok(())
+  }
+
+  pub fn stop_health_check(&self, id: PluginId) {
+    if let Some(task) = self.health_checks.lock().remove(&id) {
+      task.handle.abort();
+    }
+  }
+
+  /// Watches the requests sent to the plugin and reports requests that got neither a response
+  /// nor a stream message for [WatchdogConfig::threshold] as [PluginEvent::PluginUnresponsive],
+  /// so a wedged model doesn't hang the app silently.
+  pub fn start_watchdog(&self, id: PluginId, config: WatchdogConfig) -> Result<(), PluginError> {
+    let plugin = self.find_plugin(id)?;
+    let events = self.events.clone();
+    let task_config = config.clone();
+    let handle = tokio::spawn(async move {
+      let config = task_config;
+      let mut interval = tokio::time::interval(config.interval);
+      let mut reported = HashSet::new();
+      // Requests don't make progress while the plugin is suspended
+      let mut resumed_at = Instant::now();
+      loop {
+        interval.tick().await;
+        let plugin = match plugin.upgrade() {
+          Some(plugin) => plugin,
+          None => break,
+        };
+        if plugin.running_state.borrow().is_suspended() {
+          resumed_at = Instant::now();
+          continue;
+        }
+
+        let threshold = match config.keepalive_threshold {
+          Some(keepalive_threshold) if plugin.supports(KEEPALIVE) => keepalive_threshold,
+          _ => config.threshold,
+        };
+        let stalled = plugin.stalled_requests(threshold);
+        // Forget requests that finished or made progress again
+        reported.retain(|id| stalled.iter().any(|request| request.id == *id));
+        if resumed_at.elapsed() < threshold {
+          continue;
+        }
+        for request in stalled {
+          if !reported.insert(request.id) {
+            continue;
+          }
+          error!(
+            "[RPC] plugin {} did not answer {} for {:?}",
+            plugin, request.method, request.idle
+          );
+          let _ = events.send(PluginEvent::PluginUnresponsive {
+            plugin_id: id,
+            method: request.method,
+            idle: request.idle,
+          });
+          if config.restart {
+            plugin.fail_request(request.id, PluginError::Unresponsive);
+            if !plugin.running_state.borrow().is_unhealthy() {
+              let _ = plugin
+                .running_state
+                .send(RunningState::Unhealthy { plugin_id: id });
+            }
+          }
+        }
+      }
+    });
+
+    if let Some(old) = self
+      .watchdogs
+      .lock()
+      .insert(id, WatchdogTask { config, handle })
+    {
+      old.handle.abort();
+    }
+    Ok(())
+  }
+
+  pub fn stop_watchdog(&self, id: PluginId) {
+    if let Some(task) = self.watchdogs.lock().remove(&id) {
+      task.handle.abort();
+    }
+  }
+
+  /// Subscribes to [PluginEvent]s of all plugins.
+  pub fn subscribe_events(&self) -> broadcast::Receiver<PluginEvent> {
+    self.events.subscribe()
+  }
+
+  fn stop_idle_shutdown(&self, id: PluginId) {
+    if let Some(task) = self.idle_watchers.lock().remove(&id) {
+      task.handle.abort();
+    }
+    self.idle_stopped.lock().remove(&id);
+    self
+      .relaunched
+      .lock()
+      .retain(|old_id, new_id| *old_id != id && *new_id != id);
+  }
+
+  /// Stops the plugin and starts it again with the same [PluginInfo] and `initialize` params.
+  /// The restarted plugin gets a new id, which is also reported through its running state:
+  /// [RunningState::Stopped] or [RunningState::UnexpectedStop] for the old process, followed by
+  /// the states of the new one. Its health check, watchdog and idle shutdown carry over to the
+  /// new id.
+  pub async fn restart_plugin(&self, id: PluginId) -> Result<PluginId, PluginError> {
+    self.respawn_plugin(id, None).await
+  }
+
+  /// Replaces the plugin with a process of `new_exec_path`, e.g. after the auto-updater
+  /// downloaded a new version of the plugin. Requests in flight are given up to 30 seconds to
+  /// finish before the old process is stopped. The new process is initialized with the same
+  /// `initialize` params and gets a new id, like with [Self::restart_plugin].
+  pub async fn reload_binary(
+    &self,
+    id: PluginId,
+    new_exec_path: PathBuf,
+  ) -> Result<PluginId, PluginError> {
+    if !new_exec_path.is_file() {
+      return Err(PluginError::Internal(anyhow!(
+        "plugin binary does not exist: {:?}",
+        new_exec_path
+      )));
+    }
+
+    let plugin = self
+      .acquire_plugin(id)
+      .await?
+      .upgrade()
+      .ok_or(PluginError::PluginNotConnected)?;
+    info!(
+      "[RPC] reloading plugin {} from {:?}, waiting for {} requests",
+      plugin,
+      new_exec_path,
+      plugin.pending_requests()
+    );
+    let drained = tokio::time::timeout(DRAIN_TIMEOUT, async {
+      while plugin.pending_requests() > 0 {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+      }
+    })
+    .await;
+    if drained.is_err() {
+      warn!(
+        "[RPC] plugin {} still has {} requests in flight, reloading anyway",
+        plugin,
+        plugin.pending_requests()
+      );
+    }
+    let id = plugin.id;
+    drop(plugin);
+    self.respawn_plugin(id, Some(new_exec_path)).await
+  }
+
+  async fn respawn_plugin(
+    &self,
+    id: PluginId,
+    new_exec_path: Option<PathBuf>,
+  ) -> Result<PluginId, PluginError> {
+    let plugin = self
+      .acquire_plugin(id)
+      .await?
+      .upgrade()
+      .ok_or(PluginError::PluginNotConnected)?;
+    // A plugin that was stopped for being idle is relaunched with a new id
+    let id = plugin.id;
+    let init_params = plugin
+      .init_params()
+      .ok_or_else(|| PluginError::Internal(anyhow!("plugin {} was never initialized", plugin)))?;
+    let mut plugin_info = plugin.info.clone();
+    if let Some(new_exec_path) = new_exec_path {
+      plugin_info.exec_path = new_exec_path;
+    }
+    let running_state = plugin.running_state.clone();
+    let health_check = self
+      .health_checks
+      .lock()
+      .get(&id)
+      .map(|task| task.config.clone());
+    let watchdog = self
+      .watchdogs
+      .lock()
+      .get(&id)
+      .map(|task| task.config.clone());
+    let idle_timeout = self
+      .idle_watchers
+      .lock()
+      .get(&id)
+      .map(|task| task.idle_timeout);
+    let is_unhealthy = running_state.borrow().is_unhealthy();
+    drop(plugin);
+
+    info!("[RPC] restarting plugin {:?}", id);
+    let mut rx = WatchStream::new(running_state.subscribe());
+    if is_unhealthy {
+      self.kill_plugin(id).await?;
+    } else {
+      self.remove_plugin(id).await?;
+    }
+
+    // Wait for the old process to exit, otherwise its final state could overwrite the states of
+    // the new process.
+    let _ = tokio::time::timeout(Duration::from_secs(5), async {
+      while let Some(state) = rx.next().await {
+        let stopped = matches!(
+          state,
+          RunningState::Stopped { .. } | RunningState::UnexpectedStop { .. }
+        );
+        if stopped && state.plugin_id() == Some(id) {
+          break;
+        }
+      }
+    })
+    .await;
+
+    let new_id = self.create_plugin(plugin_info, running_state).await?;
+    self.init_plugin(new_id, init_params).await?;
+    if let Some(health_check) = health_check {
+      self.start_health_check(new_id, health_check).await?;
+    }
+    if let Some(watchdog) = watchdog {
+      self.start_watchdog(new_id, watchdog)?;
+    }
+    if let Some(idle_timeout) = idle_timeout {
+      self.set_idle_shutdown(new_id, idle_timeout)?;
+    }
+    info!("[RPC] plugin {:?} restarted as {:?}", id, new_id);
+    Ok(new_id)
+  }
+
   pub async fn init_plugin(
     &self,
     id: PluginId,
@@ -90,10 +1035,20 @@ impl PluginManager {
     }
 
     let plugin = self
-      .get_plugin(id)
-      .await?
+      .find_plugin(id)?
       .upgrade()
       .ok_or_else(|| PluginError::PluginNotConnected)?;
+    if let Some(schema) = plugin.init_schema().await? {
+      let violations = validate(&schema, &init_params);
+      if !violations.is_empty() {
+        error!(
+          "[RPC] initialize params of {} don't match its schema: {:?}",
+          plugin, violations
+        );
+        return Err(PluginError::InvalidInitParams(violations.join("; ")));
+      }
+    }
+    plugin.set_init_params(init_params.clone());
     plugin.initialize(init_params)?;
     Ok(plugin.clone())
   }
@@ -105,11 +1060,11 @@ impl PluginManager {
     request: Value,
   ) -> Result<P::ValueType, PluginError> {
     let plugin = self
-      .get_plugin(id)
+      .acquire_plugin(id)
       .await?
       .upgrade()
       .ok_or_else(|| PluginError::PluginNotConnected)?;
-    let resp = plugin.request(method, &request)?;
+    let resp = plugin.request(method, &request, None)?;
     let value = P::parse_json(resp)?;
     Ok(value)
   }
@@ -121,23 +1076,33 @@ impl PluginManager {
     request: Value,
   ) -> Result<P::ValueType, PluginError> {
     let plugin = self
-      .get_plugin(id)
+      .acquire_plugin(id)
       .await?
       .upgrade()
       .ok_or_else(|| PluginError::PluginNotConnected)?;
-    let value = plugin.async_request::<P>(method, &request).await?;
+    let value = plugin.async_request::<P>(method, &request, None).await?;
     Ok(value)
   }
 }
 
 pub struct PluginState {
   plugins: Vec<Arc<Plugin>>,
+  pid_dir: Option<PathBuf>,
+  /// By [PluginInfo::name]
+  rpc_metrics: HashMap<String, Arc<RpcMetrics>>,
+  config: PluginManagerConfig,
 }
 
 impl PluginState {
   pub fn plugin_connect(&mut self, plugin: Result<Plugin, io::Error>) {
     match plugin {
-      Ok(plugin) => {
+      Ok(mut plugin) => {
+        if let (Some(pid_dir), Some(pid)) = (&self.pid_dir, plugin.pid) {
+          match PidFile::create(pid_dir, pid, &plugin.info.exec_path) {
+            Ok(pid_file) => plugin.pid_file = Some(Arc::new(pid_file)),
+            Err(err) => warn!("[RPC] failed to write pid file of {}: {:?}", plugin, err),
+          }
+        }
         info!("[RPC] {} connected", plugin);
         self.plugins.push(Arc::new(plugin));
       },
@@ -161,6 +1126,7 @@ impl PluginState {
       Some(idx) => {
         let plugin = self.plugins.remove(idx);
         plugin.shutdown();
+        plugin.terminate_process_group();
         Some(plugin)
       },
       None => {
@@ -179,6 +1145,22 @@ impl WeakPluginState {
     self.0.upgrade()
   }
 
+  /// The metrics of the plugins named `name`, see [PluginManager::rpc_metrics].
+  pub(crate) fn rpc_metrics(&self, name: &str) -> Option<Arc<RpcMetrics>> {
+    let state = self.upgrade()?;
+    let mut state = state.lock();
+    let metrics = state.rpc_metrics.entry(name.to_string()).or_default();
+    Some(metrics.clone())
+  }
+
+  /// The config of the [PluginManager], or the default one if it was dropped.
+  pub(crate) fn config(&self) -> PluginManagerConfig {
+    self
+      .upgrade()
+      .map(|state| state.lock().config.clone())
+      .unwrap_or_default()
+  }
+
   pub fn plugin_connect(&self, plugin: Result<Plugin, io::Error>) {
     if let Some(state) = self.upgrade() {
       state.lock().plugin_connect(plugin)
@@ -204,3 +1186,73 @@ impl Handler for WeakPluginState {
     Ok(ResponsePayload::empty_json())
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tokio::sync::watch;
+
+  fn launch(name: &str, depends_on: &[&str]) -> PluginLaunch {
+    let (running_state, _) = watch::channel(RunningState::Connecting);
+    PluginLaunch {
+      info: PluginInfo {
+        name: name.to_string(),
+        exec_path: PathBuf::from(name),
+        resource_limits: Default::default(),
+        priority: Default::default(),
+        env: Default::default(),
+        args: vec![],
+        sandbox: None,
+        startup_timeout: None,
+        request_queue: None,
+        compression: None,
+        transport: Default::default(),
+        depends_on: depends_on.iter().map(|name| name.to_string()).collect(),
+        max_message_size: None,
+        recording: None,
+      },
+      init_params: Value::Null,
+      running_state: Arc::new(running_state),
+    }
+  }
+
+  #[test]
+  fn start_order_test() {
+    let plugins = vec![
+      launch("chat", &["embedding", "ocr"]),
+      launch("embedding", &[]),
+      launch("ocr", &["embedding"]),
+      launch("search", &[]),
+    ];
+    assert_eq!(start_order(&plugins).unwrap(), vec![1, 3, 2, 0]);
+    assert!(start_order(&[]).unwrap().is_empty());
+  }
+
+  #[test]
+  fn start_order_ignores_dependencies_outside_the_batch_test() {
+    // Checked against the running plugins by `check_dependencies` instead
+    let plugins = vec![launch("chat", &["embedding"]), launch("ocr", &[])];
+    assert_eq!(start_order(&plugins).unwrap(), vec![0, 1]);
+  }
+
+  #[test]
+  fn start_order_detects_cycle_test() {
+    let plugins = vec![
+      launch("embedding", &[]),
+      launch("chat", &["ocr", "embedding"]),
+      launch("ocr", &["chat"]),
+    ];
+    match start_order(&plugins) {
+      Err(PluginError::Internal(err)) => {
+        assert_eq!(
+          err.to_string(),
+          "dependency cycle between plugins: chat, ocr"
+        )
+      },
+      other => panic!("unexpected result: {:?}", other),
+    }
+
+    let plugins = vec![launch("chat", &["chat"])];
+    assert!(start_order(&plugins).is_err());
+  }
+}
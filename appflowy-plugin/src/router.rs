@@ -0,0 +1,74 @@
+use crate::core::plugin::{Plugin, PluginId};
+use crate::error::PluginError;
+use crate::manager::PluginManager;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum RoutingStrategy {
+  /// Requests are sent to the instances in turn
+  RoundRobin,
+  /// Requests are sent to the instance with the fewest requests in flight
+  #[default]
+  LeastBusy,
+}
+
+/// Spreads requests over several instances of the same plugin binary, created with
+/// [PluginManager::create_plugin_instances].
+pub struct PluginRouter {
+  plugin_manager: Arc<PluginManager>,
+  plugin_ids: Vec<PluginId>,
+  strategy: RoutingStrategy,
+  next: AtomicUsize,
+}
+
+impl PluginRouter {
+  pub fn new(
+    plugin_manager: Arc<PluginManager>,
+    plugin_ids: Vec<PluginId>,
+    strategy: RoutingStrategy,
+  ) -> Self {
+    Self {
+      plugin_manager,
+      plugin_ids,
+      strategy,
+      next: AtomicUsize::new(0),
+    }
+  }
+
+  pub fn plugin_ids(&self) -> &[PluginId] {
+    &self.plugin_ids
+  }
+
+  /// Returns the instance the next request should be sent to. Instances that are no longer
+  /// connected are skipped.
+  pub async fn route(&self) -> Result<Weak<Plugin>, PluginError> {
+    let mut plugins = Vec::with_capacity(self.plugin_ids.len());
+    for plugin_id in &self.plugin_ids {
+      if let Some(plugin) = self
+        .plugin_manager
+        .acquire_plugin(*plugin_id)
+        .await
+        .ok()
+        .and_then(|plugin| plugin.upgrade())
+      {
+        plugins.push(plugin);
+      }
+    }
+
+    if plugins.is_empty() {
+      return Err(PluginError::PluginNotConnected);
+    }
+
+    // Rotating the start index also spreads requests evenly among equally busy instances
+    let start = self.next.fetch_add(1, Ordering::Relaxed) % plugins.len();
+    let plugin = match self.strategy {
+      RoutingStrategy::RoundRobin => &plugins[start],
+      RoutingStrategy::LeastBusy => (0..plugins.len())
+        .map(|offset| &plugins[(start + offset) % plugins.len()])
+        .min_by_key(|plugin| plugin.pending_requests())
+        .unwrap(),
+    };
+    Ok(Arc::downgrade(plugin))
+  }
+}